@@ -29,7 +29,7 @@ use tikv::raftstore::Result;
 use tikv::server::transport::RaftStoreRouter;
 use tikv::storage::engine::raftkv::CmdRes;
 use tikv::storage::engine::{
-    Callback as EngineCallback, CbContext, Modify, Result as EngineResult,
+    BatchCallback, Callback as EngineCallback, CbContext, Modify, Result as EngineResult,
 };
 use tikv::storage::types::Key;
 use tikv::storage::{Engine, RaftKv, ALL_CFS, CF_DEFAULT};
@@ -69,7 +69,10 @@ impl SyncBenchRouter {
                     let cmd_type = request.get_requests()[0].get_cmd_type();
                     resp.set_cmd_type(cmd_type);
                     response.mut_responses().push(resp);
-                    cb(WriteResponse { response })
+                    cb(WriteResponse {
+                        response,
+                        applied_index: 0,
+                    })
                 }
                 _ => unreachable!(),
             }
@@ -157,6 +160,31 @@ fn bench_async_snapshot(b: &mut test::Bencher) {
     });
 }
 
+#[bench]
+fn bench_async_batch_snapshots(b: &mut test::Bencher) {
+    let leader = util::new_peer(2, 3);
+    let mut region = Region::new();
+    region.set_id(1);
+    region.set_start_key(vec![]);
+    region.set_end_key(vec![]);
+    region.mut_peers().push(leader.clone());
+    region.mut_region_epoch().set_version(2);
+    region.mut_region_epoch().set_conf_ver(5);
+    let (_tmp, db) = new_engine();
+    let kv = RaftKv::new(SyncBenchRouter::new(region.clone(), db));
+
+    let mut ctx = Context::new();
+    ctx.set_region_id(region.get_id());
+    ctx.set_region_epoch(region.get_region_epoch().clone());
+    ctx.set_peer(leader.clone());
+    b.iter(|| {
+        let on_finished: BatchCallback<RegionSnapshot> = Box::new(move |results| {
+            test::black_box(results);
+        });
+        kv.async_batch_snapshot(vec![ctx.clone(); 16], on_finished).unwrap();
+    });
+}
+
 #[bench]
 fn bench_async_write(b: &mut test::Bencher) {
     let leader = util::new_peer(2, 3);
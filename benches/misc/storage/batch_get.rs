@@ -0,0 +1,79 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use test::Bencher;
+
+use kvproto::kvrpcpb::Context;
+
+use test_storage::{SyncTestStorage, SyncTestStorageBuilder};
+use test_util::*;
+use tikv::storage::{Key, Mutation, RocksEngine};
+
+const REGION_COUNT: u64 = 3;
+const KEYS_PER_REGION: usize = 32;
+
+fn new_region_context(region_id: u64) -> Context {
+    let mut ctx = Context::new();
+    ctx.set_region_id(region_id);
+    ctx
+}
+
+/// Writes `REGION_COUNT * KEYS_PER_REGION` keys and returns them grouped by region.
+fn prepare_store() -> (SyncTestStorage<RocksEngine>, Vec<Vec<Key>>) {
+    let store = SyncTestStorageBuilder::new().build().unwrap();
+    let kvs: Vec<_> = KvGenerator::new(32, 128)
+        .take(REGION_COUNT as usize * KEYS_PER_REGION)
+        .collect();
+    for &(ref k, ref v) in &kvs {
+        store
+            .prewrite(
+                Context::new(),
+                vec![Mutation::Put((Key::from_raw(k), v.clone()))],
+                k.clone(),
+                1,
+            )
+            .unwrap();
+        store
+            .commit(Context::new(), vec![Key::from_raw(k)], 1, 2)
+            .unwrap();
+    }
+    let keys = kvs
+        .chunks(KEYS_PER_REGION)
+        .map(|c| c.iter().map(|&(ref k, _)| Key::from_raw(k)).collect())
+        .collect();
+    (store, keys)
+}
+
+/// Keys spread over several regions, read with one `batch_get` per region.
+#[bench]
+fn bench_batch_get_per_region(b: &mut Bencher) {
+    let (store, keys) = prepare_store();
+    b.iter(|| {
+        for (i, keys) in keys.iter().enumerate() {
+            let ctx = new_region_context(i as u64 + 1);
+            store.batch_get(ctx, keys, 3).unwrap();
+        }
+    })
+}
+
+/// Keys spread over several regions, read with a single `batch_get_multi_region`.
+#[bench]
+fn bench_batch_get_multi_region(b: &mut Bencher) {
+    let (store, keys) = prepare_store();
+    let ctxs: Vec<_> = (1..REGION_COUNT + 1).map(new_region_context).collect();
+    b.iter(|| {
+        store
+            .batch_get_multi_region(ctxs.clone(), keys.clone(), 3)
+            .unwrap();
+    })
+}
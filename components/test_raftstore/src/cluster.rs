@@ -82,7 +82,11 @@ pub trait Simulator {
     ) -> Result<RaftCmdResponse> {
         let (cb, rx) = make_cb(&request);
 
-        self.async_command_on_node(node_id, request, cb)?;
+        match self.async_command_on_node(node_id, request, cb) {
+            // Routers may reject commands to absent regions without invoking the callback.
+            Err(e @ Error::RegionNotFound(_)) => return Ok(cmd_resp::new_error(e)),
+            res => res?,
+        }
         rx.recv_timeout(timeout)
             .map_err(|_| Error::Timeout(format!("request timeout for {:?}", timeout)))
     }
@@ -130,6 +130,7 @@ pub struct NodeCluster {
     pd_client: Arc<TestPdClient>,
     nodes: HashMap<u64, Node<TestPdClient>>,
     simulate_trans: HashMap<u64, SimulateChannelTransport>,
+    hosted_regions: HashMap<u64, HostedRegions>,
     post_create_coprocessor_host: Option<Box<Fn(u64, &mut CoprocessorHost)>>,
 }
 
@@ -140,6 +141,7 @@ impl NodeCluster {
             pd_client,
             nodes: HashMap::default(),
             simulate_trans: HashMap::default(),
+            hosted_regions: HashMap::default(),
             post_create_coprocessor_host: None,
         }
     }
@@ -151,6 +153,11 @@ impl NodeCluster {
         self.trans.rl().routers.get(&node_id).cloned().unwrap()
     }
 
+    #[allow(dead_code)]
+    pub fn get_hosted_regions(&self, node_id: u64) -> HostedRegions {
+        self.hosted_regions[&node_id].clone()
+    }
+
     // Set a function that will be invoked after creating each CoprocessorHost. The first argument
     // of `op` is the node_id.
     // Set this before invoking `run_node`.
@@ -176,6 +183,7 @@ impl Simulator for NodeCluster {
         let local_reader = Worker::new("test-local-reader");
         let local_ch = local_reader.scheduler();
 
+        let hosted_regions = HostedRegions::default();
         let simulate_trans = SimulateTransport::new(self.trans.clone());
         let mut node = Node::new(
             &mut event_loop,
@@ -220,6 +228,7 @@ impl Simulator for NodeCluster {
             local_reader,
             coprocessor_host,
             importer,
+            hosted_regions.clone(),
         ).unwrap();
         assert!(
             engines
@@ -242,14 +251,20 @@ impl Simulator for NodeCluster {
         }
 
         let node_id = node.id();
-        let router =
-            ServerRaftStoreRouter::new(node.get_sendch(), snap_status_sender.clone(), local_ch);
+        let router = ServerRaftStoreRouter::new(
+            node.get_sendch(),
+            snap_status_sender.clone(),
+            local_ch,
+            hosted_regions.clone(),
+            &cfg.server,
+        );
         self.trans
             .wl()
             .routers
             .insert(node_id, SimulateTransport::new(router));
         self.nodes.insert(node_id, node);
         self.simulate_trans.insert(node_id, simulate_trans);
+        self.hosted_regions.insert(node_id, hosted_regions);
 
         (node_id, engines, path)
     }
@@ -268,6 +283,7 @@ impl Simulator for NodeCluster {
             node.stop().unwrap();
         }
         self.trans.wl().routers.remove(&node_id).unwrap();
+        self.hosted_regions.remove(&node_id);
     }
 
     fn get_node_ids(&self) -> HashSet<u64> {
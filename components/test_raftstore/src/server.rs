@@ -26,7 +26,7 @@ use tikv::config::TiKvConfig;
 use tikv::coprocessor;
 use tikv::import::{ImportSSTService, SSTImporter};
 use tikv::raftstore::coprocessor::CoprocessorHost;
-use tikv::raftstore::store::{Callback, Engines, Msg as StoreMsg, SnapManager};
+use tikv::raftstore::store::{Callback, Engines, HostedRegions, Msg as StoreMsg, SnapManager};
 use tikv::raftstore::{store, Result};
 use tikv::server::readpool::ReadPool;
 use tikv::server::resolve::{self, Task as ResolveTask};
@@ -124,8 +124,14 @@ impl Simulator for ServerCluster {
         let local_reader = Worker::new("test-local-reader");
         let local_ch = local_reader.scheduler();
 
-        let raft_router =
-            ServerRaftStoreRouter::new(store_sendch.clone(), snap_status_sender, local_ch);
+        let hosted_regions = HostedRegions::default();
+        let raft_router = ServerRaftStoreRouter::new(
+            store_sendch.clone(),
+            snap_status_sender,
+            local_ch,
+            hosted_regions.clone(),
+            &cfg.server,
+        );
         let sim_router = SimulateTransport::new(raft_router);
 
         // Create engine
@@ -180,6 +186,7 @@ impl Simulator for ServerCluster {
                 snap_mgr.clone(),
                 Some(engines.clone()),
                 Some(import_service.clone()),
+                vec![],
             ));
             match server {
                 Some(Ok(_)) => break,
@@ -221,9 +228,11 @@ impl Simulator for ServerCluster {
             local_reader,
             coprocessor_host,
             importer,
+            hosted_regions,
         ).unwrap();
         assert!(node_id == 0 || node_id == node.id());
         let node_id = node.id();
+        trans.set_local_store_id(node_id);
         if let Some(tmp) = tmp {
             self.snap_paths.insert(node_id, tmp);
         }
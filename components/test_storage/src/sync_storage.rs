@@ -94,6 +94,18 @@ impl<E: Engine> SyncTestStorage<E> {
             .wait()
     }
 
+    #[allow(dead_code)]
+    pub fn batch_get_multi_region(
+        &self,
+        ctxs: Vec<Context>,
+        keys: Vec<Vec<Key>>,
+        start_ts: u64,
+    ) -> Result<Vec<Result<Vec<Result<KvPair>>>>> {
+        self.store
+            .async_batch_get_multi_region(ctxs, keys, start_ts)
+            .wait()
+    }
+
     pub fn scan(
         &self,
         ctx: Context,
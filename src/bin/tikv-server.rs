@@ -56,17 +56,19 @@ use std::usize;
 use clap::{App, Arg};
 use fs2::FileExt;
 
-use tikv::config::{check_and_persist_critical_config, TiKvConfig};
+use tikv::config::{check_and_persist_critical_config, ReadPoolIsolation, TiKvConfig};
 use tikv::coprocessor;
 use tikv::import::{ImportSSTService, SSTImporter};
 use tikv::pd::{PdClient, RpcClient};
 use tikv::raftstore::coprocessor::CoprocessorHost;
-use tikv::raftstore::store::{self, new_compaction_listener, Engines, SnapManagerBuilder};
+use tikv::raftstore::store::{
+    self, new_compaction_listener, Engines, HostedRegions, SnapManagerBuilder,
+};
 use tikv::server::readpool::ReadPool;
 use tikv::server::resolve;
 use tikv::server::status_server::StatusServer;
 use tikv::server::transport::ServerRaftStoreRouter;
-use tikv::server::{create_raft_storage, Node, Server, DEFAULT_CLUSTER_ID};
+use tikv::server::{create_raft_storage, Node, PdLiveStoreSource, Server, DEFAULT_CLUSTER_ID};
 use tikv::storage::{self, DEFAULT_ROCKSDB_SUB_DIR};
 use tikv::util::rocksdb::metrics_flusher::{MetricsFlusher, DEFAULT_FLUSHER_INTERVAL};
 use tikv::util::security::SecurityManager;
@@ -135,8 +137,14 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig, security_mgr: Arc<Sec
     let local_ch = local_reader.scheduler();
 
     // Create router.
-    let raft_router =
-        ServerRaftStoreRouter::new(store_sendch.clone(), significant_msg_sender, local_ch);
+    let hosted_regions = HostedRegions::default();
+    let raft_router = ServerRaftStoreRouter::new(
+        store_sendch.clone(),
+        significant_msg_sender,
+        local_ch,
+        hosted_regions.clone(),
+        &cfg.server,
+    );
     let compaction_listener = new_compaction_listener(store_sendch.clone());
 
     // Create pd client and pd worker
@@ -159,6 +167,25 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig, security_mgr: Arc<Sec
             let pd_sender = pd_sender.clone();
             move || storage::ReadPoolContext::new(pd_sender.clone())
         });
+    let cop_read_pool_cfg = cfg.readpool.coprocessor.build_config();
+    let cop_context_factory = || {
+        let pd_sender = pd_sender.clone();
+        move || coprocessor::ReadPoolContext::new(pd_sender.clone())
+    };
+    let cop_read_pool = match cfg.readpool.coprocessor_isolation {
+        ReadPoolIsolation::Dedicated => {
+            ReadPool::new("cop", &cop_read_pool_cfg, cop_context_factory)
+        }
+        ReadPoolIsolation::Shared => {
+            info!("coprocessor requests share the threads of the storage read pool");
+            ReadPool::new_on(
+                &storage_read_pool,
+                "cop",
+                &cop_read_pool_cfg,
+                cop_context_factory,
+            )
+        }
+    };
     let storage = create_raft_storage(
         raft_router.clone(),
         &cfg.storage,
@@ -183,6 +210,9 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig, security_mgr: Arc<Sec
     let snap_mgr = SnapManagerBuilder::default()
         .max_write_bytes_per_sec(cfg.server.snap_max_write_bytes_per_sec.0)
         .max_total_size(cfg.server.snap_max_total_size.0)
+        .max_pending_size(cfg.server.snap_max_pending_size.0)
+        .min_available_space(cfg.server.snap_min_available_space.0)
+        .prefetch_on_apply(cfg.server.snap_prefetch_on_apply)
         .build(
             snap_path.as_path().to_str().unwrap().to_owned(),
             Some(store_sendch),
@@ -198,22 +228,20 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig, security_mgr: Arc<Sec
 
     let server_cfg = Arc::new(cfg.server.clone());
     // Create server
-    let cop_read_pool = ReadPool::new("cop", &cfg.readpool.coprocessor.build_config(), || {
-        let pd_sender = pd_sender.clone();
-        move || coprocessor::ReadPoolContext::new(pd_sender.clone())
-    });
     let cop = coprocessor::Endpoint::new(&server_cfg, storage.get_engine(), cop_read_pool);
-    let mut server = Server::new(
-        &server_cfg,
-        &security_mgr,
-        storage.clone(),
-        cop,
-        raft_router,
-        resolver,
-        snap_mgr.clone(),
-        Some(engines.clone()),
-        Some(import_service),
-    ).unwrap_or_else(|e| fatal!("failed to create server: {:?}", e));
+    let mut server = Server::builder()
+        .cfg(Arc::clone(&server_cfg))
+        .security_mgr(Arc::clone(&security_mgr))
+        .storage(storage.clone())
+        .cop(cop)
+        .raft_router(raft_router.clone())
+        .resolver(resolver)
+        .snap_mgr(snap_mgr.clone())
+        .debug_engines(engines.clone())
+        .import_service(import_service)
+        .live_store_source(Box::new(PdLiveStoreSource::new(Arc::clone(&pd_client))))
+        .build()
+        .unwrap_or_else(|e| fatal!("failed to create server: {:?}", e));
     let trans = server.transport();
 
     // Create node.
@@ -232,7 +260,10 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig, security_mgr: Arc<Sec
         local_reader,
         coprocessor_host,
         importer,
+        hosted_regions,
     ).unwrap_or_else(|e| fatal!("failed to start node: {:?}", e));
+    raft_router.set_local_store_id(node.id());
+    server.transport().set_local_store_id(node.id());
     initial_metric(&cfg.metric, Some(node.id()));
 
     let mut metrics_flusher = MetricsFlusher::new(
@@ -737,6 +737,7 @@ macro_rules! readpool_config {
             pub max_tasks_per_worker_normal: usize,
             pub max_tasks_per_worker_low: usize,
             pub stack_size: ReadableSize,
+            pub high_priority_weight: usize,
         }
 
         impl $struct_name {
@@ -749,6 +750,7 @@ macro_rules! readpool_config {
                     max_tasks_per_worker_normal: self.max_tasks_per_worker_normal,
                     max_tasks_per_worker_low: self.max_tasks_per_worker_low,
                     stack_size: self.stack_size,
+                    high_priority_weight: self.high_priority_weight,
                 }
             }
 
@@ -865,6 +867,7 @@ impl Default for StorageReadPoolConfig {
             max_tasks_per_worker_normal: readpool::config::DEFAULT_MAX_TASKS_PER_WORKER,
             max_tasks_per_worker_low: readpool::config::DEFAULT_MAX_TASKS_PER_WORKER,
             stack_size: ReadableSize::mb(readpool::config::DEFAULT_STACK_SIZE_MB),
+            high_priority_weight: 0,
         }
     }
 }
@@ -893,14 +896,33 @@ impl Default for CoprocessorReadPoolConfig {
             max_tasks_per_worker_normal: readpool::config::DEFAULT_MAX_TASKS_PER_WORKER,
             max_tasks_per_worker_low: readpool::config::DEFAULT_MAX_TASKS_PER_WORKER,
             stack_size: ReadableSize::mb(readpool::config::DEFAULT_STACK_SIZE_MB),
+            high_priority_weight: 0,
         }
     }
 }
 
+/// How the coprocessor read pool is isolated from the storage read pool.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadPoolIsolation {
+    /// The coprocessor runs on threads of its own, so that heavy scans can't hold up KV reads.
+    Dedicated,
+    /// The coprocessor runs on the threads of the storage read pool.
+    Shared,
+}
+
+impl Default for ReadPoolIsolation {
+    fn default() -> ReadPoolIsolation {
+        ReadPoolIsolation::Dedicated
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Default)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
 pub struct ReadPoolConfig {
+    /// With `Shared`, the concurrencies and the stack size of `coprocessor` are ignored.
+    pub coprocessor_isolation: ReadPoolIsolation,
     pub storage: StorageReadPoolConfig,
     pub coprocessor: CoprocessorReadPoolConfig,
 }
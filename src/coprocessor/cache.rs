@@ -0,0 +1,364 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kvproto::{coprocessor as coppb, kvrpcpb};
+use protobuf::Message;
+
+use util::collections::HashMap;
+
+use coprocessor::dag::executor::ExecutorMetrics;
+use coprocessor::metrics::*;
+use coprocessor::*;
+
+/// `CacheKey` identifies a unary DAG request by its region, the region epoch and the
+/// request itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    region_id: u64,
+    conf_ver: u64,
+    version: u64,
+    isolation_level: i32,
+    data: Vec<u8>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CacheKey {
+    pub fn new(ctx: &kvrpcpb::Context, data: &[u8], ranges: &[coppb::KeyRange]) -> CacheKey {
+        CacheKey {
+            region_id: ctx.get_region_id(),
+            conf_ver: ctx.get_region_epoch().get_conf_ver(),
+            version: ctx.get_region_epoch().get_version(),
+            isolation_level: ctx.get_isolation_level() as i32,
+            data: data.to_vec(),
+            ranges: ranges
+                .iter()
+                .map(|r| (r.get_start().to_vec(), r.get_end().to_vec()))
+                .collect(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        let ranges: usize = self.ranges.iter().map(|&(ref s, ref e)| s.len() + e.len()).sum();
+        self.data.len() + ranges
+    }
+}
+
+struct Entry {
+    resp: coppb::Response,
+    applied_index: u64,
+    insert_time: Instant,
+    tick: u64,
+    size: usize,
+}
+
+struct RegionEntries {
+    conf_ver: u64,
+    version: u64,
+    count: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Arc<CacheKey>, Entry>,
+    // tick of the last access -> key, the least recently used key comes first.
+    lru: BTreeMap<u64, Arc<CacheKey>>,
+    regions: HashMap<u64, RegionEntries>,
+    next_tick: u64,
+    size: usize,
+}
+
+impl Inner {
+    fn next_tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn insert(&mut self, key: CacheKey, entry: Entry) {
+        let region = self
+            .regions
+            .entry(key.region_id)
+            .or_insert_with(|| RegionEntries {
+                conf_ver: key.conf_ver,
+                version: key.version,
+                count: 0,
+            });
+        region.count += 1;
+        let key = Arc::new(key);
+        self.size += entry.size;
+        self.lru.insert(entry.tick, Arc::clone(&key));
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> bool {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.lru.remove(&entry.tick);
+        self.size -= entry.size;
+        let drained = match self.regions.get_mut(&key.region_id) {
+            Some(region) => {
+                region.count -= 1;
+                region.count == 0
+            }
+            None => false,
+        };
+        if drained {
+            self.regions.remove(&key.region_id);
+        }
+        true
+    }
+
+    // Returns false if the epoch of `key` is older than the one of the results cached for
+    // its region. If it's newer, the results cached for the region are dropped.
+    fn check_epoch(&mut self, key: &CacheKey) -> bool {
+        let (conf_ver, version) = match self.regions.get(&key.region_id) {
+            Some(region) => (region.conf_ver, region.version),
+            None => return true,
+        };
+        if key.conf_ver == conf_ver && key.version == version {
+            return true;
+        }
+        if key.conf_ver < conf_ver || key.version < version {
+            return false;
+        }
+        let stale: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|k| k.region_id == key.region_id)
+            .cloned()
+            .collect();
+        for k in stale {
+            self.remove(&k);
+            COPR_CACHE_COUNTER_VEC.with_label_values(&["evict"]).inc();
+        }
+        true
+    }
+}
+
+/// `ResultCache` is an LRU cache of the responses of unary DAG requests, for clients like
+/// dashboards that issue identical requests over and over.
+///
+/// A response is cached with the applied index of the snapshot it's computed on, and is
+/// only served to requests whose snapshots are at the same applied index, that is, when
+/// nothing has been applied to the region since then. The responses of a region are
+/// dropped once its epoch changes, and a response is never served after `ttl`.
+pub struct ResultCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl ResultCache {
+    /// `capacity` is the max bytes of the requests and responses cached.
+    pub fn new(capacity: usize, ttl: Duration) -> ResultCache {
+        ResultCache {
+            capacity,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey, applied_index: u64) -> Option<coppb::Response> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let fresh = inner.check_epoch(key) && match inner.entries.get(key) {
+            Some(entry) => {
+                entry.applied_index == applied_index && entry.insert_time.elapsed() < self.ttl
+            }
+            None => false,
+        };
+        if !fresh {
+            if inner.remove(key) {
+                COPR_CACHE_COUNTER_VEC.with_label_values(&["evict"]).inc();
+            }
+            COPR_CACHE_COUNTER_VEC.with_label_values(&["miss"]).inc();
+            return None;
+        }
+
+        let tick = inner.next_tick();
+        let entry = inner.entries.get_mut(key).unwrap();
+        let lru_key = inner.lru.remove(&entry.tick).unwrap();
+        inner.lru.insert(tick, lru_key);
+        entry.tick = tick;
+        COPR_CACHE_COUNTER_VEC.with_label_values(&["hit"]).inc();
+        Some(entry.resp.clone())
+    }
+
+    pub fn put(&self, key: CacheKey, applied_index: u64, resp: coppb::Response) {
+        let size = key.size() + resp.compute_size() as usize;
+        if size > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.check_epoch(&key) {
+            return;
+        }
+        inner.remove(&key);
+        let tick = inner.next_tick();
+        inner.insert(
+            key,
+            Entry {
+                resp,
+                applied_index,
+                insert_time: Instant::now(),
+                tick,
+                size,
+            },
+        );
+        while inner.size > self.capacity {
+            let key = Arc::clone(inner.lru.values().next().unwrap());
+            inner.remove(&key);
+            COPR_CACHE_COUNTER_VEC.with_label_values(&["evict"]).inc();
+        }
+    }
+}
+
+// Only successful responses are cached. Errors like region errors depend on more than the
+// data of the region.
+fn is_cacheable(resp: &coppb::Response) -> bool {
+    !resp.has_region_error() && !resp.has_locked() && resp.get_other_error().is_empty()
+}
+
+/// Responds with a cached response, without scanning the snapshot.
+pub struct CachedRequestHandler {
+    resp: Option<coppb::Response>,
+}
+
+impl CachedRequestHandler {
+    pub fn new(resp: coppb::Response) -> CachedRequestHandler {
+        CachedRequestHandler { resp: Some(resp) }
+    }
+}
+
+impl RequestHandler for CachedRequestHandler {
+    fn handle_request(&mut self) -> Result<coppb::Response> {
+        Ok(self.resp.take().unwrap())
+    }
+}
+
+/// Puts the response of the inner handler into the cache.
+pub struct CachingRequestHandler {
+    inner: Box<RequestHandler + Send>,
+    cache: Arc<ResultCache>,
+    key: Option<CacheKey>,
+    applied_index: u64,
+}
+
+impl CachingRequestHandler {
+    pub fn new(
+        inner: Box<RequestHandler + Send>,
+        cache: Arc<ResultCache>,
+        key: CacheKey,
+        applied_index: u64,
+    ) -> CachingRequestHandler {
+        CachingRequestHandler {
+            inner,
+            cache,
+            key: Some(key),
+            applied_index,
+        }
+    }
+}
+
+impl RequestHandler for CachingRequestHandler {
+    fn handle_request(&mut self) -> Result<coppb::Response> {
+        let resp = self.inner.handle_request()?;
+        if is_cacheable(&resp) {
+            let key = self.key.take().unwrap();
+            self.cache.put(key, self.applied_index, resp.clone());
+        }
+        Ok(resp)
+    }
+
+    fn collect_metrics_into(&mut self, metrics: &mut ExecutorMetrics) {
+        self.inner.collect_metrics_into(metrics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn new_key(region_id: u64, version: u64, data: &[u8]) -> CacheKey {
+        let mut ctx = kvrpcpb::Context::new();
+        ctx.set_region_id(region_id);
+        ctx.mut_region_epoch().set_conf_ver(1);
+        ctx.mut_region_epoch().set_version(version);
+        CacheKey::new(&ctx, data, &[])
+    }
+
+    fn new_resp(data: &[u8]) -> coppb::Response {
+        let mut resp = coppb::Response::new();
+        resp.set_data(data.to_vec());
+        resp
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let cache = ResultCache::new(1024, Duration::from_secs(60));
+        let key = new_key(1, 1, b"req");
+        assert!(cache.get(&key, 10).is_none());
+        cache.put(key.clone(), 10, new_resp(b"resp"));
+        assert_eq!(cache.get(&key, 10).unwrap().get_data(), b"resp");
+
+        // The region has applied more since the response is cached.
+        assert!(cache.get(&key, 11).is_none());
+        assert!(cache.get(&key, 10).is_none());
+
+        // Responses of a region are dropped once its epoch changes.
+        cache.put(key.clone(), 10, new_resp(b"resp"));
+        cache.put(new_key(2, 1, b"req"), 10, new_resp(b"resp"));
+        let new_epoch = new_key(1, 2, b"req");
+        assert!(cache.get(&new_epoch, 10).is_none());
+        assert!(cache.get(&key, 10).is_none());
+        assert!(cache.get(&new_key(2, 1, b"req"), 10).is_some());
+        // Requests of older epochs are not cached.
+        cache.put(new_epoch.clone(), 10, new_resp(b"resp"));
+        cache.put(key.clone(), 10, new_resp(b"resp"));
+        assert!(cache.get(&key, 10).is_none());
+        assert!(cache.get(&new_epoch, 10).is_some());
+
+        // Responses larger than the cache are never cached.
+        let large = new_key(3, 1, b"large");
+        cache.put(large.clone(), 10, new_resp(&[0; 1024]));
+        assert!(cache.get(&large, 10).is_none());
+
+        // The least recently used response is evicted first.
+        let cache = ResultCache::new(80, Duration::from_secs(60));
+        let keys: Vec<_> = (0..3).map(|i| new_key(i, 1, &[0; 10])).collect();
+        cache.put(keys[0].clone(), 10, new_resp(&[0; 20]));
+        cache.put(keys[1].clone(), 10, new_resp(&[0; 20]));
+        assert!(cache.get(&keys[0], 10).is_some());
+        cache.put(keys[2].clone(), 10, new_resp(&[0; 20]));
+        assert!(cache.get(&keys[0], 10).is_some());
+        assert!(cache.get(&keys[1], 10).is_none());
+        assert!(cache.get(&keys[2], 10).is_some());
+    }
+
+    #[test]
+    fn test_result_cache_ttl() {
+        let cache = ResultCache::new(1024, Duration::from_millis(100));
+        let key = new_key(1, 1, b"req");
+        cache.put(key.clone(), 10, new_resp(b"resp"));
+        assert!(cache.get(&key, 10).is_some());
+        thread::sleep(Duration::from_millis(200));
+        assert!(cache.get(&key, 10).is_none());
+    }
+}
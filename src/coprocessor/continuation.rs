@@ -0,0 +1,195 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use kvproto::{coprocessor as coppb, errorpb};
+use protobuf::{Message, RepeatedField};
+use tipb::executor::ExecType;
+use tipb::select::DAGRequest;
+
+use util::codec::bytes::{self, BytesEncoder};
+use util::codec::number::{self, NumberEncoder};
+
+use super::{Error, Result, REQ_TYPE_DAG};
+
+/// `ContinuationToken` marks where a streaming DAG request stopped, so that a request
+/// interrupted in the middle can be resumed rather than redone from the start.
+///
+/// The token records the region epoch carried by the original request, and the key
+/// to resume from. Only requests made of scans and selections can be resumed, since
+/// other executors keep states across rows that the token doesn't carry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContinuationToken {
+    region_id: u64,
+    conf_ver: u64,
+    version: u64,
+    desc: bool,
+    resume_key: Vec<u8>,
+}
+
+impl ContinuationToken {
+    /// Builds the token to resume `req` after its streaming response `resp`. Returns `None`
+    /// if `req` can't be resumed, or if it has finished.
+    pub fn from_stream_response(
+        req: &coppb::Request,
+        resp: &coppb::Response,
+    ) -> Result<Option<ContinuationToken>> {
+        if req.get_tp() != REQ_TYPE_DAG || !resp.has_range() {
+            return Ok(None);
+        }
+        let mut dag = DAGRequest::new();
+        box_try!(dag.merge_from_bytes(req.get_data()));
+        let mut desc = false;
+        for exec in dag.get_executors() {
+            match exec.get_tp() {
+                ExecType::TypeTableScan => desc = exec.get_tbl_scan().get_desc(),
+                ExecType::TypeIndexScan => desc = exec.get_idx_scan().get_desc(),
+                ExecType::TypeSelection => {}
+                _ => return Ok(None),
+            }
+        }
+        let range = resp.get_range();
+        let resume_key = if desc {
+            range.get_start()
+        } else {
+            range.get_end()
+        };
+        let ctx = req.get_context();
+        Ok(Some(ContinuationToken {
+            region_id: ctx.get_region_id(),
+            conf_ver: ctx.get_region_epoch().get_conf_ver(),
+            version: ctx.get_region_epoch().get_version(),
+            desc,
+            resume_key: resume_key.to_vec(),
+        }))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8 * 3 + 1 + self.resume_key.len() + 10);
+        buf.encode_u64(self.region_id).unwrap();
+        buf.encode_u64(self.conf_ver).unwrap();
+        buf.encode_u64(self.version).unwrap();
+        buf.push(self.desc as u8);
+        buf.encode_compact_bytes(&self.resume_key).unwrap();
+        buf
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<ContinuationToken> {
+        let region_id = box_try!(number::decode_u64(&mut data));
+        let conf_ver = box_try!(number::decode_u64(&mut data));
+        let version = box_try!(number::decode_u64(&mut data));
+        if data.is_empty() {
+            return Err(box_err!("invalid continuation token"));
+        }
+        let desc = data[0] != 0;
+        data = &data[1..];
+        let resume_key = box_try!(bytes::decode_compact_bytes(&mut data));
+        if !data.is_empty() {
+            return Err(box_err!("invalid continuation token"));
+        }
+        Ok(ContinuationToken {
+            region_id,
+            conf_ver,
+            version,
+            desc,
+            resume_key,
+        })
+    }
+
+    /// Trims the ranges of `req` to what is left to scan. It fails if `req` is not for the
+    /// same region and epoch as the request the token is built from.
+    pub fn resume(&self, req: &mut coppb::Request) -> Result<()> {
+        {
+            let ctx = req.get_context();
+            let epoch = ctx.get_region_epoch();
+            if ctx.get_region_id() != self.region_id
+                || epoch.get_conf_ver() != self.conf_ver
+                || epoch.get_version() != self.version
+            {
+                let mut err = errorpb::Error::new();
+                err.set_message(format!(
+                    "region {} epoch changed since the continuation token is made",
+                    self.region_id
+                ));
+                err.set_stale_epoch(errorpb::StaleEpoch::new());
+                return Err(Error::Region(err));
+            }
+        }
+
+        let key = self.resume_key.as_slice();
+        let mut ranges = req.take_ranges().into_vec();
+        if self.desc {
+            ranges.retain(|r| r.get_start() < key);
+            for r in &mut ranges {
+                if r.get_end() > key {
+                    r.set_end(key.to_vec());
+                }
+            }
+        } else {
+            ranges.retain(|r| r.get_end() > key);
+            for r in &mut ranges {
+                if r.get_start() < key {
+                    r.set_start(key.to_vec());
+                }
+            }
+        }
+        req.set_ranges(RepeatedField::from_vec(ranges));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_range(start: &[u8], end: &[u8]) -> coppb::KeyRange {
+        let mut range = coppb::KeyRange::new();
+        range.set_start(start.to_vec());
+        range.set_end(end.to_vec());
+        range
+    }
+
+    #[test]
+    fn test_resume_ranges() {
+        let mut token = ContinuationToken {
+            region_id: 1,
+            conf_ver: 2,
+            version: 3,
+            desc: false,
+            resume_key: b"c".to_vec(),
+        };
+        let ranges = vec![new_range(b"a", b"b"), new_range(b"b", b"d"), new_range(b"e", b"f")];
+        let mut req = coppb::Request::new();
+        req.mut_context().set_region_id(1);
+        req.mut_context().mut_region_epoch().set_conf_ver(2);
+        req.mut_context().mut_region_epoch().set_version(3);
+        req.set_ranges(RepeatedField::from_vec(ranges));
+
+        let mut asc = req.clone();
+        token.resume(&mut asc).unwrap();
+        assert_eq!(asc.get_ranges(), &[new_range(b"c", b"d"), new_range(b"e", b"f")]);
+
+        token.desc = true;
+        let mut desc = req.clone();
+        token.resume(&mut desc).unwrap();
+        assert_eq!(desc.get_ranges(), &[new_range(b"a", b"b"), new_range(b"b", b"c")]);
+
+        assert_eq!(ContinuationToken::decode(&token.encode()).unwrap(), token);
+        assert!(ContinuationToken::decode(b"invalid").is_err());
+
+        req.mut_context().mut_region_epoch().set_version(4);
+        match token.resume(&mut req) {
+            Err(Error::Region(ref e)) if e.has_stale_epoch() => {}
+            res => panic!("expect stale epoch, but got {:?}", res),
+        }
+    }
+}
@@ -28,6 +28,7 @@ pub struct DAGContext {
     exec: Box<Executor + Send>,
     output_offsets: Vec<u32>,
     batch_row_limit: usize,
+    memory_quota: usize,
 }
 
 impl DAGContext {
@@ -76,9 +77,18 @@ impl DAGContext {
             exec: dag_executor,
             output_offsets: req.take_output_offsets(),
             batch_row_limit,
+            memory_quota: req_ctx.memory_quota,
         })
     }
 
+    /// Returns error if the results of `used` bytes exceed the memory quota.
+    fn check_memory_quota(&self, used: usize) -> Result<()> {
+        if self.memory_quota > 0 && used > self.memory_quota {
+            return Err(Error::MemoryQuotaExceeded(self.memory_quota));
+        }
+        Ok(())
+    }
+
     fn make_stream_response(&mut self, chunk: Chunk, range: Option<KeyRange>) -> Result<Response> {
         let mut s_resp = StreamResponse::new();
         s_resp.set_data(box_try!(chunk.write_to_bytes()));
@@ -101,6 +111,7 @@ impl RequestHandler for DAGContext {
     fn handle_request(&mut self) -> Result<Response> {
         let mut record_cnt = 0;
         let mut chunks = Vec::new();
+        let mut used = 0;
         loop {
             match self.exec.next() {
                 Ok(Some(row)) => {
@@ -114,6 +125,8 @@ impl RequestHandler for DAGContext {
                     record_cnt += 1;
                     // for default encode type
                     let value = row.get_binary(&self.output_offsets)?;
+                    used += value.len();
+                    self.check_memory_quota(used)?;
                     chunk.mut_rows_data().extend_from_slice(&value);
                 }
                 Ok(None) => {
@@ -153,6 +166,7 @@ impl RequestHandler for DAGContext {
                     self.deadline.check_if_exceeded()?;
                     record_cnt += 1;
                     let value = row.get_binary(&self.output_offsets)?;
+                    self.check_memory_quota(chunk.get_rows_data().len() + value.len())?;
                     chunk.mut_rows_data().extend_from_slice(&value);
                 }
                 Ok(None) => {
@@ -11,10 +11,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::sync::mpsc;
-use futures::{future, stream, Future, Stream};
+use futures::{future, stream, Async, AsyncSink, Future, Poll, Sink, Stream};
+use protobuf::error::{ProtobufError, WireError};
 use protobuf::{CodedInputStream, Message};
 
 use kvproto::{coprocessor as coppb, errorpb, kvrpcpb};
@@ -25,26 +28,41 @@ use tipb::select::DAGRequest;
 
 use server::readpool::{self, ReadPool};
 use server::Config;
-use storage::{self, Engine};
+use storage::{self, Engine, Snapshot};
 use util::Either;
 
+use coprocessor::cache::{CacheKey, CachedRequestHandler, CachingRequestHandler, ResultCache};
 use coprocessor::dag::executor::ExecutorMetrics;
 use coprocessor::metrics::*;
+use coprocessor::overload::OverloadDetector;
 use coprocessor::tracker::Tracker;
 use coprocessor::util as cop_util;
 use coprocessor::*;
 
 const OUTDATED_ERROR_MSG: &str = "request outdated.";
 const BUSY_ERROR_MSG: &str = "server is busy (coprocessor full).";
+const PAUSED_ERROR_MSG: &str = "server is busy (coprocessor paused).";
+const OVERLOADED_ERROR_MSG: &str = "server is busy (coprocessor overloaded).";
 
 pub struct Endpoint<E: Engine> {
     engine: E,
     read_pool: ReadPool<ReadPoolContext>,
-    recursion_limit: u32,
+    // `recursion_limit` and `stream_channel_size` are shared by all clones and can be
+    // updated at runtime, so they are read for every request.
+    recursion_limit: Arc<AtomicUsize>,
     batch_row_limit: usize,
     stream_batch_row_limit: usize,
-    stream_channel_size: usize,
+    stream_channel_size: Arc<AtomicUsize>,
     max_handle_duration: Duration,
+    memory_quota: usize,
+    slow_log_threshold: Duration,
+    // New requests are refused while `paused` is set, see `Server::pause_coprocessor`.
+    paused: Arc<AtomicBool>,
+    // Caches the results of unary DAG requests, `None` if it's disabled.
+    cache: Option<Arc<ResultCache>>,
+    // Refuses new requests while the read pool of their priority is overloaded, one detector
+    // for the high, normal and low-priority pools each. `None` if it's disabled.
+    overload: Option<Arc<[OverloadDetector; 3]>>,
 }
 
 impl<E: Engine> Clone for Endpoint<E> {
@@ -52,6 +70,11 @@ impl<E: Engine> Clone for Endpoint<E> {
         Self {
             engine: self.engine.clone(),
             read_pool: self.read_pool.clone(),
+            recursion_limit: Arc::clone(&self.recursion_limit),
+            stream_channel_size: Arc::clone(&self.stream_channel_size),
+            paused: Arc::clone(&self.paused),
+            cache: self.cache.clone(),
+            overload: self.overload.clone(),
             ..*self
         }
     }
@@ -64,14 +87,55 @@ impl<E: Engine> Endpoint<E> {
         Self {
             engine,
             read_pool,
-            recursion_limit: cfg.end_point_recursion_limit,
+            recursion_limit: Arc::new(AtomicUsize::new(cfg.end_point_recursion_limit as usize)),
             batch_row_limit: cfg.end_point_batch_row_limit,
             stream_batch_row_limit: cfg.end_point_stream_batch_row_limit,
-            stream_channel_size: cfg.end_point_stream_channel_size,
+            stream_channel_size: Arc::new(AtomicUsize::new(cfg.end_point_stream_channel_size)),
             max_handle_duration: cfg.end_point_request_max_handle_duration.0,
+            memory_quota: cfg.end_point_memory_quota.0 as usize,
+            slow_log_threshold: cfg.end_point_slow_log_threshold.0,
+            paused: Arc::new(AtomicBool::new(false)),
+            cache: if cfg.end_point_cache_capacity.0 > 0 {
+                Some(Arc::new(ResultCache::new(
+                    cfg.end_point_cache_capacity.0 as usize,
+                    cfg.end_point_cache_ttl.0,
+                )))
+            } else {
+                None
+            },
+            overload: if cfg.end_point_overload_high_water > 0.0 {
+                let new_detector = || {
+                    OverloadDetector::new(
+                        cfg.end_point_overload_high_water,
+                        cfg.end_point_overload_low_water,
+                        cfg.end_point_overload_window.0,
+                    )
+                };
+                Some(Arc::new([new_detector(), new_detector(), new_detector()]))
+            } else {
+                None
+            },
         }
     }
 
+    /// Returns the recursion limit used when parsing requests. Updating it affects
+    /// all clones of this `Endpoint`.
+    pub fn recursion_limit(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.recursion_limit)
+    }
+
+    /// Returns the channel size of streaming requests. Updating it affects all clones
+    /// of this `Endpoint`.
+    pub fn stream_channel_size(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.stream_channel_size)
+    }
+
+    /// Returns the flag telling whether new requests are refused. Setting it affects all
+    /// clones of this `Endpoint`, while requests already accepted still run to the end.
+    pub fn paused(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.paused)
+    }
+
     /// Parse the raw `Request` to create `RequestHandlerBuilder` and `ReqContext`.
     /// Returns `Err` if fails.
     fn try_parse_request(
@@ -86,16 +150,25 @@ impl<E: Engine> Endpoint<E> {
             req.take_ranges().to_vec(),
         );
 
+        let tp = req.get_tp();
+        let cache_key = match self.cache {
+            Some(_) if tp == REQ_TYPE_DAG && !is_streaming => {
+                Some(CacheKey::new(&context, &data, &ranges))
+            }
+            _ => None,
+        };
+        let recursion_limit = self.recursion_limit.load(Ordering::Relaxed) as u32;
         let mut is = CodedInputStream::from_bytes(&data);
-        is.set_recursion_limit(self.recursion_limit);
+        is.set_recursion_limit(recursion_limit);
+        let parse_err = |e, peer: &Option<String>| parse_error(e, recursion_limit, tp, peer);
 
-        let req_ctx: ReqContext;
-        let builder: RequestHandlerBuilder<E::Snap>;
+        let mut req_ctx: ReqContext;
+        let mut builder: RequestHandlerBuilder<E::Snap>;
 
-        match req.get_tp() {
+        match tp {
             REQ_TYPE_DAG => {
                 let mut dag = DAGRequest::new();
-                box_try!(dag.merge_from(&mut is));
+                dag.merge_from(&mut is).map_err(|e| parse_err(e, &peer))?;
                 let mut table_scan = false;
                 let mut is_desc_scan = false;
                 if let Some(scan) = dag.get_executors().iter().next() {
@@ -124,7 +197,7 @@ impl<E: Engine> Endpoint<E> {
             }
             REQ_TYPE_ANALYZE => {
                 let mut analyze = AnalyzeReq::new();
-                box_try!(analyze.merge_from(&mut is));
+                analyze.merge_from(&mut is).map_err(|e| parse_err(e, &peer))?;
                 let table_scan = analyze.get_tp() == AnalyzeType::TypeColumn;
                 req_ctx = ReqContext::new(
                     make_tag(table_scan),
@@ -142,7 +215,7 @@ impl<E: Engine> Endpoint<E> {
             }
             REQ_TYPE_CHECKSUM => {
                 let mut checksum = ChecksumRequest::new();
-                box_try!(checksum.merge_from(&mut is));
+                checksum.merge_from(&mut is).map_err(|e| parse_err(e, &peer))?;
                 let table_scan = checksum.get_scan_on() == ChecksumScanOn::Table;
                 req_ctx = ReqContext::new(
                     make_tag(table_scan),
@@ -160,9 +233,36 @@ impl<E: Engine> Endpoint<E> {
             }
             tp => return Err(box_err!("unsupported tp {}", tp)),
         };
+        if let (Some(cache), Some(key)) = (self.cache.as_ref(), cache_key) {
+            builder = Self::cached_builder(Arc::clone(cache), key, builder);
+        }
+        req_ctx.memory_quota = self.memory_quota;
+        req_ctx.slow_log_threshold = self.slow_log_threshold;
         Ok((builder, req_ctx))
     }
 
+    /// Wraps `builder` so that the request is served from `cache` if the region is not changed
+    /// since the result is cached, and its result is cached otherwise. Snapshots without
+    /// applied indexes bypass the cache.
+    fn cached_builder(
+        cache: Arc<ResultCache>,
+        key: CacheKey,
+        builder: RequestHandlerBuilder<E::Snap>,
+    ) -> RequestHandlerBuilder<E::Snap> {
+        box move |snap: E::Snap, req_ctx: &_| {
+            // See rust-lang#41078 to know why we have `: &_` here.
+            let applied_index = match snap.applied_index() {
+                Some(index) => index,
+                None => return builder.call_box((snap, req_ctx)),
+            };
+            if let Some(resp) = cache.get(&key, applied_index) {
+                return Ok(CachedRequestHandler::new(resp).into_boxed());
+            }
+            let handler = builder.call_box((snap, req_ctx))?;
+            Ok(CachingRequestHandler::new(handler, cache, key, applied_index).into_boxed())
+        }
+    }
+
     /// Parse the raw `Request` to create `RequestHandlerBuilder` and `ReqContext`.
     #[inline]
     fn parse_request(
@@ -171,26 +271,44 @@ impl<E: Engine> Endpoint<E> {
         peer: Option<String>,
         is_streaming: bool,
     ) -> (RequestHandlerBuilder<E::Snap>, ReqContext) {
+        if self.paused.load(Ordering::Acquire) {
+            return Self::error_request(Error::Paused);
+        }
+        if let Some(ref overload) = self.overload {
+            // A saturated pool doesn't refuse the requests running on the other pools.
+            let priority = readpool::Priority::from(req.get_context().get_priority());
+            let detector = match priority {
+                readpool::Priority::High => &overload[0],
+                readpool::Priority::Normal => &overload[1],
+                readpool::Priority::Low => &overload[2],
+            };
+            let utilization = self.read_pool.get_utilization(priority);
+            if detector.on_utilization(utilization, Instant::now()) {
+                return Self::error_request(Error::Overloaded);
+            }
+        }
         match self.try_parse_request(req, peer, is_streaming) {
             Ok(v) => v,
-            Err(err) => {
-                // If there are errors when parsing requests, create a dummy request handler.
-                let builder =
-                    box |_, _: &_| Ok(cop_util::ErrorRequestHandler::new(err).into_boxed());
-                let req_ctx = ReqContext::new(
-                    "invalid",
-                    kvrpcpb::Context::new(),
-                    &[],
-                    Duration::from_secs(60), // Large enough to avoid becoming outdated error
-                    None,
-                    None,
-                    None,
-                );
-                (builder, req_ctx)
-            }
+            // If there are errors when parsing requests, create a dummy request handler.
+            Err(err) => Self::error_request(err),
         }
     }
 
+    /// Creates a dummy request handler which responds with `err` only.
+    fn error_request(err: Error) -> (RequestHandlerBuilder<E::Snap>, ReqContext) {
+        let builder = box |_, _: &_| Ok(cop_util::ErrorRequestHandler::new(err).into_boxed());
+        let req_ctx = ReqContext::new(
+            "invalid",
+            kvrpcpb::Context::new(),
+            &[],
+            Duration::from_secs(60), // Large enough to avoid becoming outdated error
+            None,
+            None,
+            None,
+        );
+        (builder, req_ctx)
+    }
+
     /// Get the batch row limit configuration.
     #[inline]
     fn get_batch_row_limit(&self, is_streaming: bool) -> usize {
@@ -206,11 +324,8 @@ impl<E: Engine> Endpoint<E> {
         engine: E,
         ctx: &kvrpcpb::Context,
     ) -> impl Future<Item = E::Snap, Error = Error> {
-        let (callback, future) = ::util::future::paired_future_callback();
-        let val = engine.async_snapshot(ctx, callback);
-        future::result(val)
-            .and_then(|_| future.map_err(|cancel| storage::engine::Error::Other(box_err!(cancel))))
-            .and_then(|(_ctx, result)| result)
+        engine
+            .snapshot_future(ctx)
             // map engine::Error -> coprocessor::Error
             .map_err(Error::from)
     }
@@ -257,6 +372,9 @@ impl<E: Engine> Endpoint<E> {
 
                 tracker.on_finish_item(Some(exec_metrics));
                 let exec_details = tracker.get_item_exec_details();
+                if let Ok(ref resp) = result {
+                    tracker.on_result(resp.get_data().len());
+                }
 
                 tracker.on_finish_all_items();
 
@@ -373,7 +491,10 @@ impl<E: Engine> Endpoint<E> {
                                     let next_state = None;
                                     return Some(Ok((yielded, next_state)));
                                 }
-                                Ok((Some(resp), finished)) => (resp, finished),
+                                Ok((Some(resp), finished)) => {
+                                    tracker.on_result(resp.get_data().len());
+                                    (resp, finished)
+                                }
                             };
                             resp.set_exec_details(exec_details);
 
@@ -405,7 +526,8 @@ impl<E: Engine> Endpoint<E> {
         req_ctx: ReqContext,
         handler_builder: RequestHandlerBuilder<E::Snap>,
     ) -> impl Stream<Item = coppb::Response, Error = ()> {
-        let (tx, rx) = mpsc::channel::<coppb::Response>(self.stream_channel_size);
+        let channel_size = self.stream_channel_size.load(Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel::<coppb::Response>(channel_size);
         let engine = self.engine.clone();
         let priority = readpool::Priority::from(req_ctx.context.get_priority());
         // Must be created befure `future_execute`, otherwise wait time is not tracked.
@@ -415,13 +537,13 @@ impl<E: Engine> Endpoint<E> {
         let result = self.read_pool.future_execute(priority, move |ctxd| {
             tracker.attach_ctxd(ctxd);
 
-            Self::handle_stream_request_impl(engine, tracker, handler_builder)
-                .or_else(|e| Ok::<_, mpsc::SendError<_>>(make_error_response(e)))
-                // Although returning `Ok()` from `or_else` will continue the stream,
-                // our stream has already ended when error is returned.
-                // Thus the stream will not continue any more even after we converting errors
-                // into a response.
-                .forward(tx1)
+            let stream = Self::handle_stream_request_impl(engine, tracker, handler_builder)
+                .or_else(|e| Ok::<_, ()>(make_error_response(e)));
+            // Although returning `Ok()` from `or_else` will continue the stream,
+            // our stream has already ended when error is returned.
+            // Thus the stream will not continue any more even after we converting errors
+            // into a response.
+            BackpressureForward::new(stream, tx1)
         });
 
         match result {
@@ -454,6 +576,75 @@ impl<E: Engine> Endpoint<E> {
         let (handler_builder, req_ctx) = self.parse_request(req, peer, true);
         self.handle_stream_request(req_ctx, handler_builder)
     }
+
+    /// Handles the streaming request `req` from where the previous attempt of it stops,
+    /// which is recorded by `token`. See `ContinuationToken` for the requests supported.
+    pub fn resume_stream_request(
+        &self,
+        mut req: coppb::Request,
+        token: &[u8],
+        peer: Option<String>,
+    ) -> impl Stream<Item = coppb::Response, Error = ()> {
+        let resumed = ContinuationToken::decode(token).and_then(|t| t.resume(&mut req));
+        let (handler_builder, req_ctx) = match resumed {
+            Ok(()) => self.parse_request(req, peer, true),
+            Err(err) => Self::error_request(err),
+        };
+        self.handle_stream_request(req_ctx, handler_builder)
+    }
+}
+
+/// `BackpressureForward` forwards the responses of a streaming request to the channel
+/// drained by the outbound gRPC stream. The next response is pulled from the handler only
+/// when the channel has room for it, so a slow client parks the handler until the channel
+/// drains, instead of having responses generated ahead of it. Each pause is counted as a
+/// stall.
+struct BackpressureForward<S: Stream> {
+    stream: S,
+    sink: mpsc::Sender<S::Item>,
+    stalled: bool,
+}
+
+impl<S: Stream> BackpressureForward<S> {
+    fn new(stream: S, sink: mpsc::Sender<S::Item>) -> BackpressureForward<S> {
+        BackpressureForward {
+            stream,
+            sink,
+            stalled: false,
+        }
+    }
+}
+
+impl<S: Stream> Future for BackpressureForward<S> {
+    type Item = ();
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<(), S::Error> {
+        loop {
+            match self.sink.poll_ready() {
+                Ok(Async::Ready(())) => self.stalled = false,
+                Ok(Async::NotReady) => {
+                    if !self.stalled {
+                        self.stalled = true;
+                        COPR_STREAM_BACKPRESSURE_STALLS.inc();
+                    }
+                    return Ok(Async::NotReady);
+                }
+                // The client has gone, no need to produce more.
+                Err(_) => return Ok(Async::Ready(())),
+            }
+            let item = match try_ready!(self.stream.poll()) {
+                Some(item) => item,
+                None => return Ok(Async::Ready(())),
+            };
+            match self.sink.start_send(item) {
+                Ok(AsyncSink::Ready) => {}
+                // The channel is just polled ready.
+                Ok(AsyncSink::NotReady(_)) => unreachable!(),
+                Err(_) => return Ok(Async::Ready(())),
+            }
+        }
+    }
 }
 
 fn make_tag(is_table_scan: bool) -> &'static str {
@@ -464,6 +655,21 @@ fn make_tag(is_table_scan: bool) -> &'static str {
     }
 }
 
+// Errors caused by the recursion limit are told apart, so that the client knows which
+// limit to raise. The client address is logged as such requests may be malicious.
+fn parse_error(e: ProtobufError, limit: u32, tp: i64, peer: &Option<String>) -> Error {
+    match e {
+        ProtobufError::WireError(WireError::OverRecursionLimit) => {
+            warn!(
+                "request of type {} from {:?} exceeds the recursion limit {}",
+                tp, peer, limit
+            );
+            Error::RecursionLimitExceeded(limit, tp)
+        }
+        e => box_err!(e),
+    }
+}
+
 fn make_error_response(e: Error) -> coppb::Response {
     error!("{:?}", e);
     let mut resp = coppb::Response::new();
@@ -493,6 +699,32 @@ fn make_error_response(e: Error) -> coppb::Response {
             errorpb.set_server_is_busy(server_is_busy_err);
             resp.set_region_error(errorpb);
         }
+        Error::Paused => {
+            tag = "paused";
+            let mut errorpb = errorpb::Error::new();
+            errorpb.set_message("Coprocessor end-point is paused".to_owned());
+            let mut server_is_busy_err = errorpb::ServerIsBusy::new();
+            server_is_busy_err.set_reason(PAUSED_ERROR_MSG.to_owned());
+            errorpb.set_server_is_busy(server_is_busy_err);
+            resp.set_region_error(errorpb);
+        }
+        Error::Overloaded => {
+            tag = "overloaded";
+            let mut errorpb = errorpb::Error::new();
+            errorpb.set_message("Coprocessor end-point is overloaded".to_owned());
+            let mut server_is_busy_err = errorpb::ServerIsBusy::new();
+            server_is_busy_err.set_reason(OVERLOADED_ERROR_MSG.to_owned());
+            errorpb.set_server_is_busy(server_is_busy_err);
+            resp.set_region_error(errorpb);
+        }
+        Error::MemoryQuotaExceeded(_) => {
+            tag = "memory_quota";
+            resp.set_other_error(format!("{}", e));
+        }
+        Error::RecursionLimitExceeded(..) => {
+            tag = "recursion_limit";
+            resp.set_other_error(format!("{}", e));
+        }
         Error::Other(_) | Error::Eval(_) => {
             tag = "other";
             resp.set_other_error(format!("{}", e));
@@ -514,6 +746,7 @@ mod tests {
     use tipb::expression::Expr;
 
     use storage::TestEngineBuilder;
+    use util::config::ReadableDuration;
     use util::worker::FutureWorker;
 
     /// A unary `RequestHandler` that always produces a fixture.
@@ -704,6 +937,51 @@ mod tests {
             req
         };
 
+        match cop.try_parse_request(req.clone(), None, false) {
+            Err(Error::RecursionLimitExceeded(5, REQ_TYPE_DAG)) => {}
+            Err(e) => panic!("expect recursion limit exceeded, but got {:?}", e),
+            Ok(_) => panic!("expect recursion limit exceeded, but got ok"),
+        }
+        let resp: coppb::Response = cop
+            .parse_and_handle_unary_request(req, None)
+            .wait()
+            .unwrap();
+        assert!(resp.get_other_error().contains("recursion limit 5"));
+    }
+
+    #[test]
+    fn test_update_limits() {
+        let pd_worker = FutureWorker::new("test-pd-worker");
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let read_pool = ReadPool::new("readpool", &readpool::Config::default_for_test(), || {
+            || ReadPoolContext::new(pd_worker.scheduler())
+        });
+        let cop = Endpoint::new(&Config::default(), engine, read_pool);
+        let cop2 = cop.clone();
+
+        // Limits updated through one clone are visible to the others.
+        cop2.recursion_limit().store(5, atomic::Ordering::SeqCst);
+        cop2.stream_channel_size().store(3, atomic::Ordering::SeqCst);
+        assert_eq!(cop.recursion_limit().load(atomic::Ordering::SeqCst), 5);
+        assert_eq!(cop.stream_channel_size().load(atomic::Ordering::SeqCst), 3);
+
+        let req = {
+            let mut expr = Expr::new();
+            for _ in 0..10 {
+                let mut e = Expr::new();
+                e.mut_children().push(expr);
+                expr = e;
+            }
+            let mut e = Executor::new();
+            e.mut_selection().mut_conditions().push(expr);
+            let mut dag = DAGRequest::new();
+            dag.mut_executors().push(e);
+            let mut req = coppb::Request::new();
+            req.set_tp(REQ_TYPE_DAG);
+            req.set_data(dag.write_to_bytes().unwrap());
+            req
+        };
+
         let resp: coppb::Response = cop
             .parse_and_handle_unary_request(req, None)
             .wait()
@@ -750,6 +1028,118 @@ mod tests {
         assert!(!resp.get_other_error().is_empty());
     }
 
+    #[test]
+    fn test_paused() {
+        let pd_worker = FutureWorker::new("test-pd-worker");
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let read_pool = ReadPool::new("readpool", &readpool::Config::default_for_test(), || {
+            || ReadPoolContext::new(pd_worker.scheduler())
+        });
+        let cop = Endpoint::new(&Config::default(), engine, read_pool);
+
+        // A request accepted before pausing runs to the end.
+        let mut response = coppb::Response::new();
+        response.set_data(vec![1, 2, 3]);
+        let handler_builder =
+            box |_, _: &_| Ok(UnaryFixture::new_with_duration(Ok(response), 500).into_boxed());
+        let in_flight = cop.handle_unary_request(ReqContext::default_for_test(), handler_builder);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || tx.send(in_flight.wait().unwrap()));
+        thread::sleep(Duration::from_millis(100));
+
+        cop.clone().paused().store(true, atomic::Ordering::SeqCst);
+        let mut req = coppb::Request::new();
+        req.set_tp(9999);
+        let resp = cop
+            .parse_and_handle_unary_request(req.clone(), None)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            resp.get_region_error().get_server_is_busy().get_reason(),
+            PAUSED_ERROR_MSG
+        );
+        let resps = cop
+            .parse_and_handle_stream_request(req.clone(), None)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(resps.len(), 1);
+        assert!(resps[0].get_region_error().has_server_is_busy());
+
+        let resp = rx.recv().unwrap();
+        assert_eq!(resp.get_data(), [1, 2, 3]);
+
+        cop.paused().store(false, atomic::Ordering::SeqCst);
+        let resp = cop.parse_and_handle_unary_request(req, None).wait().unwrap();
+        assert!(!resp.has_region_error());
+        assert!(!resp.get_other_error().is_empty());
+    }
+
+    #[test]
+    fn test_overloaded() {
+        let pd_worker = FutureWorker::new("test-pd-worker");
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let read_pool = ReadPool::new(
+            "readpool",
+            &readpool::Config {
+                high_concurrency: 1,
+                normal_concurrency: 2,
+                low_concurrency: 1,
+                ..readpool::Config::default_for_test()
+            },
+            || || ReadPoolContext::new(pd_worker.scheduler()),
+        );
+        let cfg = Config {
+            end_point_overload_high_water: 0.9,
+            end_point_overload_low_water: 0.2,
+            end_point_overload_window: ReadableDuration::millis(0),
+            ..Config::default()
+        };
+        let cop = Endpoint::new(&cfg, engine, read_pool);
+
+        // Keep the threads of the normal pool busy.
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..2 {
+            let handler_builder = box |_, _: &_| {
+                let resp = coppb::Response::new();
+                Ok(UnaryFixture::new_with_duration(Ok(resp), 500).into_boxed())
+            };
+            let future = cop.handle_unary_request(ReqContext::default_for_test(), handler_builder);
+            let tx = tx.clone();
+            thread::spawn(move || tx.send(future.wait().unwrap()));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let mut req = coppb::Request::new();
+        req.set_tp(9999);
+        let resp = cop
+            .parse_and_handle_unary_request(req.clone(), None)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            resp.get_region_error().get_server_is_busy().get_reason(),
+            OVERLOADED_ERROR_MSG
+        );
+        // Requests to the other pools are still accepted.
+        let mut high_req = req.clone();
+        high_req
+            .mut_context()
+            .set_priority(kvrpcpb::CommandPri::High);
+        let resp = cop
+            .parse_and_handle_unary_request(high_req, None)
+            .wait()
+            .unwrap();
+        assert!(!resp.has_region_error());
+
+        // Requests are accepted again once the pool is idle.
+        for _ in 0..2 {
+            assert!(!rx.recv().unwrap().has_region_error());
+        }
+        let resp = cop.parse_and_handle_unary_request(req, None).wait().unwrap();
+        assert!(!resp.has_region_error());
+        assert!(!resp.get_other_error().is_empty());
+    }
+
     #[test]
     fn test_full() {
         let pd_worker = FutureWorker::new("test-pd-worker");
@@ -1008,6 +1398,97 @@ mod tests {
         assert!(counter.load(atomic::Ordering::SeqCst) < 14);
     }
 
+    #[test]
+    fn test_stream_backpressure() {
+        let pd_worker = FutureWorker::new("test-pd-worker");
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let read_pool = ReadPool::new("readpool", &readpool::Config::default_for_test(), || {
+            || ReadPoolContext::new(pd_worker.scheduler())
+        });
+        let cop = Endpoint::new(
+            &Config {
+                end_point_stream_channel_size: 3,
+                ..Config::default()
+            },
+            engine,
+            read_pool,
+        );
+
+        let counter = Arc::new(atomic::AtomicIsize::new(0));
+        let counter_clone = Arc::clone(&counter);
+        let handler = StreamFromClosure::new(move |nth| {
+            // produce an infinite stream
+            let mut resp = coppb::Response::new();
+            resp.set_data(vec![1, 2, nth as u8]);
+            counter_clone.fetch_add(1, atomic::Ordering::SeqCst);
+            Ok((Some(resp), false))
+        });
+        let handler_builder = box move |_, _: &_| Ok(handler.into_boxed());
+        let stalls = COPR_STREAM_BACKPRESSURE_STALLS.get();
+        let mut resp_iter = cop
+            .handle_stream_request(ReqContext::default_for_test(), handler_builder)
+            .wait();
+
+        // The channel holds 3 responses, plus 1 for the sender of the producer. A client not
+        // reading at all holds the producer at that many.
+        let cap = 4;
+        thread::sleep(Duration::from_millis(200));
+        assert!(counter.load(atomic::Ordering::SeqCst) <= cap);
+        // Consume slowly, the producer should only make up for what is consumed.
+        for i in 1..4 {
+            assert!(resp_iter.next().unwrap().is_ok());
+            thread::sleep(Duration::from_millis(100));
+            let produced = counter.load(atomic::Ordering::SeqCst);
+            assert!(produced >= i && produced <= cap + i, "produced {}", produced);
+        }
+        assert!(COPR_STREAM_BACKPRESSURE_STALLS.get() > stalls);
+    }
+
+    #[test]
+    fn test_slow_log() {
+        let pd_worker = FutureWorker::new("test-pd-worker");
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let read_pool = ReadPool::new(
+            "readpool",
+            &readpool::Config::default_with_concurrency(1),
+            || || ReadPoolContext::new(pd_worker.scheduler()),
+        );
+        let cop = Endpoint::new(&Config::default(), engine, read_pool);
+
+        let mut req_ctx = ReqContext::default_for_test();
+        // Other tests may run slow requests with the default tag.
+        req_ctx.tag = "slow_log_test";
+        req_ctx.slow_log_threshold = Duration::from_millis(100);
+        let slow_queries = COPR_SLOW_QUERY_COUNTER.with_label_values(&["slow_log_test"]);
+        let count = slow_queries.get();
+        let new_builder = |handle_duration_millis| -> RequestHandlerBuilder<_> {
+            box move |_, _: &_| {
+                let mut resp = coppb::Response::new();
+                resp.set_data(vec![0; 10]);
+                Ok(UnaryFixture::new_with_duration(Ok(resp), handle_duration_millis).into_boxed())
+            }
+        };
+
+        cop.handle_unary_request(req_ctx.clone(), new_builder(0))
+            .wait()
+            .unwrap();
+        assert_eq!(slow_queries.get(), count);
+        let resp = cop
+            .handle_unary_request(req_ctx.clone(), new_builder(300))
+            .wait()
+            .unwrap();
+        assert_eq!(slow_queries.get(), count + 1);
+        // Slow queries always carry their handle time.
+        assert!(resp.get_exec_details().has_handle_time());
+
+        // The slow log is disabled with a threshold of 0.
+        req_ctx.slow_log_threshold = Duration::from_secs(0);
+        cop.handle_unary_request(req_ctx, new_builder(300))
+            .wait()
+            .unwrap();
+        assert_eq!(slow_queries.get(), count + 1);
+    }
+
     #[test]
     fn test_handle_time() {
         use util::config::ReadableDuration;
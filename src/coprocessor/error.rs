@@ -38,6 +38,21 @@ quick_error! {
         Full {
             description("Coprocessor end-point thread pool is full")
         }
+        Paused {
+            description("Coprocessor end-point is paused")
+        }
+        Overloaded {
+            description("Coprocessor end-point is overloaded")
+        }
+        MemoryQuotaExceeded(quota: usize) {
+            description("memory quota exceeded")
+            display("request exceeds the memory quota of {} bytes", quota)
+        }
+        RecursionLimitExceeded(limit: u32, tp: i64) {
+            description("recursion limit exceeded")
+            display("request of type {} is nested deeper than the recursion limit {}, \
+                     see server.end-point-recursion-limit", tp, limit)
+        }
         Eval(err: tipb::select::Error) {
             from()
             description("eval failed")
@@ -69,4 +69,18 @@ lazy_static! {
         "Total number of rocksdb query of get or scan count",
         &["type"]
     ).unwrap();
+    pub static ref COPR_CACHE_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_coprocessor_cache_total",
+        "Total number of coprocessor result cache hits, misses and evictions",
+        &["type"]
+    ).unwrap();
+    pub static ref COPR_SLOW_QUERY_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "tikv_coprocessor_slow_request_total",
+        "Total number of coprocessor requests logged as slow queries",
+        &["req"]
+    ).unwrap();
+    pub static ref COPR_STREAM_BACKPRESSURE_STALLS: IntCounter = register_int_counter!(
+        "tikv_coprocessor_stream_backpressure_stalls",
+        "Total number of times a streaming request paused because the client was not ready"
+    ).unwrap();
 }
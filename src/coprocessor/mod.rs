@@ -11,18 +11,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod cache;
 mod checksum;
 pub mod codec;
+mod continuation;
 pub mod dag;
 mod endpoint;
 mod error;
 pub mod local_metrics;
 mod metrics;
+mod overload;
 mod readpool_context;
 mod statistics;
 mod tracker;
 pub mod util;
 
+pub use self::continuation::ContinuationToken;
 pub use self::endpoint::Endpoint;
 pub use self::error::{Error, Result};
 pub use self::readpool_context::Context as ReadPoolContext;
@@ -39,6 +43,9 @@ pub const REQ_TYPE_CHECKSUM: i64 = 105;
 
 const SINGLE_GROUP: &[u8] = b"SingleGroup";
 
+// Requests built without the config, like the ones in tests, take it as the threshold.
+const DEFAULT_SLOW_LOG_THRESHOLD: Duration = Duration::from_secs(1);
+
 type HandlerStreamStepResult = Result<(Option<coppb::Response>, bool)>;
 
 trait RequestHandler: Send {
@@ -125,6 +132,13 @@ pub struct ReqContext {
 
     /// The transaction start_ts of the request
     pub txn_start_ts: Option<u64>,
+
+    /// The max bytes of results that the request can hold, 0 means no limit
+    pub memory_quota: usize,
+
+    /// The request is logged as a slow query if it takes longer than it to process, 0 means
+    /// it's never logged
+    pub slow_log_threshold: Duration,
 }
 
 impl ReqContext {
@@ -147,6 +161,8 @@ impl ReqContext {
             txn_start_ts,
             first_range: ranges.first().cloned(),
             ranges_len: ranges.len(),
+            memory_quota: 0,
+            slow_log_threshold: DEFAULT_SLOW_LOG_THRESHOLD,
         }
     }
 
@@ -0,0 +1,103 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct State {
+    overloaded: bool,
+    // When the utilization rose above the high water, `None` if it's below.
+    above_since: Option<Instant>,
+}
+
+/// `OverloadDetector` tells whether the read pool has been overloaded, so that new
+/// requests can be refused instead of queueing up behind the ones being handled.
+///
+/// The pool turns overloaded once its utilization stays above `high_water` for `window`,
+/// and recovers once it drops below `low_water`, so that short bursts are still accepted
+/// and it doesn't flip on every sample around the threshold.
+pub struct OverloadDetector {
+    high_water: f64,
+    low_water: f64,
+    window: Duration,
+    state: Mutex<State>,
+}
+
+impl OverloadDetector {
+    /// `high_water` and `low_water` are fractions of the threads of the pool. A `low_water`
+    /// of 0 means the same as `high_water`.
+    pub fn new(high_water: f64, low_water: f64, window: Duration) -> OverloadDetector {
+        OverloadDetector {
+            high_water,
+            low_water: if low_water > 0.0 { low_water } else { high_water },
+            window,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Updates the detector with the `utilization` sampled at `now`, and returns whether
+    /// the pool is overloaded.
+    pub fn on_utilization(&self, utilization: f64, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.overloaded {
+            if utilization < self.low_water {
+                info!(
+                    "read pool utilization {:.3} drops below {}, stop refusing requests",
+                    utilization, self.low_water
+                );
+                state.overloaded = false;
+                state.above_since = None;
+            }
+            return state.overloaded;
+        }
+
+        if utilization < self.high_water {
+            state.above_since = None;
+            return false;
+        }
+        let since = *state.above_since.get_or_insert(now);
+        if now.duration_since(since) >= self.window {
+            warn!(
+                "read pool utilization {:.3} exceeds {} for {:?}, start refusing requests",
+                utilization, self.high_water, self.window
+            );
+            state.overloaded = true;
+        }
+        state.overloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overload_detector() {
+        let detector = OverloadDetector::new(0.9, 0.5, Duration::from_secs(10));
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+        assert!(!detector.on_utilization(0.95, at(0)));
+        assert!(!detector.on_utilization(0.95, at(5)));
+        // A dip below the high water restarts the window.
+        assert!(!detector.on_utilization(0.8, at(6)));
+        assert!(!detector.on_utilization(0.95, at(7)));
+        assert!(!detector.on_utilization(0.95, at(16)));
+        assert!(detector.on_utilization(1.0, at(17)));
+
+        // It recovers only after the utilization drops below the low water.
+        assert!(detector.on_utilization(0.8, at(18)));
+        assert!(!detector.on_utilization(0.4, at(19)));
+        assert!(!detector.on_utilization(0.95, at(20)));
+    }
+}
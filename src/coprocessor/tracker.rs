@@ -18,11 +18,9 @@ use util::futurepool;
 use util::time::{self, Duration, Instant};
 
 use coprocessor::dag::executor::ExecutorMetrics;
+use coprocessor::metrics::*;
 use coprocessor::*;
 
-// If handle time is larger than the lower bound, the query is considered as slow query.
-const SLOW_QUERY_LOWER_BOUND: f64 = 1.0; // 1 second.
-
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum TrackerState {
     /// The tracker is just created and not initialized. Initialize means `ctxd` is attached.
@@ -62,6 +60,7 @@ pub struct Tracker {
     total_process_time: Duration,
     total_exec_metrics: ExecutorMetrics,
     total_perf_statistics: PerfStatisticsDelta, // Accumulated perf statistics
+    total_result_bytes: usize,
 
     // Request info, used to print slow log.
     pub req_ctx: ReqContext,
@@ -87,6 +86,7 @@ impl Tracker {
             total_process_time: Duration::default(),
             total_exec_metrics: ExecutorMetrics::default(),
             total_perf_statistics: PerfStatisticsDelta::default(),
+            total_result_bytes: 0,
 
             req_ctx,
 
@@ -132,11 +132,22 @@ impl Tracker {
         self.current_stage = TrackerState::ItemFinished;
     }
 
+    /// Records the data size of a response produced by the request.
+    pub fn on_result(&mut self, bytes: usize) {
+        self.total_result_bytes += bytes;
+    }
+
+    // Whether the request is a slow query if it takes `process_time` to process.
+    fn is_slow_query(&self, process_time: Duration) -> bool {
+        let threshold = self.req_ctx.slow_log_threshold;
+        threshold > Duration::default() && process_time > threshold
+    }
+
     /// Get current item's ExecDetail according to previous collected metrics.
     /// TiDB asks for ExecDetail to be printed in its log.
     pub fn get_item_exec_details(&self) -> kvrpcpb::ExecDetails {
         assert!(self.current_stage == TrackerState::ItemFinished);
-        let is_slow_query = time::duration_to_sec(self.item_process_time) > SLOW_QUERY_LOWER_BOUND;
+        let is_slow_query = self.is_slow_query(self.item_process_time);
         let mut exec_details = kvrpcpb::ExecDetails::new();
         if self.req_ctx.context.get_handle_time() || is_slow_query {
             let mut handle = kvrpcpb::HandleTime::new();
@@ -167,7 +178,7 @@ impl Tracker {
         }
 
         // Print slow log if *process* time is long.
-        if time::duration_to_sec(self.total_process_time) > SLOW_QUERY_LOWER_BOUND {
+        if self.is_slow_query(self.total_process_time) {
             let some_table_id = self.req_ctx.first_range.as_ref().map(|range| {
                 super::codec::table::decode_table_id(range.get_start()).unwrap_or_default()
             });
@@ -176,7 +187,7 @@ impl Tracker {
                 "[region {}] [slow-query] execute takes {:?}, wait takes {:?}, \
                  peer: {:?}, start_ts: {:?}, table_id: {:?}, \
                  tag: {} (desc: {:?}) \
-                 [keys: {}, hit: {}, ranges: {} ({:?}), perf: {:?}]",
+                 [keys: {}, hit: {}, result_size: {}, ranges: {} ({:?}), perf: {:?}]",
                 self.req_ctx.context.get_region_id(),
                 self.total_process_time,
                 self.wait_time,
@@ -187,10 +198,14 @@ impl Tracker {
                 self.req_ctx.is_desc_scan,
                 self.total_exec_metrics.cf_stats.total_op_count(),
                 self.total_exec_metrics.cf_stats.total_processed(),
+                self.total_result_bytes,
                 self.req_ctx.ranges_len,
                 self.req_ctx.first_range,
                 self.total_perf_statistics,
             );
+            COPR_SLOW_QUERY_COUNTER
+                .with_label_values(&[self.req_ctx.tag])
+                .inc();
         }
 
         let total_exec_metrics =
@@ -63,6 +63,7 @@ extern crate lazy_static;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate lz4_sys;
 extern crate mio;
 extern crate murmur3;
 extern crate num;
@@ -199,6 +199,12 @@ impl Into<errorpb::Error> for Error {
                 server_is_busy_err.set_reason(RAFTSTORE_IS_BUSY.to_owned());
                 errorpb.set_server_is_busy(server_is_busy_err);
             }
+            Error::Transport(transport::Error::Busy(reason))
+            | Error::Transport(transport::Error::Throttled(reason)) => {
+                let mut server_is_busy_err = errorpb::ServerIsBusy::new();
+                server_is_busy_err.set_reason(reason);
+                errorpb.set_server_is_busy(server_is_busy_err);
+            }
             _ => {}
         };
 
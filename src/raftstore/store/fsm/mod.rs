@@ -21,7 +21,8 @@ mod store;
 
 pub use self::peer::DestroyPeerJob;
 pub use self::store::{
-    create_event_loop, new_compaction_listener, StoreChannel, StoreInfo, StoreStat,
+    create_event_loop, new_compaction_listener, HostedRegions, LeaderChangeCallback, StoreChannel,
+    StoreInfo, StoreStat,
 };
 
 use std::cell::RefCell;
@@ -65,6 +66,8 @@ pub struct Store<T, C: 'static> {
 
     // region_id -> peers
     region_peers: HashMap<u64, Peer>,
+    // A view of the keys of `region_peers` shared with other threads.
+    hosted_regions: HostedRegions,
     merging_regions: Option<Vec<metapb::Region>>,
     pending_raft_groups: HashSet<u64>,
     // region end key -> region id
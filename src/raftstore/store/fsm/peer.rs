@@ -31,7 +31,7 @@ use kvproto::raft_serverpb::{
     MergeState, PeerState, RaftMessage, RaftSnapshotData, RaftTruncatedState, RegionLocalState,
 };
 use raft::eraftpb::ConfChangeType;
-use raft::{self, SnapshotStatus, INVALID_INDEX, NO_LIMIT};
+use raft::{self, SnapshotStatus, StateRole, INVALID_INDEX, NO_LIMIT};
 
 use pd::{PdClient, PdTask};
 use raftstore::{Error, Result};
@@ -65,7 +65,7 @@ pub struct DestroyPeerJob {
     pub peer: metapb::Peer,
 }
 
-impl<T, C> Store<T, C> {
+impl<T: Transport, C: PdClient> Store<T, C> {
     pub fn poll_significant_msg(&mut self) {
         // Poll all snapshot messages and handle them.
         loop {
@@ -81,9 +81,13 @@ impl<T, C> Store<T, C> {
                 Ok(SignificantMsg::Unreachable {
                     region_id,
                     to_peer_id,
+                    ..
                 }) => if let Some(peer) = self.region_peers.get_mut(&region_id) {
                     peer.raft_group.report_unreachable(to_peer_id);
                 },
+                Ok(SignificantMsg::RaftMessage(msg)) => if let Err(e) = self.on_raft_message(msg) {
+                    error!("{} handle raft message err: {:?}", self.tag, e);
+                },
                 Err(TryRecvError::Empty) => {
                     // The snapshot status receiver channel is empty
                     return;
@@ -692,6 +696,24 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
         self.raft_metrics.ready.has_ready_region += append_res.len() as u64;
 
+        for &(ref ready, ref invoke_ctx) in &append_res {
+            if let Some(ref ss) = ready.ss {
+                let is_leader = ss.raft_state == StateRole::Leader;
+                // Other soft state changes, e.g. a follower learning a new leader, leave the
+                // role of the peer as it is.
+                let flipped = match self.region_peers.get_mut(&invoke_ctx.region_id) {
+                    Some(peer) if peer.was_leader != is_leader => {
+                        peer.was_leader = is_leader;
+                        true
+                    }
+                    _ => false,
+                };
+                if flipped {
+                    self.hosted_regions.notify_leader_change(invoke_ctx.region_id, is_leader);
+                }
+            }
+        }
+
         // apply_snapshot, peer_destroy will clear_meta, so we need write region state first.
         // otherwise, if program restart between two write, raft log will be removed,
         // but region state may not changed in disk.
@@ -835,6 +857,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             },
         };
 
+        self.hosted_regions.remove(region_id);
         info!("[region {}] destroy peer {:?}", region_id, peer);
         // We can't destroy a peer which is applying snapshot.
         assert!(!p.is_applying_snapshot());
@@ -1073,6 +1096,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
             new_peer.activate();
             self.region_peers.insert(new_region_id, new_peer);
+            self.hosted_regions.insert(new_region_id);
 
             if !campaigned {
                 if let Some(msg) = self
@@ -16,8 +16,9 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::Bound::{Excluded, Included, Unbounded};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver as StdReceiver};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 use std::{thread, u64};
 use time;
@@ -34,14 +35,14 @@ use pd::{PdClient, PdRunner, PdTask};
 use raftstore::coprocessor::split_observer::SplitObserver;
 use raftstore::coprocessor::{CoprocessorHost, RegionChangeEvent};
 use raftstore::store::util::{is_initial_msg, KeysInfoFormatter};
-use raftstore::Result;
+use raftstore::{Error, Result};
 use storage::{CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 use util::collections::{HashMap, HashSet};
 use util::rocksdb::{CompactedEvent, CompactionListener};
 use util::time::{duration_to_sec, SlowTimer};
 use util::transport::SendCh;
 use util::worker::{FutureWorker, Scheduler, Worker};
-use util::{rocksdb, sys as util_sys, RingQueue};
+use util::{rocksdb, sys as util_sys, HandyRwLock, RingQueue};
 
 use import::SSTImporter;
 use raftstore::store::config::Config;
@@ -60,8 +61,8 @@ use raftstore::store::worker::{
     SplitCheckRunner,
 };
 use raftstore::store::{
-    util, Engines, Msg, SeekRegionCallback, SeekRegionFilter, SeekRegionResult, SignificantMsg,
-    SnapManager, SnapshotDeleter, Store, Tick,
+    util, Engines, Msg, RegionReadProgress, RegionReadProgressCallback, SeekRegionCallback,
+    SeekRegionFilter, SeekRegionResult, SignificantMsg, SnapManager, SnapshotDeleter, Store, Tick,
 };
 
 type Key = Vec<u8>;
@@ -75,6 +76,82 @@ pub struct StoreChannel {
     pub significant_msg_receiver: StdReceiver<SignificantMsg>,
 }
 
+/// Called on the raftstore thread with whether the local peer of a region is leader, each
+/// time its raft role changes. It should return quickly.
+pub type LeaderChangeCallback = Box<Fn(bool) + Send + Sync>;
+
+/// The ids of the regions that have a peer on the store. The store keeps it updated as peers
+/// are created and destroyed, so that other threads can tell whether a region is hosted
+/// without asking the store.
+///
+/// Other threads can also subscribe to the leadership changes of the hosted regions. The
+/// subscriptions of a region are dropped when its peer is destroyed.
+#[derive(Clone, Default)]
+pub struct HostedRegions {
+    regions: Arc<RwLock<HashSet<u64>>>,
+    // region id -> subscription id -> callback.
+    leader_subscribers: Arc<RwLock<HashMap<u64, HashMap<u64, Arc<Fn(bool) + Send + Sync>>>>>,
+    next_subscription_id: Arc<AtomicUsize>,
+}
+
+impl HostedRegions {
+    pub fn contains(&self, region_id: u64) -> bool {
+        self.regions.rl().contains(&region_id)
+    }
+
+    pub fn insert(&self, region_id: u64) {
+        self.regions.wl().insert(region_id);
+    }
+
+    pub fn remove(&self, region_id: u64) {
+        let mut regions = self.regions.wl();
+        regions.remove(&region_id);
+        self.leader_subscribers.wl().remove(&region_id);
+    }
+
+    /// Registers `cb` for the leadership changes of the region. Returns the id to unsubscribe
+    /// with, or `None` if the region is not hosted.
+    pub fn subscribe_leader_change(&self, region_id: u64, cb: LeaderChangeCallback) -> Option<u64> {
+        // Hold the lock so that the region can't be removed before the callback is added.
+        let regions = self.regions.rl();
+        if !regions.contains(&region_id) {
+            return None;
+        }
+        let id = self.next_subscription_id.fetch_add(1, Ordering::Relaxed) as u64;
+        self.leader_subscribers
+            .wl()
+            .entry(region_id)
+            .or_insert_with(HashMap::default)
+            .insert(id, Arc::from(cb));
+        Some(id)
+    }
+
+    pub fn unsubscribe_leader_change(&self, region_id: u64, id: u64) {
+        let mut subscribers = self.leader_subscribers.wl();
+        let is_empty = match subscribers.get_mut(&region_id) {
+            Some(cbs) => {
+                cbs.remove(&id);
+                cbs.is_empty()
+            }
+            None => return,
+        };
+        if is_empty {
+            subscribers.remove(&region_id);
+        }
+    }
+
+    pub fn notify_leader_change(&self, region_id: u64, is_leader: bool) {
+        // Call them without the lock, so that the callbacks can unsubscribe.
+        let cbs: Vec<_> = match self.leader_subscribers.rl().get(&region_id) {
+            Some(cbs) => cbs.values().cloned().collect(),
+            None => return,
+        };
+        for cb in cbs {
+            cb(is_leader);
+        }
+    }
+}
+
 pub struct StoreStat {
     pub lock_cf_bytes_written: u64,
 
@@ -131,6 +208,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         local_reader: Worker<ReadTask>,
         mut coprocessor_host: CoprocessorHost,
         importer: Arc<SSTImporter>,
+        hosted_regions: HostedRegions,
     ) -> Result<Store<T, C>> {
         // TODO: we can get cluster meta regularly too later.
         cfg.validate()?;
@@ -150,6 +228,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             sendch,
             significant_msg_receiver: ch.significant_msg_receiver,
             region_peers: HashMap::default(),
+            hosted_regions,
             merging_regions: Some(vec![]),
             pending_raft_groups: HashSet::default(),
             split_check_worker: Worker::new("split-check"),
@@ -240,6 +319,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             // No need to check duplicated here, because we use region id as the key
             // in DB.
             self.region_peers.insert(region_id, peer);
+            self.hosted_regions.insert(region_id);
             self.coprocessor_host
                 .on_region_changed(region, RegionChangeEvent::Create);
             Ok(true)
@@ -266,6 +346,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             self.region_ranges
                 .insert(enc_end_key(&region), region.get_id());
             self.region_peers.insert(region.get_id(), peer);
+            self.hosted_regions.insert(region.get_id());
         }
 
         // recover prepare_merge
@@ -594,6 +675,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         // following snapshot may overlap, should insert into region_ranges after
         // snapshot is applied.
         self.region_peers.insert(region_id, peer);
+        self.hosted_regions.insert(region_id);
         Ok(true)
     }
 
@@ -704,6 +786,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
         let used_size = self.snap_mgr.get_total_snap_size();
         stats.set_used_size(used_size);
+        STORE_SNAPSHOT_SIZE_GAUGE.set(used_size as i64);
         stats.set_store_id(self.store_id());
         stats.set_region_count(self.region_peers.len() as u32);
 
@@ -994,6 +1077,18 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         callback(SeekRegionResult::Ended);
     }
 
+    fn on_region_read_progress(&self, region_id: u64, callback: RegionReadProgressCallback) {
+        let peer = match self.region_peers.get(&region_id) {
+            Some(peer) => peer,
+            None => return callback(Err(Error::RegionNotFound(region_id))),
+        };
+        callback(Ok(RegionReadProgress {
+            committed_index: peer.raft_group.raft.raft_log.committed,
+            applied_index: peer.get_store().applied_index(),
+            leader: peer.get_peer_from_cache(peer.leader_id()),
+        }))
+    }
+
     fn clear_region_size_in_range(&mut self, start_key: &[u8], end_key: &[u8]) {
         let start_key = data_key(start_key);
         let end_key = data_end_key(end_key);
@@ -1100,6 +1195,10 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
             Msg::ClearRegionSizeInRange { start_key, end_key } => {
                 self.clear_region_size_in_range(&start_key, &end_key)
             }
+            Msg::RegionReadProgress {
+                region_id,
+                callback,
+            } => self.on_region_read_progress(region_id, callback),
         }
     }
 
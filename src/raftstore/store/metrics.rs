@@ -84,6 +84,18 @@ lazy_static! {
             &["type"]
         ).unwrap();
 
+    pub static ref STORE_SNAPSHOT_SIZE_GAUGE: IntGauge =
+        register_int_gauge!(
+            "tikv_raftstore_snapshot_size_bytes",
+            "Total size of snapshot files on disk."
+        ).unwrap();
+
+    pub static ref STORE_SNAPSHOT_AVAILABLE_SPACE_GAUGE: IntGauge =
+        register_int_gauge!(
+            "tikv_raftstore_snapshot_available_space_bytes",
+            "Available space of the disk of the snapshot directory."
+        ).unwrap();
+
     pub static ref STORE_SNAPSHOT_VALIDATION_FAILURE_COUNTER: IntCounterVec =
         register_int_counter_vec!(
             "tikv_raftstore_snapshot_validation_failure_total",
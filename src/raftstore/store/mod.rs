@@ -36,12 +36,13 @@ pub use self::bootstrap::{
 pub use self::config::Config;
 pub use self::engine::{Iterable, Mutable, Peekable};
 pub use self::fsm::{
-    create_event_loop, new_compaction_listener, DestroyPeerJob, Store, StoreChannel, StoreInfo,
-    StoreStat,
+    create_event_loop, new_compaction_listener, DestroyPeerJob, HostedRegions,
+    LeaderChangeCallback, Store, StoreChannel, StoreInfo, StoreStat,
 };
 pub use self::msg::{
-    Callback, Msg, ReadCallback, ReadResponse, SeekRegionCallback, SeekRegionFilter,
-    SeekRegionResult, SignificantMsg, Tick, WriteCallback, WriteResponse,
+    Callback, ConfChange, ConfChangeCallback, Msg, ReadCallback, ReadResponse, RegionReadProgress,
+    RegionReadProgressCallback, SeekRegionCallback, SeekRegionFilter, SeekRegionResult,
+    SignificantMsg, Tick, UnreachableReason, WriteCallback, WriteResponse,
 };
 pub use self::peer::{
     Peer, PeerStat, ProposalContext, ReadExecutor, RequestInspector, RequestPolicy,
@@ -62,4 +63,6 @@ pub use self::worker::{KeyEntry, ReadTask};
 
 // Only used in tests
 #[cfg(test)]
+pub use self::snap::tests::{gen_test_region, open_test_db};
+#[cfg(test)]
 pub use self::worker::{SplitCheckRunner, SplitCheckTask};
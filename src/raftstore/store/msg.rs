@@ -13,8 +13,10 @@
 
 use std::boxed::FnBox;
 use std::fmt;
+use std::result;
 use std::time::Instant;
 
+use kvproto::errorpb;
 use kvproto::import_sstpb::SSTMeta;
 use kvproto::metapb;
 use kvproto::metapb::RegionEpoch;
@@ -22,8 +24,10 @@ use kvproto::pdpb::CheckPolicy;
 use kvproto::raft_cmdpb::{RaftCmdRequest, RaftCmdResponse};
 use kvproto::raft_serverpb::RaftMessage;
 
+use raft::eraftpb::ConfChangeType;
 use raft::SnapshotStatus;
 use raftstore::store::util::KeysInfoFormatter;
+use raftstore::Result;
 use util::escape;
 use util::rocksdb::CompactedEvent;
 
@@ -38,6 +42,9 @@ pub struct ReadResponse {
 #[derive(Debug)]
 pub struct WriteResponse {
     pub response: RaftCmdResponse,
+    /// The log index the command is applied at, 0 if it's not applied, e.g. refused before
+    /// being proposed.
+    pub applied_index: u64,
 }
 
 #[derive(Debug)]
@@ -58,6 +65,48 @@ pub type WriteCallback = Box<FnBox(WriteResponse) + Send>;
 pub type SeekRegionCallback = Box<FnBox(SeekRegionResult) + Send>;
 pub type SeekRegionFilter = Box<Fn(&Peer) -> bool + Send>;
 
+/// The replication progress of a local peer, see `RaftStoreRouter::region_read_progress`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionReadProgress {
+    pub committed_index: u64,
+    pub applied_index: u64,
+    /// `None` if the peer doesn't know the leader yet.
+    pub leader: Option<metapb::Peer>,
+}
+
+pub type RegionReadProgressCallback = Box<FnBox(Result<RegionReadProgress>) + Send>;
+
+/// A change of the members of a region, proposed through `RaftStoreRouter::async_conf_change`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfChange {
+    pub change_type: ConfChangeType,
+    pub peer: metapb::Peer,
+}
+
+impl ConfChange {
+    pub fn new(change_type: ConfChangeType, peer: metapb::Peer) -> ConfChange {
+        ConfChange { change_type, peer }
+    }
+
+    /// Checks the peer has both its id and store id set, and is a learner if and only if
+    /// it's added as a learner. Checks against the region, e.g. removing the leader, are
+    /// left to the leader.
+    pub fn validate(&self) -> Result<()> {
+        if self.peer.get_id() == 0 || self.peer.get_store_id() == 0 {
+            return Err(box_err!("invalid peer {:?} to change", self.peer));
+        }
+        match (self.change_type, self.peer.get_is_learner()) {
+            (ConfChangeType::AddNode, true) | (ConfChangeType::AddLearnerNode, false) => Err(
+                box_err!("conf change type {:?}, but got peer {:?}", self.change_type, self.peer),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Called with the region after the conf change is applied, or the error of the response.
+pub type ConfChangeCallback = Box<FnBox(result::Result<metapb::Region, errorpb::Error>) + Send>;
+
 /// Variants of callbacks for `Msg`.
 ///  - `Read`: a callbak for read only requests including `StatusRequest`,
 ///         `GetRequest` and `SnapRequest`
@@ -74,6 +123,11 @@ pub enum Callback {
 
 impl Callback {
     pub fn invoke_with_response(self, resp: RaftCmdResponse) {
+        self.invoke_applied(resp, 0)
+    }
+
+    /// Same as `invoke_with_response`, for the command applied at `applied_index`.
+    pub fn invoke_applied(self, resp: RaftCmdResponse, applied_index: u64) {
         match self {
             Callback::None => (),
             Callback::Read(read) => {
@@ -84,7 +138,10 @@ impl Callback {
                 read(resp);
             }
             Callback::Write(write) => {
-                let resp = WriteResponse { response: resp };
+                let resp = WriteResponse {
+                    response: resp,
+                    applied_index,
+                };
                 write(resp);
             }
         }
@@ -144,6 +201,31 @@ impl Tick {
     }
 }
 
+/// The cause of a peer being reported unreachable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnreachableReason {
+    /// The cause is not given by the reporter.
+    Unknown,
+    /// The address of the target store is still being resolved.
+    Resolving,
+    /// The address of the target store can't be resolved.
+    ResolveFailed,
+    /// The message can't be handed to the connection of the target store.
+    SendFailed,
+    /// The message exceeds the max raft message size.
+    MessageTooLarge,
+    /// The message isn't flushed to the connection of the target store in time.
+    SendTimeout,
+    /// The target store has been removed from the cluster.
+    StoreTombstone,
+}
+
+impl Default for UnreachableReason {
+    fn default() -> UnreachableReason {
+        UnreachableReason::Unknown
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SignificantMsg {
     SnapshotStatus {
@@ -154,7 +236,10 @@ pub enum SignificantMsg {
     Unreachable {
         region_id: u64,
         to_peer_id: u64,
+        reason: UnreachableReason,
     },
+    /// A raft message that must not be dropped when the store channel is full.
+    RaftMessage(RaftMessage),
 }
 
 pub enum Msg {
@@ -228,6 +313,12 @@ pub enum Msg {
         start_key: Vec<u8>,
         end_key: Vec<u8>,
     },
+
+    // For the replication progress of a region
+    RegionReadProgress {
+        region_id: u64,
+        callback: RegionReadProgressCallback,
+    },
 }
 
 impl fmt::Debug for Msg {
@@ -285,6 +376,9 @@ impl fmt::Debug for Msg {
                 "Clear Region size in range {:?} to {:?}",
                 start_key, end_key
             ),
+            Msg::RegionReadProgress { region_id, .. } => {
+                write!(fmt, "Region read progress region_id {}", region_id)
+            }
         }
     }
 }
@@ -327,7 +421,7 @@ mod tests {
         sendch: &SendCh<Msg>,
         request: RaftCmdRequest,
         timeout: Duration,
-    ) -> Result<RaftCmdResponse, Error> {
+    ) -> result::Result<RaftCmdResponse, Error> {
         wait_op!(
             |cb: Box<FnBox(RaftCmdResponse) + 'static + Send>| {
                 let callback = Callback::Write(Box::new(move |write_resp: WriteResponse| {
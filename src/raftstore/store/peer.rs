@@ -300,6 +300,9 @@ pub struct Peer {
     leader_missing_time: Option<Instant>,
 
     leader_lease: Lease,
+    // Whether the peer is leader as of the last soft state change, so that the leadership
+    // subscribers are only notified when it flips.
+    pub was_leader: bool,
 
     // If a snapshot is being applied asynchronously, messages should not be sent.
     pending_messages: Vec<eraftpb::Message>,
@@ -435,6 +438,7 @@ impl Peer {
             raft_log_size_hint: 0,
             raft_entry_max_size: cfg.raft_entry_max_size.0,
             leader_lease: Lease::new(cfg.raft_store_max_leader_lease()),
+            was_leader: false,
             cfg,
             pending_messages: vec![],
             peer_stat: PeerStat::default(),
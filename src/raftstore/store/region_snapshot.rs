@@ -12,6 +12,7 @@
 // limitations under the License.
 
 use kvproto::metapb::Region;
+use kvproto::raft_serverpb::RaftApplyState;
 use rocksdb::{DBIterator, DBVector, SeekKey, TablePropertiesCollection, DB};
 use std::cmp;
 use std::sync::Arc;
@@ -19,6 +20,7 @@ use std::sync::Arc;
 use raftstore::store::engine::{IterOption, Peekable, Snapshot, SyncSnapshot};
 use raftstore::store::{keys, util, PeerStorage};
 use raftstore::Result;
+use storage::CF_RAFT;
 use util::set_panic_mark;
 
 /// Snapshot of a region.
@@ -113,6 +115,26 @@ impl RegionSnapshot {
         util::get_region_properties_cf(&self.snap.get_db(), cf, self.get_region())
     }
 
+    /// Estimates the bytes and the keys of the region from the table properties. Only the
+    /// range of the region is taken from the snapshot, the properties are the latest ones.
+    pub fn get_approximate_size(&self) -> Result<(u64, u64)> {
+        let db = self.snap.get_db();
+        let size = util::get_region_approximate_size(&db, self.get_region())?;
+        let keys = util::get_region_approximate_keys(&db, self.get_region())?;
+        Ok((size, keys))
+    }
+
+    /// Returns the applied index of the region in the snapshot. The apply state is written
+    /// along with the data, so the index tells exactly what the snapshot sees.
+    pub fn get_applied_index(&self) -> Result<u64> {
+        let region_id = self.region.get_id();
+        let key = keys::apply_state_key(region_id);
+        match self.snap.get_msg_cf::<RaftApplyState>(CF_RAFT, &key)? {
+            Some(state) => Ok(state.get_applied_index()),
+            None => Err(box_err!("region {} has no apply state", region_id)),
+        }
+    }
+
     pub fn get_start_key(&self) -> &[u8] {
         self.region.get_start_key()
     }
@@ -17,9 +17,10 @@ use std::fs::{self, Metadata};
 use std::io::{self, BufReader, ErrorKind, Read, Write};
 use std::path::Path;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{error, result, str, thread, time, u64};
 
+use fs2;
 use kvproto::metapb::Region;
 use kvproto::raft_serverpb::RaftSnapshotData;
 use protobuf::Message;
@@ -43,7 +44,8 @@ use raftstore::store::keys::{self, enc_end_key, enc_start_key};
 
 use raftstore::store::metrics::{
     INGEST_SST_DURATION_SECONDS, SNAPSHOT_BUILD_TIME_HISTOGRAM, SNAPSHOT_CF_KV_COUNT,
-    SNAPSHOT_CF_SIZE,
+    SNAPSHOT_CF_SIZE, STORE_SNAPSHOT_AVAILABLE_SPACE_GAUGE,
+    STORE_SNAPSHOT_VALIDATION_FAILURE_COUNTER,
 };
 use raftstore::store::peer_storage::JOB_STATUS_CANCELLING;
 
@@ -930,6 +932,11 @@ impl Snapshot for Snap {
             }
             let checksum = cf_file.write_digest.as_ref().unwrap().sum32();
             if checksum != cf_file.checksum {
+                // The snapshot is corrupted during the transfer. Failing to save it makes
+                // the sender report the failure, so that the leader sends it again.
+                STORE_SNAPSHOT_VALIDATION_FAILURE_COUNTER
+                    .with_label_values(&["checksum"])
+                    .inc();
                 return Err(io::Error::new(
                     ErrorKind::Other,
                     format!(
@@ -1105,6 +1112,14 @@ struct SnapManagerCore {
     snap_size: Arc<AtomicU64>,
 }
 
+// The available space of the disk is got again at most once in so long.
+const AVAILABLE_SPACE_REFRESH_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+struct AvailableSpace {
+    bytes: u64,
+    refresh_time: Option<time::Instant>,
+}
+
 fn notify_stats(ch: Option<&SendCh<Msg>>) {
     if let Some(ch) = ch {
         if let Err(e) = ch.try_send(Msg::SnapshotStats) {
@@ -1121,6 +1136,10 @@ pub struct SnapManager {
     ch: Option<SendCh<Msg>>,
     limiter: Option<Arc<IOLimiter>>,
     max_total_size: u64,
+    max_pending_size: u64,
+    min_available_space: u64,
+    available_space: Arc<Mutex<AvailableSpace>>,
+    prefetch_on_apply: bool,
 }
 
 impl SnapManager {
@@ -1318,6 +1337,58 @@ impl SnapManager {
         self.max_total_size
     }
 
+    /// Whether the snapshot files on disk exceed the max pending size, in which case
+    /// no more snapshots should be received until some are applied or cleaned up.
+    pub fn is_pending_full(&self) -> bool {
+        self.get_total_snap_size() >= self.max_pending_size
+    }
+
+    /// Whether the disk of the snapshot directory has less available space than the min
+    /// available space, in which case no more snapshots should be received. The available
+    /// space is cached for a short while, so it's cheap to check for every snapshot.
+    pub fn is_disk_full(&self) -> bool {
+        self.min_available_space > 0 && self.get_available_space() < self.min_available_space
+    }
+
+    /// Whether the data of a region is read into the block cache after its snapshot is
+    /// applied.
+    pub fn prefetch_on_apply(&self) -> bool {
+        self.prefetch_on_apply
+    }
+
+    /// Gets the available space of the disk of the snapshot directory, which may be
+    /// cached for a short while.
+    pub fn get_available_space(&self) -> u64 {
+        let mut space = self.available_space.lock().unwrap();
+        let now = time::Instant::now();
+        let expired = space
+            .refresh_time
+            .map_or(true, |t| now.duration_since(t) >= AVAILABLE_SPACE_REFRESH_INTERVAL);
+        if expired {
+            let base = self.core.rl().base.clone();
+            match fs2::available_space(&base) {
+                Ok(bytes) => {
+                    space.bytes = bytes;
+                    STORE_SNAPSHOT_AVAILABLE_SPACE_GAUGE.set(bytes as i64);
+                }
+                // Keeps the last known space.
+                Err(e) => warn!("failed to get the available space of {}: {:?}", base, e),
+            }
+            space.refresh_time = Some(now);
+        }
+        space.bytes
+    }
+
+    /// Whether writing snapshot files is rate limited.
+    pub fn is_throttled(&self) -> bool {
+        self.limiter.is_some()
+    }
+
+    /// The limiter of the snapshot IO, if it's rate limited.
+    pub fn io_limiter(&self) -> Option<Arc<IOLimiter>> {
+        self.limiter.clone()
+    }
+
     pub fn register(&self, key: SnapKey, entry: SnapEntry) {
         debug!("register [key: {}, entry: {:?}]", key, entry);
         let mut core = self.core.wl();
@@ -1413,6 +1484,9 @@ impl SnapshotDeleter for SnapManager {
 pub struct SnapManagerBuilder {
     max_write_bytes_per_sec: u64,
     max_total_size: u64,
+    max_pending_size: u64,
+    min_available_space: u64,
+    prefetch_on_apply: bool,
 }
 
 impl SnapManagerBuilder {
@@ -1424,6 +1498,18 @@ impl SnapManagerBuilder {
         self.max_total_size = bytes;
         self
     }
+    pub fn max_pending_size(&mut self, bytes: u64) -> &mut SnapManagerBuilder {
+        self.max_pending_size = bytes;
+        self
+    }
+    pub fn min_available_space(&mut self, bytes: u64) -> &mut SnapManagerBuilder {
+        self.min_available_space = bytes;
+        self
+    }
+    pub fn prefetch_on_apply(&mut self, enabled: bool) -> &mut SnapManagerBuilder {
+        self.prefetch_on_apply = enabled;
+        self
+    }
     pub fn build<T: Into<String>>(&self, path: T, ch: Option<SendCh<Msg>>) -> SnapManager {
         let limiter = if self.max_write_bytes_per_sec > 0 {
             Some(Arc::new(IOLimiter::new(self.max_write_bytes_per_sec)))
@@ -1435,6 +1521,11 @@ impl SnapManagerBuilder {
         } else {
             u64::MAX
         };
+        let max_pending_size = if self.max_pending_size > 0 {
+            self.max_pending_size
+        } else {
+            u64::MAX
+        };
         SnapManager {
             core: Arc::new(RwLock::new(SnapManagerCore {
                 base: path.into(),
@@ -1444,6 +1535,14 @@ impl SnapManagerBuilder {
             ch,
             limiter,
             max_total_size,
+            max_pending_size,
+            min_available_space: self.min_available_space,
+            // Nothing is refused until the space is got.
+            available_space: Arc::new(Mutex::new(AvailableSpace {
+                bytes: u64::MAX,
+                refresh_time: None,
+            })),
+            prefetch_on_apply: self.prefetch_on_apply,
         }
     }
 }
@@ -1472,6 +1571,7 @@ pub mod tests {
 
     use raftstore::store::engine::{Iterable, Mutable, Peekable, Snapshot as DbSnapshot};
     use raftstore::store::keys;
+    use raftstore::store::metrics::STORE_SNAPSHOT_VALIDATION_FAILURE_COUNTER;
     use raftstore::store::peer_storage::JOB_STATUS_RUNNING;
     use raftstore::Result;
     use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
@@ -2064,6 +2164,69 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_snap_transfer_checksum_mismatch() {
+        let region_id = 1;
+        let region = gen_test_region(region_id, 1, 1);
+        let db_dir = TempDir::new("test-snap-checksum-db").unwrap();
+        let db = open_test_db(&db_dir, None).unwrap();
+        let snapshot = DbSnapshot::new(db);
+
+        let dir = TempDir::new("test-snap-checksum").unwrap();
+        let key = SnapKey::new(region_id, 1, 1);
+        let size_track = Arc::new(AtomicU64::new(0));
+        let deleter = Box::new(DummyDeleter {});
+        let mut s1 = Snap::new_for_building(
+            dir.path(),
+            &key,
+            &snapshot,
+            Arc::clone(&size_track),
+            deleter.clone(),
+            None,
+        ).unwrap();
+        let mut snap_data = RaftSnapshotData::new();
+        snap_data.set_region(region.clone());
+        let mut stat = SnapshotStatistics::new();
+        s1.build(
+            &snapshot,
+            &region,
+            &mut snap_data,
+            &mut stat,
+            deleter.clone(),
+        ).unwrap();
+
+        // The receiver expects another checksum, as if the data is corrupted on the way.
+        let mut meta = snap_data.get_meta().clone();
+        for cf in meta.mut_cf_files().iter_mut() {
+            if cf.get_size() > 0 {
+                let checksum = cf.get_checksum();
+                cf.set_checksum(checksum.wrapping_add(1));
+            }
+        }
+        let mut from = Snap::new_for_sending(
+            dir.path(),
+            &key,
+            Arc::clone(&size_track),
+            deleter.clone(),
+        ).unwrap();
+        let dst_dir = TempDir::new("test-snap-checksum-dst").unwrap();
+        let mut to = Snap::new_for_receiving(
+            dst_dir.path(),
+            &key,
+            meta,
+            Arc::clone(&size_track),
+            deleter,
+            None,
+        ).unwrap();
+
+        let failures = STORE_SNAPSHOT_VALIDATION_FAILURE_COUNTER.with_label_values(&["checksum"]);
+        let count = failures.get();
+        io::copy(&mut from, &mut to).unwrap();
+        assert!(to.save().is_err());
+        assert!(!to.exists());
+        assert!(failures.get() > count);
+    }
+
     #[test]
     fn test_snap_corruption_on_meta_file() {
         let region_id = 1;
@@ -2406,4 +2569,69 @@ pub mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_snapshot_max_pending_size() {
+        let kv_path = TempDir::new("test-snapshot-max-pending-size-db").unwrap();
+        let kv = get_test_db_for_regions(&kv_path, None, &[1]).unwrap();
+        let snapshot = DbSnapshot::new(kv);
+
+        let snapfiles_path = TempDir::new("test-snapshot-max-pending-size-snapshots").unwrap();
+        let snap_mgr = SnapManagerBuilder::default()
+            .max_pending_size(1)
+            .build(snapfiles_path.path().to_str().unwrap(), None);
+        assert!(!snap_mgr.is_pending_full());
+
+        let key = SnapKey::new(1, 1, 1);
+        let mut s = snap_mgr.get_snapshot_for_building(&key, &snapshot).unwrap();
+        let mut snap_data = RaftSnapshotData::new();
+        let mut stat = SnapshotStatistics::new();
+        s.build(
+            &snapshot,
+            &gen_test_region(1, 1, 1),
+            &mut snap_data,
+            &mut stat,
+            Box::new(snap_mgr.clone()),
+        ).unwrap();
+        assert!(snap_mgr.is_pending_full());
+
+        // Cleaning up the snapshot frees the space.
+        assert!(snap_mgr.delete_snapshot(&key, s.as_ref(), false));
+        assert!(!snap_mgr.is_pending_full());
+
+        // Existing snapshot files are counted after restarting.
+        let mut s = snap_mgr.get_snapshot_for_building(&key, &snapshot).unwrap();
+        s.build(
+            &snapshot,
+            &gen_test_region(1, 1, 1),
+            &mut snap_data,
+            &mut stat,
+            Box::new(snap_mgr.clone()),
+        ).unwrap();
+        let snap_mgr = SnapManagerBuilder::default()
+            .max_pending_size(1)
+            .build(snapfiles_path.path().to_str().unwrap(), None);
+        snap_mgr.init().unwrap();
+        assert!(snap_mgr.is_pending_full());
+    }
+
+    #[test]
+    fn test_snapshot_min_available_space() {
+        let snapfiles_path = TempDir::new("test-snapshot-min-available-space").unwrap();
+        let path = snapfiles_path.path().to_str().unwrap();
+        let snap_mgr = SnapManager::new(path, None);
+        assert!(!snap_mgr.is_disk_full());
+        let available = snap_mgr.get_available_space();
+        assert!(available > 0 && available < u64::MAX);
+
+        // No disk has so much space.
+        let snap_mgr = SnapManagerBuilder::default()
+            .min_available_space(u64::MAX)
+            .build(path, None);
+        assert!(snap_mgr.is_disk_full());
+        let snap_mgr = SnapManagerBuilder::default()
+            .min_available_space(1)
+            .build(path, None);
+        assert!(!snap_mgr.is_disk_full());
+    }
 }
@@ -26,6 +26,7 @@ use raftstore::store::keys;
 use raftstore::{Error, Result};
 use rocksdb::{Range, TablePropertiesCollection, Writable, WriteBatch, DB};
 use time::{Duration, Timespec};
+use uuid::Uuid;
 
 use storage::{Key, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE, LARGE_CFS};
 use util::escape;
@@ -346,6 +347,27 @@ pub fn check_peer_id(req: &RaftCmdRequest, peer_id: u64) -> Result<()> {
     }
 }
 
+/// Formats the trace id of a command, which is the uuid in the header of its request and
+/// response.
+pub fn format_trace_id(uuid: &[u8]) -> String {
+    match Uuid::from_bytes(uuid) {
+        Ok(id) => id.to_string(),
+        Err(_) => escape(uuid),
+    }
+}
+
+/// Returns the trace ids of the commands carried by the entries of `msg`, so that the
+/// transport events of a message can be told apart by the requests they hold up.
+pub fn msg_trace_ids(msg: &eraftpb::Message) -> Vec<String> {
+    msg.get_entries()
+        .iter()
+        .filter(|e| e.get_entry_type() == eraftpb::EntryType::EntryNormal)
+        .filter_map(|e| protobuf::parse_from_bytes::<RaftCmdRequest>(e.get_data()).ok())
+        .filter(|cmd| !cmd.get_header().get_uuid().is_empty())
+        .map(|cmd| format_trace_id(cmd.get_header().get_uuid()))
+        .collect()
+}
+
 pub fn get_region_properties_cf(
     db: &DB,
     cfname: &str,
@@ -814,6 +836,31 @@ impl RemoteLease {
         }
     }
 
+    /// Returns how long the lease stays valid after `ts`, which is zero if it has expired.
+    pub fn remaining(&self, ts: Timespec) -> Duration {
+        let expired_time = u64_to_timespec(self.expired_time.load(AtomicOrdering::Acquire));
+        if ts < expired_time {
+            expired_time - ts
+        } else {
+            Duration::zero()
+        }
+    }
+
+    /// Returns how long the lease has expired at `ts`, which is zero if it's still valid, or
+    /// `None` if it's expired explicitly, e.g. when the leader steps down.
+    pub fn expired_for(&self, ts: Timespec) -> Option<Duration> {
+        let expired_time = self.expired_time.load(AtomicOrdering::Acquire);
+        if expired_time == 0 {
+            return None;
+        }
+        let expired_time = u64_to_timespec(expired_time);
+        if ts < expired_time {
+            Some(Duration::zero())
+        } else {
+            Some(ts - expired_time)
+        }
+    }
+
     fn renew(&self, bound: Timespec) {
         self.expired_time
             .store(timespec_to_u64(bound), AtomicOrdering::Release);
@@ -1232,6 +1279,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_msg_trace_ids() {
+        let uuid = Uuid::new_v4();
+        let mut cmd = RaftCmdRequest::new();
+        cmd.mut_header().set_uuid(uuid.as_bytes().to_vec());
+        let mut msg = Message::new();
+        msg.set_msg_type(MessageType::MsgAppend);
+        let mut entry = eraftpb::Entry::new();
+        entry.set_data(protobuf::Message::write_to_bytes(&cmd).unwrap());
+        msg.mut_entries().push(entry.clone());
+        // Neither empty entries nor conf changes carry commands.
+        msg.mut_entries().push(eraftpb::Entry::new());
+        entry.set_entry_type(eraftpb::EntryType::EntryConfChange);
+        msg.mut_entries().push(entry);
+        assert_eq!(msg_trace_ids(&msg), vec![uuid.to_string()]);
+        assert!(msg_trace_ids(&Message::new()).is_empty());
+    }
+
     #[test]
     fn test_conf_change_type_str() {
         assert_eq!(
@@ -203,7 +203,8 @@ pub enum ExecResult {
 
 struct ApplyCallback {
     region: Region,
-    cbs: Vec<(Option<Callback>, RaftCmdResponse)>,
+    // (callback, response, the index the command is applied at)
+    cbs: Vec<(Option<Callback>, RaftCmdResponse, u64)>,
 }
 
 impl ApplyCallback {
@@ -213,16 +214,16 @@ impl ApplyCallback {
     }
 
     fn invoke_all(self, host: &CoprocessorHost) {
-        for (cb, mut resp) in self.cbs {
+        for (cb, mut resp, index) in self.cbs {
             host.post_apply(&self.region, &mut resp);
             if let Some(cb) = cb {
-                cb.invoke_with_response(resp)
+                cb.invoke_applied(resp, index)
             };
         }
     }
 
-    fn push(&mut self, cb: Option<Callback>, resp: RaftCmdResponse) {
-        self.cbs.push((cb, resp));
+    fn push(&mut self, cb: Option<Callback>, resp: RaftCmdResponse, index: u64) {
+        self.cbs.push((cb, resp, index));
     }
 }
 
@@ -639,11 +640,11 @@ impl ApplyDelegate {
         assert!(term > 0);
         while let Some(mut cmd) = self.pending_cmds.pop_normal(term - 1) {
             // apprently, all the callbacks whose term is less than entry's term are stale.
-            apply_ctx
-                .cbs
-                .last_mut()
-                .unwrap()
-                .push(cmd.cb.take(), cmd_resp::err_resp(Error::StaleCommand, term));
+            apply_ctx.cbs.last_mut().unwrap().push(
+                cmd.cb.take(),
+                cmd_resp::err_resp(Error::StaleCommand, term),
+                0,
+            );
         }
         None
     }
@@ -728,7 +729,7 @@ impl ApplyDelegate {
         // TODO: if we have exec_result, maybe we should return this callback too. Outer
         // store will call it after handing exec result.
         cmd_resp::bind_term(&mut resp, self.term);
-        apply_ctx.cbs.last_mut().unwrap().push(cmd_cb, resp);
+        apply_ctx.cbs.last_mut().unwrap().push(cmd_cb, resp, index);
 
         exec_result
     }
@@ -2907,6 +2908,7 @@ mod tests {
         core.cbs.last_mut().unwrap().push(
             Some(Callback::Write(Box::new(move |_| tx1.send(1).unwrap()))),
             RaftCmdResponse::new(),
+            4,
         );
         core.exec_ctx
             .as_mut()
@@ -2926,6 +2928,7 @@ mod tests {
         core.cbs.last_mut().unwrap().push(
             Some(Callback::Write(Box::new(move |_| tx1.send(2).unwrap()))),
             RaftCmdResponse::new(),
+            2,
         );
         delegate2.apply_state = core.exec_ctx.take().unwrap().apply_state;
         core.finish_for(&mut delegate2, vec![]);
@@ -2938,6 +2941,7 @@ mod tests {
         core.cbs.last_mut().unwrap().push(
             Some(Callback::Write(Box::new(move |_| tx1.send(3).unwrap()))),
             RaftCmdResponse::new(),
+            4,
         );
         delegate1.apply_state = core.exec_ctx.take().unwrap().apply_state;
         core.finish_for(&mut delegate1, vec![]);
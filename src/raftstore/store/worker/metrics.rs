@@ -11,7 +11,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::{exponential_buckets, Gauge, Histogram, HistogramVec, IntCounterVec};
+use prometheus::{
+    exponential_buckets, Gauge, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec,
+};
 
 lazy_static! {
     pub static ref SNAP_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
@@ -58,6 +60,15 @@ lazy_static! {
         "Total number of rejections from the local read thread.",
         &["reason"]
     ).unwrap();
+    pub static ref LOCAL_READ_STALE: IntCounter = register_int_counter!(
+        "tikv_raftstore_local_read_stale_total",
+        "Total number of local reads served with an expired lease within their staleness bounds."
+    ).unwrap();
+    pub static ref LOCAL_READ_LEASE_REMAINING: GaugeVec = register_gauge_vec!(
+        "tikv_raftstore_local_read_lease_remaining_seconds",
+        "Remaining lease time of the leaders whose leases expire first.",
+        &["region"]
+    ).unwrap();
     pub static ref LOCAL_READ_WAIT_DURATION: Histogram = register_histogram!(
         "tikv_raftstore_local_read_requests_wait_duration",
         "Bucketed histogram of local read requests wait duration.",
@@ -68,4 +79,8 @@ lazy_static! {
         "Bucketed histogram of local read batch requests size.",
         exponential_buckets(1.0, 2.0, 15).unwrap()
     ).unwrap();
+    pub static ref SNAP_PREFETCH_BYTES_COUNTER: IntCounter = register_int_counter!(
+        "tikv_raftstore_snapshot_prefetch_bytes",
+        "Total bytes read into the block cache after snapshots are applied."
+    ).unwrap();
 }
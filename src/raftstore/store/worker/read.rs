@@ -22,7 +22,7 @@ use kvproto::raft_cmdpb::{CmdType, RaftCmdRequest, RaftCmdResponse};
 use mio;
 use prometheus::local::LocalHistogram;
 use rocksdb::DB;
-use time::Timespec;
+use time::{Duration as TimeDuration, Timespec};
 
 use raftstore::errors::RAFTSTORE_IS_BUSY;
 use raftstore::store::msg::Callback;
@@ -33,7 +33,7 @@ use raftstore::store::{
 };
 use raftstore::Result;
 use util::collections::HashMap;
-use util::time::duration_to_sec;
+use util::time::{duration_to_sec, monotonic_raw_now};
 use util::timer::Timer;
 use util::transport::{NotifyError, Sender};
 use util::worker::{Runnable, RunnableWithTimer};
@@ -86,10 +86,15 @@ impl ReadDelegate {
         }
     }
 
+    // Reads with `max_staleness` may also be served after the lease expires. No other leader
+    // can be elected before the lease expires, so the local data lag behind the leader for
+    // at most as long as the lease has expired.
+    //
     // TODO: return ReadResponse once we remove batch snapshot.
     fn handle_read(
         &self,
         req: &RaftCmdRequest,
+        max_staleness: Option<TimeDuration>,
         executor: &mut ReadExecutor,
         metrics: &mut ReadMetrics,
     ) -> Option<ReadResponse> {
@@ -107,6 +112,16 @@ impl ReadDelegate {
                     // Leader can read local if and only if it is in lease.
                     cmd_resp::bind_term(&mut resp.response, term);
                     return Some(resp);
+                }
+                let within_staleness = match (max_staleness, lease.expired_for(snapshot_time)) {
+                    (Some(max_staleness), Some(staleness)) => staleness <= max_staleness,
+                    _ => false,
+                };
+                if within_staleness {
+                    metrics.stale_reads += 1;
+                    let mut resp = executor.execute(req, &self.region);
+                    cmd_resp::bind_term(&mut resp.response, term);
+                    return Some(resp);
                 } else {
                     metrics.rejected_by_lease_expire += 1;
                     debug!("{} rejected by lease expire", self.tag);
@@ -166,6 +181,7 @@ pub enum Task {
     Register(ReadDelegate),
     Update((u64, Progress)),
     Read(StoreMsg),
+    StaleRead(StoreMsg, Duration),
     Destroy(u64),
 }
 
@@ -187,6 +203,12 @@ impl Task {
         Task::Read(msg)
     }
 
+    /// Like `read`, but the read may be served without a valid lease, as long as the local
+    /// data lag behind the leader for at most `max_staleness`.
+    pub fn stale_read(msg: StoreMsg, max_staleness: Duration) -> Task {
+        Task::StaleRead(msg, max_staleness)
+    }
+
     /// Task accepts `Mag`s that contain Get/Snap requests.
     /// Returns `true`, it can be saftly sent to localreader,
     /// Returns `false`, it must not be sent to localreader.
@@ -221,6 +243,11 @@ impl Display for Task {
         match *self {
             Task::Register(ref delegate) => write!(f, "localreader Task::Register {:?}", delegate),
             Task::Read(ref msg) => write!(f, "localreader Task::Msg {:?}", msg),
+            Task::StaleRead(ref msg, max_staleness) => write!(
+                f,
+                "localreader Task::StaleRead {:?} within {:?}",
+                msg, max_staleness
+            ),
             Task::Update(ref progress) => write!(f, "localreader Task::Update {:?}", progress),
             Task::Destroy(region_id) => write!(f, "localreader Task::Destroy region {}", region_id),
         }
@@ -255,6 +282,8 @@ pub struct LocalReader<C: Sender<StoreMsg>> {
     delegates: HashMap<u64, ReadDelegate>,
     // A channel to raftstore.
     ch: C,
+    // Regions whose remaining leases are exported.
+    lease_metrics_regions: Vec<u64>,
     tag: String,
 }
 
@@ -277,6 +306,7 @@ impl LocalReader<mio::Sender<StoreMsg>> {
             kv_engine: store.kv_engine(),
             ch: store.get_sendch().into_inner(),
             metrics: Default::default(),
+            lease_metrics_regions: vec![],
             tag: format!("[store {}]", store_id),
         }
     }
@@ -289,6 +319,34 @@ impl LocalReader<mio::Sender<StoreMsg>> {
 }
 
 impl<C: Sender<StoreMsg>> LocalReader<C> {
+    // Exports the remaining leases of the leaders whose leases expire first. Leases of
+    // former terms are skipped, since the peers are not leaders any more.
+    fn flush_lease_metrics(&mut self) {
+        let now = monotonic_raw_now();
+        let mut remainings: Vec<_> = self
+            .delegates
+            .iter()
+            .filter_map(|(region_id, delegate)| match delegate.leader_lease {
+                Some(ref lease) if lease.term() == delegate.term => {
+                    Some((lease.remaining(now), *region_id))
+                }
+                _ => None,
+            })
+            .collect();
+        remainings.sort();
+        remainings.truncate(LEASE_METRICS_TOP_N);
+
+        for region_id in self.lease_metrics_regions.drain(..) {
+            let _ = LOCAL_READ_LEASE_REMAINING.remove_label_values(&[&region_id.to_string()]);
+        }
+        for (remaining, region_id) in remainings {
+            LOCAL_READ_LEASE_REMAINING
+                .with_label_values(&[&region_id.to_string()])
+                .set(remaining.num_milliseconds() as f64 / 1000.0);
+            self.lease_metrics_regions.push(region_id);
+        }
+    }
+
     fn redirect(&self, cmd: StoreMsg) {
         debug!("{} localreader redirect {:?}", self.tag, cmd);
         match self.ch.send(cmd) {
@@ -370,13 +428,18 @@ impl<C: Sender<StoreMsg>> LocalReader<C> {
         request: RaftCmdRequest,
         callback: Callback,
         send_time: Instant,
+        max_staleness: Option<Duration>,
         executor: &mut ReadExecutor,
     ) {
         let region_id = request.get_header().get_region_id();
+        let max_staleness = max_staleness
+            .map(|d| TimeDuration::from_std(d).unwrap_or_else(|_| TimeDuration::max_value()));
         match self.pre_propose_raft_command(&request) {
             Ok(Some(delegate)) => {
                 let mut metrics = self.metrics.borrow_mut();
-                if let Some(resp) = delegate.handle_read(&request, executor, &mut *metrics) {
+                if let Some(resp) =
+                    delegate.handle_read(&request, max_staleness, executor, &mut *metrics)
+                {
                     callback.invoke_read(resp);
                     return;
                 }
@@ -461,12 +524,31 @@ impl<C: Sender<StoreMsg>> Runnable<Task> for LocalReader<C> {
                     request,
                     callback,
                 }) => {
-                    self.propose_raft_command(request, callback, send_time, &mut executor);
+                    self.propose_raft_command(request, callback, send_time, None, &mut executor);
                     if sent.is_none() {
                         sent = Some(send_time);
                     }
                 }
-                Task::Read(other) => {
+                Task::StaleRead(
+                    StoreMsg::RaftCmd {
+                        send_time,
+                        request,
+                        callback,
+                    },
+                    max_staleness,
+                ) => {
+                    self.propose_raft_command(
+                        request,
+                        callback,
+                        send_time,
+                        Some(max_staleness),
+                        &mut executor,
+                    );
+                    if sent.is_none() {
+                        sent = Some(send_time);
+                    }
+                }
+                Task::Read(other) | Task::StaleRead(other, _) => {
                     unimplemented!("unsupported Msg {:?}", other);
                 }
                 Task::Update((region_id, progress)) => {
@@ -497,10 +579,13 @@ impl<C: Sender<StoreMsg>> Runnable<Task> for LocalReader<C> {
 }
 
 const METRICS_FLUSH_INTERVAL: u64 = 15; // 15s
+// How many regions have their remaining leases exported.
+const LEASE_METRICS_TOP_N: usize = 16;
 
 impl<C: Sender<StoreMsg>> RunnableWithTimer<Task, ()> for LocalReader<C> {
     fn on_timeout(&mut self, timer: &mut Timer<()>, _: ()) {
         self.metrics.borrow_mut().flush();
+        self.flush_lease_metrics();
         timer.add_task(Duration::from_secs(METRICS_FLUSH_INTERVAL), ());
     }
 }
@@ -519,6 +604,8 @@ struct ReadMetrics {
     rejected_by_epoch: i64,
     rejected_by_appiled_term: i64,
     rejected_by_channel_full: i64,
+    // Reads served with an expired lease within their staleness bounds.
+    stale_reads: i64,
 }
 
 impl Default for ReadMetrics {
@@ -535,6 +622,7 @@ impl Default for ReadMetrics {
             rejected_by_epoch: 0,
             rejected_by_appiled_term: 0,
             rejected_by_channel_full: 0,
+            stale_reads: 0,
         }
     }
 }
@@ -597,6 +685,10 @@ impl ReadMetrics {
                 .inc_by(self.rejected_by_channel_full);
             self.rejected_by_channel_full = 0;
         }
+        if self.stale_reads > 0 {
+            LOCAL_READ_STALE.inc_by(self.stale_reads);
+            self.stale_reads = 0;
+        }
     }
 }
 
@@ -613,7 +705,6 @@ mod tests {
     use raftstore::store::Callback;
     use storage::ALL_CFS;
     use util::rocksdb;
-    use util::time::monotonic_raw_now;
 
     use super::*;
 
@@ -634,6 +725,7 @@ mod tests {
             kv_engine: Arc::new(db),
             delegates: HashMap::default(),
             metrics: Default::default(),
+            lease_metrics_regions: vec![],
             tag: "foo".to_owned(),
         };
         (path, reader, rx)
@@ -890,4 +982,129 @@ mod tests {
         assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
         assert!(reader.delegates.get(&1).is_none());
     }
+
+    #[test]
+    fn test_lease_metrics() {
+        let store_id = 2;
+        let (_tmp, mut reader, rx) = new_reader("test-local-reader-lease-metrics", store_id);
+
+        let mut region = metapb::Region::new();
+        region.set_id(10);
+        let prs = new_peers(store_id, vec![2, 3, 4]);
+        region.set_peers(prs.clone().into());
+        let term = 6;
+        let mut lease = Lease::new(Duration::seconds(10));
+        lease.renew(monotonic_raw_now());
+        let register = Task::Register(ReadDelegate {
+            tag: String::new(),
+            region: region.clone(),
+            peer_id: prs[0].get_id(),
+            term,
+            applied_index_term: term,
+            leader_lease: Some(lease.maybe_new_remote_lease(term).unwrap()),
+            last_valid_ts: RefCell::new(Timespec::new(0, 0)),
+        });
+        reader.run_batch(&mut vec![register]);
+
+        reader.flush_lease_metrics();
+        let gauge = LOCAL_READ_LEASE_REMAINING.with_label_values(&["10"]);
+        assert!(gauge.get() > 0.0);
+
+        let mut cmd = RaftCmdRequest::new();
+        cmd.mut_header().set_region_id(10);
+        cmd.mut_header().set_peer(prs[0].clone());
+        cmd.mut_header().set_region_epoch(region.get_region_epoch().clone());
+        cmd.mut_header().set_term(term);
+        let mut req = Request::new();
+        req.set_cmd_type(CmdType::Snap);
+        cmd.set_requests(vec![req].into());
+
+        // Reads fall back to raftstore once the lease expires.
+        lease.expire();
+        must_redirect(&mut reader, &rx, cmd);
+        assert_eq!(reader.metrics.borrow().rejected_by_lease_expire, 1);
+        let fallback = LOCAL_READ_REJECT.with_label_values(&["lease_expire"]);
+        let count = fallback.get();
+        reader.metrics.borrow_mut().flush();
+        assert!(fallback.get() > count);
+        assert_eq!(reader.metrics.borrow().rejected_by_lease_expire, 0);
+
+        reader.flush_lease_metrics();
+        let gauge = LOCAL_READ_LEASE_REMAINING.with_label_values(&["10"]);
+        assert_eq!(gauge.get(), 0.0);
+
+        // Leases of former terms are not exported.
+        reader.run_batch(&mut vec![Task::update(10, Progress::term(term + 1))]);
+        reader.flush_lease_metrics();
+        assert!(reader.lease_metrics_regions.is_empty());
+    }
+
+    #[test]
+    fn test_stale_read() {
+        let store_id = 2;
+        let (_tmp, mut reader, rx) = new_reader("test-local-reader-stale-read", store_id);
+
+        let mut region = metapb::Region::new();
+        region.set_id(10);
+        let prs = new_peers(store_id, vec![2, 3, 4]);
+        region.set_peers(prs.clone().into());
+        let term = 6;
+        let mut lease = Lease::new(Duration::milliseconds(100));
+        lease.renew(monotonic_raw_now());
+        let register = Task::Register(ReadDelegate {
+            tag: String::new(),
+            region: region.clone(),
+            peer_id: prs[0].get_id(),
+            term,
+            applied_index_term: term,
+            leader_lease: Some(lease.maybe_new_remote_lease(term).unwrap()),
+            last_valid_ts: RefCell::new(Timespec::new(0, 0)),
+        });
+        reader.run_batch(&mut vec![register]);
+
+        let mut cmd = RaftCmdRequest::new();
+        cmd.mut_header().set_region_id(10);
+        cmd.mut_header().set_peer(prs[0].clone());
+        cmd.mut_header().set_region_epoch(region.get_region_epoch().clone());
+        cmd.mut_header().set_term(term);
+        let mut req = Request::new();
+        req.set_cmd_type(CmdType::Snap);
+        cmd.set_requests(vec![req].into());
+
+        {
+            let (tx, served) = channel();
+            let mut stale_read = |max_staleness| {
+                let tx = tx.clone();
+                let msg = StoreMsg::new_raft_cmd(
+                    cmd.clone(),
+                    Callback::Read(Box::new(move |resp: ReadResponse| {
+                        tx.send(resp.snapshot.is_some()).unwrap();
+                    })),
+                );
+                reader.run_batch(&mut vec![Task::stale_read(msg, max_staleness)]);
+            };
+
+            // Wait for expiration.
+            thread::sleep(Duration::milliseconds(200).to_std().unwrap());
+            stale_read(Duration::seconds(10).to_std().unwrap());
+            assert!(served.try_recv().unwrap());
+            assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+            // The lease has expired for longer than the bound.
+            stale_read(Duration::milliseconds(1).to_std().unwrap());
+            assert_eq!(served.try_recv().unwrap_err(), TryRecvError::Empty);
+            must_extract_cmds(rx.try_recv().unwrap());
+
+            // The staleness is unknown once the lease is expired manually.
+            lease.expire();
+            stale_read(Duration::seconds(10).to_std().unwrap());
+            assert_eq!(served.try_recv().unwrap_err(), TryRecvError::Empty);
+            must_extract_cmds(rx.try_recv().unwrap());
+        }
+
+        assert_eq!(reader.metrics.borrow().stale_reads, 1);
+        let stale_reads = LOCAL_READ_STALE.get();
+        reader.metrics.borrow_mut().flush();
+        assert!(LOCAL_READ_STALE.get() >= stale_reads + 1);
+    }
 }
@@ -23,7 +23,7 @@ use kvproto::raft_serverpb::{PeerState, RaftApplyState, RegionLocalState};
 use raft::eraftpb::Snapshot as RaftSnapshot;
 use rocksdb::{Writable, WriteBatch};
 
-use raftstore::store::engine::{Mutable, Snapshot};
+use raftstore::store::engine::{Iterable, Mutable, Snapshot};
 use raftstore::store::peer_storage::{
     JOB_STATUS_CANCELLED, JOB_STATUS_CANCELLING, JOB_STATUS_FAILED, JOB_STATUS_FINISHED,
     JOB_STATUS_PENDING, JOB_STATUS_RUNNING,
@@ -39,7 +39,7 @@ use util::threadpool::{DefaultContext, ThreadPool, ThreadPoolBuilder};
 use util::time;
 use util::timer::Timer;
 use util::worker::{Runnable, RunnableWithTimer};
-use util::{escape, rocksdb};
+use util::{escape, rocksdb, sys};
 
 use super::super::util;
 use super::metrics::*;
@@ -56,6 +56,11 @@ pub const PENDING_APPLY_CHECK_INTERVAL: u64 = 1_000; // 1000 milliseconds
 
 const CLEANUP_MAX_DURATION: Duration = Duration::from_secs(5);
 
+// Prefetches beyond it are skipped, so that the prefetch pool can't fall far behind.
+const MAX_PENDING_PREFETCHES: usize = 8;
+// At most this many bytes of a region are read into the block cache after it's applied.
+const PREFETCH_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
 /// region related task.
 #[derive(Debug)]
 pub enum Task {
@@ -339,6 +344,65 @@ impl SnapContext {
         Ok(())
     }
 
+    // Reads the data of the region through the block cache, so that the first reads after
+    // applying a snapshot are served from memory. At most `PREFETCH_MAX_BYTES` are read,
+    // rate limited by the snapshot IO limiter. Returns the bytes read.
+    fn prefetch(&self, region_id: u64) -> Result<u64> {
+        let region_key = keys::region_state_key(region_id);
+        let region_state: RegionLocalState =
+            match box_try!(self.engines.kv.get_msg_cf(CF_RAFT, &region_key)) {
+                Some(state) => state,
+                None => return Ok(0),
+            };
+        let start_key = keys::enc_start_key(region_state.get_region());
+        let end_key = keys::enc_end_key(region_state.get_region());
+        let limiter = self.mgr.io_limiter();
+        let base = limiter.as_ref().map_or(0, |l| l.get_max_bytes_per_time());
+        let mut unlimited_bytes = 0;
+        let mut bytes = 0;
+        for cf in SNAPSHOT_CFS {
+            if bytes >= PREFETCH_MAX_BYTES {
+                break;
+            }
+            box_try!(
+                self.engines
+                    .kv
+                    .scan_cf(cf, &start_key, &end_key, true, |k, v| {
+                        let l = (k.len() + v.len()) as u64;
+                        if let Some(ref limiter) = limiter {
+                            if unlimited_bytes >= base {
+                                unlimited_bytes = 0;
+                                limiter.request(base);
+                            }
+                            unlimited_bytes += l as i64;
+                        }
+                        bytes += l;
+                        Ok(bytes < PREFETCH_MAX_BYTES)
+                    })
+            );
+        }
+        Ok(bytes)
+    }
+
+    fn handle_prefetch(&self, region_id: u64) {
+        if let Err(e) = sys::thread::set_priority(sys::LOW_PRI) {
+            debug!("failed to lower the priority of snapshot prefetch: {:?}", e);
+        }
+        let timer = Instant::now();
+        match self.prefetch(region_id) {
+            Ok(bytes) => {
+                SNAP_PREFETCH_BYTES_COUNTER.inc_by(bytes as i64);
+                info!(
+                    "[region {}] prefetch {} bytes of applied snapshot takes {:?}",
+                    region_id,
+                    bytes,
+                    timer.elapsed()
+                );
+            }
+            Err(e) => warn!("[region {}] failed to prefetch applied snapshot: {:?}", region_id, e),
+        }
+    }
+
     // check the number of files at level 0 to avoid write stall after ingesting sst,
     // return indicate whether ingest will cause write stall or not.
     fn ingest_maybe_stall(&self) -> bool {
@@ -501,6 +565,10 @@ impl SnapContext {
 
 pub struct Runner {
     pool: ThreadPool<DefaultContext>,
+    // Warms up the block cache for the applied snapshots if `SnapManager::prefetch_on_apply`.
+    prefetch_pool: Option<ThreadPool<DefaultContext>>,
+    // The number of prefetches queued or running in `prefetch_pool`.
+    pending_prefetches: Arc<AtomicUsize>,
     ctx: SnapContext,
 
     // we may delay some apply tasks if level 0 files to write stall threshold,
@@ -516,10 +584,18 @@ impl Runner {
         use_delete_range: bool,
         clean_stale_peer_delay: Duration,
     ) -> Runner {
+        let prefetch_pool = if mgr.prefetch_on_apply() {
+            let builder = ThreadPoolBuilder::with_default_factory(thd_name!("snap-prefetch"));
+            Some(builder.thread_count(1).build())
+        } else {
+            None
+        };
         Runner {
             pool: ThreadPoolBuilder::with_default_factory(thd_name!("snap-generator"))
                 .thread_count(GENERATE_POOL_SIZE)
                 .build(),
+            prefetch_pool,
+            pending_prefetches: Arc::new(AtomicUsize::new(0)),
             ctx: SnapContext {
                 engines,
                 mgr,
@@ -545,6 +621,27 @@ impl Runner {
         timer
     }
 
+    fn schedule_prefetch(&self, region_id: u64) {
+        let pool = match self.prefetch_pool {
+            Some(ref pool) => pool,
+            None => return,
+        };
+        if self.pending_prefetches.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_PREFETCHES {
+            self.pending_prefetches.fetch_sub(1, Ordering::SeqCst);
+            debug!(
+                "[region {}] skip prefetching applied snapshot, too many pending prefetches",
+                region_id
+            );
+            return;
+        }
+        let ctx = self.ctx.clone();
+        let pending_prefetches = Arc::clone(&self.pending_prefetches);
+        pool.execute(move |_| {
+            ctx.handle_prefetch(region_id);
+            pending_prefetches.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
     // try to apply pending tasks if there is some.
     fn handle_pending_applies(&mut self) {
         while !self.pending_applies.is_empty() {
@@ -554,7 +651,11 @@ impl Runner {
                 break;
             }
             if let Some(Task::Apply { region_id, status }) = self.pending_applies.pop_front() {
-                self.ctx.handle_apply(region_id, status);
+                self.ctx.handle_apply(region_id, Arc::clone(&status));
+                // The snapshot is applied before prefetching starts, so it's never delayed.
+                if status.load(Ordering::SeqCst) == JOB_STATUS_FINISHED {
+                    self.schedule_prefetch(region_id);
+                }
             }
         }
     }
@@ -607,6 +708,11 @@ impl Runnable<Task> for Runner {
         if let Err(e) = self.pool.stop() {
             warn!("Stop threadpool failed with {:?}", e);
         }
+        if let Some(ref mut pool) = self.prefetch_pool {
+            if let Err(e) = pool.stop() {
+                warn!("Stop prefetch threadpool failed with {:?}", e);
+            }
+        }
     }
 }
 
@@ -640,7 +746,7 @@ impl RunnableWithTimer<Task, Event> for Runner {
 #[cfg(test)]
 mod tests {
     use std::io;
-    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{mpsc, Arc};
     use std::thread;
     use std::time::Duration;
@@ -650,7 +756,7 @@ mod tests {
     use raftstore::store::peer_storage::JOB_STATUS_PENDING;
     use raftstore::store::snap::tests::get_test_db_for_regions;
     use raftstore::store::worker::RegionRunner;
-    use raftstore::store::{keys, Engines, SnapKey, SnapManager};
+    use raftstore::store::{keys, Engines, SnapKey, SnapManager, SnapManagerBuilder};
     use rocksdb::{ColumnFamilyOptions, Writable, WriteBatch};
     use storage::{CF_DEFAULT, CF_RAFT};
     use tempdir::TempDir;
@@ -659,9 +765,11 @@ mod tests {
     use util::timer::Timer;
     use util::worker::Worker;
 
+    use super::super::metrics::SNAP_PREFETCH_BYTES_COUNTER;
     use super::Event;
     use super::PendingDeleteRanges;
     use super::Task;
+    use super::MAX_PENDING_PREFETCHES;
 
     fn insert_range(
         pending_delete_ranges: &mut PendingDeleteRanges,
@@ -882,4 +990,114 @@ mod tests {
         // the last one pending task finished
         assert_eq!(rocksdb::get_cf_num_files_at_level(&db, cf, 0).unwrap(), 2);
     }
+
+    #[test]
+    fn test_prefetch_on_apply() {
+        let temp_dir = TempDir::new("test_prefetch_on_apply").unwrap();
+        let db = get_test_db_for_regions(&temp_dir, None, &[1]).unwrap();
+        for cf_name in db.cf_names() {
+            let cf = db.cf_handle(cf_name).unwrap();
+            db.put_cf(cf, &keys::data_key(b"k1"), b"v1").unwrap();
+        }
+
+        let snap_dir = TempDir::new("snap_dir").unwrap();
+        let snap_path = snap_dir.path().to_str().unwrap();
+        let mgr = SnapManagerBuilder::default()
+            .prefetch_on_apply(true)
+            .build(snap_path, None);
+        let mut worker = Worker::new("snap-manager");
+        let sched = worker.scheduler();
+        let runner = RegionRunner::new(
+            Engines::new(Arc::clone(&db), Arc::clone(&db)),
+            mgr.clone(),
+            0,
+            true,
+            Duration::from_secs(0),
+        );
+        let mut timer = Timer::new(1);
+        timer.add_task(Duration::from_millis(100), Event::CheckApply);
+        worker.start_with_timer(runner, timer).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel(1);
+        sched
+            .schedule(Task::Gen {
+                region_id: 1,
+                notifier: tx,
+            })
+            .unwrap();
+        let s1 = rx.recv().unwrap();
+        let key = SnapKey::from_snap(&s1).unwrap();
+        let mut s2 = mgr.get_snapshot_for_sending(&key).unwrap();
+        let mut s3 = mgr
+            .get_snapshot_for_receiving(&key, &s1.get_data()[..])
+            .unwrap();
+        io::copy(&mut s2, &mut s3).unwrap();
+        s3.save().unwrap();
+
+        let wb = WriteBatch::new();
+        let handle = db.cf_handle(CF_RAFT).unwrap();
+        let region_key = keys::region_state_key(1);
+        let mut region_state = db
+            .get_msg_cf::<RegionLocalState>(CF_RAFT, &region_key)
+            .unwrap()
+            .unwrap();
+        region_state.set_state(PeerState::Applying);
+        wb.put_msg_cf(handle, &region_key, &region_state).unwrap();
+        db.write(wb).unwrap();
+
+        let bytes = SNAP_PREFETCH_BYTES_COUNTER.get();
+        let status = Arc::new(AtomicUsize::new(JOB_STATUS_PENDING));
+        sched
+            .schedule(Task::Apply {
+                region_id: 1,
+                status,
+            })
+            .unwrap();
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(100));
+            if SNAP_PREFETCH_BYTES_COUNTER.get() > bytes {
+                break;
+            }
+        }
+        assert!(SNAP_PREFETCH_BYTES_COUNTER.get() > bytes);
+        let state = db
+            .get_msg_cf::<RegionLocalState>(CF_RAFT, &region_key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.get_state(), PeerState::Normal);
+        worker.stop().unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_prefetch_queue_bound() {
+        let temp_dir = TempDir::new("test_prefetch_queue_bound").unwrap();
+        let db = get_test_db_for_regions(&temp_dir, None, &[1]).unwrap();
+        let snap_dir = TempDir::new("snap_dir").unwrap();
+        let mgr = SnapManagerBuilder::default()
+            .prefetch_on_apply(true)
+            .build(snap_dir.path().to_str().unwrap(), None);
+        let runner = RegionRunner::new(
+            Engines::new(Arc::clone(&db), Arc::clone(&db)),
+            mgr,
+            0,
+            true,
+            Duration::from_secs(0),
+        );
+
+        // Skipped when too many prefetches are pending.
+        let pending = &runner.pending_prefetches;
+        pending.store(MAX_PENDING_PREFETCHES, Ordering::SeqCst);
+        runner.schedule_prefetch(1);
+        assert_eq!(pending.load(Ordering::SeqCst), MAX_PENDING_PREFETCHES);
+
+        pending.store(0, Ordering::SeqCst);
+        runner.schedule_prefetch(1);
+        for _ in 0..100 {
+            if pending.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pending.load(Ordering::SeqCst), 0);
+    }
 }
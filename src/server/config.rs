@@ -15,6 +15,8 @@ use std::i32;
 
 use super::Result;
 use grpc::CompressionAlgorithms;
+use protobuf::ProtobufEnum;
+use raft::eraftpb::MessageType;
 
 use util::collections::HashMap;
 use util::config::{self, ReadableDuration, ReadableSize};
@@ -28,9 +30,15 @@ pub const DEFAULT_LISTENING_ADDR: &str = "127.0.0.1:20160";
 const DEFAULT_ADVERTISE_LISTENING_ADDR: &str = "";
 const DEFAULT_STATUS_ADDR: &str = "127.0.0.1:20180";
 const DEFAULT_GRPC_CONCURRENCY: usize = 4;
+const DEFAULT_GRPC_BULK_CONCURRENCY: usize = 1;
 const DEFAULT_GRPC_CONCURRENT_STREAM: i32 = 1024;
 const DEFAULT_GRPC_RAFT_CONN_NUM: usize = 10;
 const DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE: u64 = 2 * 1024 * 1024;
+// Same as the max gRPC message length of raft connections.
+const DEFAULT_MAX_RAFT_MSG_SIZE: u64 = 10 * 1024 * 1024;
+// Snapshot chunks must fit in a gRPC message even if they grow a little after compression.
+const MIN_SNAP_SEND_CHUNK_SIZE: u64 = 64 * 1024;
+const MAX_SNAP_SEND_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
 
 // Number of rows in each chunk.
 pub const DEFAULT_ENDPOINT_BATCH_ROW_LIMIT: usize = 64;
@@ -39,6 +47,10 @@ pub const DEFAULT_ENDPOINT_BATCH_ROW_LIMIT: usize = 64;
 // be timeout already, so it can be safely aborted.
 pub const DEFAULT_ENDPOINT_REQUEST_MAX_HANDLE_SECS: u64 = 60;
 
+// Deeply nested coprocessor requests are refused below this limit, which every valid
+// request should fit in.
+pub const MIN_ENDPOINT_RECURSION_LIMIT: u32 = 100;
+
 // Number of rows in each chunk for streaming coprocessor.
 pub const DEFAULT_ENDPOINT_STREAM_BATCH_ROW_LIMIT: usize = 128;
 
@@ -50,6 +62,36 @@ pub enum GrpcCompressionType {
     Gzip,
 }
 
+/// How snapshot files are compressed when they are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapCompression {
+    None,
+    Lz4,
+}
+
+/// How raft messages are encoded when they are sent to other stores.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RaftMsgCodec {
+    Protobuf,
+    /// Protobuf, recording the sizes of the messages encoded and decoded.
+    SizeRecording,
+}
+
+/// What to do with a raft message when the raftstore channel is full.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RaftMsgFullPolicy {
+    /// Drops the message, and relies on Raft to retransmit it.
+    Drop,
+    /// Resends the message on the timer for at most `cmd_send_max_retry` times with a short
+    /// backoff, and then drops it. The connection the message is received from is not held up.
+    Block,
+    /// Sends the message through the significant message channel, which is never full.
+    ForceSend,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
@@ -57,7 +99,8 @@ pub struct Config {
     #[serde(skip)]
     pub cluster_id: u64,
 
-    // Server listening address.
+    // Server listening address. Several addresses can be given separated by commas, e.g. to
+    // listen on both IPv4 and IPv6.
     pub addr: String,
 
     // Server advertise listening address for outer communication.
@@ -68,6 +111,13 @@ pub struct Config {
     pub status_addr: String,
     pub status_thread_pool_size: usize,
 
+    /// If set, the debug and import services are served on this address by a separate gRPC
+    /// server with `grpc_bulk_concurrency` threads of its own, so that bulk loading and
+    /// debugging don't take the threads of the KV service. Otherwise they share `addr`.
+    /// Once it's set, the debug and import services are no longer served on `addr`.
+    pub bulk_addr: String,
+    pub grpc_bulk_concurrency: usize,
+
     // TODO: use CompressionAlgorithms instead once it supports traits like Clone etc.
     pub grpc_compression_type: GrpcCompressionType,
     pub grpc_concurrency: usize,
@@ -76,6 +126,74 @@ pub struct Config {
     pub grpc_stream_initial_window_size: ReadableSize,
     pub grpc_keepalive_time: ReadableDuration,
     pub grpc_keepalive_timeout: ReadableDuration,
+    /// If a raft connection can't be established in this duration, it's dropped and
+    /// the store address will be resolved again. 0 means no limit.
+    pub grpc_connect_timeout: ReadableDuration,
+    /// The max random delay before raft messages flushed to an idle connection are
+    /// written, so that the flushes of different raftstore threads are spread out.
+    pub raft_client_flush_jitter: ReadableDuration,
+    /// How long to wait before reconnecting to a store whose connection breaks. It doubles on
+    /// each failure up to `raft_client_max_reconnect_backoff`, and is reset once a connection
+    /// is established. 0 means reconnecting right away.
+    pub raft_client_reconnect_backoff: ReadableDuration,
+    pub raft_client_max_reconnect_backoff: ReadableDuration,
+    /// How often the addresses and connections of the stores no longer in the cluster are
+    /// dropped, if the server is given the live stores. 0 means they are never dropped.
+    pub raft_client_gc_interval: ReadableDuration,
+    /// Raft messages not flushed to the connections in so long are dropped, and their peers
+    /// are reported unreachable. 0 means no limit.
+    pub raft_msg_send_timeout: ReadableDuration,
+    /// Raft messages larger than it are refused before being sent. 0 means no limit.
+    pub max_raft_msg_size: ReadableSize,
+    /// Commands are refused as if the store is busy when so many of them are sent to
+    /// raftstore but not finished. 0 means no limit.
+    pub max_outstanding_callbacks: usize,
+    /// How many times a command is resent when the raftstore channel is full, for senders
+    /// that choose to retry.
+    pub cmd_send_max_retry: usize,
+    /// How many times a raft message of the "drop" policy is resent when the raftstore
+    /// channel is full, before it's dropped. 0 means it's dropped right away.
+    pub raft_msg_send_max_retry: usize,
+    /// How long to wait before resending a raft message to the full raftstore channel.
+    pub raft_msg_send_retry_backoff: ReadableDuration,
+    /// Local reads of the hottest region are refused as if the store is busy when the local
+    /// reader has so many pending reads. 0 means reads are never refused.
+    pub local_read_shed_threshold: usize,
+    /// The window the reads of the regions are counted in to find the hottest one.
+    pub local_read_shed_window: ReadableDuration,
+    /// How many reads a region can serve per second. Reads beyond it are refused as if the
+    /// store is busy. 0 means no limit.
+    pub region_read_quota: u64,
+    /// How many write commands a region can propose per second. Writes beyond it are refused
+    /// as if the store is busy. 0 means no limit.
+    pub region_write_quota: u64,
+    /// Logs one of every so many raft messages sent, for protocol debugging. 0 means no
+    /// message is logged.
+    pub raft_msg_log_sample_interval: usize,
+    /// Repeated unreachable reports of a peer in this duration are dropped, so that they
+    /// don't flood the raftstore during network partitions. 0 means no report is dropped.
+    pub unreachable_report_dedup_interval: ReadableDuration,
+    /// Messages to a store whose address failed to resolve are dropped in this duration
+    /// without resolving it again, so that PD isn't asked on every message. 0 means the
+    /// store is resolved again on the next message.
+    pub resolve_negative_cache_ttl: ReadableDuration,
+    /// How snapshot files are compressed when they are sent. Receivers that don't know
+    /// compression can't receive compressed snapshots, so it should only be enabled when all
+    /// the stores are upgraded.
+    pub snap_compression: SnapCompression,
+    /// How raft messages are encoded when they are sent. Stores can receive messages
+    /// of all the codecs they know, but stores of older versions only know protobuf.
+    pub raft_msg_codec: RaftMsgCodec,
+    /// Snapshot files are read and sent in chunks of this size. Larger chunks make better use
+    /// of links with high latency, at the cost of more memory for each snapshot being sent.
+    pub snap_send_chunk_size: ReadableSize,
+    /// Snapshot sends are aborted once the receiver takes no chunk for so long. 0 means
+    /// never.
+    pub snap_send_stall_timeout: ReadableDuration,
+    /// Snapshots to a peer reported unreachable within this duration fail right away
+    /// instead of being sent, as they would most likely be wasted. 0 means they are always
+    /// sent.
+    pub snap_send_unreachable_window: ReadableDuration,
     /// How many snapshots can be sent concurrently.
     pub concurrent_send_snap_limit: usize,
     /// How many snapshots can be recv concurrently.
@@ -85,14 +203,63 @@ pub struct Config {
     pub end_point_batch_row_limit: usize,
     pub end_point_stream_batch_row_limit: usize,
     pub end_point_request_max_handle_duration: ReadableDuration,
+    /// Coprocessor requests whose results exceed it are aborted. 0 means no limit.
+    pub end_point_memory_quota: ReadableSize,
+    /// Max bytes of the results of unary DAG requests that are cached, so that identical
+    /// requests to unchanged regions are served without scanning them again. 0 disables
+    /// the cache.
+    pub end_point_cache_capacity: ReadableSize,
+    /// How long a cached result can be served.
+    pub end_point_cache_ttl: ReadableDuration,
+    /// New coprocessor requests are refused once the fraction of the busy threads of the
+    /// coprocessor read pool exceeds it for `end_point_overload_window`, until it drops
+    /// below `end_point_overload_low_water`. 0 disables it.
+    pub end_point_overload_high_water: f64,
+    /// 0 means the same as `end_point_overload_high_water`.
+    pub end_point_overload_low_water: f64,
+    pub end_point_overload_window: ReadableDuration,
+    /// Coprocessor requests that take longer than it to process are logged as slow queries.
+    /// 0 disables the slow log.
+    pub end_point_slow_log_threshold: ReadableDuration,
     pub snap_max_write_bytes_per_sec: ReadableSize,
     pub snap_max_total_size: ReadableSize,
+    /// Incoming snapshots are refused when the snapshot files on disk exceed it. 0 means
+    /// no limit.
+    pub snap_max_pending_size: ReadableSize,
+    /// Incoming snapshots are refused when the disk of the snapshot directory has less
+    /// available space than it. 0 means no limit.
+    pub snap_min_available_space: ReadableSize,
+    /// Whether the data of a region is read into the block cache in the background after a
+    /// snapshot of it is applied, so that the first reads of the region are not slowed down.
+    pub snap_prefetch_on_apply: bool,
     pub stats_concurrency: usize,
     pub heavy_load_threshold: usize,
+    /// How long to wait for in-flight requests when stopping the server.
+    pub graceful_shutdown_timeout: ReadableDuration,
+    /// How many KV and coprocessor requests a client IP can send per second. 0 means no limit.
+    pub max_requests_per_sec_per_client: u64,
+    /// How many KV and coprocessor requests a single connection can have in flight,
+    /// on top of the HTTP/2 stream limit. 0 means no limit.
+    pub max_inflight_requests_per_connection: usize,
+    /// Rejects commands whose peers are not on the local store before they are sent to
+    /// the raftstore, so that misrouted requests fail early with a clear error.
+    pub strict_peer_store_check: bool,
+    /// New requests of `memory_pressure_shed_methods` are refused once the memory used by
+    /// the process exceeds this fraction of the system memory, until it drops below
+    /// `memory_pressure_low_water`. 0 disables it.
+    pub memory_pressure_high_water: f64,
+    /// 0 means the same as `memory_pressure_high_water`.
+    pub memory_pressure_low_water: f64,
+    /// The requests refused under memory pressure, named like "kv_scan".
+    pub memory_pressure_shed_methods: Vec<String>,
 
     // Server labels to specify some attributes about this server.
     pub labels: HashMap<String, String>,
 
+    /// What to do with raft messages of the given types, e.g. "MsgRequestVote", when the
+    /// raftstore channel is full. Messages of other types are dropped.
+    pub raft_msg_full_policy: HashMap<String, RaftMsgFullPolicy>,
+
     // deprecated. use readpool.coprocessor.xx_concurrency.
     #[doc(hidden)]
     #[serde(skip_serializing)]
@@ -118,6 +285,8 @@ impl Default for Config {
             advertise_addr: DEFAULT_ADVERTISE_LISTENING_ADDR.to_owned(),
             status_addr: DEFAULT_STATUS_ADDR.to_owned(),
             status_thread_pool_size: 1,
+            bulk_addr: String::new(),
+            grpc_bulk_concurrency: DEFAULT_GRPC_BULK_CONCURRENCY,
             grpc_compression_type: GrpcCompressionType::None,
             grpc_concurrency: DEFAULT_GRPC_CONCURRENCY,
             grpc_concurrent_stream: DEFAULT_GRPC_CONCURRENT_STREAM,
@@ -127,6 +296,29 @@ impl Default for Config {
             // than 10 senconds.
             grpc_keepalive_time: ReadableDuration::secs(10),
             grpc_keepalive_timeout: ReadableDuration::secs(3),
+            grpc_connect_timeout: ReadableDuration::secs(5),
+            raft_client_flush_jitter: ReadableDuration::secs(0),
+            raft_client_reconnect_backoff: ReadableDuration::millis(100),
+            raft_client_max_reconnect_backoff: ReadableDuration::secs(1),
+            raft_client_gc_interval: ReadableDuration::minutes(10),
+            raft_msg_send_timeout: ReadableDuration::secs(10),
+            max_raft_msg_size: ReadableSize(DEFAULT_MAX_RAFT_MSG_SIZE),
+            max_outstanding_callbacks: 0,
+            cmd_send_max_retry: 3,
+            raft_msg_send_max_retry: 0,
+            raft_msg_send_retry_backoff: ReadableDuration::millis(1),
+            local_read_shed_threshold: 0,
+            local_read_shed_window: ReadableDuration::secs(1),
+            region_read_quota: 0,
+            region_write_quota: 0,
+            raft_msg_log_sample_interval: 0,
+            unreachable_report_dedup_interval: ReadableDuration::secs(0),
+            resolve_negative_cache_ttl: ReadableDuration::secs(0),
+            snap_compression: SnapCompression::None,
+            raft_msg_codec: RaftMsgCodec::Protobuf,
+            snap_send_chunk_size: ReadableSize::mb(1),
+            snap_send_stall_timeout: ReadableDuration::secs(60),
+            snap_send_unreachable_window: ReadableDuration::secs(0),
             concurrent_send_snap_limit: 32,
             concurrent_recv_snap_limit: 32,
             end_point_concurrency: None, // deprecated
@@ -139,24 +331,73 @@ impl Default for Config {
             end_point_request_max_handle_duration: ReadableDuration::secs(
                 DEFAULT_ENDPOINT_REQUEST_MAX_HANDLE_SECS,
             ),
+            end_point_memory_quota: ReadableSize(0),
+            end_point_cache_capacity: ReadableSize(0),
+            end_point_cache_ttl: ReadableDuration::secs(60),
+            end_point_overload_high_water: 0.0,
+            end_point_overload_low_water: 0.0,
+            end_point_overload_window: ReadableDuration::secs(5),
+            end_point_slow_log_threshold: ReadableDuration::secs(1),
             snap_max_write_bytes_per_sec: ReadableSize(DEFAULT_SNAP_MAX_BYTES_PER_SEC),
             snap_max_total_size: ReadableSize(0),
+            snap_max_pending_size: ReadableSize(0),
+            snap_min_available_space: ReadableSize(0),
+            snap_prefetch_on_apply: false,
             stats_concurrency: 1,
             // 100 means gRPC threads are under heavy load if their total CPU usage
             // is greater than 100%.
             heavy_load_threshold: 100,
+            graceful_shutdown_timeout: ReadableDuration::secs(10),
+            max_requests_per_sec_per_client: 0,
+            max_inflight_requests_per_connection: 0,
+            strict_peer_store_check: false,
+            memory_pressure_high_water: 0.0,
+            memory_pressure_low_water: 0.0,
+            memory_pressure_shed_methods: vec![
+                "coprocessor".to_owned(),
+                "coprocessor_stream".to_owned(),
+                "kv_scan".to_owned(),
+                "kv_batch_get".to_owned(),
+                "kv_scan_lock".to_owned(),
+                "raw_scan".to_owned(),
+                "raw_batch_get".to_owned(),
+                "raw_batch_scan".to_owned(),
+            ],
+            // Votes are not retransmitted until the election times out, so losing them
+            // delays the election.
+            raft_msg_full_policy: map![
+                "MsgRequestVote".to_owned() => RaftMsgFullPolicy::ForceSend,
+                "MsgRequestVoteResponse".to_owned() => RaftMsgFullPolicy::ForceSend,
+                "MsgRequestPreVote".to_owned() => RaftMsgFullPolicy::ForceSend,
+                "MsgRequestPreVoteResponse".to_owned() => RaftMsgFullPolicy::ForceSend
+            ],
         }
     }
 }
 
 impl Config {
+    /// Returns the addresses listed in `addr`.
+    pub fn listening_addrs(&self) -> Vec<&str> {
+        self.addr
+            .split(',')
+            .map(|addr| addr.trim())
+            .filter(|addr| !addr.is_empty())
+            .collect()
+    }
+
     pub fn validate(&mut self) -> Result<()> {
-        box_try!(config::check_addr(&self.addr));
+        let first_addr = match self.listening_addrs().first() {
+            Some(addr) => addr.to_string(),
+            None => return Err(box_err!("invalid addr: {:?}", self.addr)),
+        };
+        for addr in self.listening_addrs() {
+            box_try!(config::check_addr(addr));
+        }
         if !self.advertise_addr.is_empty() {
             box_try!(config::check_addr(&self.advertise_addr));
         } else {
             info!("no advertise-addr is specified, fall back to addr.");
-            self.advertise_addr = self.addr.clone();
+            self.advertise_addr = first_addr;
         }
         if self.advertise_addr.starts_with("0.") {
             return Err(box_err!(
@@ -173,6 +414,20 @@ impl Config {
                 self.advertise_addr
             ));
         }
+        if !self.bulk_addr.is_empty() {
+            box_try!(config::check_addr(&self.bulk_addr));
+            if self.listening_addrs().contains(&self.bulk_addr.as_str())
+                || self.bulk_addr == self.status_addr
+            {
+                return Err(box_err!(
+                    "bulk-addr has already been used: {:?}",
+                    self.bulk_addr
+                ));
+            }
+            if self.grpc_bulk_concurrency == 0 {
+                return Err(box_err!("grpc-bulk-concurrency should not be 0"));
+            }
+        }
         let non_zero_entries = vec![
             (
                 "concurrent-send-snap-limit",
@@ -182,6 +437,10 @@ impl Config {
                 "concurrent-recv-snap-limit",
                 self.concurrent_recv_snap_limit,
             ),
+            (
+                "end-point-stream-channel-size",
+                self.end_point_stream_channel_size,
+            ),
         ];
         for (label, value) in non_zero_entries {
             if value == 0 {
@@ -189,7 +448,7 @@ impl Config {
             }
         }
 
-        if self.end_point_recursion_limit < 100 {
+        if self.end_point_recursion_limit < MIN_ENDPOINT_RECURSION_LIMIT {
             return Err(box_err!("server.end-point-recursion-limit is too small"));
         }
 
@@ -201,6 +460,47 @@ impl Config {
             ));
         }
 
+        if self.snap_send_chunk_size.0 < MIN_SNAP_SEND_CHUNK_SIZE
+            || self.snap_send_chunk_size.0 > MAX_SNAP_SEND_CHUNK_SIZE
+        {
+            return Err(box_err!("server.snap-send-chunk-size should be between 64KB and 8MB"));
+        }
+
+        if self.raft_client_max_reconnect_backoff.0 < self.raft_client_reconnect_backoff.0 {
+            return Err(box_err!(
+                "server.raft-client-max-reconnect-backoff should not be less than \
+                 server.raft-client-reconnect-backoff"
+            ));
+        }
+
+        if self.memory_pressure_high_water < 0.0 || self.memory_pressure_high_water > 1.0 {
+            return Err(box_err!("server.memory-pressure-high-water should be between 0 and 1"));
+        }
+        if self.memory_pressure_low_water < 0.0
+            || self.memory_pressure_low_water > self.memory_pressure_high_water
+        {
+            return Err(box_err!(
+                "server.memory-pressure-low-water should be between 0 and \
+                 server.memory-pressure-high-water"
+            ));
+        }
+
+        if self.local_read_shed_threshold > 0 && self.local_read_shed_window.as_millis() == 0 {
+            return Err(box_err!("server.local-read-shed-window should not be 0"));
+        }
+
+        if self.end_point_overload_high_water < 0.0 || self.end_point_overload_high_water > 1.0 {
+            return Err(box_err!("server.end-point-overload-high-water should be between 0 and 1"));
+        }
+        if self.end_point_overload_low_water < 0.0
+            || self.end_point_overload_low_water > self.end_point_overload_high_water
+        {
+            return Err(box_err!(
+                "server.end-point-overload-low-water should be between 0 and \
+                 server.end-point-overload-high-water"
+            ));
+        }
+
         if self.grpc_stream_initial_window_size.0 > i32::MAX as u64 {
             return Err(box_err!(
                 "server.grpc_stream_initial_window_size is too large."
@@ -212,6 +512,15 @@ impl Config {
             validate_label(v, "value")?;
         }
 
+        for tp in self.raft_msg_full_policy.keys() {
+            if parse_msg_type(tp).is_none() {
+                return Err(box_err!(
+                    "server.raft-msg-full-policy: unknown message type {:?}",
+                    tp
+                ));
+            }
+        }
+
         Ok(())
     }
 
@@ -222,6 +531,21 @@ impl Config {
             GrpcCompressionType::Gzip => CompressionAlgorithms::Gzip,
         }
     }
+
+    /// Returns `raft_msg_full_policy` keyed by the message types. Unknown types are ignored.
+    pub fn raft_msg_full_policies(&self) -> HashMap<MessageType, RaftMsgFullPolicy> {
+        self.raft_msg_full_policy
+            .iter()
+            .filter_map(|(tp, policy)| parse_msg_type(tp).map(|tp| (tp, *policy)))
+            .collect()
+    }
+}
+
+fn parse_msg_type(name: &str) -> Option<MessageType> {
+    MessageType::values()
+        .iter()
+        .find(|tp| format!("{:?}", tp) == name)
+        .cloned()
 }
 
 fn validate_label(s: &str, tp: &str) -> Result<()> {
@@ -279,25 +603,97 @@ mod tests {
         invalid_cfg.end_point_recursion_limit = 0;
         assert!(invalid_cfg.validate().is_err());
 
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.end_point_stream_channel_size = 0;
+        assert!(invalid_cfg.validate().is_err());
+
         let mut invalid_cfg = cfg.clone();
         invalid_cfg.end_point_request_max_handle_duration = ReadableDuration::secs(0);
         assert!(invalid_cfg.validate().is_err());
 
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.local_read_shed_threshold = 10;
+        invalid_cfg.local_read_shed_window = ReadableDuration::secs(0);
+        assert!(invalid_cfg.validate().is_err());
+
         invalid_cfg = Config::default();
         invalid_cfg.addr = "0.0.0.0:1000".to_owned();
         assert!(invalid_cfg.validate().is_err());
         invalid_cfg.advertise_addr = "127.0.0.1:1000".to_owned();
         invalid_cfg.validate().unwrap();
 
+        // The first listening address is advertised by default.
+        let mut multi_addr_cfg = Config::default();
+        multi_addr_cfg.addr = "127.0.0.1:1000, [::1]:1000".to_owned();
+        multi_addr_cfg.validate().unwrap();
+        assert_eq!(
+            multi_addr_cfg.listening_addrs(),
+            vec!["127.0.0.1:1000", "[::1]:1000"]
+        );
+        assert_eq!(multi_addr_cfg.advertise_addr, "127.0.0.1:1000");
+        for invalid in &["", " , ", "127.0.0.1:1000,127.0.0.1"] {
+            invalid_cfg = Config::default();
+            invalid_cfg.addr = invalid.to_string();
+            assert!(invalid_cfg.validate().is_err(), "{}", invalid);
+        }
+        invalid_cfg = multi_addr_cfg.clone();
+        invalid_cfg.bulk_addr = "[::1]:1000".to_owned();
+        assert!(invalid_cfg.validate().is_err());
+
         let mut invalid_cfg = cfg.clone();
         invalid_cfg.advertise_addr = "127.0.0.1:1000".to_owned();
         invalid_cfg.status_addr = "127.0.0.1:1000".to_owned();
         assert!(invalid_cfg.validate().is_err());
 
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.bulk_addr = cfg.addr.clone();
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.bulk_addr = "127.0.0.1:20170".to_owned();
+        invalid_cfg.validate().unwrap();
+        invalid_cfg.grpc_bulk_concurrency = 0;
+        assert!(invalid_cfg.validate().is_err());
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.snap_send_chunk_size = ReadableSize::kb(1);
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.snap_send_chunk_size = ReadableSize::mb(16);
+        assert!(invalid_cfg.validate().is_err());
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.raft_client_max_reconnect_backoff = ReadableDuration::millis(10);
+        assert!(invalid_cfg.validate().is_err());
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.memory_pressure_high_water = 1.5;
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.memory_pressure_high_water = 0.8;
+        invalid_cfg.memory_pressure_low_water = 0.9;
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.memory_pressure_low_water = 0.7;
+        invalid_cfg.validate().unwrap();
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.end_point_overload_high_water = -0.1;
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.end_point_overload_high_water = 0.8;
+        invalid_cfg.end_point_overload_low_water = 0.9;
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.end_point_overload_low_water = 0.5;
+        invalid_cfg.validate().unwrap();
+
         let mut invalid_cfg = cfg.clone();
         invalid_cfg.grpc_stream_initial_window_size = ReadableSize(i32::MAX as u64 + 1);
         assert!(invalid_cfg.validate().is_err());
 
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg
+            .raft_msg_full_policy
+            .insert("MsgUnknown".to_owned(), RaftMsgFullPolicy::Block);
+        assert!(invalid_cfg.validate().is_err());
+        let policies = cfg.raft_msg_full_policies();
+        assert_eq!(policies[&MessageType::MsgRequestVote], RaftMsgFullPolicy::ForceSend);
+        assert!(!policies.contains_key(&MessageType::MsgAppend));
+
         cfg.labels.insert("k1".to_owned(), "v1".to_owned());
         cfg.validate().unwrap();
         cfg.labels.insert("k2".to_owned(), "v2?".to_owned());
@@ -277,6 +277,31 @@ impl Debugger {
         MvccInfoIterator::new(&self.engines.kv, start, end, limit)
     }
 
+    /// Scans the raw keys of `cf` in `[start, end)` with at most `limit` keys, along with
+    /// the lengths of their values. The keys are read from a consistent view of the db
+    /// lazily, so a large range isn't loaded into memory at once.
+    pub fn scan_raw(
+        &self,
+        db: DBType,
+        cf: &str,
+        start: &[u8],
+        end: &[u8],
+        limit: u64,
+    ) -> Result<RawKeyIterator> {
+        validate_db_and_cf(db, cf)?;
+        if limit == 0 {
+            return Err(Error::InvalidArgument("no limit".to_owned()));
+        }
+        if !end.is_empty() && start >= end {
+            return Err(Error::InvalidArgument("start should be less than end".to_owned()));
+        }
+        let db = match db {
+            DBType::KV => &self.engines.kv,
+            _ => &self.engines.raft,
+        };
+        RawKeyIterator::new(db, cf, start, end, limit)
+    }
+
     /// Compact the cf[start..end) in the db.
     pub fn compact(
         &self,
@@ -1210,6 +1235,42 @@ impl Iterator for MvccInfoIterator {
     }
 }
 
+/// `RawKeyIterator` yields the raw keys and the lengths of their values in a range.
+pub struct RawKeyIterator {
+    limit: u64,
+    count: u64,
+    iter: DBIterator,
+}
+
+impl RawKeyIterator {
+    fn new(db: &Arc<DB>, cf: &str, from: &[u8], to: &[u8], limit: u64) -> Result<Self> {
+        let to = if to.is_empty() { None } else { Some(to) };
+        let readopts = IterOption::new(None, to.map(Vec::from), false).build_read_opts();
+        let handle = box_try!(get_cf_handle(db.as_ref(), cf));
+        let mut iter = DBIterator::new_cf(Arc::clone(db), handle, readopts);
+        iter.seek(SeekKey::from(from));
+        Ok(RawKeyIterator {
+            limit,
+            count: 0,
+            iter,
+        })
+    }
+}
+
+impl Iterator for RawKeyIterator {
+    type Item = (Vec<u8>, usize);
+
+    fn next(&mut self) -> Option<(Vec<u8>, usize)> {
+        if self.count >= self.limit || !self.iter.valid() {
+            return None;
+        }
+        let item = (self.iter.key().to_vec(), self.iter.value().len());
+        self.count += 1;
+        self.iter.next();
+        Some(item)
+    }
+}
+
 pub fn validate_db_and_cf(db: DBType, cf: &str) -> Result<()> {
     match (db, cf) {
         (DBType::KV, CF_DEFAULT)
@@ -1674,6 +1735,32 @@ mod tests {
         assert!(debugger.scan_mvcc(b"z", b"x", 3).is_err());
     }
 
+    #[test]
+    fn test_scan_raw() {
+        let debugger = new_debugger();
+        let engine = &debugger.engines.kv;
+        let write_cf = engine.cf_handle(CF_WRITE).unwrap();
+        for (i, key) in [b"k1", b"k2", b"k3", b"k4", b"k5"].iter().enumerate() {
+            engine.put_cf(write_cf, *key, &vec![b'v'; i]).unwrap();
+        }
+        engine.put(b"k0", b"v").unwrap();
+
+        let scan = |start: &[u8], end: &[u8], limit| {
+            debugger
+                .scan_raw(DBType::KV, CF_WRITE, start, end, limit)
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scan(b"k2", b"k4", 10), vec![(b"k2".to_vec(), 1), (b"k3".to_vec(), 2)]);
+        assert_eq!(scan(b"", b"", 2), vec![(b"k1".to_vec(), 0), (b"k2".to_vec(), 1)]);
+        assert_eq!(scan(b"k4", b"", 10).len(), 2);
+
+        // Test scan with bad cf, range or limit.
+        assert!(debugger.scan_raw(DBType::RAFT, CF_WRITE, b"", b"", 1).is_err());
+        assert!(debugger.scan_raw(DBType::KV, CF_WRITE, b"k3", b"k1", 1).is_err());
+        assert!(debugger.scan_raw(DBType::KV, CF_WRITE, b"", b"", 0).is_err());
+    }
+
     #[test]
     fn test_tombstone_regions() {
         let debugger = new_debugger();
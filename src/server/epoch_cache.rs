@@ -0,0 +1,151 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::RwLock;
+
+use kvproto::metapb::Region;
+use kvproto::raft_cmdpb::{RaftCmdRequest, RaftCmdResponse};
+
+use raftstore::store::util::check_region_epoch;
+use raftstore::{Error, Result};
+use util::collections::HashMap;
+use util::HandyRwLock;
+
+// The cache is simply cleared once it grows so large, as most of the regions are likely
+// gone or moved away by then.
+const MAX_CACHED_REGIONS: usize = 64 * 1024;
+
+/// `EpochCache` remembers the latest region metadata seen in the stale epoch errors of the
+/// responses, so that commands carrying epochs older than that can be rejected without
+/// a round trip through the raftstore.
+///
+/// It's best-effort. The epoch of a region never goes back, so a command found stale here
+/// would be found stale by the peer as well, while a region missing from the cache is
+/// never rejected.
+#[derive(Default)]
+pub struct EpochCache {
+    regions: RwLock<HashMap<u64, Region>>,
+}
+
+impl EpochCache {
+    /// Returns the stale epoch error the peer would respond `req` with, if any.
+    pub fn check(&self, req: &RaftCmdRequest) -> Result<()> {
+        if !req.get_header().has_region_epoch() {
+            return Ok(());
+        }
+        let regions = self.regions.rl();
+        let region = match regions.get(&req.get_header().get_region_id()) {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+        match check_region_epoch(req, region, true) {
+            Err(e @ Error::StaleEpoch(..)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Learns the regions carried by the stale epoch error of `resp`.
+    pub fn on_response(&self, resp: &RaftCmdResponse) {
+        let header = resp.get_header();
+        if !header.get_error().has_stale_epoch() {
+            return;
+        }
+        let mut regions = self.regions.wl();
+        if regions.len() >= MAX_CACHED_REGIONS {
+            regions.clear();
+        }
+        for region in header.get_error().get_stale_epoch().get_new_regions() {
+            let epoch = region.get_region_epoch();
+            let newer = match regions.get(&region.get_id()) {
+                Some(cached) => {
+                    let cached = cached.get_region_epoch();
+                    epoch.get_version() >= cached.get_version()
+                        && epoch.get_conf_ver() >= cached.get_conf_ver()
+                }
+                None => true,
+            };
+            if newer {
+                regions.insert(region.get_id(), region.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::raft_cmdpb::{AdminCmdType, CmdType, Request};
+
+    use super::*;
+    use raftstore::store::cmd_resp;
+
+    fn new_region(id: u64, conf_ver: u64, version: u64) -> Region {
+        let mut region = Region::new();
+        region.set_id(id);
+        region.mut_region_epoch().set_conf_ver(conf_ver);
+        region.mut_region_epoch().set_version(version);
+        region
+    }
+
+    fn new_put(region_id: u64, conf_ver: u64, version: u64) -> RaftCmdRequest {
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(region_id);
+        req.mut_header().mut_region_epoch().set_conf_ver(conf_ver);
+        req.mut_header().mut_region_epoch().set_version(version);
+        let mut put = Request::new();
+        put.set_cmd_type(CmdType::Put);
+        req.mut_requests().push(put);
+        req
+    }
+
+    fn stale_epoch_resp(regions: Vec<Region>) -> RaftCmdResponse {
+        cmd_resp::new_error(Error::StaleEpoch("stale".to_owned(), regions))
+    }
+
+    #[test]
+    fn test_epoch_cache() {
+        let cache = EpochCache::default();
+        cache.check(&new_put(1, 1, 1)).unwrap();
+
+        cache.on_response(&stale_epoch_resp(vec![new_region(1, 2, 3), new_region(2, 1, 1)]));
+        match cache.check(&new_put(1, 2, 2)) {
+            Err(Error::StaleEpoch(_, ref regions)) => {
+                assert_eq!(*regions, vec![new_region(1, 2, 3)])
+            }
+            res => panic!("expect stale epoch, but got {:?}", res),
+        }
+        // Normal commands don't care about the conf version, and requests with newer epochs
+        // may just be ahead of the cache.
+        cache.check(&new_put(1, 1, 3)).unwrap();
+        cache.check(&new_put(1, 2, 4)).unwrap();
+        cache.check(&new_put(2, 1, 1)).unwrap();
+        let mut no_epoch = new_put(1, 1, 1);
+        no_epoch.mut_header().clear_region_epoch();
+        cache.check(&no_epoch).unwrap();
+        let mut compact = RaftCmdRequest::new();
+        compact.mut_header().set_region_id(1);
+        compact.mut_header().mut_region_epoch().set_version(1);
+        compact
+            .mut_admin_request()
+            .set_cmd_type(AdminCmdType::CompactLog);
+        cache.check(&compact).unwrap();
+
+        // Older regions never replace newer ones.
+        cache.on_response(&stale_epoch_resp(vec![new_region(1, 2, 2)]));
+        assert!(cache.check(&new_put(1, 2, 2)).is_err());
+        cache.on_response(&stale_epoch_resp(vec![new_region(1, 2, 5)]));
+        assert!(cache.check(&new_put(1, 2, 4)).is_err());
+        // Other responses are ignored.
+        cache.on_response(&RaftCmdResponse::new());
+        assert!(cache.check(&new_put(1, 2, 4)).is_err());
+    }
+}
@@ -99,6 +99,14 @@ quick_error!{
         Sink {
             description("failed to poll from mpsc receiver")
         }
+        StoreTombstone(store_id: u64) {
+            description("store has been removed")
+            display("store {} has been removed", store_id)
+        }
+        RaftMessageTooLarge(size: u64, limit: u64) {
+            description("raft message is too large")
+            display("raft message size {} exceeds the limit {}", size, limit)
+        }
         Canceled(err: Canceled) {
             from()
             cause(err)
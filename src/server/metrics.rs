@@ -57,6 +57,60 @@ lazy_static! {
         "Bucketed histogram of server send snapshots duration",
         exponential_buckets(0.05, 2.0, 20).unwrap()
     ).unwrap();
+    pub static ref SNAP_BYTES_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_snapshot_bytes_total",
+        "Total number of snapshot bytes sent or received",
+        &["direction", "throttled"]
+    ).unwrap();
+    pub static ref SNAP_THROUGHPUT_GAUGE_VEC: GaugeVec = register_gauge_vec!(
+        "tikv_server_snapshot_throughput_bytes_per_second",
+        "Throughput of the last snapshot sent or received",
+        &["direction"]
+    ).unwrap();
+    pub static ref SNAP_COMPRESSION_BYTES_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_snapshot_compression_bytes_total",
+        "Total number of snapshot bytes sent before and after compression",
+        &["type"]
+    ).unwrap();
+    pub static ref SNAP_CHUNK_SEND_HISTOGRAM: Histogram = register_histogram!(
+        "tikv_server_snapshot_chunk_send_duration_seconds",
+        "Bucketed histogram of the time for the gRPC stream to take a snapshot chunk",
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref SNAP_SEND_STALLED_COUNTER: IntCounter = register_int_counter!(
+        "tikv_server_snapshot_send_stalled_total",
+        "Total number of snapshot sends aborted because the receiver stopped taking chunks"
+    ).unwrap();
+    pub static ref SNAP_RECEIVING_GAUGE: IntGauge = register_int_gauge!(
+        "tikv_server_snapshot_receiving",
+        "Number of snapshots being received"
+    ).unwrap();
+    pub static ref RAFT_OUTSTANDING_CALLBACKS_GAUGE: IntGauge = register_int_gauge!(
+        "tikv_server_raft_outstanding_callbacks",
+        "Number of callbacks of commands sent to raftstore but not finished"
+    ).unwrap();
+    pub static ref STALE_EPOCH_FAST_REJECT_COUNTER: IntCounter = register_int_counter!(
+        "tikv_server_stale_epoch_fast_reject_total",
+        "Total number of commands rejected for stale epochs before being sent to raftstore"
+    ).unwrap();
+    pub static ref LOCAL_READ_SHED_COUNTER: IntCounter = register_int_counter!(
+        "tikv_server_local_read_shed_total",
+        "Total number of local reads shed for hot regions"
+    ).unwrap();
+    pub static ref REGION_READ_THROTTLED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_region_read_throttled_total",
+        "Total number of reads throttled for exceeding the read quota of the region",
+        &["region"]
+    ).unwrap();
+    pub static ref REGION_WRITE_THROTTLED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_region_write_throttled_total",
+        "Total number of writes throttled for exceeding the write quota of the region",
+        &["region"]
+    ).unwrap();
+    pub static ref SNAP_DRAINING_GAUGE: IntGauge = register_int_gauge!(
+        "tikv_server_snapshot_draining",
+        "Whether the snapshot worker refuses new snapshots"
+    ).unwrap();
     pub static ref SNAP_TASK_COUNTER: IntCounterVec = register_int_counter_vec!(
         "tikv_server_snapshot_task_total",
         "Total number of snapshot task",
@@ -75,6 +129,11 @@ lazy_static! {
         "Total number of handle grpc message failure",
         &["type"]
     ).unwrap();
+    pub static ref SENT_RAFT_MSG_BY_TYPE_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_raft_message_sent_total",
+        "Total number of raft messages sent by message type",
+        &["type"]
+    ).unwrap();
     pub static ref RAFT_MESSAGE_RECV_COUNTER: IntCounter = register_int_counter!(
         "tikv_server_raft_message_recv_total",
         "Total number of raft messages received"
@@ -84,6 +143,43 @@ lazy_static! {
         "Total number of resolving store",
         &["type"]
     ).unwrap();
+    pub static ref RESOLVE_STORE_DURATION: HistogramVec = register_histogram_vec!(
+        "tikv_server_resolve_store_duration_seconds",
+        "Bucketed histogram of resolving store address duration",
+        &["type"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref RAFT_MSG_SEND_LATENCY: HistogramVec = register_histogram_vec!(
+        "tikv_server_raft_message_send_latency_seconds",
+        "Bucketed histogram of raft message latency from the transport to the connection",
+        &["type"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref RAFT_MSG_LAST_SEND_GAUGE_VEC: GaugeVec = register_gauge_vec!(
+        "tikv_server_raft_message_last_send_seconds",
+        "Seconds since raft messages are last written to the store",
+        &["store_id"]
+    ).unwrap();
+    pub static ref RAFT_CONN_UPTIME_GAUGE_VEC: GaugeVec = register_gauge_vec!(
+        "tikv_server_raft_connection_uptime_seconds",
+        "Seconds since the oldest established raft connection to the store is up",
+        &["store_id"]
+    ).unwrap();
+    pub static ref RAFT_CONN_PENDING_MSG_GAUGE_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "tikv_server_raft_connection_pending_messages",
+        "Number of raft messages buffered or queued in the connections to the store",
+        &["store_id"]
+    ).unwrap();
+    pub static ref RAFT_CONN_PENDING_BYTES_GAUGE_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "tikv_server_raft_connection_pending_bytes",
+        "Bytes of raft messages buffered or queued in the connections to the store",
+        &["store_id"]
+    ).unwrap();
+    pub static ref RAFT_CONN_RECONNECT_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_raft_connection_reconnect_total",
+        "Total number of raft connections rebuilt after the previous ones are dropped",
+        &["store_id"]
+    ).unwrap();
     pub static ref REPORT_FAILURE_MSG_COUNTER: IntCounterVec = register_int_counter_vec!(
         "tikv_server_report_failure_msg_total",
         "Total number of reporting failure messages",
@@ -93,4 +189,65 @@ lazy_static! {
         "tikv_server_raft_message_flush_total",
         "Total number of raft messages flushed"
     ).unwrap();
+    pub static ref RAFT_MESSAGE_SEND_OUTCOME_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_server_raft_message_send_outcome_total",
+        "Total number of raft messages passed to the raft client by what is done with them",
+        &["outcome"]
+    ).unwrap();
+    pub static ref RAFT_MESSAGE_CODEC_SIZE_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_server_raft_message_codec_size_bytes",
+        "Bucketed histogram of the sizes of raft messages encoded and decoded",
+        &["type"],
+        exponential_buckets(64.0, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref RAFT_MESSAGE_LOCAL_DELIVERY_COUNTER: IntCounter = register_int_counter!(
+        "tikv_server_raft_message_local_delivery_total",
+        "Total number of raft messages to the local store delivered without the network"
+    ).unwrap();
+    pub static ref GRPC_IN_FLIGHT_REQUESTS_GAUGE: IntGauge = register_int_gauge!(
+        "tikv_grpc_in_flight_requests",
+        "Number of KV and coprocessor requests being handled"
+    ).unwrap();
+    pub static ref GRPC_CONN_IN_FLIGHT_REQUESTS_GAUGE_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "tikv_grpc_connection_in_flight_requests",
+        "Number of KV and coprocessor requests being handled of each connection",
+        &["conn"]
+    ).unwrap();
+    pub static ref GRPC_CONN_BUSY_COUNTER: IntCounter = register_int_counter!(
+        "tikv_grpc_connection_busy_total",
+        "Total number of gRPC requests refused by the per connection in-flight limit"
+    ).unwrap();
+    pub static ref COPR_PAUSED_GAUGE: IntGauge = register_int_gauge!(
+        "tikv_coprocessor_paused",
+        "Whether new coprocessor requests are refused, 1 for paused and 0 for running"
+    ).unwrap();
+    pub static ref GRPC_INTERCEPTED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_grpc_msg_intercepted_total",
+        "Total number of gRPC requests refused by interceptors",
+        &["type"]
+    ).unwrap();
+    pub static ref GRPC_RATE_LIMITED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_grpc_msg_rate_limited_total",
+        "Total number of gRPC requests refused by the per client rate limit",
+        &["type"]
+    ).unwrap();
+    pub static ref GRPC_MEMORY_SHED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_grpc_msg_memory_shed_total",
+        "Total number of gRPC requests refused under memory pressure",
+        &["type"]
+    ).unwrap();
+    pub static ref MEMORY_USAGE_GAUGE: Gauge = register_gauge!(
+        "tikv_server_memory_usage_ratio",
+        "Fraction of the system memory used by the process"
+    ).unwrap();
+    pub static ref RAFT_PING_HISTOGRAM: Histogram = register_histogram!(
+        "tikv_server_raft_ping_duration_seconds",
+        "Bucketed histogram of round trip time of pinging other stores",
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref RAFT_MSG_SEND_RETRY_HISTOGRAM: Histogram = register_histogram!(
+        "tikv_server_raft_message_send_retry_duration_seconds",
+        "Bucketed histogram of time spent sending raft messages to raftstore with retries",
+        exponential_buckets(0.0001, 2.0, 16).unwrap()
+    ).unwrap();
 }
@@ -11,9 +11,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod epoch_cache;
 mod load_statistics;
 mod metrics;
 mod raft_client;
+mod raft_codec;
+mod read_shedder;
+mod region_quota;
 mod service;
 
 pub mod config;
@@ -30,7 +34,9 @@ pub mod transport;
 pub use self::config::{Config, DEFAULT_CLUSTER_ID, DEFAULT_LISTENING_ADDR};
 pub use self::errors::{Error, Result};
 pub use self::node::{create_raft_storage, Node};
-pub use self::raft_client::RaftClient;
-pub use self::resolve::{PdStoreAddrResolver, StoreAddrResolver};
-pub use self::server::Server;
+pub use self::raft_client::{RaftClient, SendOutcome};
+pub use self::resolve::{
+    LiveStoreSource, PdLiveStoreSource, PdStoreAddrResolver, StoreAddrResolver,
+};
+pub use self::server::{Server, ServerBuilder};
 pub use self::transport::{ServerRaftStoreRouter, ServerTransport};
@@ -28,8 +28,8 @@ use pd::{Error as PdError, PdClient, PdTask, INVALID_ID};
 use protobuf::RepeatedField;
 use raftstore::coprocessor::dispatcher::CoprocessorHost;
 use raftstore::store::{
-    self, keys, Config as StoreConfig, Engines, Msg, Peekable, ReadTask, SignificantMsg,
-    SnapManager, Store, StoreChannel, Transport,
+    self, keys, Config as StoreConfig, Engines, HostedRegions, Msg, Peekable, ReadTask,
+    SignificantMsg, SnapManager, Store, StoreChannel, Transport,
 };
 use rocksdb::DB;
 use server::readpool::ReadPool;
@@ -52,7 +52,19 @@ pub fn create_raft_storage<S>(
 where
     S: RaftStoreRouter + 'static,
 {
-    let engine = RaftKv::new(router);
+    let mut engine = RaftKv::new(router);
+    if cfg.region_metrics_top_n > 0 {
+        engine = engine.with_region_metrics(cfg.region_metrics_top_n);
+    }
+    if cfg.write_limit_bytes_per_sec.0 > 0 {
+        engine = engine.with_write_limiter(
+            cfg.write_limit_bytes_per_sec.0,
+            cfg.write_limit_bypass_threshold.0 as usize,
+        );
+    }
+    if let Some(ref db) = local_storage {
+        engine = engine.with_local_engine(Arc::clone(db));
+    }
     let store = Storage::from_engine(engine, cfg, read_pool, local_storage, raft_store_router)?;
     Ok(store)
 }
@@ -105,7 +117,7 @@ where
         let mut store = metapb::Store::new();
         store.set_id(INVALID_ID);
         if cfg.advertise_addr.is_empty() {
-            store.set_address(cfg.addr.clone());
+            store.set_address(cfg.listening_addrs()[0].to_owned());
         } else {
             store.set_address(cfg.advertise_addr.clone())
         }
@@ -143,6 +155,7 @@ where
         local_read_worker: Worker<ReadTask>,
         coprocessor_host: CoprocessorHost,
         importer: Arc<SSTImporter>,
+        hosted_regions: HostedRegions,
     ) -> Result<()>
     where
         T: Transport + 'static,
@@ -184,6 +197,7 @@ where
             local_read_worker,
             coprocessor_host,
             importer,
+            hosted_regions,
         )?;
         Ok(())
     }
@@ -335,6 +349,7 @@ where
         local_read_worker: Worker<ReadTask>,
         coprocessor_host: CoprocessorHost,
         importer: Arc<SSTImporter>,
+        hosted_regions: HostedRegions,
     ) -> Result<()>
     where
         T: Transport + 'static,
@@ -369,6 +384,7 @@ where
                 local_read_worker,
                 coprocessor_host,
                 importer,
+                hosted_regions,
             ) {
                 Err(e) => panic!("construct store {} err {:?}", store_id, e),
                 Ok(s) => s,
@@ -11,21 +11,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::boxed::FnBox;
+use std::cmp;
 use std::ffi::CString;
-use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
-use std::sync::Arc;
+use std::fmt::Debug;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::sync::mpsc::{self, UnboundedSender};
 use futures::sync::oneshot::{self, Sender};
-use futures::{stream, Future, Sink, Stream};
-use grpc::{ChannelBuilder, Environment, WriteFlags};
+use futures::{future, stream, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use grpc::{
+    CallOption, ChannelBuilder, Client, Environment, Error as GrpcError, RpcStatusCode, WriteFlags,
+};
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::tikvpb_grpc::TikvClient;
+use protobuf::Message;
+use raft::eraftpb::MessageType;
+use rand::{thread_rng, Rng};
 
 use super::metrics::*;
+use super::raft_codec::raft_method;
 use super::{Config, Error, Result};
-use util::collections::HashMap;
+use raftstore::store::util::msg_trace_ids;
+use util::collections::{HashMap, HashSet};
 use util::security::SecurityManager;
+use util::time::{duration_to_nanos, duration_to_sec};
+use util::timer::GLOBAL_TIMER_HANDLE;
 
 const MAX_GRPC_RECV_MSG_LEN: i32 = 10 * 1024 * 1024;
 const MAX_GRPC_SEND_MSG_LEN: i32 = 10 * 1024 * 1024;
@@ -33,13 +47,173 @@ const PRESERVED_MSG_BUFFER_COUNT: usize = 1024;
 
 static CONN_ID: AtomicI32 = AtomicI32::new(0);
 
+pub type PingCallback = Box<FnBox(Result<Duration>) + Send>;
+pub type FlushCallback = Box<FnBox(Result<()>) + Send>;
+
+// Invokes the callback once all the tokens of the flush are dropped, with an error if any
+// of them is dropped before being acknowledged.
+struct FlushAck {
+    cb: Mutex<Option<FlushCallback>>,
+    failed: AtomicBool,
+}
+
+impl Drop for FlushAck {
+    fn drop(&mut self) {
+        let cb = self.cb.lock().unwrap().take().unwrap();
+        if self.failed.load(Ordering::SeqCst) {
+            cb(Err(box_err!("some raft messages are not sent")));
+        } else {
+            cb(Ok(()));
+        }
+    }
+}
+
+// Follows the messages of a flush through a connection, and is acknowledged once the
+// messages before it have been written.
+struct AckToken {
+    ack: Arc<FlushAck>,
+    acked: bool,
+}
+
+impl AckToken {
+    fn new(ack: &Arc<FlushAck>) -> AckToken {
+        AckToken {
+            ack: Arc::clone(ack),
+            acked: false,
+        }
+    }
+}
+
+impl Drop for AckToken {
+    fn drop(&mut self) {
+        if !self.acked {
+            self.ack.failed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// The messages flushed to a connection but not taken by its gRPC stream yet.
+#[derive(Default)]
+struct ConnQueue {
+    msgs: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+// Follows a batch of messages through a connection, and takes them out of the queue of the
+// connection once it's dropped, either after the messages are taken by the gRPC stream or
+// along with the connection.
+struct QueueToken {
+    queue: Arc<ConnQueue>,
+    msgs: usize,
+    bytes: usize,
+}
+
+impl QueueToken {
+    fn new(queue: &Arc<ConnQueue>, msgs: usize, bytes: usize) -> QueueToken {
+        queue.msgs.fetch_add(msgs, Ordering::SeqCst);
+        queue.bytes.fetch_add(bytes, Ordering::SeqCst);
+        QueueToken {
+            queue: Arc::clone(queue),
+            msgs,
+            bytes,
+        }
+    }
+}
+
+impl Drop for QueueToken {
+    fn drop(&mut self) {
+        self.queue.msgs.fetch_sub(self.msgs, Ordering::SeqCst);
+        self.queue.bytes.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+enum ConnItem<T> {
+    Msg(T),
+    Dequeue(QueueToken),
+    Ack(AckToken),
+}
+
+// The messages flushed at once, followed by the token of the batch, and the token of the
+// flush if it's acknowledged.
+type ConnBatch = (
+    Vec<(RaftMessage, WriteFlags)>,
+    Option<QueueToken>,
+    Option<AckToken>,
+);
+
+/// A `Sink` wrapper which marks the connection as established once a write completes,
+/// records when the writes last complete, and acknowledges the flushes whose messages
+/// are all written.
+struct ConnSink<S> {
+    sink: S,
+    written: bool,
+    established: Arc<AtomicBool>,
+    last_send: Arc<Mutex<Instant>>,
+    acks: Vec<AckToken>,
+}
+
+impl<S: Sink> Sink for ConnSink<S> {
+    type SinkItem = ConnItem<S::SinkItem>;
+    type SinkError = S::SinkError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let msg = match item {
+            ConnItem::Msg(msg) => msg,
+            // All the messages of the batch are taken.
+            ConnItem::Dequeue(_) => return Ok(AsyncSink::Ready),
+            ConnItem::Ack(token) => {
+                self.acks.push(token);
+                return Ok(AsyncSink::Ready);
+            }
+        };
+        match self.sink.start_send(msg)? {
+            AsyncSink::Ready => {
+                self.written = true;
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(msg) => Ok(AsyncSink::NotReady(ConnItem::Msg(msg))),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let res = self.sink.poll_complete()?;
+        // A write can only be completed after the connection is established.
+        if res.is_ready() && self.written {
+            self.established.store(true, Ordering::SeqCst);
+            *self.last_send.lock().unwrap() = Instant::now();
+            self.written = false;
+        }
+        if res.is_ready() {
+            for mut token in self.acks.drain(..) {
+                token.acked = true;
+            }
+        }
+        Ok(res)
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.sink.close()
+    }
+}
+
 struct Conn {
-    stream: UnboundedSender<Vec<(RaftMessage, WriteFlags)>>,
+    stream: UnboundedSender<ConnBatch>,
     buffer: Option<Vec<(RaftMessage, WriteFlags)>>,
+    buffer_bytes: usize,
+    queue: Arc<ConnQueue>,
+    // When the buffered messages are passed to the transport.
+    enqueue_times: Vec<Instant>,
     store_id: u64,
     alive: Arc<AtomicBool>,
+    established: Arc<AtomicBool>,
+    create_time: Instant,
+    // When the connection is first found established by a flush.
+    established_time: Option<Instant>,
+    // When the messages are last written to the stream, or when the connection is created
+    // if nothing has been written yet.
+    last_send: Arc<Mutex<Instant>>,
 
-    _client: TikvClient,
+    client: TikvClient,
     _close: Sender<()>,
 }
 
@@ -55,6 +229,9 @@ impl Conn {
 
         let alive = Arc::new(AtomicBool::new(true));
         let alive1 = Arc::clone(&alive);
+        let established = Arc::new(AtomicBool::new(false));
+        let create_time = Instant::now();
+        let last_send = Arc::new(Mutex::new(create_time));
         let cb = ChannelBuilder::new(env)
             .stream_initial_window_size(cfg.grpc_stream_initial_window_size.0 as i32)
             .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
@@ -68,17 +245,45 @@ impl Conn {
                 CONN_ID.fetch_add(1, Ordering::SeqCst),
             );
         let channel = security_mgr.connect(cb, addr);
-        let client = TikvClient::new(channel);
+        let client = TikvClient::new(channel.clone());
         let (tx, rx) = mpsc::unbounded();
         let (tx_close, rx_close) = oneshot::channel();
-        let (sink, receiver) = client.raft().unwrap();
+        let (sink, receiver) = Client::new(channel)
+            .client_streaming(&raft_method(cfg.raft_msg_codec), CallOption::default())
+            .unwrap();
+        let sink = ConnSink {
+            sink,
+            written: false,
+            established: Arc::clone(&established),
+            last_send: Arc::clone(&last_send),
+            acks: vec![],
+        };
         let addr = addr.to_owned();
+        let mut jitter = FlushJitter::new(cfg.raft_client_flush_jitter.0);
+        let rx = rx.and_then(move |batch| {
+            let now = Instant::now();
+            match jitter.delay(now) {
+                None => future::Either::A(future::ok(batch)),
+                Some(delay) => {
+                    let delay = GLOBAL_TIMER_HANDLE.delay(now + delay);
+                    future::Either::B(delay.then(move |_| Ok(batch)))
+                }
+            }
+        });
+        let rx = rx.map(|(msgs, queue_token, ack_token): ConnBatch| {
+            let items = msgs.into_iter().map(ConnItem::Msg);
+            let tokens = queue_token
+                .map(ConnItem::Dequeue)
+                .into_iter()
+                .chain(ack_token.map(ConnItem::Ack));
+            stream::iter_ok(items.chain(tokens))
+        });
         client.spawn(
             rx_close
                 .map_err(|_| ())
                 .select(
                     sink.sink_map_err(Error::from)
-                        .send_all(rx.map(stream::iter_ok).flatten().map_err(|()| Error::Sink))
+                        .send_all(rx.flatten().map_err(|()| Error::Sink))
                         .then(move |r| {
                             alive.store(false, Ordering::SeqCst);
                             r
@@ -97,20 +302,145 @@ impl Conn {
         Conn {
             stream: tx,
             buffer: Some(Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT)),
+            buffer_bytes: 0,
+            queue: Arc::new(ConnQueue::default()),
+            enqueue_times: Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT),
             store_id,
             alive: alive1,
+            established,
+            create_time,
+            established_time: None,
+            last_send,
 
-            _client: client,
+            client,
             _close: tx_close,
         }
     }
 }
 
+/// Decides the delays of the batches written to a connection. Only the first batch after
+/// the connection is idle is delayed. Batches arriving one after another mean the
+/// connection is busy, and there is no burst to spread.
+struct FlushJitter {
+    jitter: Duration,
+    last_flush: Option<Instant>,
+}
+
+impl FlushJitter {
+    fn new(jitter: Duration) -> FlushJitter {
+        FlushJitter {
+            jitter,
+            last_flush: None,
+        }
+    }
+
+    fn delay(&mut self, now: Instant) -> Option<Duration> {
+        let last_flush = mem::replace(&mut self.last_flush, Some(now));
+        if self.jitter == Duration::from_secs(0) {
+            return None;
+        }
+        match last_flush {
+            Some(t) if now.duration_since(t) < self.jitter => None,
+            _ => Some(flush_delay(self.jitter)),
+        }
+    }
+}
+
+/// Logs the commands carried by `msg`, which fails to be sent for `reason`, so that slow
+/// requests can be correlated with the transport failures by their trace ids. The entries
+/// are only decoded with debug logs on.
+pub fn log_held_up_commands<R: Debug>(msg: &RaftMessage, reason: R) {
+    if !log_enabled!(::log::LogLevel::Debug) {
+        return;
+    }
+    let trace_ids = msg_trace_ids(msg.get_message());
+    if !trace_ids.is_empty() {
+        debug!(
+            "[region {}] commands {:?} to peer {} are held up: {:?}",
+            msg.get_region_id(),
+            trace_ids,
+            msg.get_to_peer().get_id(),
+            reason
+        );
+    }
+}
+
+// Picks a random delay less than `jitter`.
+fn flush_delay(jitter: Duration) -> Duration {
+    Duration::from_nanos(thread_rng().gen_range(0, duration_to_nanos(jitter)))
+}
+
+// Heartbeats and votes are sent ahead of the other messages in a batch, so that they are not
+// delayed by bulk appends and snapshots until elections time out.
+fn is_urgent(msg_type: MessageType) -> bool {
+    match msg_type {
+        MessageType::MsgHeartbeat
+        | MessageType::MsgHeartbeatResponse
+        | MessageType::MsgRequestVote
+        | MessageType::MsgRequestVoteResponse
+        | MessageType::MsgRequestPreVote
+        | MessageType::MsgRequestPreVoteResponse => true,
+        _ => false,
+    }
+}
+
+// Moves urgent messages to the front. The sort is stable, so messages of the same priority,
+// like the appends of a region, keep their order.
+fn prioritize(msgs: &mut [(RaftMessage, WriteFlags)]) {
+    msgs.sort_by_key(|&(ref msg, _)| !is_urgent(msg.get_message().get_msg_type()));
+}
+
+// Connections to a store are not created again until `next_attempt` after they break.
+struct ReconnectBackoff {
+    delay: Duration,
+    next_attempt: Instant,
+    // Messages that need new connections before the next attempt, along with the addresses
+    // and the enqueue times.
+    pending_msgs: Vec<(String, RaftMessage, Instant)>,
+}
+
+/// What `RaftClient::send` does with a raft message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendOutcome {
+    /// The message is buffered until the next flush, or until the backoff of reconnecting to
+    /// the store elapses.
+    Buffered,
+    /// The message filled up the buffer of its connection, so the buffer is handed to the
+    /// connection right away.
+    Flushed,
+    /// The message filled up the buffer of its connection, but the connection is closed, so
+    /// the buffered messages are dropped.
+    Dropped,
+}
+
+impl SendOutcome {
+    pub fn tag(self) -> &'static str {
+        match self {
+            SendOutcome::Buffered => "buffered",
+            SendOutcome::Flushed => "flushed",
+            SendOutcome::Dropped => "dropped",
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReconnectStats {
+    // Connections dropped and not created again yet.
+    dropped: usize,
+    reconnects: u64,
+}
+
 /// `RaftClient` is used for sending raft messages to other stores.
 pub struct RaftClient {
     env: Arc<Environment>,
     conns: HashMap<(String, usize), Conn>,
     pub addrs: HashMap<u64, String>,
+    // store id -> the backoff of reconnecting to the store.
+    backoffs: HashMap<u64, ReconnectBackoff>,
+    // store id -> how many times the connections to the store are rebuilt.
+    reconnect_stats: HashMap<u64, ReconnectStats>,
+    // The stores whose last send times are reported.
+    last_send_stores: HashSet<u64>,
     cfg: Arc<Config>,
     security_mgr: Arc<SecurityManager>,
 }
@@ -125,6 +455,9 @@ impl RaftClient {
             env,
             conns: HashMap::default(),
             addrs: HashMap::default(),
+            backoffs: HashMap::default(),
+            reconnect_stats: HashMap::default(),
+            last_send_stores: HashSet::default(),
             cfg,
             security_mgr,
         }
@@ -132,72 +465,1070 @@ impl RaftClient {
 
     fn get_conn(&mut self, addr: &str, region_id: u64, store_id: u64) -> &mut Conn {
         let index = region_id as usize % self.cfg.grpc_raft_conn_num;
+        // TODO: avoid to_owned
+        let key = (addr.to_owned(), index);
+        if !self.conns.contains_key(&key) {
+            if let Some(stats) = self.reconnect_stats.get_mut(&store_id) {
+                if stats.dropped > 0 {
+                    stats.dropped -= 1;
+                    stats.reconnects += 1;
+                    RAFT_CONN_RECONNECT_COUNTER_VEC
+                        .with_label_values(&[&store_id.to_string()])
+                        .inc();
+                }
+            }
+        }
         let cfg = &self.cfg;
         let security_mgr = &self.security_mgr;
         let env = &self.env;
-        // TODO: avoid to_owned
         self.conns
-            .entry((addr.to_owned(), index))
+            .entry(key)
             .or_insert_with(|| Conn::new(Arc::clone(env), addr, cfg, security_mgr, store_id))
     }
 
-    pub fn send(&mut self, store_id: u64, addr: &str, msg: RaftMessage) -> Result<()> {
-        let conn = self.get_conn(addr, msg.region_id, store_id);
-        conn.buffer
-            .as_mut()
-            .unwrap()
-            .push((msg, WriteFlags::default().buffer_hint(true)));
-        Ok(())
+    pub fn send(&mut self, store_id: u64, addr: &str, msg: RaftMessage) -> Result<SendOutcome> {
+        self.send_with_enqueue_time(store_id, addr, msg, Instant::now())
     }
 
-    pub fn flush(&mut self) {
-        let addrs = &mut self.addrs;
-        let mut counter: u64 = 0;
-        self.conns.retain(|&(ref addr, _), conn| {
-            let store_id = conn.store_id;
-            if !conn.alive.load(Ordering::SeqCst) {
-                if let Some(addr_current) = addrs.remove(&store_id) {
-                    if addr_current != *addr {
-                        addrs.insert(store_id, addr_current);
-                    }
+    /// Buffers `msg` until the next flush, or flushes the buffer of its connection once it's
+    /// full. `enqueue_time` is when the message is passed to the transport, and the duration
+    /// from it to the flush is reported as the send latency.
+    pub fn send_with_enqueue_time(
+        &mut self,
+        store_id: u64,
+        addr: &str,
+        msg: RaftMessage,
+        enqueue_time: Instant,
+    ) -> Result<SendOutcome> {
+        let size = msg.compute_size();
+        let limit = self.cfg.max_raft_msg_size.0;
+        if limit > 0 && u64::from(size) > limit {
+            log_held_up_commands(&msg, "message too large");
+            return Err(Error::RaftMessageTooLarge(u64::from(size), limit));
+        }
+        if let Some(backoff) = self.backoffs.get_mut(&store_id) {
+            let index = msg.region_id as usize % self.cfg.grpc_raft_conn_num;
+            if Instant::now() < backoff.next_attempt
+                && !self.conns.contains_key(&(addr.to_owned(), index))
+            {
+                if backoff.pending_msgs.len() >= PRESERVED_MSG_BUFFER_COUNT {
+                    REPORT_FAILURE_MSG_COUNTER
+                        .with_label_values(&["reconnect_backoff", &store_id.to_string()])
+                        .inc();
+                    log_held_up_commands(&msg, "waiting for reconnection");
+                    return Err(box_err!(
+                        "too many messages to store {} waiting for reconnection",
+                        store_id
+                    ));
                 }
-                return false;
+                backoff.pending_msgs.push((addr.to_owned(), msg, enqueue_time));
+                return Ok(SendOutcome::Buffered);
             }
+        }
+        Ok(self.buffer_msg(store_id, addr, msg, size as usize, enqueue_time))
+    }
 
-            if conn.buffer.as_ref().unwrap().is_empty() {
-                return true;
+    // `size` is the encoded size of `msg`.
+    fn buffer_msg(
+        &mut self,
+        store_id: u64,
+        addr: &str,
+        msg: RaftMessage,
+        size: usize,
+        enqueue_time: Instant,
+    ) -> SendOutcome {
+        {
+            let conn = self.get_conn(addr, msg.region_id, store_id);
+            conn.buffer_bytes += size;
+            conn.buffer
+                .as_mut()
+                .unwrap()
+                .push((msg, WriteFlags::default().buffer_hint(true)));
+            conn.enqueue_times.push(enqueue_time);
+            if conn.buffer.as_ref().unwrap().len() < PRESERVED_MSG_BUFFER_COUNT {
+                return SendOutcome::Buffered;
             }
 
-            counter += 1;
+            // Don't let the buffer grow beyond its capacity while waiting for the next flush.
             let mut msgs = conn.buffer.take().unwrap();
+            conn.buffer = Some(Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT));
+            prioritize(&mut msgs);
             msgs.last_mut().unwrap().1 = WriteFlags::default();
-            if let Err(e) = conn.stream.unbounded_send(msgs) {
-                error!(
-                    "server: drop conn with tikv endpoint {} flush conn error: {:?}",
-                    addr, e
-                );
-
-                if let Some(addr_current) = addrs.remove(&store_id) {
-                    if addr_current != *addr {
-                        addrs.insert(store_id, addr_current);
-                    }
+            let token = QueueToken::new(&conn.queue, msgs.len(), conn.buffer_bytes);
+            conn.buffer_bytes = 0;
+            if let Err(e) = conn.stream.unbounded_send((msgs, Some(token), None)) {
+                for &(ref msg, _) in &e.into_inner().0 {
+                    log_held_up_commands(msg, "connection closed");
                 }
-                return false;
+                // The connection is removed in the next flush.
+                conn.alive.store(false, Ordering::SeqCst);
+                conn.enqueue_times.clear();
+                return SendOutcome::Dropped;
             }
+            RAFT_MESSAGE_FLUSH_COUNTER.inc();
+            let now = Instant::now();
+            let latency = RAFT_MSG_SEND_LATENCY.with_label_values(&["raft"]);
+            for t in conn.enqueue_times.drain(..) {
+                latency.observe(duration_to_sec(now.duration_since(t)));
+            }
+        }
+        SendOutcome::Flushed
+    }
 
-            conn.buffer = Some(Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT));
-            true
+    // Passes the messages held by the backoffs that have elapsed to new connections.
+    fn reconnect(&mut self, now: Instant) {
+        let mut msgs = vec![];
+        for (store_id, backoff) in &mut self.backoffs {
+            if now >= backoff.next_attempt {
+                msgs.extend(backoff.pending_msgs.drain(..).map(|m| (*store_id, m)));
+            }
+        }
+        for (store_id, (addr, msg, enqueue_time)) in msgs {
+            // The size is computed and cached when the message is sent.
+            let size = msg.get_cached_size() as usize;
+            self.buffer_msg(store_id, &addr, msg, size, enqueue_time);
+        }
+    }
+
+    /// Measures the round trip time to the store at `addr`. It opens an empty raft
+    /// stream on the connection to the store and waits for the peer to finish it.
+    ///
+    /// An established connection to the store is used if there is one. Otherwise the
+    /// connection is taken as established once the ping finishes, so that a connection only
+    /// used by pings isn't dropped as failing to connect.
+    pub fn ping(&mut self, store_id: u64, addr: &str, cb: PingCallback) {
+        let index = {
+            let conns = &self.conns;
+            (0..self.cfg.grpc_raft_conn_num).find(|i| {
+                conns
+                    .get(&(addr.to_owned(), *i))
+                    .map_or(false, |c| c.established.load(Ordering::SeqCst))
+            })
+        };
+        let conn = self.get_conn(addr, index.unwrap_or(0) as u64, store_id);
+        let (mut sink, receiver) = match conn.client.raft() {
+            Ok(r) => r,
+            Err(e) => return cb(Err(Error::from(e))),
+        };
+        let established = Arc::clone(&conn.established);
+        let start = Instant::now();
+        conn.client.spawn(
+            future::poll_fn(move || sink.close())
+                .and_then(|_| receiver)
+                .then(move |res| {
+                    let res = match res {
+                        Ok(_) => Ok(()),
+                        // The raft service always finishes a stream with `Unknown`.
+                        Err(GrpcError::RpcFailure(ref s)) if s.status == RpcStatusCode::Unknown => {
+                            Ok(())
+                        }
+                        Err(e) => Err(Error::from(e)),
+                    };
+                    cb(res.map(|_| {
+                        established.store(true, Ordering::SeqCst);
+                        let elapsed = start.elapsed();
+                        RAFT_PING_HISTOGRAM.observe(duration_to_sec(elapsed));
+                        elapsed
+                    }));
+                    Ok(())
+                }),
+        );
+    }
+
+    pub fn flush(&mut self) {
+        self.flush_impl(None)
+    }
+
+    /// Flushes the buffered messages, and invokes `cb` once they have been written to the
+    /// connections, or with an error if any of them fails. Messages flushed before through
+    /// established connections are waited for as well. `cb` is invoked exactly once, right
+    /// away if there is nothing to wait for.
+    ///
+    /// Messages held for the stores in reconnect backoff have no connection to be written
+    /// to yet, so they are not waited for. Instead `cb` is invoked with an error, while the
+    /// messages are still sent once the backoff elapses.
+    pub fn flush_with_ack(&mut self, cb: FlushCallback) {
+        let ack = Arc::new(FlushAck {
+            cb: Mutex::new(Some(cb)),
+            failed: AtomicBool::new(false),
         });
+        self.flush_impl(Some(&ack));
+        if self.backoffs.values().any(|b| !b.pending_msgs.is_empty()) {
+            ack.failed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn flush_impl(&mut self, ack: Option<&Arc<FlushAck>>) {
+        let now = Instant::now();
+        self.reconnect(now);
+
+        let mut established_stores = vec![];
+        // A store may have several connections broken by the same failure, the backoff is
+        // escalated once for all of them.
+        let mut failed_stores = HashSet::default();
+        let mut counter: u64 = 0;
+        {
+            let addrs = &mut self.addrs;
+            let reconnect_stats = &mut self.reconnect_stats;
+            let connect_timeout = self.cfg.grpc_connect_timeout.0;
+            let mut on_failure = |store_id| {
+                reconnect_stats
+                    .entry(store_id)
+                    .or_insert_with(ReconnectStats::default)
+                    .dropped += 1;
+                failed_stores.insert(store_id);
+            };
+            self.conns.retain(|&(ref addr, _), conn| {
+                let store_id = conn.store_id;
+                if !conn.alive.load(Ordering::SeqCst) {
+                    if let Some(addr_current) = addrs.remove(&store_id) {
+                        if addr_current != *addr {
+                            addrs.insert(store_id, addr_current);
+                        }
+                    }
+                    on_failure(store_id);
+                    return false;
+                }
+
+                if connect_timeout > Duration::from_secs(0)
+                    && !conn.established.load(Ordering::SeqCst)
+                    && conn.create_time.elapsed() >= connect_timeout
+                {
+                    let store = store_id.to_string();
+                    REPORT_FAILURE_MSG_COUNTER
+                        .with_label_values(&["connect_timeout", &*store])
+                        .inc();
+                    warn!(
+                        "server: drop conn with tikv endpoint {}, not connected in {:?}",
+                        addr, connect_timeout
+                    );
+                    // Remove the address so that it will be resolved again.
+                    if let Some(addr_current) = addrs.remove(&store_id) {
+                        if addr_current != *addr {
+                            addrs.insert(store_id, addr_current);
+                        }
+                    }
+                    on_failure(store_id);
+                    return false;
+                }
+                if conn.established.load(Ordering::SeqCst) {
+                    established_stores.push(store_id);
+                    if conn.established_time.is_none() {
+                        conn.established_time = Some(now);
+                    }
+                }
+
+                if conn.buffer.as_ref().unwrap().is_empty() {
+                    if let Some(ack) = ack {
+                        if conn.established.load(Ordering::SeqCst) {
+                            let token = AckToken::new(ack);
+                            let _ = conn.stream.unbounded_send((vec![], None, Some(token)));
+                        }
+                    }
+                    return true;
+                }
+
+                counter += 1;
+                let mut msgs = conn.buffer.take().unwrap();
+                prioritize(&mut msgs);
+                msgs.last_mut().unwrap().1 = WriteFlags::default();
+                let queue_token = QueueToken::new(&conn.queue, msgs.len(), conn.buffer_bytes);
+                conn.buffer_bytes = 0;
+                let batch = (msgs, Some(queue_token), ack.map(AckToken::new));
+                if let Err(e) = conn.stream.unbounded_send(batch) {
+                    error!(
+                        "server: drop conn with tikv endpoint {} flush conn error: {:?}",
+                        addr, e
+                    );
+
+                    if let Some(addr_current) = addrs.remove(&store_id) {
+                        if addr_current != *addr {
+                            addrs.insert(store_id, addr_current);
+                        }
+                    }
+                    on_failure(store_id);
+                    return false;
+                }
+
+                conn.buffer = Some(Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT));
+                let latency = RAFT_MSG_SEND_LATENCY.with_label_values(&["raft"]);
+                for t in conn.enqueue_times.drain(..) {
+                    latency.observe(duration_to_sec(now.duration_since(t)));
+                }
+                true
+            });
+        }
 
         if counter > 0 {
             RAFT_MESSAGE_FLUSH_COUNTER.inc_by(counter as i64);
         }
+        let base_backoff = self.cfg.raft_client_reconnect_backoff.0;
+        let max_backoff = self.cfg.raft_client_max_reconnect_backoff.0;
+        if base_backoff > Duration::from_secs(0) {
+            for store_id in failed_stores {
+                let backoff = self
+                    .backoffs
+                    .entry(store_id)
+                    .or_insert_with(|| ReconnectBackoff {
+                        delay: Duration::from_secs(0),
+                        next_attempt: now,
+                        pending_msgs: vec![],
+                    });
+                backoff.delay = if backoff.delay == Duration::from_secs(0) {
+                    base_backoff
+                } else {
+                    cmp::min(backoff.delay * 2, max_backoff)
+                };
+                backoff.next_attempt = now + backoff.delay;
+            }
+        }
+        // The messages held for the store, if any, are sent in the next flush.
+        for store_id in established_stores {
+            let is_empty = match self.backoffs.get_mut(&store_id) {
+                Some(backoff) => {
+                    backoff.delay = Duration::from_secs(0);
+                    backoff.next_attempt = now;
+                    backoff.pending_msgs.is_empty()
+                }
+                None => continue,
+            };
+            if is_empty {
+                self.backoffs.remove(&store_id);
+            }
+        }
+        self.update_last_send_gauge(now);
+        self.update_uptime_gauge(now);
+    }
+
+    /// Takes the messages that have been held by the client for longer than `timeout` since
+    /// they are passed to the transport, either in the buffers of the connections or waiting
+    /// for reconnections, so that the peers can be reported unreachable instead of the
+    /// messages getting lost silently.
+    pub fn take_expired_msgs(&mut self, timeout: Duration, now: Instant) -> Vec<RaftMessage> {
+        let expired = |t: &Instant| now.duration_since(*t) >= timeout;
+        let mut msgs = vec![];
+        for backoff in self.backoffs.values_mut() {
+            if !backoff.pending_msgs.iter().any(|&(_, _, ref t)| expired(t)) {
+                continue;
+            }
+            let (old, pending): (Vec<_>, Vec<_>) = backoff
+                .pending_msgs
+                .drain(..)
+                .partition(|&(_, _, ref t)| expired(t));
+            backoff.pending_msgs = pending;
+            msgs.extend(old.into_iter().map(|(_, msg, _)| msg));
+        }
+        for conn in self.conns.values_mut() {
+            if !conn.enqueue_times.iter().any(&expired) {
+                continue;
+            }
+            let buffer = conn.buffer.take().unwrap();
+            let mut kept = Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT);
+            let mut kept_times = Vec::with_capacity(PRESERVED_MSG_BUFFER_COUNT);
+            for (m, t) in buffer.into_iter().zip(conn.enqueue_times.drain(..)) {
+                if expired(&t) {
+                    msgs.push(m.0);
+                } else {
+                    kept.push(m);
+                    kept_times.push(t);
+                }
+            }
+            conn.buffer_bytes = kept.iter().map(|m| m.0.compute_size() as usize).sum();
+            conn.buffer = Some(kept);
+            conn.enqueue_times = kept_times;
+        }
+        msgs
+    }
+
+    /// Drops the addresses, connections and messages held for the stores not in `live`, and
+    /// returns how many stores are dropped. An empty `live` is ignored, as the cluster
+    /// always has stores.
+    pub fn retain_stores(&mut self, live: &HashSet<u64>) -> usize {
+        if live.is_empty() {
+            return 0;
+        }
+        let mut dead: HashSet<u64> = self
+            .addrs
+            .keys()
+            .chain(self.backoffs.keys())
+            .chain(self.reconnect_stats.keys())
+            .cloned()
+            .filter(|id| !live.contains(id))
+            .collect();
+        dead.extend(
+            self.conns
+                .values()
+                .map(|c| c.store_id)
+                .filter(|id| !live.contains(id)),
+        );
+        if dead.is_empty() {
+            return 0;
+        }
+        // Dropping the connections closes their streams.
+        self.conns.retain(|_, conn| !dead.contains(&conn.store_id));
+        for store_id in &dead {
+            info!("server: drop the address of store {} not in the cluster", store_id);
+            self.addrs.remove(store_id);
+            self.backoffs.remove(store_id);
+            self.reconnect_stats.remove(store_id);
+            self.last_send_stores.remove(store_id);
+            let store = store_id.to_string();
+            let _ = RAFT_CONN_UPTIME_GAUGE_VEC.remove_label_values(&[&store]);
+            let _ = RAFT_CONN_RECONNECT_COUNTER_VEC.remove_label_values(&[&store]);
+            let _ = RAFT_MSG_LAST_SEND_GAUGE_VEC.remove_label_values(&[&store]);
+            let _ = RAFT_CONN_PENDING_MSG_GAUGE_VEC.remove_label_values(&[&store]);
+            let _ = RAFT_CONN_PENDING_BYTES_GAUGE_VEC.remove_label_values(&[&store]);
+        }
+        dead.len()
+    }
+
+    /// Returns how long the oldest established connection to `store_id` has been up, and
+    /// how many times the connections to the store have been rebuilt after being dropped.
+    pub fn conn_stats(&self, store_id: u64) -> (Option<Duration>, u64) {
+        let now = Instant::now();
+        let uptime = self
+            .established_times()
+            .remove(&store_id)
+            .map(|t| now.duration_since(t));
+        let reconnects = self
+            .reconnect_stats
+            .get(&store_id)
+            .map_or(0, |s| s.reconnects);
+        (uptime, reconnects)
+    }
+
+    /// Returns how many raft messages to `store_id` are held by its connections and their
+    /// bytes, either buffered until the next flush or flushed but not taken by the gRPC
+    /// streams yet. A queue that stays deep points to a slow or unreachable store.
+    pub fn pending_msgs(&self, store_id: u64) -> (usize, usize) {
+        self.pending_by_store().remove(&store_id).unwrap_or((0, 0))
+    }
+
+    // store id -> (messages, bytes) held by the connections to the store.
+    fn pending_by_store(&self) -> HashMap<u64, (usize, usize)> {
+        let mut pending: HashMap<u64, (usize, usize)> = HashMap::default();
+        for conn in self.conns.values() {
+            let p = pending.entry(conn.store_id).or_insert((0, 0));
+            p.0 += conn.buffer.as_ref().unwrap().len() + conn.queue.msgs.load(Ordering::SeqCst);
+            p.1 += conn.buffer_bytes + conn.queue.bytes.load(Ordering::SeqCst);
+        }
+        pending
+    }
+
+    /// Reports the messages held for each store, which drop to 0 once the store has no
+    /// connections. It's called periodically rather than on every flush, as it walks all
+    /// the connections.
+    pub fn update_pending_gauge(&self) {
+        let pending = self.pending_by_store();
+        let store_ids = self.conns.values().map(|c| c.store_id);
+        for store_id in store_ids.chain(self.reconnect_stats.keys().cloned()) {
+            let (msgs, bytes) = pending.get(&store_id).cloned().unwrap_or((0, 0));
+            let store = store_id.to_string();
+            RAFT_CONN_PENDING_MSG_GAUGE_VEC
+                .with_label_values(&[&store])
+                .set(msgs as i64);
+            RAFT_CONN_PENDING_BYTES_GAUGE_VEC
+                .with_label_values(&[&store])
+                .set(bytes as i64);
+        }
+    }
+
+    // store id -> when the oldest established connection to the store is found established.
+    fn established_times(&self) -> HashMap<u64, Instant> {
+        let mut times: HashMap<u64, Instant> = HashMap::default();
+        for conn in self.conns.values() {
+            if let Some(established_time) = conn.established_time {
+                let t = times.entry(conn.store_id).or_insert(established_time);
+                if *t > established_time {
+                    *t = established_time;
+                }
+            }
+        }
+        times
+    }
+
+    // Reports the uptime of the connections to each store, which drops to 0 while the store
+    // has no established connections.
+    fn update_uptime_gauge(&self, now: Instant) {
+        let times = self.established_times();
+        let store_ids = self.conns.values().map(|c| c.store_id);
+        for store_id in store_ids.chain(self.reconnect_stats.keys().cloned()) {
+            let uptime = match times.get(&store_id) {
+                Some(t) if now > *t => now.duration_since(*t),
+                _ => Duration::from_secs(0),
+            };
+            RAFT_CONN_UPTIME_GAUGE_VEC
+                .with_label_values(&[&store_id.to_string()])
+                .set(duration_to_sec(uptime));
+        }
+    }
+
+    // Reports the seconds since the messages are last written to each store. A store whose
+    // value keeps growing while messages are flushed to it can't be sent to. The stores with
+    // no connections left are not reported any more.
+    fn update_last_send_gauge(&mut self, now: Instant) {
+        let mut last_sends: HashMap<u64, Instant> = HashMap::default();
+        for conn in self.conns.values() {
+            let last_send = *conn.last_send.lock().unwrap();
+            let t = last_sends.entry(conn.store_id).or_insert(last_send);
+            if *t < last_send {
+                *t = last_send;
+            }
+        }
+        for store_id in &self.last_send_stores {
+            if !last_sends.contains_key(store_id) {
+                let _ = RAFT_MSG_LAST_SEND_GAUGE_VEC.remove_label_values(&[&store_id.to_string()]);
+            }
+        }
+        self.last_send_stores = last_sends.keys().cloned().collect();
+        for (store_id, t) in last_sends {
+            let elapsed = if now > t {
+                now.duration_since(t)
+            } else {
+                Duration::from_secs(0)
+            };
+            RAFT_MSG_LAST_SEND_GAUGE_VEC
+                .with_label_values(&[&store_id.to_string()])
+                .set(duration_to_sec(elapsed));
+        }
     }
 }
 
 impl Drop for RaftClient {
     fn drop(&mut self) {
+        let store_ids: HashSet<_> = self
+            .conns
+            .values()
+            .map(|c| c.store_id)
+            .chain(self.reconnect_stats.keys().cloned())
+            .collect();
+        for store_id in store_ids {
+            let store = store_id.to_string();
+            let _ = RAFT_CONN_UPTIME_GAUGE_VEC.remove_label_values(&[&store]);
+            let _ = RAFT_CONN_RECONNECT_COUNTER_VEC.remove_label_values(&[&store]);
+        }
         // Drop conns here to make sure all streams are dropped before Environment.
         self.conns.clear();
+        for store_id in self.last_send_stores.drain() {
+            let _ = RAFT_MSG_LAST_SEND_GAUGE_VEC.remove_label_values(&[&store_id.to_string()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+    use util::config::ReadableDuration;
+
+    // Creates a client, along with a listener for it to connect to and the address of the
+    // listener. Connections to the listener are neither established nor broken by themselves.
+    fn new_test_client(cfg: Config) -> (RaftClient, TcpListener, String) {
+        let env = Arc::new(Environment::new(1));
+        let security_mgr = Arc::new(SecurityManager::default());
+        let client = RaftClient::new(env, Arc::new(cfg), security_mgr);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (client, listener, addr)
+    }
+
+    #[test]
+    fn test_raft_msg_send_latency() {
+        let env = Arc::new(Environment::new(1));
+        let security_mgr = Arc::new(SecurityManager::default());
+        let mut client = RaftClient::new(env, Arc::new(Config::default()), security_mgr);
+        let latency = RAFT_MSG_SEND_LATENCY.with_label_values(&["raft"]);
+        let (count, sum) = (latency.get_sample_count(), latency.get_sample_sum());
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        client.send(2, "127.0.0.1:0", msg).unwrap();
+        // The message waits in the buffer until it's flushed.
+        thread::sleep(Duration::from_millis(50));
+        client.flush();
+
+        assert!(latency.get_sample_count() > count);
+        assert!(latency.get_sample_sum() - sum >= 0.05);
+    }
+
+    #[test]
+    fn test_raft_msg_last_send() {
+        let env = Arc::new(Environment::new(1));
+        let security_mgr = Arc::new(SecurityManager::default());
+        let mut client = RaftClient::new(env, Arc::new(Config::default()), security_mgr);
+        let gauge = RAFT_MSG_LAST_SEND_GAUGE_VEC.with_label_values(&["7"]);
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        client.send(7, "127.0.0.1:0", msg).unwrap();
+        client.flush();
+        // Nothing can be written to the invalid address, so the gauge keeps growing.
+        thread::sleep(Duration::from_millis(10));
+        let now = Instant::now();
+        client.update_last_send_gauge(now + Duration::from_secs(10));
+        assert!(gauge.get() >= 10.0);
+
+        // Only completed writes refresh the last send time.
+        let last_send = Arc::clone(&client.conns.values().next().unwrap().last_send);
+        let (tx, _rx) = mpsc::unbounded();
+        let mut sink = ConnSink {
+            sink: tx,
+            written: false,
+            established: Arc::new(AtomicBool::new(false)),
+            last_send: Arc::clone(&last_send),
+            acks: vec![],
+        };
+        sink.poll_complete().unwrap();
+        assert!(*last_send.lock().unwrap() < now);
+        sink = sink.send(ConnItem::Msg(())).wait().unwrap();
+        assert!(*last_send.lock().unwrap() >= now);
+        assert!(sink.established.load(Ordering::SeqCst));
+
+        client.update_last_send_gauge(now + Duration::from_secs(10));
+        assert!(gauge.get() <= 10.0);
+        client.update_last_send_gauge(*last_send.lock().unwrap());
+        assert_eq!(gauge.get(), 0.0);
+
+        // The gauge is removed once the store has no connections.
+        client.conns.clear();
+        client.update_last_send_gauge(now);
+        RAFT_MSG_LAST_SEND_GAUGE_VEC
+            .remove_label_values(&["7"])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_send_outcome() {
+        let (mut client, _listener, addr) = new_test_client(Config::default());
+        let send = |client: &mut RaftClient| {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(1);
+            client.send(2, &addr, msg).unwrap()
+        };
+
+        // The buffer is flushed once it's full.
+        for _ in 1..PRESERVED_MSG_BUFFER_COUNT {
+            assert_eq!(send(&mut client), SendOutcome::Buffered);
+        }
+        assert_eq!(send(&mut client), SendOutcome::Flushed);
+        assert_eq!(send(&mut client), SendOutcome::Buffered);
+        client.flush();
+
+        // Messages are dropped if the connection is closed.
+        let (tx, _) = mpsc::unbounded();
+        client.conns.values_mut().next().unwrap().stream = tx;
+        for _ in 1..PRESERVED_MSG_BUFFER_COUNT {
+            assert_eq!(send(&mut client), SendOutcome::Buffered);
+        }
+        assert_eq!(send(&mut client), SendOutcome::Dropped);
+        client.flush();
+        assert!(client.conns.is_empty());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut cfg = Config::default();
+        cfg.raft_client_reconnect_backoff = ReadableDuration::millis(100);
+        cfg.raft_client_max_reconnect_backoff = ReadableDuration::millis(300);
+        cfg.grpc_raft_conn_num = 3;
+        let (mut client, _listener, addr) = new_test_client(cfg);
+        let new_msg = |region_id| {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(region_id);
+            msg
+        };
+        // Messages of the regions go through all the 3 connections to the store.
+        let send_to_all_conns = |client: &mut RaftClient| {
+            for region_id in 0..3 {
+                client.send(2, &addr, new_msg(region_id)).unwrap();
+            }
+        };
+        let break_all_conns = |client: &RaftClient| {
+            for conn in client.conns.values() {
+                conn.alive.store(false, Ordering::SeqCst);
+            }
+        };
+
+        send_to_all_conns(&mut client);
+        client.flush();
+        assert_eq!(client.conns.len(), 3);
+        for &delay in &[100, 200, 300, 300] {
+            let delay = Duration::from_millis(delay);
+            // All the connections broken at once escalate the backoff only once.
+            break_all_conns(&client);
+            client.flush();
+            assert!(client.conns.is_empty());
+            assert_eq!(client.backoffs[&2].delay, delay);
+
+            // Messages are held without reconnecting until the backoff elapses.
+            send_to_all_conns(&mut client);
+            client.flush();
+            assert!(client.conns.is_empty());
+            thread::sleep(delay);
+            client.flush();
+            assert_eq!(client.conns.len(), 3);
+            assert!(client.backoffs[&2].pending_msgs.is_empty());
+        }
+
+        // Messages are dropped once too many of them are held.
+        break_all_conns(&client);
+        client.flush();
+        for _ in 0..PRESERVED_MSG_BUFFER_COUNT {
+            client.send(2, &addr, new_msg(1)).unwrap();
+        }
+        assert!(client.send(2, &addr, new_msg(1)).is_err());
+
+        // The backoff is reset once a connection is established.
+        thread::sleep(Duration::from_millis(300));
+        client.flush();
+        client.conns.values().next().unwrap().established.store(true, Ordering::SeqCst);
+        client.flush();
+        assert!(!client.backoffs.contains_key(&2));
+    }
+
+    #[test]
+    fn test_conn_stats() {
+        let mut cfg = Config::default();
+        cfg.raft_client_reconnect_backoff = ReadableDuration::secs(0);
+        let (mut client, _listener, addr) = new_test_client(cfg);
+        let new_msg = || {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(1);
+            msg
+        };
+        let reconnects = RAFT_CONN_RECONNECT_COUNTER_VEC.with_label_values(&["5"]);
+        let uptime = RAFT_CONN_UPTIME_GAUGE_VEC.with_label_values(&["5"]);
+
+        client.send(5, &addr, new_msg()).unwrap();
+        client.flush();
+        assert_eq!(client.conn_stats(5), (None, 0));
+
+        for i in 1..3 {
+            client.conns.values().next().unwrap().alive.store(false, Ordering::SeqCst);
+            client.flush();
+            client.send(5, &addr, new_msg()).unwrap();
+            client.flush();
+            assert_eq!(client.conn_stats(5), (None, i));
+            assert_eq!(reconnects.get(), i as i64);
+            assert_eq!(uptime.get(), 0.0);
+        }
+
+        client.conns.values().next().unwrap().established.store(true, Ordering::SeqCst);
+        client.flush();
+        thread::sleep(Duration::from_millis(10));
+        let (up, count) = client.conn_stats(5);
+        assert!(up.unwrap() >= Duration::from_millis(10));
+        assert_eq!(count, 2);
+        client.update_uptime_gauge(Instant::now() + Duration::from_secs(10));
+        assert!(uptime.get() >= 10.0);
+
+        // The metrics are removed with the client.
+        drop(client);
+        RAFT_CONN_UPTIME_GAUGE_VEC
+            .remove_label_values(&["5"])
+            .unwrap_err();
+        RAFT_CONN_RECONNECT_COUNTER_VEC
+            .remove_label_values(&["5"])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let mut cfg = Config::default();
+        cfg.grpc_connect_timeout = ReadableDuration::millis(100);
+        cfg.raft_client_reconnect_backoff = ReadableDuration::secs(0);
+        let (mut client, _listener, addr) = new_test_client(cfg);
+        let timeouts = REPORT_FAILURE_MSG_COUNTER.with_label_values(&["connect_timeout", "11"]);
+        let count = timeouts.get();
+
+        client.addrs.insert(11, addr.clone());
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        client.send(11, &addr, msg).unwrap();
+        client.flush();
+        assert_eq!(client.conns.len(), 1);
+
+        // The connection is dropped once the timeout elapses, and the address is resolved
+        // again.
+        thread::sleep(Duration::from_millis(100));
+        client.flush();
+        assert!(client.conns.is_empty());
+        assert!(!client.addrs.contains_key(&11));
+        assert_eq!(client.reconnect_stats[&11].dropped, 1);
+        assert!(timeouts.get() > count);
+    }
+
+    #[test]
+    fn test_retain_stores() {
+        let mut cfg = Config::default();
+        cfg.raft_client_reconnect_backoff = ReadableDuration::secs(0);
+        let (mut client, _listener, addr) = new_test_client(cfg);
+        for store_id in 1..4 {
+            client.addrs.insert(store_id, addr.clone());
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(store_id);
+            client.send(store_id, &addr, msg).unwrap();
+        }
+        client.flush();
+        assert_eq!(client.conns.len(), 3);
+
+        // An empty live set is ignored.
+        assert_eq!(client.retain_stores(&HashSet::default()), 0);
+        assert_eq!(client.addrs.len(), 3);
+
+        RAFT_CONN_RECONNECT_COUNTER_VEC
+            .with_label_values(&["2"])
+            .inc();
+        let live = vec![1, 3].into_iter().collect();
+        assert_eq!(client.retain_stores(&live), 1);
+        assert!(!client.addrs.contains_key(&2));
+        // The metrics of the store are removed with it.
+        RAFT_CONN_RECONNECT_COUNTER_VEC
+            .remove_label_values(&["2"])
+            .unwrap_err();
+        assert!(client.conns.values().all(|c| c.store_id != 2));
+        assert_eq!(client.conns.len(), 2);
+        assert_eq!(client.retain_stores(&live), 0);
+    }
+
+    #[test]
+    fn test_take_expired_msgs() {
+        let env = Arc::new(Environment::new(1));
+        let security_mgr = Arc::new(SecurityManager::default());
+        let mut client = RaftClient::new(env, Arc::new(Config::default()), security_mgr);
+        let now = Instant::now();
+        let timeout = Duration::from_secs(10);
+        let new_msg = |region_id| {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(region_id);
+            msg
+        };
+        let new = now + Duration::from_secs(10);
+        client
+            .send_with_enqueue_time(1, "127.0.0.1:0", new_msg(1), now)
+            .unwrap();
+        client
+            .send_with_enqueue_time(1, "127.0.0.1:0", new_msg(2), new)
+            .unwrap();
+        // Messages waiting for the reconnection to store 2.
+        client.backoffs.insert(
+            2,
+            ReconnectBackoff {
+                delay: Duration::from_secs(60),
+                next_attempt: now + Duration::from_secs(60),
+                pending_msgs: vec![],
+            },
+        );
+        client
+            .send_with_enqueue_time(2, "127.0.0.1:1", new_msg(3), now)
+            .unwrap();
+        client
+            .send_with_enqueue_time(2, "127.0.0.1:1", new_msg(4), new)
+            .unwrap();
+
+        let check_time = now + Duration::from_secs(15);
+        let mut expired: Vec<_> = client
+            .take_expired_msgs(timeout, check_time)
+            .iter()
+            .map(|m| m.get_region_id())
+            .collect();
+        expired.sort();
+        assert_eq!(expired, vec![1, 3]);
+        assert!(client.take_expired_msgs(timeout, check_time).is_empty());
+
+        // The others are kept, and expire later.
+        let later = new + timeout;
+        let mut expired: Vec<_> = client
+            .take_expired_msgs(timeout, later)
+            .iter()
+            .map(|m| m.get_region_id())
+            .collect();
+        expired.sort();
+        assert_eq!(expired, vec![2, 4]);
+        assert!(client.conns.values().all(|c| c.buffer.as_ref().unwrap().is_empty()));
+        assert!(client.conns.values().all(|c| c.enqueue_times.is_empty()));
+    }
+
+    #[test]
+    fn test_pending_msgs() {
+        let (mut client, _listener, addr) = new_test_client(Config::default());
+        let mut size = 0;
+        for i in 0..3 {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(i);
+            size += msg.compute_size() as usize;
+            client.send(6, &addr, msg).unwrap();
+        }
+        assert_eq!(client.pending_msgs(6), (3, size));
+        assert_eq!(client.pending_msgs(7), (0, 0));
+
+        // Flushed messages are left in the queue until the gRPC stream takes them.
+        let (msg_tx, msg_rx) = mpsc::unbounded();
+        let mut sink = ConnSink {
+            sink: msg_tx,
+            written: false,
+            established: Arc::new(AtomicBool::new(false)),
+            last_send: Arc::new(Mutex::new(Instant::now())),
+            acks: vec![],
+        };
+        let queue = Arc::new(ConnQueue::default());
+        let token = QueueToken::new(&queue, 2, 10);
+        assert_eq!(queue.msgs.load(Ordering::SeqCst), 2);
+        assert_eq!(queue.bytes.load(Ordering::SeqCst), 10);
+        let items = vec![ConnItem::Msg(1), ConnItem::Msg(2), ConnItem::Dequeue(token)];
+        let items = stream::iter_ok::<_, mpsc::SendError<i32>>(items);
+        sink = sink.send_all(items).wait().unwrap().0;
+        assert_eq!(queue.msgs.load(Ordering::SeqCst), 0);
+        assert_eq!(queue.bytes.load(Ordering::SeqCst), 0);
+        drop(sink);
+        assert_eq!(msg_rx.collect().wait().unwrap(), vec![1, 2]);
+
+        // Messages are taken out of the queue as well when the connection is dropped.
+        let msgs = RAFT_CONN_PENDING_MSG_GAUGE_VEC.with_label_values(&["6"]);
+        let bytes = RAFT_CONN_PENDING_BYTES_GAUGE_VEC.with_label_values(&["6"]);
+        client.flush();
+        client.update_pending_gauge();
+        assert!(msgs.get() <= 3);
+        assert!(bytes.get() as usize <= size);
+        client.conns.values().next().unwrap().alive.store(false, Ordering::SeqCst);
+        client.flush();
+        client.update_pending_gauge();
+        assert_eq!(client.pending_msgs(6), (0, 0));
+        assert_eq!(msgs.get(), 0);
+        assert_eq!(bytes.get(), 0);
+    }
+
+    #[test]
+    fn test_flush_with_ack() {
+        let (mut client, _listener, addr) = new_test_client(Config::default());
+        let (tx, rx) = channel();
+        let new_cb = || -> FlushCallback {
+            let tx = tx.clone();
+            box move |res: Result<()>| tx.send(res.is_ok()).unwrap()
+        };
+
+        // Nothing to wait for.
+        client.flush_with_ack(new_cb());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(true));
+
+        // Messages waiting for the reconnection to a store are not written by the flush.
+        client.backoffs.insert(
+            3,
+            ReconnectBackoff {
+                delay: Duration::from_secs(60),
+                next_attempt: Instant::now() + Duration::from_secs(60),
+                pending_msgs: vec![],
+            },
+        );
+        client.send(3, "127.0.0.1:0", RaftMessage::new()).unwrap();
+        client.flush_with_ack(new_cb());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(false));
+        assert_eq!(client.backoffs[&3].pending_msgs.len(), 1);
+        client.backoffs.clear();
+
+        // The flush is acknowledged once the messages before it are written.
+        let (msg_tx, msg_rx) = mpsc::unbounded();
+        let mut sink = ConnSink {
+            sink: msg_tx,
+            written: false,
+            established: Arc::new(AtomicBool::new(false)),
+            last_send: Arc::new(Mutex::new(Instant::now())),
+            acks: vec![],
+        };
+        let ack = Arc::new(FlushAck {
+            cb: Mutex::new(Some(new_cb())),
+            failed: AtomicBool::new(false),
+        });
+        let items = vec![ConnItem::Msg(1), ConnItem::Msg(2), ConnItem::Ack(AckToken::new(&ack))];
+        drop(ack);
+        let items = stream::iter_ok::<_, mpsc::SendError<i32>>(items);
+        sink = sink.send_all(items).wait().unwrap().0;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(true));
+        drop(sink);
+        assert_eq!(msg_rx.collect().wait().unwrap(), vec![1, 2]);
+
+        // The messages are dropped along with the connection before they are written.
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        client.send(2, &addr, msg).unwrap();
+        client.flush_with_ack(new_cb());
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+        client.conns.values().next().unwrap().alive.store(false, Ordering::SeqCst);
+        client.flush();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(false));
+    }
+
+    #[test]
+    fn test_flush_delay() {
+        let jitter = Duration::from_millis(2);
+        for _ in 0..100 {
+            assert!(flush_delay(jitter) < jitter);
+        }
+        // Delays are spread out instead of being the same.
+        let delays: Vec<_> = (0..100).map(|_| flush_delay(jitter)).collect();
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn test_flush_jitter() {
+        let mut jitter = FlushJitter::new(Duration::from_secs(0));
+        assert_eq!(jitter.delay(Instant::now()), None);
+
+        let mut jitter = FlushJitter::new(Duration::from_millis(10));
+        let mut now = Instant::now();
+        // The first batch is written after idle.
+        assert!(jitter.delay(now).unwrap() < Duration::from_millis(10));
+        // Back-to-back batches are not delayed.
+        for _ in 0..3 {
+            now += Duration::from_millis(9);
+            assert_eq!(jitter.delay(now), None);
+        }
+        // Idle again.
+        now += Duration::from_millis(20);
+        assert!(jitter.delay(now).is_some());
+    }
+
+    #[test]
+    fn test_prioritize() {
+        let new_msg = |region_id, msg_type, index| {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(region_id);
+            msg.mut_message().set_msg_type(msg_type);
+            msg.mut_message().set_index(index);
+            (msg, WriteFlags::default().buffer_hint(true))
+        };
+        let mut msgs = vec![
+            new_msg(1, MessageType::MsgAppend, 1),
+            new_msg(2, MessageType::MsgAppend, 1),
+            new_msg(1, MessageType::MsgHeartbeat, 0),
+            new_msg(1, MessageType::MsgAppend, 2),
+            new_msg(3, MessageType::MsgRequestVote, 0),
+            new_msg(2, MessageType::MsgAppend, 2),
+            new_msg(2, MessageType::MsgHeartbeatResponse, 0),
+        ];
+        prioritize(&mut msgs);
+        let order: Vec<_> = msgs
+            .iter()
+            .map(|&(ref m, _)| {
+                let msg = m.get_message();
+                (m.get_region_id(), msg.get_msg_type(), msg.get_index())
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (1, MessageType::MsgHeartbeat, 0),
+                (3, MessageType::MsgRequestVote, 0),
+                (2, MessageType::MsgHeartbeatResponse, 0),
+                (1, MessageType::MsgAppend, 1),
+                (2, MessageType::MsgAppend, 1),
+                (1, MessageType::MsgAppend, 2),
+                (2, MessageType::MsgAppend, 2),
+            ]
+        );
     }
 }
@@ -0,0 +1,106 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use util::collections::HashMap;
+
+struct Window {
+    start: Instant,
+    counts: HashMap<u64, u64>,
+    // The region with the most reads in the last window.
+    hottest: Option<u64>,
+}
+
+/// `ReadShedder` keeps a hot region from monopolizing the local reader.
+///
+/// It counts the local reads of each region in windows of `window_size`. Once the local
+/// reader has `threshold` pending tasks, reads of the region that was read the most in the
+/// last window are shed, so that the other regions can still be served in time. 0 means reads
+/// are never shed.
+pub struct ReadShedder {
+    threshold: usize,
+    window_size: Duration,
+    window: Mutex<Window>,
+}
+
+impl ReadShedder {
+    pub fn new(threshold: usize, window_size: Duration) -> ReadShedder {
+        ReadShedder {
+            threshold,
+            window_size,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                counts: HashMap::default(),
+                hottest: None,
+            }),
+        }
+    }
+
+    /// Records a local read of `region_id` when the local reader has `pending_tasks`.
+    /// Returns true if the read should be shed.
+    pub fn on_read(&self, region_id: u64, pending_tasks: usize) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        self.on_read_at(region_id, pending_tasks, Instant::now())
+    }
+
+    fn on_read_at(&self, region_id: u64, pending_tasks: usize, now: Instant) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let w = &mut *window;
+        if now.duration_since(w.start) >= self.window_size {
+            w.hottest = w.counts.iter().max_by_key(|&(_, c)| *c).map(|(id, _)| *id);
+            w.counts.clear();
+            w.start = now;
+        }
+        *w.counts.entry(region_id).or_insert(0) += 1;
+        pending_tasks >= self.threshold && w.hottest == Some(region_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_shedder() {
+        let window = Duration::from_secs(1);
+        let shedder = ReadShedder::new(10, window);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(!shedder.on_read_at(1, 100, now));
+        }
+        assert!(!shedder.on_read_at(2, 100, now));
+
+        // Region 1 is the hottest one of the last window, and only its reads are shed
+        // when the local reader is saturated.
+        let later = now + window;
+        assert!(shedder.on_read_at(1, 10, later));
+        assert!(!shedder.on_read_at(1, 9, later));
+        assert!(!shedder.on_read_at(2, 100, later));
+
+        // Region 1 turns cold in the next window.
+        for _ in 0..10 {
+            shedder.on_read_at(2, 0, later);
+        }
+        assert!(shedder.on_read_at(2, 10, later + window));
+        assert!(!shedder.on_read_at(1, 10, later + window));
+
+        let disabled = ReadShedder::new(0, window);
+        for _ in 0..10 {
+            assert!(!disabled.on_read(1, 100));
+        }
+    }
+}
@@ -30,6 +30,9 @@ pub struct Config {
     pub max_tasks_per_worker_normal: usize,
     pub max_tasks_per_worker_low: usize,
     pub stack_size: ReadableSize,
+    /// While high-priority tasks are queued, a low-priority task is accepted only after
+    /// every `high_priority_weight` high-priority ones. 0 disables it.
+    pub high_priority_weight: usize,
 }
 
 impl Config {
@@ -43,6 +46,7 @@ impl Config {
             max_tasks_per_worker_normal: DEFAULT_MAX_TASKS_PER_WORKER,
             max_tasks_per_worker_low: DEFAULT_MAX_TASKS_PER_WORKER,
             stack_size: ReadableSize::mb(DEFAULT_STACK_SIZE_MB),
+            high_priority_weight: 0,
         }
     }
 
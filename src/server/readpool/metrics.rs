@@ -0,0 +1,27 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use prometheus::*;
+
+lazy_static! {
+    pub static ref READ_POOL_TASK_CPU_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_readpool_task_cpu_duration_seconds",
+        "Bucketed histogram of the CPU time taken by read pool tasks",
+        &["priority"],
+        exponential_buckets(0.0001, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref READ_POOL_CPU_SECONDS_COUNTER: Counter = register_counter!(
+        "tikv_readpool_cpu_seconds_total",
+        "Total CPU time taken by read pool tasks"
+    ).unwrap();
+}
@@ -12,17 +12,25 @@
 // limitations under the License.
 
 pub mod config;
+mod metrics;
 mod priority;
 
 use std::error::Error;
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use futures::Future;
+use futures::{Async, Future, Poll};
 use futures_cpupool::CpuFuture;
+use prometheus::Histogram;
 
 use util;
 use util::futurepool::{self, FuturePool};
+use util::sys;
+use util::time::duration_to_sec;
+
+use self::metrics::*;
 
 pub use self::config::Config;
 pub use self::priority::Priority;
@@ -36,6 +44,13 @@ pub struct ReadPool<T: futurepool::Context + 'static> {
     max_tasks_high: usize,
     max_tasks_normal: usize,
     max_tasks_low: usize,
+    // The CPU time histograms of the tasks, looked up once rather than on every spawn.
+    cpu_histogram_high: Histogram,
+    cpu_histogram_normal: Histogram,
+    cpu_histogram_low: Histogram,
+    high_priority_weight: usize,
+    // High-priority tasks accepted while they are queued since the last low-priority one.
+    high_tasks_since_low: Arc<AtomicUsize>,
 }
 
 impl<T: futurepool::Context + 'static> util::AssertSend for ReadPool<T> {}
@@ -47,6 +62,10 @@ impl<T: futurepool::Context + 'static> Clone for ReadPool<T> {
             pool_high: self.pool_high.clone(),
             pool_normal: self.pool_normal.clone(),
             pool_low: self.pool_low.clone(),
+            cpu_histogram_high: self.cpu_histogram_high.clone(),
+            cpu_histogram_normal: self.cpu_histogram_normal.clone(),
+            cpu_histogram_low: self.cpu_histogram_low.clone(),
+            high_tasks_since_low: Arc::clone(&self.high_tasks_since_low),
             ..*self
         }
     }
@@ -62,31 +81,85 @@ impl<T: futurepool::Context + 'static> ReadPool<T> {
     {
         let tick_interval = Duration::from_secs(TICK_INTERVAL_SEC);
 
+        let pool_high = FuturePool::new(
+            config.high_concurrency,
+            config.stack_size.0 as usize,
+            &format!("{}-high", name_prefix),
+            tick_interval,
+            context_factory_builder.build(),
+        );
+        let pool_normal = FuturePool::new(
+            config.normal_concurrency,
+            config.stack_size.0 as usize,
+            &format!("{}-normal", name_prefix),
+            tick_interval,
+            context_factory_builder.build(),
+        );
+        let pool_low = FuturePool::new(
+            config.low_concurrency,
+            config.stack_size.0 as usize,
+            &format!("{}-low", name_prefix),
+            tick_interval,
+            context_factory_builder.build(),
+        );
+        ReadPool::with_pools(pool_high, pool_normal, pool_low, config)
+    }
+
+    /// Creates a read pool running its tasks on the threads of `base`, so that a load on
+    /// either pool slows down the other. The concurrencies and the stack size of `config`
+    /// are ignored, and the limits of tasks apply to the threads of `base`.
+    pub fn new_on<U, F, CF>(
+        base: &ReadPool<U>,
+        name_prefix: &str,
+        config: &Config,
+        context_factory_builder: F,
+    ) -> Self
+    where
+        U: futurepool::Context + 'static,
+        F: futurepool::Factory<CF>,
+        CF: futurepool::Factory<T>,
+    {
+        let tick_interval = Duration::from_secs(TICK_INTERVAL_SEC);
+
+        let pool_high = FuturePool::new_on(
+            &base.pool_high,
+            &format!("{}-high", name_prefix),
+            tick_interval,
+            context_factory_builder.build(),
+        );
+        let pool_normal = FuturePool::new_on(
+            &base.pool_normal,
+            &format!("{}-normal", name_prefix),
+            tick_interval,
+            context_factory_builder.build(),
+        );
+        let pool_low = FuturePool::new_on(
+            &base.pool_low,
+            &format!("{}-low", name_prefix),
+            tick_interval,
+            context_factory_builder.build(),
+        );
+        ReadPool::with_pools(pool_high, pool_normal, pool_low, config)
+    }
+
+    fn with_pools(
+        pool_high: FuturePool<T>,
+        pool_normal: FuturePool<T>,
+        pool_low: FuturePool<T>,
+        config: &Config,
+    ) -> Self {
         ReadPool {
-            pool_high: FuturePool::new(
-                config.high_concurrency,
-                config.stack_size.0 as usize,
-                &format!("{}-high", name_prefix),
-                tick_interval,
-                context_factory_builder.build(),
-            ),
-            pool_normal: FuturePool::new(
-                config.normal_concurrency,
-                config.stack_size.0 as usize,
-                &format!("{}-normal", name_prefix),
-                tick_interval,
-                context_factory_builder.build(),
-            ),
-            pool_low: FuturePool::new(
-                config.low_concurrency,
-                config.stack_size.0 as usize,
-                &format!("{}-low", name_prefix),
-                tick_interval,
-                context_factory_builder.build(),
-            ),
-            max_tasks_high: config.max_tasks_per_worker_high * config.high_concurrency,
-            max_tasks_normal: config.max_tasks_per_worker_normal * config.normal_concurrency,
-            max_tasks_low: config.max_tasks_per_worker_low * config.low_concurrency,
+            max_tasks_high: config.max_tasks_per_worker_high * pool_high.get_pool_size(),
+            max_tasks_normal: config.max_tasks_per_worker_normal * pool_normal.get_pool_size(),
+            max_tasks_low: config.max_tasks_per_worker_low * pool_low.get_pool_size(),
+            cpu_histogram_high: READ_POOL_TASK_CPU_HISTOGRAM_VEC.with_label_values(&["high"]),
+            cpu_histogram_normal: READ_POOL_TASK_CPU_HISTOGRAM_VEC.with_label_values(&["normal"]),
+            cpu_histogram_low: READ_POOL_TASK_CPU_HISTOGRAM_VEC.with_label_values(&["low"]),
+            pool_high,
+            pool_normal,
+            pool_low,
+            high_priority_weight: config.high_priority_weight,
+            high_tasks_since_low: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -108,9 +181,59 @@ impl<T: futurepool::Context + 'static> ReadPool<T> {
         }
     }
 
+    #[inline]
+    fn get_cpu_histogram_by_priority(&self, priority: Priority) -> &Histogram {
+        match priority {
+            Priority::High => &self.cpu_histogram_high,
+            Priority::Normal => &self.cpu_histogram_normal,
+            Priority::Low => &self.cpu_histogram_low,
+        }
+    }
+
+    /// Gets the tasks running or waiting in all the pools.
+    pub fn get_running_task_count(&self) -> usize {
+        self.pool_high.get_running_task_count()
+            + self.pool_normal.get_running_task_count()
+            + self.pool_low.get_running_task_count()
+    }
+
+    /// Gets the fraction of the threads of the pool for `priority` that are busy running
+    /// tasks.
+    pub fn get_utilization(&self, priority: Priority) -> f64 {
+        self.get_pool_by_priority(priority).get_utilization()
+    }
+
+    // Returns false if a low-priority task should give way to the queued high-priority ones.
+    // So that low-priority tasks are not starved, one is still accepted after every
+    // `high_priority_weight` high-priority ones.
+    fn admit_by_weight(&self, priority: Priority) -> bool {
+        if self.high_priority_weight == 0 {
+            return true;
+        }
+        let high_queued = self.pool_high.get_running_task_count() > self.pool_high.get_pool_size();
+        if !high_queued {
+            return true;
+        }
+        match priority {
+            Priority::High => {
+                self.high_tasks_since_low.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Priority::Normal => true,
+            Priority::Low => {
+                if self.high_tasks_since_low.load(Ordering::SeqCst) < self.high_priority_weight {
+                    return false;
+                }
+                self.high_tasks_since_low.store(0, Ordering::SeqCst);
+                true
+            }
+        }
+    }
+
     /// Executes a future (generated by the `future_factory`) on specified future pool,
     /// returning a success future representing the produced value, or a fail future if
-    /// the future pool is full.
+    /// the future pool is full, or if it's a low-priority one giving way to high-priority
+    /// ones.
     pub fn future_execute<F, R>(
         &self,
         priority: Priority,
@@ -130,12 +253,73 @@ impl<T: futurepool::Context + 'static> ReadPool<T> {
                 current_tasks,
                 max_tasks,
             })
+        } else if !self.admit_by_weight(priority) {
+            // It gives way to the high-priority tasks queued beyond the pool size, whose counts
+            // are reported instead.
+            Err(Full {
+                current_tasks: self.pool_high.get_running_task_count(),
+                max_tasks: self.pool_high.get_pool_size(),
+            })
         } else {
-            Ok(pool.spawn(future_factory))
+            let histogram = self.get_cpu_histogram_by_priority(priority).clone();
+            Ok(pool.spawn(move |ctxd| {
+                let start = sys::thread::cpu_time();
+                let future = future_factory(ctxd);
+                CpuTimed {
+                    future,
+                    cpu_time: sys::thread::cpu_time() - start,
+                    histogram: Some(histogram),
+                }
+            }))
         }
     }
 }
 
+/// `CpuTimed` sums up the CPU time taken to build and poll a read pool task, and records it
+/// once the task is finished or dropped.
+///
+/// The time spent waiting for the pool or for other futures is not counted, unlike the
+/// wall-clock duration of the task. It's the wall-clock time of the polls on platforms
+/// without per-thread CPU clocks.
+struct CpuTimed<F> {
+    future: F,
+    cpu_time: Duration,
+    // `None` once the CPU time is recorded.
+    histogram: Option<Histogram>,
+}
+
+impl<F> CpuTimed<F> {
+    fn record(&mut self) {
+        if let Some(histogram) = self.histogram.take() {
+            let secs = duration_to_sec(self.cpu_time);
+            histogram.observe(secs);
+            READ_POOL_CPU_SECONDS_COUNTER.inc_by(secs);
+        }
+    }
+}
+
+impl<F: Future> Future for CpuTimed<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let start = sys::thread::cpu_time();
+        let res = self.future.poll();
+        self.cpu_time += sys::thread::cpu_time() - start;
+        if let Ok(Async::NotReady) = res {
+            return res;
+        }
+        self.record();
+        res
+    }
+}
+
+impl<F> Drop for CpuTimed<F> {
+    fn drop(&mut self) {
+        self.record();
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Full {
     pub current_tasks: usize,
@@ -306,4 +490,154 @@ mod tests {
         // no more results
         assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
     }
+
+    #[test]
+    fn test_high_priority_weight() {
+        let (tx, rx) = channel();
+        let read_pool = ReadPool::new(
+            "test-weight",
+            &Config {
+                high_concurrency: 1,
+                high_priority_weight: 2,
+                ..Config::default_for_test()
+            },
+            || || Context {},
+        );
+        let spawn = |priority, id| {
+            read_pool.future_execute(priority, move |_| {
+                thread::sleep(Duration::from_millis(200));
+                future::ok::<u64, ()>(id)
+            })
+        };
+
+        // The high-priority pool is busy but nothing is queued yet.
+        wait_on_new_thread(tx.clone(), spawn(Priority::High, 0).unwrap());
+        wait_on_new_thread(tx.clone(), spawn(Priority::High, 1).unwrap());
+        match spawn(Priority::Low, 10) {
+            Err(full) => assert_eq!(
+                full,
+                Full {
+                    current_tasks: 2,
+                    max_tasks: 1,
+                }
+            ),
+            Ok(_) => panic!("expect the low-priority task to be refused"),
+        }
+        // Normal-priority tasks are never deferred.
+        wait_on_new_thread(tx.clone(), spawn(Priority::Normal, 20).unwrap());
+
+        // One low-priority task is accepted after every 2 high-priority ones.
+        wait_on_new_thread(tx.clone(), spawn(Priority::High, 2).unwrap());
+        assert!(spawn(Priority::Low, 11).is_err());
+        wait_on_new_thread(tx.clone(), spawn(Priority::High, 3).unwrap());
+        wait_on_new_thread(tx.clone(), spawn(Priority::Low, 12).unwrap());
+        assert!(spawn(Priority::Low, 13).is_err());
+        let queued = futurepool::FUTUREPOOL_QUEUED_TASK_VEC
+            .with_label_values(&["test-weight-high"])
+            .get();
+        assert_eq!(queued, 3);
+
+        let mut ids: Vec<_> = (0..6).map(|_| rx.recv().unwrap().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2, 3, 12, 20]);
+        // Low-priority tasks are accepted again once the high-priority queue is drained.
+        assert_eq!(spawn(Priority::Low, 14).unwrap().wait(), Ok(14));
+    }
+
+    #[test]
+    fn test_isolation() {
+        let (tx, rx) = channel();
+
+        let config = Config {
+            high_concurrency: 1,
+            ..Config::default_for_test()
+        };
+        let cop_pool = ReadPool::new("test-isolation-cop", &config, || || Context {});
+        let kv_pool = ReadPool::new("test-isolation-kv", &config, || || Context {});
+
+        let utilization = |name| {
+            futurepool::FUTUREPOOL_UTILIZATION_VEC
+                .with_label_values(&[name])
+                .get()
+        };
+
+        // Saturate the coprocessor pool.
+        for id in 0..2 {
+            wait_on_new_thread(
+                tx.clone(),
+                spawn_long_time_future(&cop_pool, id, 500).unwrap(),
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(utilization("test-isolation-cop-high"), 1.0);
+        assert_eq!(utilization("test-isolation-kv-high"), 0.0);
+        // Only the high-priority pool is busy.
+        assert_eq!(cop_pool.get_running_task_count(), 2);
+        assert_eq!(cop_pool.get_utilization(Priority::High), 1.0);
+        assert_eq!(cop_pool.get_utilization(Priority::Normal), 0.0);
+
+        // Reads on the idle pool are not blocked.
+        wait_on_new_thread(
+            tx.clone(),
+            spawn_long_time_future(&kv_pool, 10, 0).unwrap(),
+        );
+        assert_eq!(rx.recv_timeout(Duration::from_millis(200)), Ok(Ok(10)));
+        assert_eq!(rx.recv().unwrap(), Ok(0));
+        assert_eq!(rx.recv().unwrap(), Ok(1));
+
+        // A coprocessor pool sharing the threads of the KV pool holds up KV reads instead.
+        let shared_pool = ReadPool::new_on(&kv_pool, "test-isolation-shared", &config, || {
+            || Context {}
+        });
+        for id in 20..22 {
+            wait_on_new_thread(
+                tx.clone(),
+                spawn_long_time_future(&shared_pool, id, 500).unwrap(),
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(kv_pool.get_running_task_count(), 0);
+        assert_eq!(kv_pool.pool_high.get_utilization(), 1.0);
+        assert_eq!(utilization("test-isolation-shared-high"), 1.0);
+        wait_on_new_thread(
+            tx.clone(),
+            spawn_long_time_future(&kv_pool, 11, 0).unwrap(),
+        );
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        let mut finished: Vec<_> = (0..3).map(|_| rx.recv().unwrap().unwrap()).collect();
+        finished.sort();
+        assert_eq!(finished, vec![11, 20, 21]);
+    }
+
+    #[test]
+    fn test_task_cpu_time() {
+        let read_pool = ReadPool::new("readpool", &Config::default_for_test(), || || Context {});
+        let histogram = READ_POOL_TASK_CPU_HISTOGRAM_VEC.with_label_values(&["low"]);
+        let count = histogram.get_sample_count();
+        let sum = histogram.get_sample_sum();
+        let total = READ_POOL_CPU_SECONDS_COUNTER.get();
+
+        // Spins for 100ms of CPU time, half in building the future and half in polling it.
+        let spin = || {
+            let start = sys::thread::cpu_time();
+            while sys::thread::cpu_time() - start < Duration::from_millis(50) {}
+        };
+        let res = read_pool
+            .future_execute(Priority::Low, move |_| {
+                spin();
+                future::lazy(move || {
+                    spin();
+                    future::ok::<_, ()>(1)
+                })
+            })
+            .unwrap()
+            .wait();
+        assert_eq!(res, Ok(1));
+
+        // Other tests may run tasks at the same time.
+        assert!(histogram.get_sample_count() > count);
+        let cpu_time = histogram.get_sample_sum() - sum;
+        assert!(cpu_time >= 0.09, "{}", cpu_time);
+        assert!(READ_POOL_CPU_SECONDS_COUNTER.get() - total >= 0.09);
+    }
 }
@@ -0,0 +1,225 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use prometheus::IntCounterVec;
+
+use util::collections::{HashMap, HashSet};
+use util::time::duration_to_nanos;
+use util::HandyRwLock;
+
+// Regions idle for so long are forgotten when there are too many of them.
+const REGION_IDLE_DURATION: Duration = Duration::from_secs(60);
+const MAX_IDLE_REGIONS: usize = 4096;
+// The first regions throttled have their own labels, and the rest share `OTHER_REGIONS_LABEL`.
+const MAX_THROTTLED_REGION_LABELS: usize = 64;
+const OTHER_REGIONS_LABEL: &str = "other";
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// A bucket holds at most this long of requests.
+const BURST_NANOS: u64 = NANOS_PER_SEC;
+
+// The token bucket of a region, kept as the time its tokens are refilled up to, in
+// nanoseconds since `RegionQuota::base`. Every request pushes the time forward by the
+// interval of a request, and it's throttled once the time runs more than a burst ahead.
+struct Bucket {
+    full_at: AtomicUsize,
+}
+
+/// `RegionQuota` keeps a hot region from consuming the read or write capacity of the other
+/// regions.
+///
+/// The requests per second of each region are limited with a token bucket holding at most
+/// one second of requests. The quota is the same for all regions unless it's overridden for
+/// some of them. A quota of 0 means no limit.
+pub struct RegionQuota {
+    default_quota: u64,
+    overrides: RwLock<HashMap<u64, u64>>,
+    base: Instant,
+    // Requests of known regions only update the atomics of their buckets under the read
+    // lock, so that regions don't contend with each other.
+    buckets: RwLock<HashMap<u64, Bucket>>,
+    labeled: RwLock<HashSet<u64>>,
+    throttled_counter: &'static IntCounterVec,
+}
+
+impl RegionQuota {
+    /// The requests throttled are counted by `throttled_counter`, labeled by the region.
+    pub fn new(default_quota: u64, throttled_counter: &'static IntCounterVec) -> RegionQuota {
+        RegionQuota {
+            default_quota,
+            overrides: RwLock::new(HashMap::default()),
+            base: Instant::now(),
+            buckets: RwLock::new(HashMap::default()),
+            labeled: RwLock::new(HashSet::default()),
+            throttled_counter,
+        }
+    }
+
+    /// Overrides the quota of `region_id`, or restores the default one if `quota` is `None`.
+    pub fn set_region_quota(&self, region_id: u64, quota: Option<u64>) {
+        match quota {
+            Some(quota) => self.overrides.wl().insert(region_id, quota),
+            None => self.overrides.wl().remove(&region_id),
+        };
+        self.buckets.wl().remove(&region_id);
+    }
+
+    fn quota(&self, region_id: u64) -> u64 {
+        let overrides = self.overrides.rl();
+        if overrides.is_empty() {
+            return self.default_quota;
+        }
+        overrides
+            .get(&region_id)
+            .cloned()
+            .unwrap_or(self.default_quota)
+    }
+
+    /// Records a request of `region_id`. Returns true if the request should be throttled.
+    pub fn on_request(&self, region_id: u64) -> bool {
+        self.on_request_at(region_id, Instant::now())
+    }
+
+    fn on_request_at(&self, region_id: u64, now: Instant) -> bool {
+        let quota = self.quota(region_id);
+        if quota == 0 {
+            return false;
+        }
+        let interval = (NANOS_PER_SEC / quota).max(1) as usize;
+        let now = duration_to_nanos(now.duration_since(self.base)) as usize;
+        let throttled = {
+            let buckets = self.buckets.rl();
+            buckets
+                .get(&region_id)
+                .map(|b| Self::take(b, interval, now))
+        };
+        let throttled = match throttled {
+            Some(throttled) => throttled,
+            None => self.take_new(region_id, interval, now),
+        };
+        if throttled {
+            self.on_throttled(region_id);
+        }
+        throttled
+    }
+
+    // Takes a token from `bucket`. Returns true if it has none left.
+    fn take(bucket: &Bucket, interval: usize, now: usize) -> bool {
+        let mut full_at = bucket.full_at.load(Ordering::Relaxed);
+        loop {
+            let next = full_at.max(now) + interval;
+            if next - now > BURST_NANOS as usize {
+                return true;
+            }
+            let prev = bucket
+                .full_at
+                .compare_and_swap(full_at, next, Ordering::Relaxed);
+            if prev == full_at {
+                return false;
+            }
+            full_at = prev;
+        }
+    }
+
+    // Creates the bucket of a region seen for the first time, which starts full, and takes
+    // a token from it.
+    fn take_new(&self, region_id: u64, interval: usize, now: usize) -> bool {
+        let mut evicted = vec![];
+        let throttled = {
+            let mut buckets = self.buckets.wl();
+            if buckets.len() >= MAX_IDLE_REGIONS && !buckets.contains_key(&region_id) {
+                let idle_nanos = duration_to_nanos(REGION_IDLE_DURATION) as usize;
+                buckets.retain(|id, b| {
+                    let idle = b.full_at.load(Ordering::Relaxed) + idle_nanos <= now;
+                    if idle {
+                        evicted.push(*id);
+                    }
+                    !idle
+                });
+            }
+            let bucket = buckets.entry(region_id).or_insert_with(|| Bucket {
+                full_at: AtomicUsize::new(now),
+            });
+            Self::take(bucket, interval, now)
+        };
+        if !evicted.is_empty() {
+            let mut labeled = self.labeled.wl();
+            for id in evicted {
+                if labeled.remove(&id) {
+                    let _ = self
+                        .throttled_counter
+                        .remove_label_values(&[&id.to_string()]);
+                }
+            }
+        }
+        throttled
+    }
+
+    fn on_throttled(&self, region_id: u64) {
+        let mut is_labeled = self.labeled.rl().contains(&region_id);
+        if !is_labeled {
+            let mut labeled = self.labeled.wl();
+            if labeled.len() < MAX_THROTTLED_REGION_LABELS {
+                labeled.insert(region_id);
+                is_labeled = true;
+            }
+        }
+        let label = if is_labeled {
+            region_id.to_string()
+        } else {
+            OTHER_REGIONS_LABEL.to_owned()
+        };
+        self.throttled_counter.with_label_values(&[&label]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::metrics::REGION_READ_THROTTLED_COUNTER_VEC;
+
+    #[test]
+    fn test_region_quota() {
+        let quota = RegionQuota::new(10, &REGION_READ_THROTTLED_COUNTER_VEC);
+        let now = Instant::now();
+        // Region 1 is flooded, and only its reads beyond the quota are throttled.
+        let allowed = (0..100).filter(|_| !quota.on_request_at(1, now)).count();
+        assert_eq!(allowed, 10);
+        let allowed = (0..10).filter(|_| !quota.on_request_at(2, now)).count();
+        assert_eq!(allowed, 10);
+        let throttled = REGION_READ_THROTTLED_COUNTER_VEC.with_label_values(&["1"]);
+        assert!(throttled.get() >= 90);
+
+        // Tokens are refilled over time.
+        let later = now + Duration::from_millis(500);
+        let allowed = (0..100).filter(|_| !quota.on_request_at(1, later)).count();
+        assert_eq!(allowed, 5);
+
+        // Overridden quotas take effect immediately.
+        quota.set_region_quota(1, Some(0));
+        assert!((0..100).all(|_| !quota.on_request_at(1, later)));
+        quota.set_region_quota(2, Some(20));
+        let allowed = (0..100).filter(|_| !quota.on_request_at(2, later)).count();
+        assert_eq!(allowed, 20);
+        quota.set_region_quota(1, None);
+        let allowed = (0..100).filter(|_| !quota.on_request_at(1, later)).count();
+        assert_eq!(allowed, 10);
+
+        let unlimited = RegionQuota::new(0, &REGION_READ_THROTTLED_COUNTER_VEC);
+        assert!((0..100).all(|_| !unlimited.on_request(1)));
+    }
+}
@@ -13,17 +13,17 @@
 
 use std::boxed::FnBox;
 use std::fmt::{self, Display, Formatter};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use kvproto::metapb;
 
 use pd::PdClient;
-use util::collections::HashMap;
+use util::collections::{HashMap, HashSet};
 use util::worker::{Runnable, Scheduler, Worker};
 
 use super::metrics::*;
-use super::Result;
+use super::{Error, Result};
 
 const STORE_ADDRESS_REFRESH_SECONDS: u64 = 60;
 
@@ -35,6 +35,35 @@ pub trait StoreAddrResolver: Send + Clone {
     fn resolve(&self, store_id: u64, cb: Callback) -> Result<()>;
 }
 
+/// `LiveStoreSource` tells the stores currently in the cluster, so that the transport can
+/// forget the ones removed.
+pub trait LiveStoreSource: Send + Sync {
+    /// Returns the ids of the stores that are not tombstone.
+    fn live_stores(&self) -> Result<HashSet<u64>>;
+}
+
+/// Gets the live stores from PD.
+pub struct PdLiveStoreSource<T: PdClient> {
+    pd_client: Arc<T>,
+}
+
+impl<T: PdClient> PdLiveStoreSource<T> {
+    pub fn new(pd_client: Arc<T>) -> PdLiveStoreSource<T> {
+        PdLiveStoreSource { pd_client }
+    }
+}
+
+impl<T: PdClient> LiveStoreSource for PdLiveStoreSource<T> {
+    fn live_stores(&self) -> Result<HashSet<u64>> {
+        let stores = self.pd_client.get_all_stores()?;
+        Ok(stores
+            .iter()
+            .filter(|s| s.get_state() != metapb::StoreState::Tombstone)
+            .map(|s| s.get_id())
+            .collect())
+    }
+}
+
 /// Snapshot generating task.
 pub struct Task {
     store_id: u64,
@@ -82,10 +111,7 @@ impl<T: PdClient> Runner<T> {
         let pd_client = Arc::clone(&self.pd_client);
         let s = box_try!(pd_client.get_store(store_id));
         if s.get_state() == metapb::StoreState::Tombstone {
-            RESOLVE_STORE_COUNTER
-                .with_label_values(&["tombstone"])
-                .inc();
-            return Err(box_err!("store {} has been removed", store_id));
+            return Err(Error::StoreTombstone(store_id));
         }
         let addr = s.get_address().to_owned();
         // In some tests, we use empty address for store first,
@@ -142,13 +168,95 @@ impl StoreAddrResolver for PdStoreAddrResolver {
     }
 }
 
+enum CachedAddr {
+    Resolved(String),
+    Failed(String),
+    Tombstone,
+}
+
+struct CacheEntry {
+    addr: CachedAddr,
+    expire_time: Instant,
+}
+
+/// `CachingResolver` wraps a `StoreAddrResolver` and memoizes its results, both the
+/// resolved addresses and the failures, so that a burst of messages to a store that is
+/// known to be down doesn't hit the backend resolver for every message. Failures are
+/// usually cached for a shorter time than addresses.
+#[derive(Clone)]
+pub struct CachingResolver<S: StoreAddrResolver> {
+    resolver: S,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    cache: Arc<Mutex<HashMap<u64, CacheEntry>>>,
+}
+
+impl<S: StoreAddrResolver> CachingResolver<S> {
+    pub fn new(resolver: S, positive_ttl: Duration, negative_ttl: Duration) -> CachingResolver<S> {
+        CachingResolver {
+            resolver,
+            positive_ttl,
+            negative_ttl,
+            cache: Arc::new(Mutex::new(HashMap::default())),
+        }
+    }
+
+    fn get_cached(&self, store_id: u64) -> Option<Result<String>> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(&store_id) {
+            Some(e) if e.expire_time > Instant::now() => {
+                return Some(match e.addr {
+                    CachedAddr::Resolved(ref addr) => Ok(addr.clone()),
+                    CachedAddr::Failed(ref reason) => Err(box_err!("{}", reason)),
+                    CachedAddr::Tombstone => Err(Error::StoreTombstone(store_id)),
+                });
+            }
+            _ => {}
+        }
+        // Drop the expired entry if there is one.
+        cache.remove(&store_id);
+        None
+    }
+}
+
+impl<S: StoreAddrResolver> StoreAddrResolver for CachingResolver<S> {
+    fn resolve(&self, store_id: u64, cb: Callback) -> Result<()> {
+        if let Some(res) = self.get_cached(store_id) {
+            let label = if res.is_ok() { "cached" } else { "cached_failure" };
+            RESOLVE_STORE_COUNTER.with_label_values(&[label]).inc();
+            cb(res);
+            return Ok(());
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let (positive_ttl, negative_ttl) = (self.positive_ttl, self.negative_ttl);
+        self.resolver.resolve(
+            store_id,
+            box move |res: Result<String>| {
+                let (addr, ttl) = match res {
+                    Ok(ref addr) => (CachedAddr::Resolved(addr.clone()), positive_ttl),
+                    Err(Error::StoreTombstone(_)) => (CachedAddr::Tombstone, negative_ttl),
+                    Err(ref e) => (CachedAddr::Failed(format!("{}", e)), negative_ttl),
+                };
+                let entry = CacheEntry {
+                    addr,
+                    expire_time: Instant::now() + ttl,
+                };
+                cache.lock().unwrap().insert(store_id, entry);
+                cb(res)
+            },
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::SocketAddr;
     use std::ops::Sub;
     use std::str::FromStr;
-    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
     use std::thread;
     use std::time::{Duration, Instant};
 
@@ -272,7 +380,10 @@ mod tests {
     fn test_resolve_store_state_tombstone() {
         let store = new_store(STORE_ADDR, metapb::StoreState::Tombstone);
         let mut runner = new_runner(store);
-        assert!(runner.get_address(0).is_err());
+        match runner.get_address(0) {
+            Err(Error::StoreTombstone(0)) => {}
+            res => panic!("expect store tombstone, but got {:?}", res),
+        }
     }
 
     #[test]
@@ -308,4 +419,63 @@ mod tests {
         new_sock = runner.resolve(store_id).unwrap();
         assert_eq!(sock, new_sock);
     }
+
+    #[derive(Clone)]
+    struct CountingResolver {
+        count: Arc<AtomicUsize>,
+    }
+
+    impl StoreAddrResolver for CountingResolver {
+        fn resolve(&self, store_id: u64, cb: Callback) -> ::server::Result<()> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            // Only store 1 is up.
+            if store_id == 1 {
+                cb(Ok(STORE_ADDR.to_owned()));
+            } else {
+                cb(Err(box_err!("store {} is down", store_id)));
+            }
+            Ok(())
+        }
+    }
+
+    fn resolve_ok<S: StoreAddrResolver>(resolver: &S, store_id: u64) -> bool {
+        let (tx, rx) = mpsc::channel();
+        resolver
+            .resolve(
+                store_id,
+                box move |res: ::server::Result<String>| tx.send(res.is_ok()).unwrap(),
+            )
+            .unwrap();
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn test_caching_resolver() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let resolver = CachingResolver::new(
+            CountingResolver {
+                count: Arc::clone(&count),
+            },
+            Duration::from_secs(60),
+            Duration::from_millis(100),
+        );
+
+        // Both addresses and failures are cached.
+        for _ in 0..3 {
+            assert!(resolve_ok(&resolver, 1));
+            assert!(!resolve_ok(&resolver, 2));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Failures expire sooner than addresses.
+        thread::sleep(Duration::from_millis(200));
+        assert!(resolve_ok(&resolver, 1));
+        assert!(!resolve_ok(&resolver, 2));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // Clones share the same cache.
+        let resolver2 = resolver.clone();
+        assert!(!resolve_ok(&resolver2, 2));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
 }
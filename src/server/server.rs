@@ -12,13 +12,19 @@
 // limitations under the License.
 
 use std::i32;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, RwLock};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use futures::Stream;
-use grpc::{ChannelBuilder, EnvBuilder, Environment, Server as GrpcServer, ServerBuilder};
+use grpc::{
+    ChannelBuilder, EnvBuilder, Environment, Server as GrpcServer,
+    ServerBuilder as GrpcServerBuilder, Service,
+};
 use kvproto::debugpb_grpc::create_debug;
 use kvproto::import_sstpb_grpc::create_import_sst;
 use kvproto::tikvpb_grpc::*;
@@ -28,29 +34,45 @@ use tokio::timer::Interval;
 use coprocessor::Endpoint;
 use import::ImportSSTService;
 use raftstore::store::{Engines, SnapManager};
+use storage::engine::EngineStats;
 use storage::{Engine, Storage};
+use sys_info;
+use util::config::KB;
 use util::security::SecurityManager;
+use util::sys::memory;
 use util::worker::Worker;
 
 use super::load_statistics::*;
+use super::metrics::COPR_PAUSED_GAUGE;
 use super::raft_client::RaftClient;
-use super::resolve::StoreAddrResolver;
+use super::raft_codec::create_raft_codecs;
+use super::resolve::{LiveStoreSource, StoreAddrResolver};
 use super::service::*;
 use super::snap::{Runner as SnapHandler, Task as SnapTask};
 use super::transport::{RaftStoreRouter, ServerTransport};
-use super::{Config, Result};
+use super::config::MIN_ENDPOINT_RECURSION_LIMIT;
+use super::{Config, Error, Result};
 
 const LOAD_STATISTICS_SLOTS: usize = 4;
 const LOAD_STATISTICS_INTERVAL: Duration = Duration::from_millis(100);
+const ENGINE_STATISTICS_INTERVAL: Duration = Duration::from_secs(10);
+const MEMORY_USAGE_INTERVAL: Duration = Duration::from_secs(1);
+const IDLE_CLIENT_EVICT_INTERVAL: Duration = Duration::from_secs(60);
+const RAFT_MSG_EXPIRE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const RAFT_PENDING_GAUGE_INTERVAL: Duration = Duration::from_secs(1);
 const MAX_GRPC_RECV_MSG_LEN: i32 = 10 * 1024 * 1024;
 pub const GRPC_THREAD_PREFIX: &str = "grpc-server";
+pub const GRPC_BULK_THREAD_PREFIX: &str = "grpc-bulk";
 pub const STATS_THREAD_PREFIX: &str = "transport-stats";
 
 pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> {
     env: Arc<Environment>,
     // Grpc server.
     grpc_server: GrpcServer,
-    local_addr: SocketAddr,
+    local_addrs: Vec<SocketAddr>,
+    security_mgr: Arc<SecurityManager>,
+    // Serves the debug and import services if `bulk_addr` is set, taken by `stop`.
+    bulk_server: Option<BulkServer>,
     // Transport.
     trans: ServerTransport<T, S>,
     raft_router: T,
@@ -61,9 +83,31 @@ pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static>
     // Currently load statistics is done in the thread.
     stats_runtime: Arc<Runtime>,
     thread_load: Arc<ThreadLoad>,
+    // Collects storage engine statistics, taken by `start`.
+    engine_stats: Option<Box<Fn() -> EngineStats + Send>>,
+    // Sheds requests under memory pressure, fed with the memory usage by `start`.
+    memory_shedder: Option<MemoryShedder>,
+    // Limits the request rate of clients, whose idle ones are evicted by `start`.
+    rate_limiter: Option<ClientRateLimiter>,
+    // Tells the stores to keep in the transport, taken by `start`.
+    live_store_source: Option<Box<LiveStoreSource>>,
+    // Stops the thread dropping the stores removed from the cluster once it's dropped.
+    store_gc_stop: Option<mpsc::Sender<()>>,
+
+    // Shared with the coprocessor end point so that they can be changed at runtime.
+    end_point_recursion_limit: Arc<AtomicUsize>,
+    end_point_stream_channel_size: Arc<AtomicUsize>,
+    end_point_paused: Arc<AtomicBool>,
+    // Shared with the snapshot runner so that it can be changed at runtime.
+    concurrent_recv_snap_limit: Arc<AtomicUsize>,
+
+    // In-flight KV and coprocessor requests, waited for when stopping.
+    in_flight: InFlightRequests,
+    graceful_shutdown_timeout: Duration,
 }
 
 impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
+    /// Creates a server. It's a shortcut of `Server::builder`.
     #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
     pub fn new<E: Engine>(
         cfg: &Arc<Config>,
@@ -75,7 +119,500 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         snap_mgr: SnapManager,
         debug_engines: Option<Engines>,
         import_service: Option<ImportSSTService<T>>,
+        interceptors: Vec<Box<ServerInterceptor>>,
     ) -> Result<Self> {
+        let mut builder = Server::builder()
+            .cfg(Arc::clone(cfg))
+            .security_mgr(Arc::clone(security_mgr))
+            .storage(storage)
+            .cop(cop)
+            .raft_router(raft_router)
+            .resolver(resolver)
+            .snap_mgr(snap_mgr)
+            .interceptors(interceptors);
+        if let Some(engines) = debug_engines {
+            builder = builder.debug_engines(engines);
+        }
+        if let Some(service) = import_service {
+            builder = builder.import_service(service);
+        }
+        builder.build()
+    }
+
+    pub fn builder<E: Engine>() -> ServerBuilder<E, T, S> {
+        ServerBuilder {
+            cfg: None,
+            security_mgr: None,
+            storage: None,
+            cop: None,
+            raft_router: None,
+            resolver: None,
+            snap_mgr: None,
+            debug_engines: None,
+            import_service: None,
+            interceptors: vec![],
+            live_store_source: None,
+        }
+    }
+
+    pub fn transport(&self) -> ServerTransport<T, S> {
+        self.trans.clone()
+    }
+
+    /// Replaces the resolver of store addresses, e.g. after switching PD endpoints. The
+    /// addresses being resolved are resolved by the old resolver, and the new one is used
+    /// afterwards, by the transports cloned before as well.
+    pub fn set_resolver(&self, resolver: S) {
+        self.trans.set_resolver(resolver);
+        info!("store address resolver is replaced");
+    }
+
+    /// Updates the recursion limit of coprocessor requests. It takes effect on
+    /// subsequent requests.
+    pub fn set_end_point_recursion_limit(&self, limit: u32) -> Result<()> {
+        if limit < MIN_ENDPOINT_RECURSION_LIMIT {
+            return Err(box_err!("end-point-recursion-limit is too small"));
+        }
+        self.end_point_recursion_limit.store(limit as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Updates the channel size of coprocessor streaming requests. It takes effect on
+    /// subsequent requests.
+    pub fn set_end_point_stream_channel_size(&self, size: usize) -> Result<()> {
+        if size == 0 {
+            return Err(box_err!("end-point-stream-channel-size should not be 0"));
+        }
+        self.end_point_stream_channel_size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Refuses new coprocessor requests with a server busy error, so that clients retry
+    /// them later or elsewhere. Requests already accepted are handled as usual.
+    pub fn pause_coprocessor(&self) {
+        self.end_point_paused.store(true, Ordering::Release);
+        COPR_PAUSED_GAUGE.set(1);
+        info!("coprocessor is paused");
+    }
+
+    /// Accepts coprocessor requests again after `pause_coprocessor`.
+    pub fn resume_coprocessor(&self) {
+        self.end_point_paused.store(false, Ordering::Release);
+        COPR_PAUSED_GAUGE.set(0);
+        info!("coprocessor is resumed");
+    }
+
+    /// Updates the max number of snapshots received at the same time. Snapshots beyond
+    /// the limit are refused and will be resent later.
+    pub fn set_concurrent_recv_snap_limit(&self, limit: usize) -> Result<()> {
+        if limit == 0 {
+            return Err(box_err!("concurrent-recv-snap-limit should not be 0"));
+        }
+        self.concurrent_recv_snap_limit.store(limit, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Enters or exits the drain mode of snapshots. When draining, new snapshots to send
+    /// or receive are refused, so that the node can be taken down once the transferring
+    /// ones finish.
+    pub fn set_snap_draining(&self, draining: bool) -> Result<()> {
+        self.snap_worker.schedule(SnapTask::Drain(draining))?;
+        Ok(())
+    }
+
+    /// Reloads the TLS credentials of the outgoing connections from disk. The new
+    /// credentials are used by the raft and snapshot connections made afterwards, and the
+    /// existing connections are kept. If they fail to load, the old ones stay active.
+    ///
+    /// Only the client side is reloaded. The gRPC server keeps serving incoming
+    /// connections with the credentials it's bound with, as grpcio can't change the
+    /// credentials of a listening port, so rotating them still needs a restart.
+    pub fn reload_tls(&self) -> Result<()> {
+        if let Err(e) = self.security_mgr.reload() {
+            return Err(box_err!("failed to reload tls credentials: {}", e));
+        }
+        info!(
+            "tls credentials of outgoing connections are reloaded, incoming connections keep \
+             the credentials the server is started with"
+        );
+        Ok(())
+    }
+
+    pub fn start(&mut self, cfg: Arc<Config>, security_mgr: Arc<SecurityManager>) -> Result<()> {
+        let snap_runner = SnapHandler::new(
+            Arc::clone(&self.env),
+            self.snap_mgr.clone(),
+            self.raft_router.clone(),
+            security_mgr,
+            Arc::clone(&cfg),
+            Arc::clone(&self.concurrent_recv_snap_limit),
+        );
+        box_try!(self.snap_worker.start(snap_runner));
+        self.grpc_server.start();
+        if let Some(ref mut bulk) = self.bulk_server {
+            bulk.server.start();
+            info!(
+                "debug and import services are listening on bulk address {}, and are not \
+                 served on {:?} any more",
+                bulk.addr, self.local_addrs
+            );
+        }
+
+        let mut load_stats = {
+            let tl = Arc::clone(&self.thread_load);
+            ThreadLoadStatistics::new(LOAD_STATISTICS_SLOTS, GRPC_THREAD_PREFIX, tl)
+        };
+        self.stats_runtime.executor().spawn(
+            Interval::new(Instant::now(), LOAD_STATISTICS_INTERVAL)
+                .map_err(|_| ())
+                .for_each(move |i| {
+                    load_stats.record(i);
+                    Ok(())
+                }),
+        );
+        if let Some(engine_stats) = self.engine_stats.take() {
+            self.stats_runtime.executor().spawn(
+                Interval::new(Instant::now(), ENGINE_STATISTICS_INTERVAL)
+                    .map_err(|_| ())
+                    .for_each(move |_| {
+                        engine_stats().flush_metrics();
+                        Ok(())
+                    }),
+            );
+        }
+        if let Some(shedder) = self.memory_shedder.take() {
+            match sys_info::mem_info() {
+                Ok(info) => {
+                    let total = info.total * KB;
+                    self.stats_runtime.executor().spawn(
+                        Interval::new(Instant::now(), MEMORY_USAGE_INTERVAL)
+                            .map_err(|_| ())
+                            .for_each(move |_| {
+                                match memory::get_rss() {
+                                    Ok(rss) => shedder.on_memory_usage(rss as f64 / total as f64),
+                                    Err(e) => error!("failed to get the memory usage: {:?}", e),
+                                }
+                                Ok(())
+                            }),
+                    );
+                }
+                // The shedder is never fed, so it never sheds any request.
+                Err(e) => warn!("failed to get the total memory, skip shedding by memory: {:?}", e),
+            }
+        }
+        if let Some(limiter) = self.rate_limiter.take() {
+            self.stats_runtime.executor().spawn(
+                Interval::new(Instant::now(), IDLE_CLIENT_EVICT_INTERVAL)
+                    .map_err(|_| ())
+                    .for_each(move |i| {
+                        limiter.evict_idle_clients(i);
+                        Ok(())
+                    }),
+            );
+        }
+        let gc_interval = cfg.raft_client_gc_interval.0;
+        if let Some(source) = self.live_store_source.take() {
+            if gc_interval > Duration::from_secs(0) {
+                // Getting the live stores may block on PD, so it's done in its own thread
+                // rather than on `stats_runtime`.
+                let trans = self.trans.clone();
+                let (tx, rx) = mpsc::channel();
+                let builder = thread::Builder::new().name(thd_name!("store-gc"));
+                box_try!(builder.spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(gc_interval) {
+                        match source.live_stores() {
+                            Ok(live) => trans.retain_stores(&live),
+                            Err(e) => warn!("failed to get the live stores: {:?}", e),
+                        }
+                    }
+                }));
+                self.store_gc_stop = Some(tx);
+            }
+        }
+
+        let trans = self.trans.clone();
+        self.stats_runtime.executor().spawn(
+            Interval::new(Instant::now(), RAFT_PENDING_GAUGE_INTERVAL)
+                .map_err(|_| ())
+                .for_each(move |_| {
+                    trans.update_pending_gauge();
+                    Ok(())
+                }),
+        );
+
+        let send_timeout = cfg.raft_msg_send_timeout.0;
+        if send_timeout > Duration::from_secs(0) {
+            let trans = self.trans.clone();
+            self.stats_runtime.executor().spawn(
+                Interval::new(Instant::now(), RAFT_MSG_EXPIRE_CHECK_INTERVAL)
+                    .map_err(|_| ())
+                    .for_each(move |_| {
+                        trans.expire_msgs(send_timeout);
+                        Ok(())
+                    }),
+            );
+        }
+
+        info!("TiKV is ready to serve");
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        // Refuse new requests, and wait for the in-flight ones to finish before
+        // shutting down the grpc server.
+        self.in_flight.close();
+        let start = Instant::now();
+        let in_flight = self.in_flight.wait_idle(self.graceful_shutdown_timeout);
+        if in_flight > 0 {
+            warn!(
+                "server: shutdown with {} requests in flight after {:?}",
+                in_flight, self.graceful_shutdown_timeout
+            );
+        }
+
+        // Wait for the raft messages of the requests to be written.
+        let (tx, rx) = mpsc::channel();
+        self.trans
+            .flush_raft_client_with_ack(box move |res| tx.send(res).unwrap_or(()));
+        let remaining = self
+            .graceful_shutdown_timeout
+            .checked_sub(start.elapsed())
+            .unwrap_or_default();
+        match rx.recv_timeout(remaining) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("server: shutdown with raft messages unsent: {:?}", e),
+            Err(_) => warn!("server: shutdown before raft messages are written"),
+        }
+
+        self.snap_worker.stop();
+        self.store_gc_stop.take();
+        self.grpc_server.shutdown();
+        if let Some(mut bulk) = self.bulk_server.take() {
+            bulk.server.shutdown();
+        }
+        Ok(())
+    }
+
+    // Return listening address, this may only be used for outer test
+    // to get the real address because we may use "127.0.0.1:0"
+    // in test to avoid port conflict.
+    pub fn listening_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Returns the real addresses of all the listening addresses, in the order they are
+    /// configured.
+    pub fn listening_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Returns the real address of the debug and import services, if they are served
+    /// separately.
+    pub fn bulk_listening_addr(&self) -> Option<SocketAddr> {
+        self.bulk_server.as_ref().map(|bulk| bulk.addr)
+    }
+}
+
+/// A gRPC server with its own environment, so that its services never take the threads
+/// of the KV service. The environment is held by the server, and its threads are joined
+/// once the server is shut down and dropped.
+struct BulkServer {
+    server: GrpcServer,
+    addr: SocketAddr,
+}
+
+impl BulkServer {
+    fn new(
+        cfg: &Config,
+        security_mgr: &SecurityManager,
+        services: Vec<Service>,
+    ) -> Result<BulkServer> {
+        let env = Arc::new(
+            EnvBuilder::new()
+                .cq_count(cfg.grpc_bulk_concurrency)
+                .name_prefix(thd_name!(GRPC_BULK_THREAD_PREFIX))
+                .build(),
+        );
+        let addr = resolve_listening_addr("server.bulk-addr", &cfg.bulk_addr)?;
+        let channel_args = ChannelBuilder::new(Arc::clone(&env))
+            .stream_initial_window_size(cfg.grpc_stream_initial_window_size.0 as i32)
+            .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
+            .max_send_message_len(-1)
+            .build_args();
+        let mut sb = GrpcServerBuilder::new(env).channel_args(channel_args);
+        sb = bind(sb, security_mgr, addr);
+        for service in services {
+            sb = sb.register_service(service);
+        }
+        let server = sb.build()?;
+        let addr = bound_addrs(&server)?[0];
+        Ok(BulkServer { server, addr })
+    }
+}
+
+fn bind(
+    sb: GrpcServerBuilder,
+    security_mgr: &SecurityManager,
+    addr: SocketAddr,
+) -> GrpcServerBuilder {
+    let ip = match addr.ip() {
+        IpAddr::V6(ip) => format!("[{}]", ip),
+        ip => format!("{}", ip),
+    };
+    security_mgr.bind(sb, &ip, addr.port())
+}
+
+// Returns the addresses actually bound, in the order they are bound. They differ from the
+// configured ones if the ports are 0. IPv6 hosts are bound with brackets by `bind`, so
+// the hosts and the ports always make valid socket addresses.
+fn bound_addrs(server: &GrpcServer) -> Result<Vec<SocketAddr>> {
+    let mut addrs = vec![];
+    for &(ref host, port) in server.bind_addrs() {
+        let addr = format!("{}:{}", host, port);
+        match SocketAddr::from_str(&addr) {
+            Ok(addr) => addrs.push(addr),
+            Err(e) => return Err(box_err!("invalid bound address {:?}: {}", addr, e)),
+        }
+    }
+    Ok(addrs)
+}
+
+/// Parses the listening address `addr` given by the config `name`. Besides what
+/// `SocketAddr` accepts, the host can be an IPv6 address without brackets, or a hostname
+/// which is resolved once here.
+fn resolve_listening_addr(name: &str, addr: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = SocketAddr::from_str(addr) {
+        return Ok(addr);
+    }
+    let invalid = |reason: String| -> Error { box_err!("invalid {} {:?}: {}", name, addr, reason) };
+    let pos = match addr.rfind(':') {
+        Some(pos) => pos,
+        None => return Err(invalid("no port is specified".to_owned())),
+    };
+    let (host, port) = (&addr[..pos], &addr[pos + 1..]);
+    let port: u16 = match port.parse() {
+        Ok(port) => port,
+        Err(e) => return Err(invalid(format!("bad port: {}", e))),
+    };
+    if let Ok(ip) = Ipv6Addr::from_str(host) {
+        return Ok(SocketAddr::new(IpAddr::V6(ip), port));
+    }
+    match (host, port).to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or_else(|| invalid("no address is resolved".to_owned())),
+        Err(e) => Err(invalid(format!("failed to resolve: {}", e))),
+    }
+}
+
+/// `ServerBuilder` builds a `Server`. `cfg`, `security_mgr`, `storage`, `cop`, `raft_router`,
+/// `resolver` and `snap_mgr` are required, while the others are optional.
+pub struct ServerBuilder<E, T, S>
+where
+    E: Engine,
+    T: RaftStoreRouter + 'static,
+    S: StoreAddrResolver + 'static,
+{
+    cfg: Option<Arc<Config>>,
+    security_mgr: Option<Arc<SecurityManager>>,
+    storage: Option<Storage<E>>,
+    cop: Option<Endpoint<E>>,
+    raft_router: Option<T>,
+    resolver: Option<S>,
+    snap_mgr: Option<SnapManager>,
+    debug_engines: Option<Engines>,
+    import_service: Option<ImportSSTService<T>>,
+    interceptors: Vec<Box<ServerInterceptor>>,
+    live_store_source: Option<Box<LiveStoreSource>>,
+}
+
+fn required<V>(value: Option<V>, name: &str) -> Result<V> {
+    value.ok_or_else(|| box_err!("{} is required to build the server", name))
+}
+
+impl<E, T, S> ServerBuilder<E, T, S>
+where
+    E: Engine,
+    T: RaftStoreRouter + 'static,
+    S: StoreAddrResolver + 'static,
+{
+    pub fn cfg(mut self, cfg: Arc<Config>) -> Self {
+        self.cfg = Some(cfg);
+        self
+    }
+
+    pub fn security_mgr(mut self, security_mgr: Arc<SecurityManager>) -> Self {
+        self.security_mgr = Some(security_mgr);
+        self
+    }
+
+    pub fn storage(mut self, storage: Storage<E>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn cop(mut self, cop: Endpoint<E>) -> Self {
+        self.cop = Some(cop);
+        self
+    }
+
+    pub fn raft_router(mut self, raft_router: T) -> Self {
+        self.raft_router = Some(raft_router);
+        self
+    }
+
+    pub fn resolver(mut self, resolver: S) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn snap_mgr(mut self, snap_mgr: SnapManager) -> Self {
+        self.snap_mgr = Some(snap_mgr);
+        self
+    }
+
+    /// Serves the debug service with `engines`.
+    pub fn debug_engines(mut self, engines: Engines) -> Self {
+        self.debug_engines = Some(engines);
+        self
+    }
+
+    /// Serves the import service.
+    pub fn import_service(mut self, service: ImportSSTService<T>) -> Self {
+        self.import_service = Some(service);
+        self
+    }
+
+    /// Appends an interceptor run before each KV and coprocessor request.
+    pub fn interceptor(mut self, interceptor: Box<ServerInterceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    pub fn interceptors(mut self, interceptors: Vec<Box<ServerInterceptor>>) -> Self {
+        self.interceptors.extend(interceptors);
+        self
+    }
+
+    /// Sets where the stores in the cluster are got from, so that the addresses and
+    /// connections of the removed stores are dropped every `raft_client_gc_interval`.
+    pub fn live_store_source(mut self, source: Box<LiveStoreSource>) -> Self {
+        self.live_store_source = Some(source);
+        self
+    }
+
+    /// Builds the server. It fails if any required component is missing.
+    pub fn build(self) -> Result<Server<T, S>> {
+        let cfg = required(self.cfg, "cfg")?;
+        let security_mgr = required(self.security_mgr, "security_mgr")?;
+        let storage = required(self.storage, "storage")?;
+        let cop = required(self.cop, "cop")?;
+        let raft_router = required(self.raft_router, "raft_router")?;
+        let resolver = required(self.resolver, "resolver")?;
+        let snap_mgr = required(self.snap_mgr, "snap_mgr")?;
+        let debug_engines = self.debug_engines;
+        let import_service = self.import_service;
+        let mut interceptors = self.interceptors;
+        let live_store_source = self.live_store_source;
+
         // A helper thread (or pool) for transport layer.
         let stats_runtime = Arc::new(
             RuntimeBuilder::new()
@@ -95,40 +632,86 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
 
         let snap_worker = Worker::new("snap-handler");
 
-        let kv_service = KvService::new(storage, cop, raft_router.clone(), snap_worker.scheduler());
-        let addr = SocketAddr::from_str(&cfg.addr)?;
-        info!("listening on {}", addr);
-        let ip = format!("{}", addr.ip());
+        let engine = storage.get_engine();
+        let engine_stats: Box<Fn() -> EngineStats + Send> = box move || engine.get_statistics();
+
+        let end_point_recursion_limit = cop.recursion_limit();
+        let end_point_stream_channel_size = cop.stream_channel_size();
+        let end_point_paused = cop.paused();
+        let in_flight = InFlightRequests::new(cfg.max_inflight_requests_per_connection);
+        let rate_limiter = if cfg.max_requests_per_sec_per_client > 0 {
+            let limiter = ClientRateLimiter::new(cfg.max_requests_per_sec_per_client);
+            interceptors.insert(0, box limiter.clone());
+            Some(limiter)
+        } else {
+            None
+        };
+        let memory_shedder = if cfg.memory_pressure_high_water > 0.0 {
+            let shedder = MemoryShedder::new(
+                cfg.memory_pressure_high_water,
+                cfg.memory_pressure_low_water,
+                cfg.memory_pressure_shed_methods.clone(),
+            );
+            interceptors.insert(0, box shedder.clone());
+            Some(shedder)
+        } else {
+            None
+        };
+        let kv_service = KvService::new(
+            storage,
+            cop,
+            raft_router.clone(),
+            snap_worker.scheduler(),
+            in_flight.clone(),
+            InterceptorChain::new(interceptors),
+        );
+        let mut addrs = vec![];
+        for addr in cfg.listening_addrs() {
+            addrs.push(resolve_listening_addr("server.addr", addr)?);
+        }
+        if addrs.is_empty() {
+            return Err(box_err!("invalid server.addr {:?}", cfg.addr));
+        }
+        info!("listening on {:?}", addrs);
         let channel_args = ChannelBuilder::new(Arc::clone(&env))
             .stream_initial_window_size(cfg.grpc_stream_initial_window_size.0 as i32)
             .max_concurrent_stream(cfg.grpc_concurrent_stream)
             .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
             .max_send_message_len(-1)
             .build_args();
+        let mut bulk_services = vec![];
+        if let Some(engines) = debug_engines {
+            let debug_service = DebugService::new(engines, raft_router.clone());
+            bulk_services.push(create_debug(debug_service));
+        }
+        if let Some(service) = import_service {
+            bulk_services.push(create_import_sst(service));
+        }
+        let bulk_server = if cfg.bulk_addr.is_empty() {
+            None
+        } else {
+            let services = bulk_services.drain(..).collect();
+            Some(BulkServer::new(&cfg, &security_mgr, services)?)
+        };
         let grpc_server = {
-            let mut sb = ServerBuilder::new(Arc::clone(&env))
+            let mut sb = GrpcServerBuilder::new(Arc::clone(&env))
                 .channel_args(channel_args)
+                .register_service(create_raft_codecs(kv_service.clone()))
                 .register_service(create_tikv(kv_service));
-            sb = security_mgr.bind(sb, &ip, addr.port());
-            if let Some(engines) = debug_engines {
-                let debug_service = DebugService::new(engines, raft_router.clone());
-                sb = sb.register_service(create_debug(debug_service));
+            for addr in &addrs {
+                sb = bind(sb, &security_mgr, *addr);
             }
-            if let Some(service) = import_service {
-                sb = sb.register_service(create_import_sst(service));
+            for service in bulk_services {
+                sb = sb.register_service(service);
             }
             sb.build()?
         };
-
-        let addr = {
-            let (ref host, port) = grpc_server.bind_addrs()[0];
-            SocketAddr::new(IpAddr::from_str(host)?, port as u16)
-        };
+        let local_addrs = bound_addrs(&grpc_server)?;
 
         let raft_client = Arc::new(RwLock::new(RaftClient::new(
             Arc::clone(&env),
-            Arc::clone(cfg),
-            Arc::clone(security_mgr),
+            Arc::clone(&cfg),
+            Arc::clone(&security_mgr),
         )));
 
         let trans = ServerTransport::new(
@@ -136,103 +719,103 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             snap_worker.scheduler(),
             raft_router.clone(),
             resolver,
+            &cfg,
         );
 
         let svr = Server {
             env: Arc::clone(&env),
             grpc_server,
-            local_addr: addr,
+            local_addrs,
+            security_mgr,
+            bulk_server,
             trans,
             raft_router,
             snap_mgr,
             snap_worker,
             stats_runtime,
             thread_load,
+            engine_stats: Some(engine_stats),
+            memory_shedder,
+            rate_limiter,
+            live_store_source,
+            store_gc_stop: None,
+            end_point_recursion_limit,
+            end_point_stream_channel_size,
+            end_point_paused,
+            concurrent_recv_snap_limit: Arc::new(AtomicUsize::new(cfg.concurrent_recv_snap_limit)),
+            in_flight,
+            graceful_shutdown_timeout: cfg.graceful_shutdown_timeout.0,
         };
 
         Ok(svr)
     }
-
-    pub fn transport(&self) -> ServerTransport<T, S> {
-        self.trans.clone()
-    }
-
-    pub fn start(&mut self, cfg: Arc<Config>, security_mgr: Arc<SecurityManager>) -> Result<()> {
-        let snap_runner = SnapHandler::new(
-            Arc::clone(&self.env),
-            self.snap_mgr.clone(),
-            self.raft_router.clone(),
-            security_mgr,
-            Arc::clone(&cfg),
-        );
-        box_try!(self.snap_worker.start(snap_runner));
-        self.grpc_server.start();
-
-        let mut load_stats = {
-            let tl = Arc::clone(&self.thread_load);
-            ThreadLoadStatistics::new(LOAD_STATISTICS_SLOTS, GRPC_THREAD_PREFIX, tl)
-        };
-        self.stats_runtime.executor().spawn(
-            Interval::new(Instant::now(), LOAD_STATISTICS_INTERVAL)
-                .map_err(|_| ())
-                .for_each(move |i| {
-                    load_stats.record(i);
-                    Ok(())
-                }),
-        );
-
-        info!("TiKV is ready to serve");
-        Ok(())
-    }
-
-    pub fn stop(&mut self) -> Result<()> {
-        self.snap_worker.stop();
-        self.grpc_server.shutdown();
-        Ok(())
-    }
-
-    // Return listening address, this may only be used for outer test
-    // to get the real address because we may use "127.0.0.1:0"
-    // in test to avoid port conflict.
-    pub fn listening_addr(&self) -> SocketAddr {
-        self.local_addr
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fmt::{self, Debug, Display, Formatter};
     use std::sync::atomic::*;
     use std::sync::mpsc::*;
+    use std::result;
     use std::sync::*;
+    use std::thread;
     use std::time::Duration;
+    use std::u64;
+
+    use futures::{future, Future, Sink};
+    use grpc::{
+        CallOption, Client, Error as GrpcError, RpcContext, RpcStatus, RpcStatusCode, WriteFlags,
+    };
+    use kvproto::kvrpcpb::{Context, GetRequest, ScanRequest};
+    use tempdir::TempDir;
 
     use super::*;
 
     use super::super::resolve::{Callback as ResolveCallback, StoreAddrResolver};
     use super::super::transport::RaftStoreRouter;
-    use super::super::{Config, Result};
+    use super::super::config::RaftMsgCodec;
+    use super::super::raft_codec::raft_method;
+    use super::super::{Config, Error, Result};
     use coprocessor;
     use kvproto::raft_serverpb::RaftMessage;
+    use raft::eraftpb::Entry;
     use raftstore::store::transport::Transport;
     use raftstore::store::Msg as StoreMsg;
     use raftstore::store::*;
     use raftstore::Result as RaftStoreResult;
+    use server::metrics::{
+        RAFT_MESSAGE_CODEC_SIZE_HISTOGRAM_VEC, RESOLVE_STORE_DURATION, SNAP_DRAINING_GAUGE,
+        SNAP_RECEIVING_GAUGE,
+    };
     use server::readpool::{self, ReadPool};
-    use storage::TestStorageBuilder;
+    use storage::engine;
+    use storage::{Modify, RocksEngine, TestEngineBuilder, TestStorageBuilder};
+    use util::collections::HashSet;
+    use util::config::{ReadableDuration, ReadableSize};
     use util::security::SecurityConfig;
     use util::worker::FutureWorker;
 
     #[derive(Clone)]
     struct MockResolver {
         quick_fail: Arc<AtomicBool>,
+        // Never finishes the resolution if set.
+        hang: Arc<AtomicBool>,
+        tombstone: Arc<AtomicBool>,
         addr: Arc<Mutex<Option<String>>>,
     }
 
     impl StoreAddrResolver for MockResolver {
-        fn resolve(&self, _: u64, cb: ResolveCallback) -> Result<()> {
+        fn resolve(&self, store_id: u64, cb: ResolveCallback) -> Result<()> {
             if self.quick_fail.load(Ordering::SeqCst) {
                 return Err(box_err!("quick fail"));
             }
+            if self.hang.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if self.tombstone.load(Ordering::SeqCst) {
+                cb(Err(Error::StoreTombstone(store_id)));
+                return Ok(());
+            }
             let addr = self.addr.lock().unwrap();
             cb(addr
                 .as_ref()
@@ -265,10 +848,16 @@ mod tests {
         }
     }
 
-    fn is_unreachable_to(msg: &SignificantMsg, region_id: u64, to_peer_id: u64) -> bool {
+    fn is_unreachable_to(
+        msg: &SignificantMsg,
+        region_id: u64,
+        to_peer_id: u64,
+        reason: UnreachableReason,
+    ) -> bool {
         *msg == SignificantMsg::Unreachable {
             region_id,
             to_peer_id,
+            reason,
         }
     }
 
@@ -277,6 +866,7 @@ mod tests {
     fn test_peer_resolve() {
         let mut cfg = Config::default();
         cfg.addr = "127.0.0.1:0".to_owned();
+        cfg.max_raft_msg_size = ReadableSize::kb(1);
 
         let storage = TestStorageBuilder::new().build().unwrap();
 
@@ -288,6 +878,7 @@ mod tests {
         };
 
         let quick_fail = Arc::new(AtomicBool::new(false));
+        let hang = Arc::new(AtomicBool::new(false));
         let cfg = Arc::new(cfg);
         let security_mgr = Arc::new(SecurityManager::new(&SecurityConfig::default()).unwrap());
 
@@ -308,32 +899,81 @@ mod tests {
             router,
             MockResolver {
                 quick_fail: Arc::clone(&quick_fail),
+                hang: Arc::clone(&hang),
+                tombstone: Arc::new(AtomicBool::new(false)),
                 addr: Arc::clone(&addr),
             },
             SnapManager::new("", None),
             None,
             None,
+            vec![],
         ).unwrap();
 
         server.start(cfg, security_mgr).unwrap();
 
         let mut trans = server.transport();
-        trans.report_unreachable(RaftMessage::new());
+        trans.report_unreachable(RaftMessage::new(), UnreachableReason::Unknown);
         let mut resp = significant_msg_receiver.try_recv().unwrap();
-        assert!(is_unreachable_to(&resp, 0, 0), "{:?}", resp);
+        assert!(
+            is_unreachable_to(&resp, 0, 0, UnreachableReason::Unknown),
+            "{:?}",
+            resp
+        );
+        server.raft_router.report_unreachable(0, 0).unwrap();
+        resp = significant_msg_receiver.try_recv().unwrap();
+        assert!(
+            is_unreachable_to(&resp, 0, 0, UnreachableReason::Unknown),
+            "{:?}",
+            resp
+        );
+
+        let resolve_failed = RESOLVE_STORE_DURATION.with_label_values(&["failed"]);
+        let resolve_success = RESOLVE_STORE_DURATION.with_label_values(&["success"]);
+        let (failed_count, success_count) = (
+            resolve_failed.get_sample_count(),
+            resolve_success.get_sample_count(),
+        );
 
         let mut msg = RaftMessage::new();
         msg.set_region_id(1);
         trans.send(msg.clone()).unwrap();
         trans.flush();
+        assert!(resolve_failed.get_sample_count() > failed_count);
         resp = significant_msg_receiver.try_recv().unwrap();
-        assert!(is_unreachable_to(&resp, 1, 0), "{:?}", resp);
+        assert!(
+            is_unreachable_to(&resp, 1, 0, UnreachableReason::ResolveFailed),
+            "{:?}",
+            resp
+        );
 
         *addr.lock().unwrap() = Some(format!("{}", server.listening_addr()));
 
         trans.send(msg.clone()).unwrap();
         trans.flush();
         assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(resolve_success.get_sample_count() > success_count);
+
+        // Oversized messages are refused rather than sent.
+        let mut large_msg = msg.clone();
+        let mut entry = Entry::new();
+        entry.set_data(vec![0; 2048]);
+        large_msg.mut_message().mut_entries().push(entry);
+        trans.send(large_msg).unwrap();
+        trans.flush();
+        resp = significant_msg_receiver.try_recv().unwrap();
+        assert!(
+            is_unreachable_to(&resp, 1, 0, UnreachableReason::MessageTooLarge),
+            "{:?}",
+            resp
+        );
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let (ping_tx, ping_rx) = channel();
+        trans.ping_store(0, box move |res: Result<Duration>| {
+            ping_tx.send(res.is_ok()).unwrap()
+        });
+        assert!(ping_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        trans.ping_store(3, box move |res: Result<Duration>| assert!(res.is_err()));
 
         msg.mut_to_peer().set_store_id(2);
         msg.set_region_id(2);
@@ -341,7 +981,559 @@ mod tests {
         trans.send(msg.clone()).unwrap();
         trans.flush();
         resp = significant_msg_receiver.try_recv().unwrap();
-        assert!(is_unreachable_to(&resp, 2, 0), "{:?}", resp);
+        assert!(
+            is_unreachable_to(&resp, 2, 0, UnreachableReason::ResolveFailed),
+            "{:?}",
+            resp
+        );
+
+        // Messages are dropped while the store address is being resolved.
+        msg.mut_to_peer().set_store_id(3);
+        msg.set_region_id(3);
+        quick_fail.store(false, Ordering::SeqCst);
+        hang.store(true, Ordering::SeqCst);
+        trans.send(msg.clone()).unwrap();
+        trans.flush();
+        assert!(significant_msg_receiver.try_recv().is_err());
+        trans.send(msg.clone()).unwrap();
+        trans.flush();
+        resp = significant_msg_receiver.try_recv().unwrap();
+        assert!(
+            is_unreachable_to(&resp, 3, 0, UnreachableReason::Resolving),
+            "{:?}",
+            resp
+        );
+
+        // The old resolver still fails quickly, but the new one is used after swapping,
+        // by the transport cloned before as well.
+        let tombstone = Arc::new(AtomicBool::new(false));
+        server.set_resolver(MockResolver {
+            quick_fail: Arc::new(AtomicBool::new(false)),
+            hang: Arc::new(AtomicBool::new(false)),
+            tombstone: Arc::clone(&tombstone),
+            addr: Arc::new(Mutex::new(Some(format!("{}", server.listening_addr())))),
+        });
+        quick_fail.store(true, Ordering::SeqCst);
+        msg.mut_to_peer().set_store_id(2);
+        msg.set_region_id(2);
+        trans.send(msg.clone()).unwrap();
+        trans.flush();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(significant_msg_receiver.try_recv().is_err());
+
+        // Once a store is known to be tombstone, messages to it are dropped without
+        // resolving its address again.
+        msg.mut_to_peer().set_store_id(4);
+        msg.set_region_id(4);
+        tombstone.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            trans.send(msg.clone()).unwrap();
+            trans.flush();
+            resp = significant_msg_receiver.try_recv().unwrap();
+            assert!(
+                is_unreachable_to(&resp, 4, 0, UnreachableReason::StoreTombstone),
+                "{:?}",
+                resp
+            );
+            tombstone.store(false, Ordering::SeqCst);
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        server.stop().unwrap();
+    }
+
+    fn start_test_server(
+        cfg: Config,
+        interceptors: Vec<Box<ServerInterceptor>>,
+    ) -> Server<TestRaftStoreRouter, MockResolver> {
+        start_test_server_with(cfg, interceptors, None, SnapManager::new("", None))
+    }
+
+    fn start_test_server_with(
+        cfg: Config,
+        interceptors: Vec<Box<ServerInterceptor>>,
+        live_store_source: Option<Box<LiveStoreSource>>,
+        snap_mgr: SnapManager,
+    ) -> Server<TestRaftStoreRouter, MockResolver> {
+        let engine = TestEngineBuilder::new().build().unwrap();
+        start_test_server_on(engine, cfg, interceptors, live_store_source, snap_mgr)
+    }
+
+    fn start_test_server_on<E: Engine>(
+        engine: E,
+        mut cfg: Config,
+        interceptors: Vec<Box<ServerInterceptor>>,
+        live_store_source: Option<Box<LiveStoreSource>>,
+        snap_mgr: SnapManager,
+    ) -> Server<TestRaftStoreRouter, MockResolver> {
+        if cfg.addr == Config::default().addr {
+            cfg.addr = "127.0.0.1:0".to_owned();
+        }
+
+        let storage = TestStorageBuilder::from_engine(engine).build().unwrap();
+
+        let (tx, _rx) = mpsc::channel();
+        let (significant_msg_sender, _significant_msg_receiver) = mpsc::channel();
+        let router = TestRaftStoreRouter {
+            tx,
+            significant_msg_sender,
+        };
+
+        let cfg = Arc::new(cfg);
+        let security_mgr = Arc::new(SecurityManager::new(&SecurityConfig::default()).unwrap());
+
+        let pd_worker = FutureWorker::new("test-pd-worker");
+        let cop_read_pool = ReadPool::new(
+            "cop-readpool",
+            &readpool::Config::default_for_test(),
+            || || coprocessor::ReadPoolContext::new(pd_worker.scheduler()),
+        );
+        let cop = coprocessor::Endpoint::new(&cfg, storage.get_engine(), cop_read_pool);
+
+        let mut builder = Server::builder()
+            .cfg(Arc::clone(&cfg))
+            .security_mgr(Arc::clone(&security_mgr))
+            .storage(storage)
+            .cop(cop)
+            .raft_router(router)
+            .resolver(MockResolver {
+                quick_fail: Arc::new(AtomicBool::new(false)),
+                hang: Arc::new(AtomicBool::new(false)),
+                tombstone: Arc::new(AtomicBool::new(false)),
+                addr: Arc::new(Mutex::new(None)),
+            })
+            .snap_mgr(snap_mgr)
+            .interceptors(interceptors);
+        if let Some(source) = live_store_source {
+            builder = builder.live_store_source(source);
+        }
+        let mut server = builder.build().unwrap();
+        server.start(cfg, security_mgr).unwrap();
+        server
+    }
+
+    #[test]
+    fn test_builder_missing_component() {
+        let storage = TestStorageBuilder::new().build().unwrap();
+        let res = Server::<TestRaftStoreRouter, MockResolver>::builder()
+            .cfg(Arc::new(Config::default()))
+            .storage(storage)
+            .build();
+        match res {
+            Err(e) => assert!(format!("{:?}", e).contains("security_mgr is required"), "{:?}", e),
+            Ok(_) => panic!("expect missing security_mgr"),
+        }
+    }
+
+    type SnapCallback = engine::Callback<<RocksEngine as Engine>::Snap>;
+
+    /// Holds the snapshots until `release` is called, to make requests slow.
+    #[derive(Clone)]
+    struct SlowEngine {
+        engine: RocksEngine,
+        pending: Arc<Mutex<Vec<(Context, SnapCallback)>>>,
+    }
+
+    impl SlowEngine {
+        fn release(&self) {
+            for (ctx, cb) in self.pending.lock().unwrap().drain(..) {
+                self.engine.async_snapshot(&ctx, cb).unwrap();
+            }
+        }
+    }
+
+    impl Display for SlowEngine {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "SlowEngine")
+        }
+    }
+
+    impl Debug for SlowEngine {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "SlowEngine")
+        }
+    }
+
+    impl Engine for SlowEngine {
+        type Iter = <RocksEngine as Engine>::Iter;
+        type Snap = <RocksEngine as Engine>::Snap;
+
+        fn async_write(
+            &self,
+            ctx: &Context,
+            batch: Vec<Modify>,
+            callback: engine::Callback<()>,
+        ) -> engine::Result<()> {
+            self.engine.async_write(ctx, batch, callback)
+        }
+
+        fn async_snapshot(&self, ctx: &Context, callback: SnapCallback) -> engine::Result<()> {
+            self.pending.lock().unwrap().push((ctx.clone(), callback));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let engine = SlowEngine {
+            engine: TestEngineBuilder::new().build().unwrap(),
+            pending: Arc::default(),
+        };
+        let snap_mgr = SnapManager::new("", None);
+        let mut server =
+            start_test_server_on(engine.clone(), Config::default(), vec![], None, snap_mgr);
+
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        // A slow request which finishes after 300ms.
+        let receiver = client.kv_get_async(&GetRequest::new()).unwrap();
+        for _ in 0..100 {
+            if server.in_flight.count() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.in_flight.count(), 1);
+        let finished = Arc::new(AtomicBool::new(false));
+        let finished1 = Arc::clone(&finished);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            finished1.store(true, Ordering::SeqCst);
+            engine.release();
+        });
+
+        server.stop().unwrap();
+        assert!(server.in_flight.is_closing());
+        assert!(finished.load(Ordering::SeqCst));
+        assert_eq!(server.in_flight.count(), 0);
+        // The request is answered before the server is shut down.
+        receiver.wait().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_ping_without_traffic() {
+        let mut server = start_test_server(Config::default(), vec![]);
+        let addr = format!("{}", server.listening_addr());
+        let mut cfg = Config::default();
+        cfg.grpc_connect_timeout = ReadableDuration::millis(100);
+        let env = Arc::new(Environment::new(1));
+        let security_mgr = Arc::new(SecurityManager::new(&SecurityConfig::default()).unwrap());
+        let mut client = RaftClient::new(env, Arc::new(cfg), security_mgr);
+        let ping = |client: &mut RaftClient| {
+            let (tx, rx) = mpsc::channel();
+            client.ping(1, &addr, box move |res: Result<Duration>| {
+                tx.send(res.is_ok()).unwrap()
+            });
+            assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        };
+
+        // The connection only used by the ping is kept after the connect timeout.
+        ping(&mut client);
+        thread::sleep(Duration::from_millis(100));
+        client.flush();
+        ping(&mut client);
+        client.flush();
+        let (uptime, reconnects) = client.conn_stats(1);
+        assert!(uptime.is_some());
+        assert_eq!(reconnects, 0);
+        server.stop().unwrap();
+    }
+
+    struct DenyInterceptor(&'static str);
+
+    impl ServerInterceptor for DenyInterceptor {
+        fn intercept(&self, _: &RpcContext, method: &str) -> result::Result<(), RpcStatus> {
+            if method == self.0 {
+                return Err(RpcStatus::new(RpcStatusCode::PermissionDenied, None));
+            }
+            Ok(())
+        }
+    }
+
+    struct MockLiveStoreSource {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl LiveStoreSource for MockLiveStoreSource {
+        fn live_stores(&self) -> Result<HashSet<u64>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![1].into_iter().collect())
+        }
+    }
+
+    #[test]
+    fn test_live_store_source() {
+        let mut cfg = Config::default();
+        cfg.raft_client_gc_interval = ReadableDuration::millis(10);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source = MockLiveStoreSource {
+            calls: Arc::clone(&calls),
+        };
+        let snap_mgr = SnapManager::new("", None);
+        let mut server = start_test_server_with(cfg, vec![], Some(box source), snap_mgr);
+        let start = Instant::now();
+        while calls.load(Ordering::SeqCst) < 2 {
+            if start.elapsed() > Duration::from_secs(5) {
+                panic!("live stores are got {} times", calls.load(Ordering::SeqCst));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        server.stop().unwrap();
+
+        // The live stores are never got if the interval is 0.
+        let mut cfg = Config::default();
+        cfg.raft_client_gc_interval = ReadableDuration::secs(0);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source = MockLiveStoreSource {
+            calls: Arc::clone(&calls),
+        };
+        let snap_mgr = SnapManager::new("", None);
+        let mut server = start_test_server_with(cfg, vec![], Some(box source), snap_mgr);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn test_bulk_server() {
+        let mut server = start_test_server(Config::default(), vec![]);
+        assert!(server.bulk_listening_addr().is_none());
+        server.stop().unwrap();
+
+        let mut cfg = Config::default();
+        cfg.bulk_addr = "127.0.0.1:0".to_owned();
+        let mut server = start_test_server(cfg, vec![]);
+        let bulk_addr = server.bulk_listening_addr().unwrap();
+        assert_ne!(bulk_addr, server.listening_addr());
+
+        // The KV service is not served on the bulk address.
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", bulk_addr);
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        match client.kv_get(&GetRequest::new()) {
+            Err(GrpcError::RpcFailure(ref s)) if s.status == RpcStatusCode::Unimplemented => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        server.stop().unwrap();
+        assert!(server.bulk_listening_addr().is_none());
+    }
+
+    #[test]
+    fn test_multiple_listening_addrs() {
+        let mut cfg = Config::default();
+        cfg.addr = "127.0.0.1:0, 127.0.0.1:0".to_owned();
+        let mut server = start_test_server(cfg, vec![]);
+        let addrs = server.listening_addrs().to_vec();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(server.listening_addr(), addrs[0]);
+        // Each address with port 0 gets a port of its own.
+        assert_ne!(addrs[0], addrs[1]);
+        for addr in addrs {
+            assert_ne!(addr.port(), 0);
+            let env = Arc::new(Environment::new(1));
+            let addr = format!("{}", addr);
+            let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+            client.kv_scan(&ScanRequest::new()).unwrap();
+        }
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn test_raft_msg_codec() {
+        let mut server = start_test_server(Config::default(), vec![]);
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = Client::new(ChannelBuilder::new(env).connect(&addr));
+        let decode = RAFT_MESSAGE_CODEC_SIZE_HISTOGRAM_VEC.with_label_values(&["decode"]);
+        let count = decode.get_sample_count();
+
+        // Stores receive with the codecs other than the configured one.
+        let method = raft_method(RaftMsgCodec::SizeRecording);
+        let (sink, receiver) = client
+            .client_streaming(&method, CallOption::default())
+            .unwrap();
+        let mut sink = sink
+            .send((RaftMessage::new(), WriteFlags::default()))
+            .wait()
+            .unwrap();
+        future::poll_fn(|| sink.close()).wait().unwrap();
+        let _ = receiver.wait();
+        assert!(decode.get_sample_count() > count);
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn test_interceptor() {
+        let interceptors: Vec<Box<ServerInterceptor>> = vec![box DenyInterceptor("kv_get")];
+        let mut server = start_test_server(Config::default(), interceptors);
+
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        match client.kv_get(&GetRequest::new()) {
+            Err(GrpcError::RpcFailure(ref s)) if s.status == RpcStatusCode::PermissionDenied => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        client.kv_scan(&ScanRequest::new()).unwrap();
+
+        server.stop().unwrap();
+    }
+
+    fn wait_for_receiving_snaps(count: i64) {
+        let start = Instant::now();
+        while SNAP_RECEIVING_GAUGE.get() != count {
+            if start.elapsed() > Duration::from_secs(5) {
+                panic!("receiving {} snapshots", SNAP_RECEIVING_GAUGE.get());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_recv_snap_limit() {
+        let mut server = start_test_server(Config::default(), vec![]);
+        server.set_concurrent_recv_snap_limit(0).unwrap_err();
+        server.set_concurrent_recv_snap_limit(2).unwrap();
+
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        let mut streams = vec![];
+        for i in 0..2 {
+            streams.push(client.snapshot().unwrap());
+            wait_for_receiving_snaps(i + 1);
+        }
+
+        // The third snapshot exceeds the limit.
+        let (_sink, receiver) = client.snapshot().unwrap();
+        match receiver.wait() {
+            Err(GrpcError::RpcFailure(ref s)) if s.status == RpcStatusCode::ResourceExhausted => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // The new limit takes effect immediately.
+        server.set_concurrent_recv_snap_limit(3).unwrap();
+        streams.push(client.snapshot().unwrap());
+        wait_for_receiving_snaps(3);
+
+        // Empty snapshot streams fail once they are closed.
+        for (mut sink, receiver) in streams {
+            future::poll_fn(|| sink.close()).wait().unwrap();
+            receiver.wait().unwrap_err();
+        }
+        wait_for_receiving_snaps(0);
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn test_snap_disk_full() {
+        let dir = TempDir::new("test-snap-disk-full").unwrap();
+        // No disk has so much space.
+        let snap_mgr = SnapManagerBuilder::default()
+            .min_available_space(u64::MAX)
+            .build(dir.path().to_str().unwrap(), None);
+        let mut server = start_test_server_with(Config::default(), vec![], None, snap_mgr);
+
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        let (_sink, refused) = client.snapshot().unwrap();
+        match refused.wait() {
+            Err(GrpcError::RpcFailure(ref s)) if s.status == RpcStatusCode::ResourceExhausted => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn test_snap_draining() {
+        let mut server = start_test_server(Config::default(), vec![]);
+
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        let (mut sink, receiver) = client.snapshot().unwrap();
+        wait_for_receiving_snaps(1);
+
+        server.set_snap_draining(true).unwrap();
+        let (_sink, refused) = client.snapshot().unwrap();
+        match refused.wait() {
+            Err(GrpcError::RpcFailure(ref s)) if s.status == RpcStatusCode::Unavailable => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(SNAP_DRAINING_GAUGE.get(), 1);
+        let (tx, rx) = mpsc::channel();
+        let task = SnapTask::Send {
+            addr: addr.clone(),
+            msg: RaftMessage::new(),
+            cb: box move |res| tx.send(res).unwrap(),
+        };
+        server.snap_worker.schedule(task).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap_err();
+
+        // The receiving snapshot is left to finish, and an empty one fails once closed.
+        future::poll_fn(|| sink.close()).wait().unwrap();
+        receiver.wait().unwrap_err();
+        wait_for_receiving_snaps(0);
+
+        server.set_snap_draining(false).unwrap();
+        let (mut sink, receiver) = client.snapshot().unwrap();
+        wait_for_receiving_snaps(1);
+        future::poll_fn(|| sink.close()).wait().unwrap();
+        receiver.wait().unwrap_err();
+        wait_for_receiving_snaps(0);
+        assert_eq!(SNAP_DRAINING_GAUGE.get(), 0);
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn test_resolve_listening_addr() {
+        let addr = resolve_listening_addr("addr", "127.0.0.1:20160").unwrap();
+        assert_eq!(addr, SocketAddr::from_str("127.0.0.1:20160").unwrap());
+        let addr = resolve_listening_addr("addr", "[::1]:20160").unwrap();
+        assert_eq!(addr, SocketAddr::from_str("[::1]:20160").unwrap());
+        let addr = resolve_listening_addr("addr", "::1:20160").unwrap();
+        assert_eq!(addr, SocketAddr::from_str("[::1]:20160").unwrap());
+        let addr = resolve_listening_addr("addr", "localhost:20160").unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 20160);
+
+        for invalid in &["localhost", "127.0.0.1:port", "localhost:65536"] {
+            let e = resolve_listening_addr("server.addr", invalid).unwrap_err();
+            assert!(format!("{}", e).contains("server.addr"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_client_rate_limit() {
+        let mut cfg = Config::default();
+        cfg.max_requests_per_sec_per_client = 5;
+        let mut server = start_test_server(cfg, vec![]);
+
+        let env = Arc::new(Environment::new(1));
+        let addr = format!("{}", server.listening_addr());
+        let client = TikvClient::new(ChannelBuilder::new(env).connect(&addr));
+        let mut limited = 0;
+        for _ in 0..20 {
+            match client.kv_get(&GetRequest::new()) {
+                Ok(_) => {}
+                Err(GrpcError::RpcFailure(s)) => {
+                    assert_eq!(s.status, RpcStatusCode::ResourceExhausted);
+                    limited += 1;
+                }
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+        // Some tokens may be refilled while sending the requests.
+        assert!(limited >= 10, "{} requests are limited", limited);
+
         server.stop().unwrap();
     }
 }
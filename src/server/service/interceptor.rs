@@ -0,0 +1,320 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use grpc::{RpcContext, RpcStatus, RpcStatusCode};
+
+use server::metrics::*;
+use util::collections::{HashMap, HashSet};
+use util::time::duration_to_nanos;
+use util::HandyRwLock;
+
+// Clients idle for so long are forgotten by `evict_idle_clients`.
+const CLIENT_IDLE_DURATION: Duration = Duration::from_secs(60);
+// Once so many clients are tracked, the least active one is forgotten for a new one.
+const MAX_CLIENTS: usize = 4096;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// A bucket holds at most this long of requests.
+const BURST_NANOS: u64 = NANOS_PER_SEC;
+
+/// A hook invoked before a KV or coprocessor request is handled, for cross-cutting
+/// policies such as request logging, rate limiting or authentication.
+pub trait ServerInterceptor: Send + Sync {
+    /// Checks the request `method` (e.g. "kv_get") of `ctx`. Returning an error refuses
+    /// the request with the status, and the remaining interceptors are skipped.
+    fn intercept(&self, ctx: &RpcContext, method: &str) -> result::Result<(), RpcStatus>;
+}
+
+/// `InterceptorChain` runs the interceptors in the order they are given.
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Arc<Vec<Box<ServerInterceptor>>>,
+}
+
+impl InterceptorChain {
+    pub fn new(interceptors: Vec<Box<ServerInterceptor>>) -> InterceptorChain {
+        InterceptorChain {
+            interceptors: Arc::new(interceptors),
+        }
+    }
+
+    pub fn intercept(&self, ctx: &RpcContext, method: &str) -> result::Result<(), RpcStatus> {
+        for interceptor in self.interceptors.iter() {
+            interceptor.intercept(ctx, method)?;
+        }
+        Ok(())
+    }
+}
+
+// The token bucket of a client, kept as the time its tokens are refilled up to, in
+// nanoseconds since `ClientRateLimiter::base`, like the buckets of `RegionQuota`.
+struct ClientBucket {
+    full_at: AtomicUsize,
+}
+
+/// `ClientRateLimiter` limits the requests per second of each client IP with a token
+/// bucket holding at most one second of requests. At most `MAX_CLIENTS` clients are
+/// tracked.
+#[derive(Clone)]
+pub struct ClientRateLimiter {
+    // The interval of a request in nanoseconds.
+    interval: usize,
+    base: Instant,
+    // Requests of known clients only update the atomics of their buckets under the read
+    // lock, so that clients don't contend with each other.
+    clients: Arc<RwLock<HashMap<String, ClientBucket>>>,
+}
+
+impl ClientRateLimiter {
+    pub fn new(requests_per_sec: u64) -> ClientRateLimiter {
+        ClientRateLimiter {
+            interval: (NANOS_PER_SEC / requests_per_sec.max(1)).max(1) as usize,
+            base: Instant::now(),
+            clients: Arc::new(RwLock::new(HashMap::default())),
+        }
+    }
+
+    /// Forgets the clients whose buckets have been full for a while. A forgotten client
+    /// starts with a full bucket, as it would have anyway.
+    pub fn evict_idle_clients(&self, now: Instant) {
+        let now = self.nanos_since_base(now);
+        let idle_nanos = duration_to_nanos(CLIENT_IDLE_DURATION) as usize;
+        self.clients
+            .wl()
+            .retain(|_, b| b.full_at.load(Ordering::Relaxed) + idle_nanos > now);
+    }
+
+    fn nanos_since_base(&self, now: Instant) -> usize {
+        duration_to_nanos(now.duration_since(self.base)) as usize
+    }
+
+    fn allow(&self, client: &str, now: Instant) -> bool {
+        let now = self.nanos_since_base(now);
+        let allowed = {
+            let clients = self.clients.rl();
+            clients.get(client).map(|b| self.take(b, now))
+        };
+        match allowed {
+            Some(allowed) => allowed,
+            None => self.take_new(client, now),
+        }
+    }
+
+    // Takes a token from `bucket`. Returns false if it has none left.
+    fn take(&self, bucket: &ClientBucket, now: usize) -> bool {
+        let mut full_at = bucket.full_at.load(Ordering::Relaxed);
+        loop {
+            let next = full_at.max(now) + self.interval;
+            if next - now > BURST_NANOS as usize {
+                return false;
+            }
+            let prev = bucket
+                .full_at
+                .compare_and_swap(full_at, next, Ordering::Relaxed);
+            if prev == full_at {
+                return true;
+            }
+            full_at = prev;
+        }
+    }
+
+    // Creates the bucket of a client seen for the first time, which starts full, and takes
+    // a token from it.
+    fn take_new(&self, client: &str, now: usize) -> bool {
+        let mut clients = self.clients.wl();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(client) {
+            // The bucket refilled the earliest belongs to the least active client.
+            let least_active = clients
+                .iter()
+                .min_by_key(|&(_, b)| b.full_at.load(Ordering::Relaxed))
+                .map(|(c, _)| c.clone());
+            if let Some(c) = least_active {
+                clients.remove(&c);
+            }
+        }
+        let bucket = clients
+            .entry(client.to_owned())
+            .or_insert_with(|| ClientBucket {
+                full_at: AtomicUsize::new(now),
+            });
+        self.take(bucket, now)
+    }
+}
+
+// Strips the port from peers like "ipv4:127.0.0.1:20160", so that all connections of
+// a client share one bucket.
+fn client_ip(peer: &str) -> &str {
+    peer.rsplitn(2, ':').last().unwrap_or(peer)
+}
+
+impl ServerInterceptor for ClientRateLimiter {
+    fn intercept(&self, ctx: &RpcContext, method: &str) -> result::Result<(), RpcStatus> {
+        let peer = ctx.peer();
+        let client = client_ip(&peer);
+        if self.allow(client, Instant::now()) {
+            return Ok(());
+        }
+        GRPC_RATE_LIMITED_COUNTER_VEC.with_label_values(&[method]).inc();
+        Err(RpcStatus::new(
+            RpcStatusCode::ResourceExhausted,
+            Some(format!("{} exceeds the request rate limit", client)),
+        ))
+    }
+}
+
+/// `MemoryShedder` refuses the requests of the given methods while the process is under
+/// memory pressure, leaving the memory to the requests being handled, and to cheap ones
+/// like point gets, before the process is killed for running out of memory.
+///
+/// Shedding starts once the memory used exceeds `high_water`, and stops once it drops
+/// below `low_water`, so that it doesn't flip on every sample around the threshold.
+#[derive(Clone)]
+pub struct MemoryShedder {
+    high_water: f64,
+    low_water: f64,
+    shed_methods: Arc<HashSet<String>>,
+    shedding: Arc<AtomicBool>,
+}
+
+impl MemoryShedder {
+    /// `high_water` and `low_water` are fractions of the system memory. A `low_water`
+    /// of 0 means the same as `high_water`.
+    pub fn new(high_water: f64, low_water: f64, shed_methods: Vec<String>) -> MemoryShedder {
+        MemoryShedder {
+            high_water,
+            low_water: if low_water > 0.0 { low_water } else { high_water },
+            shed_methods: Arc::new(shed_methods.into_iter().collect()),
+            shedding: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Updates the pressure with `usage`, the fraction of the system memory used by the
+    /// process.
+    pub fn on_memory_usage(&self, usage: f64) {
+        MEMORY_USAGE_GAUGE.set(usage);
+        let shedding = self.shedding.load(Ordering::Relaxed);
+        if !shedding && usage >= self.high_water {
+            warn!(
+                "memory usage {:.3} exceeds {}, start shedding {:?}",
+                usage, self.high_water, self.shed_methods
+            );
+            self.shedding.store(true, Ordering::Relaxed);
+        } else if shedding && usage < self.low_water {
+            info!("memory usage {:.3} drops below {}, stop shedding", usage, self.low_water);
+            self.shedding.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn should_shed(&self, method: &str) -> bool {
+        self.shedding.load(Ordering::Relaxed) && self.shed_methods.contains(method)
+    }
+}
+
+impl ServerInterceptor for MemoryShedder {
+    fn intercept(&self, _: &RpcContext, method: &str) -> result::Result<(), RpcStatus> {
+        if !self.should_shed(method) {
+            return Ok(());
+        }
+        GRPC_MEMORY_SHED_COUNTER_VEC.with_label_values(&[method]).inc();
+        Err(RpcStatus::new(
+            RpcStatusCode::ResourceExhausted,
+            Some("server is under memory pressure".to_owned()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip() {
+        assert_eq!(client_ip("ipv4:127.0.0.1:20160"), "ipv4:127.0.0.1");
+        assert_eq!(client_ip("ipv6:[::1]:20160"), "ipv6:[::1]");
+        assert_eq!(client_ip("unknown"), "unknown");
+    }
+
+    #[test]
+    fn test_client_rate_limiter() {
+        let limiter = ClientRateLimiter::new(10);
+        let now = Instant::now();
+        let allowed = (0..100).filter(|_| limiter.allow("a", now)).count();
+        assert_eq!(allowed, 10);
+        // Other clients are not affected.
+        assert!(limiter.allow("b", now));
+
+        // Tokens are refilled over time.
+        let later = now + Duration::from_millis(500);
+        let allowed = (0..100).filter(|_| limiter.allow("a", later)).count();
+        assert_eq!(allowed, 5);
+
+        // Only the idle clients are evicted, which are idle since their buckets are full.
+        let later = now + Duration::from_secs(1) + CLIENT_IDLE_DURATION;
+        limiter.evict_idle_clients(later);
+        assert_eq!(limiter.clients.rl().len(), 1);
+        // An evicted client starts with a full bucket.
+        let allowed = (0..100).filter(|_| limiter.allow("b", later)).count();
+        assert_eq!(allowed, 10);
+        let later = later + Duration::from_secs(1) + CLIENT_IDLE_DURATION;
+        limiter.evict_idle_clients(later);
+        assert!(limiter.clients.rl().is_empty());
+    }
+
+    #[test]
+    fn test_client_rate_limiter_max_clients() {
+        let limiter = ClientRateLimiter::new(10);
+        let now = Instant::now();
+        for i in 0..MAX_CLIENTS {
+            let client = format!("client-{}", i);
+            assert!(limiter.allow(&client, now + Duration::from_millis(i as u64)));
+        }
+        assert_eq!(limiter.clients.rl().len(), MAX_CLIENTS);
+
+        // A new client takes the place of the least active one.
+        assert!(limiter.allow("new", now + Duration::from_secs(10)));
+        let clients = limiter.clients.rl();
+        assert_eq!(clients.len(), MAX_CLIENTS);
+        assert!(clients.contains_key("new"));
+        assert!(!clients.contains_key("client-0"));
+    }
+
+    #[test]
+    fn test_memory_shedder() {
+        let methods = vec!["coprocessor".to_owned(), "kv_scan".to_owned()];
+        let shedder = MemoryShedder::new(0.9, 0.8, methods);
+        shedder.on_memory_usage(0.5);
+        assert!(!shedder.should_shed("coprocessor"));
+
+        // Expensive requests are refused under pressure, while point gets still pass.
+        shedder.clone().shedding.store(true, Ordering::SeqCst);
+        assert!(shedder.should_shed("coprocessor"));
+        assert!(shedder.should_shed("kv_scan"));
+        assert!(!shedder.should_shed("kv_get"));
+
+        // Shedding stops only after the usage drops below the low water.
+        shedder.on_memory_usage(0.85);
+        assert!(shedder.should_shed("coprocessor"));
+        shedder.on_memory_usage(0.7);
+        assert!(!shedder.should_shed("coprocessor"));
+        shedder.on_memory_usage(0.85);
+        assert!(!shedder.should_shed("coprocessor"));
+        shedder.on_memory_usage(0.95);
+        assert!(shedder.should_shed("coprocessor"));
+        assert_eq!(MEMORY_USAGE_GAUGE.get(), 0.95);
+    }
+}
@@ -11,7 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::{Future, Sink, Stream};
+use futures::{Future, Stream};
 use grpc::{
     ClientStreamingSink, Error as GrpcError, RequestStream, RpcContext, RpcStatus, RpcStatusCode,
     ServerStreamingSink, UnarySink, WriteFlags,
@@ -24,10 +24,15 @@ use kvproto::raft_serverpb::*;
 use kvproto::tikvpb_grpc;
 use protobuf::RepeatedField;
 use std::iter::{self, FromIterator};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use coprocessor::Endpoint;
 use raftstore::store::{Callback, Msg as StoreMessage};
 use server::metrics::*;
+use server::service::interceptor::InterceptorChain;
 use server::snap::Task as SnapTask;
 use server::transport::RaftStoreRouter;
 use server::Error;
@@ -36,11 +41,194 @@ use storage::mvcc::{Error as MvccError, LockType, Write as MvccWrite, WriteType}
 use storage::txn::Error as TxnError;
 use storage::{self, Engine, Key, Mutation, Options, Storage, Value};
 use util::collections::HashMap;
-use util::future::{paired_future_callback, AndThenWith};
+use util::future::{paired_future_callback, send_all_flushed, AndThenWith};
 use util::worker::Scheduler;
 
 const SCHEDULER_IS_BUSY: &str = "scheduler is busy";
 const GC_WORKER_IS_BUSY: &str = "gc worker is busy";
+const SERVER_IS_CLOSING: &str = "server is closing";
+
+/// Refuses the request with `Unavailable` if the server is closing, with the status
+/// returned by the interceptors, or with `ResourceExhausted` if its connection has too
+/// many requests in flight. Otherwise evaluates to the slot of the connection taken by
+/// the request.
+macro_rules! check_request {
+    ($self:ident, $ctx:ident, $sink:ident, $method:expr) => {{
+        if $self.in_flight.is_closing() {
+            let status = RpcStatus::new(
+                RpcStatusCode::Unavailable,
+                Some(SERVER_IS_CLOSING.to_owned()),
+            );
+            $ctx.spawn($sink.fail(status).map_err(|_| ()));
+            return;
+        }
+        if let Err(status) = $self.interceptors.intercept(&$ctx, $method) {
+            GRPC_INTERCEPTED_COUNTER_VEC.with_label_values(&[$method]).inc();
+            $ctx.spawn($sink.fail(status).map_err(|_| ()));
+            return;
+        }
+        match $self.in_flight.acquire(&$ctx) {
+            Ok(slot) => slot,
+            Err(status) => {
+                $ctx.spawn($sink.fail(status).map_err(|_| ()));
+                return;
+            }
+        }
+    }};
+}
+
+/// `InFlightRequests` tracks the KV and coprocessor requests being handled, so that the
+/// server can wait for them before shutting down, and so that a single connection can't
+/// take up all the threads with long requests.
+#[derive(Clone, Default)]
+pub struct InFlightRequests {
+    // The number of requests in flight, notified once it drops to 0.
+    count: Arc<(Mutex<usize>, Condvar)>,
+    closing: Arc<AtomicBool>,
+    max_per_connection: usize,
+    // peer of the connection -> requests in flight, only tracked with a limit.
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl InFlightRequests {
+    /// `max_per_connection` is how many requests a connection can have in flight, 0 for
+    /// no limit.
+    pub fn new(max_per_connection: usize) -> InFlightRequests {
+        InFlightRequests {
+            max_per_connection,
+            ..Default::default()
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        *(self.count.0).lock().unwrap()
+    }
+
+    /// Waits at most `timeout` for all the requests in flight to finish. Returns the number
+    /// of requests still in flight.
+    pub fn wait_idle(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let (ref count, ref idle) = *self.count;
+        let mut count = count.lock().unwrap();
+        while *count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            count = idle.wait_timeout(count, deadline - now).unwrap().0;
+        }
+        *count
+    }
+
+    /// Makes the service refuse any new requests.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Tracks `f` as an in-flight request until it's resolved or dropped.
+    pub fn track<F>(&self, f: F) -> impl Future<Item = (), Error = ()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        self.track_on(None, f)
+    }
+
+    // Like `track`, and also holds the connection `slot` until `f` is resolved or dropped.
+    fn track_on<F>(&self, slot: Option<ConnectionSlot>, f: F) -> impl Future<Item = (), Error = ()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        *(self.count.0).lock().unwrap() += 1;
+        GRPC_IN_FLIGHT_REQUESTS_GAUGE.inc();
+        let guard = InFlightGuard(Arc::clone(&self.count), slot);
+        f.then(move |res| {
+            drop(guard);
+            res
+        })
+    }
+
+    // Takes a slot of the connection of `ctx` for a new request. `None` means the
+    // connections are not limited.
+    fn acquire(&self, ctx: &RpcContext) -> result::Result<Option<ConnectionSlot>, RpcStatus> {
+        if self.max_per_connection == 0 {
+            return Ok(None);
+        }
+        let peer = ctx.peer();
+        if let Some(slot) = self.acquire_slot(peer.clone()) {
+            return Ok(Some(slot));
+        }
+        GRPC_CONN_BUSY_COUNTER.inc();
+        Err(RpcStatus::new(
+            RpcStatusCode::ResourceExhausted,
+            Some(format!("{} has too many requests in flight", peer)),
+        ))
+    }
+
+    fn acquire_slot(&self, peer: String) -> Option<ConnectionSlot> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(peer.clone()).or_insert(0);
+        if *count >= self.max_per_connection {
+            return None;
+        }
+        *count += 1;
+        GRPC_CONN_IN_FLIGHT_REQUESTS_GAUGE_VEC
+            .with_label_values(&[&peer])
+            .set(*count as i64);
+        Some(ConnectionSlot {
+            connections: Arc::clone(&self.connections),
+            peer,
+        })
+    }
+}
+
+// Releases a request slot of the connection `peer` when dropped.
+struct ConnectionSlot {
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+    peer: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        let left = match connections.get_mut(&self.peer) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return,
+        };
+        if left > 0 {
+            GRPC_CONN_IN_FLIGHT_REQUESTS_GAUGE_VEC
+                .with_label_values(&[&self.peer])
+                .set(left as i64);
+            return;
+        }
+        // Forget the connection once it's idle, as the peers of closed connections are
+        // never seen again.
+        connections.remove(&self.peer);
+        let _ = GRPC_CONN_IN_FLIGHT_REQUESTS_GAUGE_VEC.remove_label_values(&[&self.peer]);
+    }
+}
+
+// Decreases the in-flight count and releases the connection slot when the request
+// finishes or is dropped.
+struct InFlightGuard(Arc<(Mutex<usize>, Condvar)>, Option<ConnectionSlot>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let (ref count, ref idle) = *self.0;
+        let mut count = count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            idle.notify_all();
+        }
+        GRPC_IN_FLIGHT_REQUESTS_GAUGE.dec();
+    }
+}
 
 #[derive(Clone)]
 pub struct Service<T: RaftStoreRouter + 'static, E: Engine> {
@@ -52,6 +240,10 @@ pub struct Service<T: RaftStoreRouter + 'static, E: Engine> {
     ch: T,
     // For handling snapshot.
     snap_scheduler: Scheduler<SnapTask>,
+    // For waiting for requests when shutting down.
+    in_flight: InFlightRequests,
+    // Checked before handling requests.
+    interceptors: InterceptorChain,
 }
 
 impl<T: RaftStoreRouter + 'static, E: Engine> Service<T, E> {
@@ -60,12 +252,16 @@ impl<T: RaftStoreRouter + 'static, E: Engine> Service<T, E> {
         cop: Endpoint<E>,
         ch: T,
         snap_scheduler: Scheduler<SnapTask>,
+        in_flight: InFlightRequests,
+        interceptors: InterceptorChain,
     ) -> Self {
         Service {
             storage,
             cop,
             ch,
             snap_scheduler,
+            in_flight,
+            interceptors,
         }
     }
 
@@ -83,6 +279,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> Service<T, E> {
 
 impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E> {
     fn kv_get(&mut self, ctx: RpcContext, mut req: GetRequest, sink: UnarySink<GetResponse>) {
+        let slot = check_request!(self, ctx, sink, "kv_get");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_get.start_coarse_timer();
 
         let future = self
@@ -112,10 +310,12 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_get.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_scan(&mut self, ctx: RpcContext, mut req: ScanRequest, sink: UnarySink<ScanResponse>) {
+        let slot = check_request!(self, ctx, sink, "kv_scan");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_scan.start_coarse_timer();
 
         let mut options = Options::default();
@@ -154,7 +354,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_scan.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_prewrite(
@@ -163,6 +363,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: PrewriteRequest,
         sink: UnarySink<PrewriteResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_prewrite");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_prewrite.start_coarse_timer();
 
         let mutations = req
@@ -205,7 +407,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_prewrite.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_commit(
@@ -214,6 +416,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: CommitRequest,
         sink: UnarySink<CommitResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_commit");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_commit.start_coarse_timer();
 
         let keys = req.get_keys().iter().map(|x| Key::from_raw(x)).collect();
@@ -243,7 +447,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_commit.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_import(&mut self, _: RpcContext, _: ImportRequest, _: UnarySink<ImportResponse>) {
@@ -256,6 +460,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: CleanupRequest,
         sink: UnarySink<CleanupResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_cleanup");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_cleanup.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -286,7 +492,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_cleanup.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_batch_get(
@@ -295,6 +501,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: BatchGetRequest,
         sink: UnarySink<BatchGetResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_batch_get");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_batch_get.start_coarse_timer();
 
         let keys = req
@@ -322,7 +530,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_batch_get.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_batch_rollback(
@@ -331,6 +539,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: BatchRollbackRequest,
         sink: UnarySink<BatchRollbackResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_batch_rollback");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .kv_batch_rollback
             .start_coarse_timer();
@@ -362,7 +572,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_batch_rollback.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_scan_lock(
@@ -371,6 +581,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: ScanLockRequest,
         sink: UnarySink<ScanLockResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_scan_lock");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_scan_lock.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -401,7 +613,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_scan_lock.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_resolve_lock(
@@ -410,6 +622,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: ResolveLockRequest,
         sink: UnarySink<ResolveLockResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_resolve_lock");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_resolve_lock.start_coarse_timer();
 
         let txn_status = if req.get_start_version() > 0 {
@@ -446,10 +660,12 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_resolve_lock.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_gc(&mut self, ctx: RpcContext, mut req: GCRequest, sink: UnarySink<GCResponse>) {
+        let slot = check_request!(self, ctx, sink, "kv_gc");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_gc.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -473,7 +689,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_gc.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn kv_delete_range(
@@ -482,6 +698,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: DeleteRangeRequest,
         sink: UnarySink<DeleteRangeResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "kv_delete_range");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.kv_delete_range.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -508,7 +726,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.kv_delete_range.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_get(
@@ -517,6 +735,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawGetRequest,
         sink: UnarySink<RawGetResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_get");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_get.start_coarse_timer();
 
         let future = self
@@ -541,7 +761,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_get.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_batch_get(
@@ -550,6 +770,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawBatchGetRequest,
         sink: UnarySink<RawBatchGetResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_batch_get");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_batch_get.start_coarse_timer();
 
         let keys = req.take_keys().into_vec();
@@ -571,7 +793,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_batch_get.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_scan(
@@ -580,6 +802,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawScanRequest,
         sink: UnarySink<RawScanResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_scan");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_scan.start_coarse_timer();
 
         let end_key = if req.get_end_key().is_empty() {
@@ -614,7 +838,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_scan.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_batch_scan(
@@ -623,6 +847,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawBatchScanRequest,
         sink: UnarySink<RawBatchScanResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_batch_scan");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_batch_scan.start_coarse_timer();
 
         let future = self
@@ -650,7 +876,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_batch_scan.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_put(
@@ -659,6 +885,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawPutRequest,
         sink: UnarySink<RawPutResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_put");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_put.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -686,7 +914,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_put.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_batch_put(
@@ -695,6 +923,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawBatchPutRequest,
         sink: UnarySink<RawBatchPutResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_batch_put");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_batch_put.start_coarse_timer();
 
         let pairs = req
@@ -723,7 +953,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_batch_put.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_delete(
@@ -732,6 +962,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawDeleteRequest,
         sink: UnarySink<RawDeleteResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_delete");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_delete.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -755,7 +987,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_delete.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_batch_delete(
@@ -764,6 +996,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawBatchDeleteRequest,
         sink: UnarySink<RawBatchDeleteResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_batch_delete");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_batch_delete.start_coarse_timer();
 
         let keys = req.take_keys().into_vec();
@@ -788,7 +1022,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_batch_delete.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raw_delete_range(
@@ -797,6 +1031,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: RawDeleteRangeRequest,
         sink: UnarySink<RawDeleteRangeResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "raw_delete_range");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.raw_delete_range.start_coarse_timer();
 
         let (cb, f) = paired_future_callback();
@@ -824,7 +1060,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.raw_delete_range.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn unsafe_destroy_range(
@@ -833,6 +1069,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: UnsafeDestroyRangeRequest,
         sink: UnarySink<UnsafeDestroyRangeResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "unsafe_destroy_range");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .unsafe_destroy_range
             .start_coarse_timer();
@@ -865,10 +1103,12 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.unsafe_destroy_range.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn coprocessor(&mut self, ctx: RpcContext, req: Request, sink: UnarySink<Response>) {
+        let slot = check_request!(self, ctx, sink, "coprocessor");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.coprocessor.start_coarse_timer();
 
         let future = self
@@ -882,7 +1122,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.coprocessor.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn coprocessor_stream(
@@ -891,21 +1131,26 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         req: Request,
         sink: ServerStreamingSink<Response>,
     ) {
+        let slot = check_request!(self, ctx, sink, "coprocessor_stream");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .coprocessor_stream
             .start_coarse_timer();
 
+        // The next response is taken from the channel only after the previous one is written
+        // out, which gRPC flow control holds up while the client doesn't read. The channel
+        // then fills up and parks the producer, so that a slow client keeps no more than
+        // `end_point_stream_channel_size` responses in memory.
         let stream = self
             .cop
             .parse_and_handle_stream_request(req, Some(ctx.peer()))
-            .map(|resp| (resp, WriteFlags::default().buffer_hint(true)))
+            .map(|resp| (resp, WriteFlags::default()))
             .map_err(|e| {
                 let code = RpcStatusCode::Unknown;
                 let msg = Some(format!("{:?}", e));
                 GrpcError::RpcFailure(RpcStatus::new(code, msg))
             });
-        let future = sink
-            .send_all(stream)
+        let future = send_all_flushed(sink, stream)
             .map(|_| timer.observe_duration())
             .map_err(Error::from)
             .map_err(move |e| {
@@ -913,7 +1158,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.coprocessor_stream.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn raft(
@@ -970,6 +1215,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: MvccGetByKeyRequest,
         sink: UnarySink<MvccGetByKeyResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "mvcc_get_by_key");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.mvcc_get_by_key.start_coarse_timer();
 
         let key = Key::from_raw(req.get_key());
@@ -999,7 +1246,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.mvcc_get_by_key.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn mvcc_get_by_start_ts(
@@ -1008,6 +1255,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: MvccGetByStartTsRequest,
         sink: UnarySink<MvccGetByStartTsResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "mvcc_get_by_start_ts");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC
             .mvcc_get_by_start_ts
             .start_coarse_timer();
@@ -1041,7 +1290,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 debug!("{} failed: {:?}", "mvcc_get_by_start_ts", e);
                 GRPC_MSG_FAIL_COUNTER.mvcc_get_by_start_ts.inc();
             });
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 
     fn split_region(
@@ -1050,6 +1299,8 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
         mut req: SplitRegionRequest,
         sink: UnarySink<SplitRegionResponse>,
     ) {
+        let slot = check_request!(self, ctx, sink, "split_region");
+
         let timer = GRPC_MSG_HISTOGRAM_VEC.split_region.start_coarse_timer();
 
         let region_id = req.get_context().get_region_id();
@@ -1099,7 +1350,7 @@ impl<T: RaftStoreRouter + 'static, E: Engine> tikvpb_grpc::Tikv for Service<T, E
                 GRPC_MSG_FAIL_COUNTER.split_region.inc();
             });
 
-        ctx.spawn(future);
+        ctx.spawn(self.in_flight.track_on(slot, future));
     }
 }
 
@@ -1280,6 +1531,8 @@ fn extract_key_errors(res: storage::Result<Vec<storage::Result<()>>>) -> Vec<Key
 
 #[cfg(test)]
 mod tests {
+    use futures::sync::oneshot;
+
     use super::*;
     use storage;
     use storage::mvcc::Error as MvccError;
@@ -1312,4 +1565,35 @@ mod tests {
         assert_eq!(got, expect);
     }
 
+    #[test]
+    fn test_max_in_flight_requests_per_connection() {
+        let in_flight = InFlightRequests::new(2);
+        let conn = "ipv4:127.0.0.1:10001";
+        let gauge = || {
+            GRPC_CONN_IN_FLIGHT_REQUESTS_GAUGE_VEC
+                .with_label_values(&[conn])
+                .get()
+        };
+        let slot1 = in_flight.acquire_slot(conn.to_owned()).unwrap();
+        let slot2 = in_flight.acquire_slot(conn.to_owned()).unwrap();
+        assert_eq!(gauge(), 2);
+        // The third request on the connection exceeds the limit, while other connections
+        // are not affected.
+        assert!(in_flight.acquire_slot(conn.to_owned()).is_none());
+        let other = in_flight.acquire_slot("ipv4:127.0.0.1:10002".to_owned());
+        assert!(other.is_some());
+
+        // The slot is held until the request finishes.
+        let (tx, rx) = oneshot::channel::<()>();
+        let request = in_flight.track_on(Some(slot1), rx.map_err(|_| ()));
+        assert!(in_flight.acquire_slot(conn.to_owned()).is_none());
+        tx.send(()).unwrap();
+        request.wait().unwrap();
+        assert_eq!(gauge(), 1);
+        let slot3 = in_flight.acquire_slot(conn.to_owned()).unwrap();
+
+        // Idle connections are forgotten.
+        drop((slot2, slot3, other));
+        assert!(in_flight.connections.lock().unwrap().is_empty());
+    }
 }
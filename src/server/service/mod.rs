@@ -12,7 +12,9 @@
 // limitations under the License.
 
 mod debug;
+mod interceptor;
 mod kv;
 
 pub use self::debug::Service as DebugService;
-pub use self::kv::Service as KvService;
+pub use self::interceptor::{ClientRateLimiter, InterceptorChain, MemoryShedder, ServerInterceptor};
+pub use self::kv::{InFlightRequests, Service as KvService};
@@ -13,8 +13,8 @@
 
 use std::boxed::FnBox;
 use std::fmt::{self, Display, Formatter};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use futures::{future, Async, Future, Poll, Stream};
@@ -26,12 +26,19 @@ use grpc::{
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::raft_serverpb::{Done, SnapshotChunk};
 use kvproto::tikvpb_grpc::TikvClient;
+use prometheus::IntCounter;
+use tokio_timer::Delay;
 
 use raftstore::store::{SnapEntry, SnapKey, SnapManager, Snapshot};
+use util::collections::HashMap;
+use util::lz4;
 use util::security::SecurityManager;
+use util::time::duration_to_sec;
+use util::timer::GLOBAL_TIMER_HANDLE;
 use util::worker::Runnable;
 use util::DeferContext;
 
+use super::config::SnapCompression;
 use super::metrics::*;
 use super::transport::RaftStoreRouter;
 use super::{Config, Error, Result};
@@ -40,6 +47,61 @@ pub type Callback = Box<FnBox(Result<()>) + Send>;
 
 const DEFAULT_POOL_SIZE: usize = 4;
 
+const SNAP_SEND: &str = "send";
+const SNAP_RECV: &str = "recv";
+
+// The compression type is carried by the data of the head chunk, which is empty if the
+// snapshot is not compressed. Receivers of older versions ignore it.
+const SNAP_COMPRESSION_NONE: u8 = 0;
+const SNAP_COMPRESSION_LZ4: u8 = 1;
+
+// Addresses failing compressed snapshots are tried again with compression after so long, in
+// case their stores have been upgraded.
+const UNCOMPRESSED_ADDR_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Addresses that failed to receive compressed snapshots, which may run older versions.
+/// Snapshots are sent to them without compression for a while.
+#[derive(Default)]
+struct UncompressedAddrs {
+    addrs: HashMap<String, Instant>,
+}
+
+impl UncompressedAddrs {
+    fn insert(&mut self, addr: String, now: Instant) {
+        self.addrs.insert(addr, now);
+    }
+
+    fn contains(&mut self, addr: &str, now: Instant) -> bool {
+        self.addrs.retain(|_, t| now.duration_since(*t) < UNCOMPRESSED_ADDR_TTL);
+        self.addrs.contains_key(addr)
+    }
+}
+
+fn decode_compression(data: &[u8]) -> Result<SnapCompression> {
+    if data.len() > 1 {
+        return Err(box_err!("invalid snapshot compression {:?}", data));
+    }
+    match data.first() {
+        None | Some(&SNAP_COMPRESSION_NONE) => Ok(SnapCompression::None),
+        Some(&SNAP_COMPRESSION_LZ4) => Ok(SnapCompression::Lz4),
+        Some(tp) => Err(box_err!("unsupported snapshot compression {}", tp)),
+    }
+}
+
+fn snap_bytes_counter(direction: &str, throttled: bool) -> IntCounter {
+    let throttled = if throttled { "true" } else { "false" };
+    SNAP_BYTES_COUNTER_VEC.with_label_values(&[direction, throttled])
+}
+
+fn observe_snap_throughput(direction: &str, bytes: u64, elapsed: Duration) {
+    let secs = duration_to_sec(elapsed);
+    if secs > 0.0 {
+        SNAP_THROUGHPUT_GAUGE_VEC
+            .with_label_values(&[direction])
+            .set(bytes as f64 / secs);
+    }
+}
+
 pub enum Task {
     Recv {
         stream: RequestStream<SnapshotChunk>,
@@ -50,6 +112,9 @@ pub enum Task {
         msg: RaftMessage,
         cb: Callback,
     },
+    /// Enters or exits the drain mode, in which new snapshots are refused while the
+    /// transferring ones are left to finish.
+    Drain(bool),
 }
 
 impl Display for Task {
@@ -59,6 +124,7 @@ impl Display for Task {
             Task::Send {
                 ref addr, ref msg, ..
             } => write!(f, "Send Snap[to: {}, snap: {:?}]", addr, msg),
+            Task::Drain(draining) => write!(f, "Drain[{}]", draining),
         }
     }
 }
@@ -67,31 +133,54 @@ struct SnapChunk {
     first: Option<SnapshotChunk>,
     snap: Box<Snapshot>,
     remain_bytes: usize,
+    // Counted as chunks are read, so failed transfers are counted too.
+    bytes_counter: IntCounter,
+    compression: SnapCompression,
+    chunk_size: usize,
+    // When the last chunk is passed to the gRPC stream.
+    last_chunk_time: Option<Instant>,
+    // Set whenever the gRPC stream takes a chunk, see `StallGuard`.
+    progress: Arc<AtomicBool>,
 }
 
-const SNAP_CHUNK_LEN: usize = 1024 * 1024;
-
 impl Stream for SnapChunk {
     type Item = (SnapshotChunk, WriteFlags);
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        // The stream is only polled after the gRPC stream takes the previous chunk.
+        self.progress.store(true, Ordering::SeqCst);
         if let Some(t) = self.first.take() {
             let write_flags = WriteFlags::default().buffer_hint(true);
             return Ok(Async::Ready(Some((t, write_flags))));
         }
+        // The stream is polled again once it takes the last chunk.
+        if let Some(t) = self.last_chunk_time.take() {
+            SNAP_CHUNK_SEND_HISTOGRAM.observe(duration_to_sec(t.elapsed()));
+        }
 
         let mut buf = match self.remain_bytes {
             0 => return Ok(Async::Ready(None)),
-            n if n > SNAP_CHUNK_LEN => vec![0; SNAP_CHUNK_LEN],
+            n if n > self.chunk_size => vec![0; self.chunk_size],
             n => vec![0; n],
         };
         let result = self.snap.read_exact(buf.as_mut_slice());
         match result {
             Ok(_) => {
                 self.remain_bytes -= buf.len();
+                self.bytes_counter.inc_by(buf.len() as i64);
+                if self.compression == SnapCompression::Lz4 {
+                    SNAP_COMPRESSION_BYTES_COUNTER_VEC
+                        .with_label_values(&["raw"])
+                        .inc_by(buf.len() as i64);
+                    buf = box_try!(lz4::compress(&buf));
+                    SNAP_COMPRESSION_BYTES_COUNTER_VEC
+                        .with_label_values(&["compressed"])
+                        .inc_by(buf.len() as i64);
+                }
                 let mut chunk = SnapshotChunk::new();
                 chunk.set_data(buf);
+                self.last_chunk_time = Some(Instant::now());
                 Ok(Async::Ready(Some((
                     chunk,
                     WriteFlags::default().buffer_hint(true),
@@ -102,6 +191,56 @@ impl Stream for SnapChunk {
     }
 }
 
+/// `StallGuard` fails the snapshot transfer `inner` once the receiver takes no chunk for
+/// `timeout`.
+///
+/// The snapshot stream carries no acknowledgements, but a chunk is only taken by the gRPC
+/// stream once the receiver has consumed enough of the previous ones to open the HTTP/2
+/// flow control window, so a stalled stream means the receiver stops acknowledging.
+struct StallGuard<F> {
+    inner: F,
+    progress: Arc<AtomicBool>,
+    timeout: Duration,
+    delay: Delay,
+}
+
+impl<F> StallGuard<F> {
+    fn new(inner: F, progress: Arc<AtomicBool>, timeout: Duration) -> StallGuard<F> {
+        StallGuard {
+            inner,
+            progress,
+            timeout,
+            delay: GLOBAL_TIMER_HANDLE.delay(Instant::now() + timeout),
+        }
+    }
+}
+
+impl<F: Future<Error = Error>> Future for StallGuard<F> {
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        if let Async::Ready(t) = self.inner.poll()? {
+            return Ok(Async::Ready(t));
+        }
+        loop {
+            match self.delay.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {}
+                Err(e) => return Err(box_err!("snapshot stall timer failed: {:?}", e)),
+            }
+            if !self.progress.swap(false, Ordering::SeqCst) {
+                SNAP_SEND_STALLED_COUNTER.inc();
+                return Err(box_err!(
+                    "receiver takes no snapshot chunk in {:?}",
+                    self.timeout
+                ));
+            }
+            self.delay.reset(Instant::now() + self.timeout);
+        }
+    }
+}
+
 struct SendStat {
     key: SnapKey,
     total_size: u64,
@@ -118,6 +257,7 @@ fn send_snap(
     cfg: &Config,
     addr: &str,
     msg: RaftMessage,
+    compression: SnapCompression,
 ) -> Result<impl Future<Item = SendStat, Error = Error>> {
     assert!(msg.get_message().has_snapshot());
     let timer = Instant::now();
@@ -141,14 +281,23 @@ fn send_snap(
     }
     let total_size = s.total_size()?;
 
+    let progress = Arc::new(AtomicBool::new(false));
     let chunks = {
         let mut first_chunk = SnapshotChunk::new();
         first_chunk.set_message(msg);
+        if compression == SnapCompression::Lz4 {
+            first_chunk.set_data(vec![SNAP_COMPRESSION_LZ4]);
+        }
 
         SnapChunk {
             first: Some(first_chunk),
             snap: s,
             remain_bytes: total_size as usize,
+            bytes_counter: snap_bytes_counter(SNAP_SEND, mgr.is_throttled()),
+            compression,
+            chunk_size: cfg.snap_send_chunk_size.0 as usize,
+            last_chunk_time: None,
+            progress: Arc::clone(&progress),
         }
     };
 
@@ -163,6 +312,12 @@ fn send_snap(
     let (sink, receiver) = client.snapshot()?;
 
     let send = chunks.forward(sink).map_err(Error::from);
+    let stall_timeout = cfg.snap_send_stall_timeout.0;
+    let send = if stall_timeout > Duration::from_secs(0) {
+        future::Either::A(StallGuard::new(send, progress, stall_timeout))
+    } else {
+        future::Either::B(send)
+    };
     let send = send
         .and_then(|(s, _)| receiver.map_err(Error::from).map(|_| s))
         .then(move |result| {
@@ -172,13 +327,15 @@ fn send_snap(
             result.map(|s| {
                 fail_point!("snapshot_delete_after_send");
                 s.snap.delete();
+                let elapsed = timer.elapsed();
+                observe_snap_throughput(SNAP_SEND, total_size, elapsed);
                 // TODO: improve it after rustc resolves the bug.
                 // Call `info` in the closure directly will cause rustc
                 // panic with `Cannot create local mono-item for DefId`.
                 SendStat {
                     key,
                     total_size,
-                    elapsed: timer.elapsed(),
+                    elapsed,
                 }
             })
         });
@@ -189,6 +346,8 @@ struct RecvSnapContext {
     key: SnapKey,
     file: Option<Box<Snapshot>>,
     raft_msg: RaftMessage,
+    recv_bytes: u64,
+    compression: SnapCompression,
 }
 
 impl RecvSnapContext {
@@ -198,6 +357,7 @@ impl RecvSnapContext {
         if !head.has_message() {
             return Err(box_err!("no raft message in the first chunk"));
         }
+        let compression = decode_compression(head.get_data())?;
 
         let meta = head.take_message();
         let key = match SnapKey::from_snap(meta.get_message().get_snapshot()) {
@@ -225,6 +385,8 @@ impl RecvSnapContext {
             key,
             file: snap,
             raft_msg: meta,
+            recv_bytes: 0,
+            compression,
         })
     }
 
@@ -251,9 +413,27 @@ fn recv_snap<R: RaftStoreRouter + 'static>(
     snap_mgr: SnapManager,
     raft_router: R,
 ) -> impl Future<Item = (), Error = Error> {
-    let stream = stream.map_err(Error::from);
+    let f = recv_snap_chunks(stream.map_err(Error::from), snap_mgr, raft_router);
+    f.then(move |res| match res {
+        Ok(()) => sink.success(Done::new()),
+        Err(e) => {
+            let status = RpcStatus::new(RpcStatusCode::Unknown, Some(format!("{:?}", e)));
+            sink.fail(status)
+        }
+    }).map_err(Error::from)
+}
 
-    let f = stream.into_future().map_err(|(e, _)| e).and_then(
+// Saves the snapshot in `stream`, and sends its raft message to the raftstore.
+fn recv_snap_chunks<S, R>(
+    stream: S,
+    snap_mgr: SnapManager,
+    raft_router: R,
+) -> impl Future<Item = (), Error = Error>
+where
+    S: Stream<Item = SnapshotChunk, Error = Error> + Send + 'static,
+    R: RaftStoreRouter + 'static,
+{
+    stream.into_future().map_err(|(e, _)| e).and_then(
         move |(head, chunks)| -> Box<Future<Item = (), Error = Error> + Send> {
             let context = match RecvSnapContext::new(head, &snap_mgr) {
                 Ok(context) => context,
@@ -267,35 +447,45 @@ fn recv_snap<R: RaftStoreRouter + 'static>(
             let context_key = context.key.clone();
             snap_mgr.register(context.key.clone(), SnapEntry::Receiving);
 
-            let recv_chunks = chunks.fold(context, |mut context, mut chunk| -> Result<_> {
+            let timer = Instant::now();
+            let bytes_counter = snap_bytes_counter(SNAP_RECV, snap_mgr.is_throttled());
+            let recv_chunks = chunks.fold(context, move |mut context, mut chunk| -> Result<_> {
                 let data = chunk.take_data();
                 if data.is_empty() {
                     return Err(box_err!("{} receive chunk with empty data", context.key));
                 }
+                let data = match context.compression {
+                    SnapCompression::None => data,
+                    SnapCompression::Lz4 => match lz4::decompress(&data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            let e = box_err!("{} failed to decompress chunk: {}", context.key, e);
+                            return Err(e);
+                        }
+                    },
+                };
                 if let Err(e) = context.file.as_mut().unwrap().write_all(&data) {
                     let key = &context.key;
                     let path = context.file.as_mut().unwrap().path();
                     let e = box_err!("{} failed to write snapshot file {}: {}", key, path, e);
                     return Err(e);
                 }
+                bytes_counter.inc_by(data.len() as i64);
+                context.recv_bytes += data.len() as u64;
                 Ok(context)
             });
 
             box recv_chunks
-                .and_then(move |context| context.finish(raft_router))
+                .and_then(move |context| {
+                    observe_snap_throughput(SNAP_RECV, context.recv_bytes, timer.elapsed());
+                    context.finish(raft_router)
+                })
                 .then(move |r| {
                     snap_mgr.deregister(&context_key, &SnapEntry::Receiving);
                     r
                 })
         },
-    );
-    f.then(move |res| match res {
-        Ok(()) => sink.success(Done::new()),
-        Err(e) => {
-            let status = RpcStatus::new(RpcStatusCode::Unknown, Some(format!("{:?}", e)));
-            sink.fail(status)
-        }
-    }).map_err(Error::from)
+    )
 }
 
 pub struct Runner<R: RaftStoreRouter + 'static> {
@@ -307,6 +497,10 @@ pub struct Runner<R: RaftStoreRouter + 'static> {
     cfg: Arc<Config>,
     sending_count: Arc<AtomicUsize>,
     recving_count: Arc<AtomicUsize>,
+    // Shared with `Server` so that it can be changed at runtime.
+    recv_limit: Arc<AtomicUsize>,
+    draining: bool,
+    uncompressed_addrs: Arc<Mutex<UncompressedAddrs>>,
 }
 
 impl<R: RaftStoreRouter + 'static> Runner<R> {
@@ -316,6 +510,7 @@ impl<R: RaftStoreRouter + 'static> Runner<R> {
         r: R,
         security_mgr: Arc<SecurityManager>,
         cfg: Arc<Config>,
+        recv_limit: Arc<AtomicUsize>,
     ) -> Runner<R> {
         Runner {
             env,
@@ -329,6 +524,9 @@ impl<R: RaftStoreRouter + 'static> Runner<R> {
             cfg,
             sending_count: Arc::new(AtomicUsize::new(0)),
             recving_count: Arc::new(AtomicUsize::new(0)),
+            recv_limit,
+            draining: false,
+            uncompressed_addrs: Arc::new(Mutex::new(UncompressedAddrs::default())),
         }
     }
 }
@@ -337,10 +535,46 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
     fn run(&mut self, task: Task) {
         match task {
             Task::Recv { stream, sink } => {
-                if self.recving_count.load(Ordering::SeqCst) >= self.cfg.concurrent_recv_snap_limit
-                {
+                if self.draining {
+                    warn!("snapshot worker is draining, refuse receiving snapshot");
+                    let status = RpcStatus::new(
+                        RpcStatusCode::Unavailable,
+                        Some("snapshot worker is draining".to_owned()),
+                    );
+                    self.pool.spawn(sink.fail(status)).forget();
+                    return;
+                }
+                let recv_limit = self.recv_limit.load(Ordering::Relaxed);
+                if self.recving_count.load(Ordering::SeqCst) >= recv_limit {
                     warn!("too many recving snapshot tasks, ignore");
-                    let status = RpcStatus::new(RpcStatusCode::ResourceExhausted, None);
+                    let status = RpcStatus::new(
+                        RpcStatusCode::ResourceExhausted,
+                        Some(format!("receiving more than {} snapshots", recv_limit)),
+                    );
+                    self.pool.spawn(sink.fail(status)).forget();
+                    return;
+                }
+                if self.snap_mgr.is_pending_full() {
+                    warn!(
+                        "too many pending snapshot files [size: {}], ignore",
+                        self.snap_mgr.get_total_snap_size()
+                    );
+                    let status = RpcStatus::new(
+                        RpcStatusCode::ResourceExhausted,
+                        Some("too many pending snapshot files".to_owned()),
+                    );
+                    self.pool.spawn(sink.fail(status)).forget();
+                    return;
+                }
+                if self.snap_mgr.is_disk_full() {
+                    warn!(
+                        "available disk space for snapshots is too low [available: {}], ignore",
+                        self.snap_mgr.get_available_space()
+                    );
+                    let status = RpcStatus::new(
+                        RpcStatusCode::ResourceExhausted,
+                        Some("no enough disk space for snapshots".to_owned()),
+                    );
                     self.pool.spawn(sink.fail(status)).forget();
                     return;
                 }
@@ -350,8 +584,10 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                 let raft_router = self.raft_router.clone();
                 let recving_count = Arc::clone(&self.recving_count);
                 recving_count.fetch_add(1, Ordering::SeqCst);
+                SNAP_RECEIVING_GAUGE.inc();
                 let f = recv_snap(stream, sink, snap_mgr, raft_router).then(move |result| {
                     recving_count.fetch_sub(1, Ordering::SeqCst);
+                    SNAP_RECEIVING_GAUGE.dec();
                     if let Err(e) = result {
                         error!("failed to recv snapshot {}", e);
                     }
@@ -360,6 +596,26 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                 self.pool.spawn(f).forget();
             }
             Task::Send { addr, msg, cb } => {
+                if self.draining {
+                    warn!(
+                        "snapshot worker is draining, drop Send Snap[to: {}, snap: {:?}]",
+                        addr, msg
+                    );
+                    cb(Err(Error::Other("Snapshot worker is draining".into())));
+                    return;
+                }
+                // The region may be merged away while the task is queued.
+                if self.raft_router.is_snapshot_stale(&msg) {
+                    warn!(
+                        "[region {}] region is gone, drop Send Snap[to: {}, snap: {:?}]",
+                        msg.get_region_id(),
+                        addr,
+                        msg
+                    );
+                    SNAP_TASK_COUNTER.with_label_values(&["stale"]).inc();
+                    cb(Err(Error::Other("Snapshot is stale".into())));
+                    return;
+                }
                 if self.sending_count.load(Ordering::SeqCst) >= self.cfg.concurrent_send_snap_limit
                 {
                     warn!(
@@ -376,8 +632,16 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                 let security_mgr = Arc::clone(&self.security_mgr);
                 let sending_count = Arc::clone(&self.sending_count);
                 sending_count.fetch_add(1, Ordering::SeqCst);
-
-                let f = future::result(send_snap(env, mgr, security_mgr, &self.cfg, &addr, msg))
+                let uncompressed_addrs = Arc::clone(&self.uncompressed_addrs);
+                let now = Instant::now();
+                let compression = if uncompressed_addrs.lock().unwrap().contains(&addr, now) {
+                    SnapCompression::None
+                } else {
+                    self.cfg.snap_compression
+                };
+
+                let send = send_snap(env, mgr, security_mgr, &self.cfg, &addr, msg, compression);
+                let f = future::result(send)
                     .flatten()
                     .then(move |res| {
                         match res {
@@ -390,6 +654,11 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                             }
                             Err(e) => {
                                 error!("failed to send snap to {}: {:?}", addr, e);
+                                if compression != SnapCompression::None {
+                                    warn!("send snapshots to {} without compression", addr);
+                                    let now = Instant::now();
+                                    uncompressed_addrs.lock().unwrap().insert(addr, now);
+                                }
                                 cb(Err(e));
                             }
                         };
@@ -399,6 +668,284 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
 
                 self.pool.spawn(f).forget();
             }
+            Task::Drain(draining) => {
+                info!(
+                    "snapshot worker {} drain mode",
+                    if draining { "enters" } else { "exits" }
+                );
+                self.draining = draining;
+                SNAP_DRAINING_GAUGE.set(draining as i64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::sync::mpsc;
+    use futures::{stream, Future, Sink, Stream};
+    use kvproto::raft_serverpb::RaftSnapshotData;
+    use rocksdb::Writable;
+    use tempdir::TempDir;
+
+    use super::*;
+    use raftstore::store::engine::Snapshot as DbSnapshot;
+    use protobuf::Message;
+    use raftstore::store::{
+        gen_test_region, keys, open_test_db, Msg as StoreMsg, SnapshotStatistics,
+    };
+    use server::transport::testing::RecordingTransport;
+
+    fn build_test_snap(mgr: &SnapManager, db_dir: &TempDir, key: &SnapKey) -> RaftSnapshotData {
+        let db = open_test_db(db_dir, None).unwrap();
+        // Values of the same bytes are highly compressible.
+        for i in 0..100 {
+            let k = keys::data_key(format!("k{:03}", i).as_bytes());
+            db.put(&k, &[b'v'; 1024]).unwrap();
+        }
+        let snapshot = DbSnapshot::new(db);
+        let region = gen_test_region(1, 1, 1);
+        let mut s = mgr.get_snapshot_for_building(key, &snapshot).unwrap();
+        let mut snap_data = RaftSnapshotData::new();
+        snap_data.set_region(region.clone());
+        let mut stat = SnapshotStatistics::new();
+        s.build(
+            &snapshot,
+            &region,
+            &mut snap_data,
+            &mut stat,
+            Box::new(mgr.clone()),
+        ).unwrap();
+        snap_data
+    }
+
+    #[test]
+    fn test_snap_compression() {
+        let snap_dir = TempDir::new("test-snap-compression").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        mgr.init().unwrap();
+        let db_dir = TempDir::new("test-snap-compression-db").unwrap();
+        let key = SnapKey::new(1, 1, 1);
+        build_test_snap(&mgr, &db_dir, &key);
+
+        let mut s = mgr.get_snapshot_for_sending(&key).unwrap();
+        let total_size = s.total_size().unwrap();
+        let mut raw = vec![0; total_size as usize];
+        s.read_exact(&mut raw).unwrap();
+
+        let compressed_counter =
+            SNAP_COMPRESSION_BYTES_COUNTER_VEC.with_label_values(&["compressed"]);
+        let before = compressed_counter.get();
+        let chunks = SnapChunk {
+            first: None,
+            snap: mgr.get_snapshot_for_sending(&key).unwrap(),
+            remain_bytes: total_size as usize,
+            bytes_counter: snap_bytes_counter(SNAP_SEND, mgr.is_throttled()),
+            compression: SnapCompression::Lz4,
+            chunk_size: 1024 * 1024,
+            last_chunk_time: None,
+            progress: Arc::new(AtomicBool::new(false)),
+        };
+        let mut sent = 0;
+        let mut received = vec![];
+        for (chunk, _) in chunks.collect().wait().unwrap() {
+            sent += chunk.get_data().len();
+            received.extend_from_slice(&lz4::decompress(chunk.get_data()).unwrap());
         }
+        assert_eq!(received, raw);
+        assert_eq!((compressed_counter.get() - before) as usize, sent);
+
+        assert_eq!(decode_compression(&[]).unwrap(), SnapCompression::None);
+        assert_eq!(
+            decode_compression(&[SNAP_COMPRESSION_LZ4]).unwrap(),
+            SnapCompression::Lz4
+        );
+        assert!(decode_compression(&[100]).is_err());
+        assert!(decode_compression(&[SNAP_COMPRESSION_LZ4, 0]).is_err());
+    }
+
+    #[test]
+    fn test_recv_compressed_snap() {
+        let snap_dir = TempDir::new("test-recv-compressed-snap").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        mgr.init().unwrap();
+        let db_dir = TempDir::new("test-recv-compressed-snap-db").unwrap();
+        let key = SnapKey::new(1, 1, 1);
+        let snap_data = build_test_snap(&mgr, &db_dir, &key);
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        {
+            let snap = msg.mut_message().mut_snapshot();
+            snap.mut_metadata().set_term(1);
+            snap.mut_metadata().set_index(1);
+            snap.set_data(snap_data.write_to_bytes().unwrap());
+        }
+        let mut head = SnapshotChunk::new();
+        head.set_message(msg);
+        head.set_data(vec![SNAP_COMPRESSION_LZ4]);
+        let s = mgr.get_snapshot_for_sending(&key).unwrap();
+        let chunks = SnapChunk {
+            first: Some(head),
+            remain_bytes: s.total_size().unwrap() as usize,
+            snap: s,
+            bytes_counter: snap_bytes_counter(SNAP_SEND, mgr.is_throttled()),
+            compression: SnapCompression::Lz4,
+            chunk_size: 1024,
+            last_chunk_time: None,
+            progress: Arc::new(AtomicBool::new(false)),
+        };
+
+        let recv_dir = TempDir::new("test-recv-compressed-snap-recv").unwrap();
+        let recv_mgr = SnapManager::new(recv_dir.path().to_str().unwrap(), None);
+        recv_mgr.init().unwrap();
+        let router = RecordingTransport::new();
+        recv_snap_chunks(chunks.map(|(c, _)| c), recv_mgr.clone(), router.clone())
+            .wait()
+            .unwrap();
+        // The snapshot file is saved after its checksum is checked.
+        assert!(recv_mgr.get_snapshot_for_applying(&key).unwrap().exists());
+        match router.take_store_msgs().as_slice() {
+            [StoreMsg::RaftMessage(ref m)] => assert!(m.get_message().has_snapshot()),
+            msgs => panic!("unexpected messages {:?}", msgs),
+        }
+    }
+
+    #[test]
+    fn test_uncompressed_addrs() {
+        let mut addrs = UncompressedAddrs::default();
+        let now = Instant::now();
+        addrs.insert("a".to_owned(), now);
+        assert!(addrs.contains("a", now));
+        assert!(!addrs.contains("b", now));
+        // Compression is tried again after a while.
+        assert!(addrs.contains("a", now + UNCOMPRESSED_ADDR_TTL / 2));
+        assert!(!addrs.contains("a", now + UNCOMPRESSED_ADDR_TTL));
+        assert!(addrs.addrs.is_empty());
+    }
+
+    #[test]
+    fn test_snap_send_bytes() {
+        let snap_dir = TempDir::new("test-snap-send-bytes").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        mgr.init().unwrap();
+        let db_dir = TempDir::new("test-snap-send-bytes-db").unwrap();
+        let snapshot = DbSnapshot::new(open_test_db(&db_dir, None).unwrap());
+
+        let key = SnapKey::new(1, 1, 1);
+        let region = gen_test_region(1, 1, 1);
+        let mut s = mgr.get_snapshot_for_building(&key, &snapshot).unwrap();
+        let mut snap_data = RaftSnapshotData::new();
+        snap_data.set_region(region.clone());
+        let mut stat = SnapshotStatistics::new();
+        s.build(
+            &snapshot,
+            &region,
+            &mut snap_data,
+            &mut stat,
+            Box::new(mgr.clone()),
+        ).unwrap();
+
+        let s = mgr.get_snapshot_for_sending(&key).unwrap();
+        let total_size = s.total_size().unwrap();
+        assert!(total_size > 0);
+
+        let bytes_counter = snap_bytes_counter(SNAP_SEND, mgr.is_throttled());
+        let before = bytes_counter.get();
+        let chunk_count = SNAP_CHUNK_SEND_HISTOGRAM.get_sample_count();
+        let chunk_size = 64;
+        let chunks = SnapChunk {
+            first: None,
+            snap: s,
+            remain_bytes: total_size as usize,
+            bytes_counter: bytes_counter.clone(),
+            compression: SnapCompression::None,
+            chunk_size,
+            last_chunk_time: None,
+            progress: Arc::new(AtomicBool::new(false)),
+        };
+        let chunks = chunks.collect().wait().unwrap();
+        let sent: usize = chunks
+            .iter()
+            .map(|&(ref chunk, _)| chunk.get_data().len())
+            .sum();
+        assert_eq!(sent as u64, total_size);
+        assert_eq!((bytes_counter.get() - before) as u64, total_size);
+        // All the chunks but the last one are full.
+        assert_eq!(chunks.len(), (sent + chunk_size - 1) / chunk_size);
+        assert!(chunks.iter().all(|&(ref c, _)| c.get_data().len() <= chunk_size));
+        assert!(SNAP_CHUNK_SEND_HISTOGRAM.get_sample_count() - chunk_count >= chunks.len() as u64);
+    }
+
+    #[test]
+    fn test_stall_guard() {
+        let timeout = Duration::from_millis(200);
+        let new_send = |sink: mpsc::Sender<u64>, progress: Arc<AtomicBool>| {
+            let chunks = stream::iter_ok::<_, Error>(0..10u64).inspect(move |_| {
+                progress.store(true, Ordering::SeqCst);
+            });
+            chunks.forward(sink.sink_map_err(|e| -> Error { box_err!("{:?}", e) }))
+        };
+
+        // The receiver keeps taking chunks, but more slowly than the timeout in total.
+        let (tx, rx) = mpsc::channel(0);
+        let progress = Arc::new(AtomicBool::new(false));
+        let send = StallGuard::new(new_send(tx, Arc::clone(&progress)), progress, timeout);
+        let recv = thread::spawn(move || {
+            let slow = rx.map(|i| {
+                thread::sleep(Duration::from_millis(50));
+                i
+            });
+            slow.collect().wait().unwrap()
+        });
+        send.wait().unwrap();
+        assert_eq!(recv.join().unwrap(), (0..10).collect::<Vec<_>>());
+
+        // The receiver stops taking chunks, as if the acknowledgements are dropped.
+        let stalled = SNAP_SEND_STALLED_COUNTER.get();
+        let (tx, rx) = mpsc::channel(0);
+        let progress = Arc::new(AtomicBool::new(false));
+        let send = StallGuard::new(new_send(tx, Arc::clone(&progress)), progress, timeout);
+        let start = Instant::now();
+        let err = send.wait().unwrap_err();
+        assert!(format!("{:?}", err).contains("no snapshot chunk"), "{:?}", err);
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(SNAP_SEND_STALLED_COUNTER.get(), stalled + 1);
+        drop(rx);
+    }
+
+    #[test]
+    fn test_send_stale_snap() {
+        let snap_dir = TempDir::new("test-send-stale-snap").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        let router = RecordingTransport::new();
+        let mut runner = Runner::new(
+            Arc::new(Environment::new(1)),
+            mgr,
+            router.clone(),
+            Arc::new(SecurityManager::default()),
+            Arc::new(Config::default()),
+            Arc::new(AtomicUsize::new(1)),
+        );
+        router.destroy_region(1);
+
+        let stale = SNAP_TASK_COUNTER.with_label_values(&["stale"]).get();
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        runner.run(Task::Send {
+            addr: "127.0.0.1:0".to_owned(),
+            msg,
+            cb: box move |res| tx.send(res).unwrap(),
+        });
+        let err = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap_err();
+        assert!(format!("{:?}", err).contains("stale"), "{:?}", err);
+        assert_eq!(
+            SNAP_TASK_COUNTER.with_label_values(&["stale"]).get(),
+            stale + 1
+        );
+        assert_eq!(runner.sending_count.load(Ordering::SeqCst), 0);
     }
 }
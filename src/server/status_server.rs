@@ -22,6 +22,7 @@ use std::net::SocketAddr;
 use std::str::FromStr;
 
 use super::Result;
+use storage::engine::pending_batch_reads;
 use util::metrics::dump;
 
 pub struct StatusServer {
@@ -67,6 +68,9 @@ impl StatusServer {
                     *response.body_mut() = Body::from(dump());
                 }
                 (&Method::GET, "/status") => return ok(response),
+                (&Method::GET, "/debug/pending_batch_reads") => {
+                    *response.body_mut() = Body::from(dump_pending_batch_reads());
+                }
                 _ => {
                     *response.status_mut() = StatusCode::NOT_FOUND;
                 }
@@ -100,6 +104,16 @@ impl StatusServer {
     }
 }
 
+// One line for each batch read in flight, e.g. "1.5s [1, 2]" for a batch which has been
+// waiting for the reads to region 1 and 2 for 1.5s.
+fn dump_pending_batch_reads() -> String {
+    let mut s = String::new();
+    for read in pending_batch_reads() {
+        s.push_str(&format!("{:?} {:?}\n", read.elapsed, read.pending_regions));
+    }
+    s
+}
+
 #[cfg(test)]
 mod tests {
     use futures::future::{lazy, Future};
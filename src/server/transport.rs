@@ -11,24 +11,72 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use kvproto::raft_cmdpb::RaftCmdRequest;
+use futures::future::{self, Loop};
+use futures::Future;
+use futures_cpupool::{Builder as CpuPoolBuilder, CpuPool};
+use kvproto::metapb::{self, RegionEpoch};
+use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, CmdType, RaftCmdRequest};
 use kvproto::raft_serverpb::RaftMessage;
+use prometheus::IntCounter;
+use protobuf::ProtobufEnum;
 use raft::eraftpb::MessageType;
+use std::result;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use super::epoch_cache::EpochCache;
 use super::metrics::*;
 use super::resolve::StoreAddrResolver;
 use super::snap::Task as SnapTask;
 use raft::SnapshotStatus;
-use raftstore::store::{Callback, Msg as StoreMsg, ReadTask, SignificantMsg, Transport};
+use raftstore::store::util::format_trace_id;
+use raftstore::store::{
+    cmd_resp, Callback, ConfChange, ConfChangeCallback, HostedRegions, LeaderChangeCallback,
+    Msg as StoreMsg, ReadTask, RegionReadProgressCallback, SignificantMsg, Transport,
+    UnreachableReason, WriteResponse,
+};
 use raftstore::{Error as RaftStoreError, Result as RaftStoreResult};
-use server::raft_client::RaftClient;
-use server::Result;
-use util::collections::HashSet;
-use util::transport::SendCh;
-use util::worker::Scheduler;
+use server::config::{Config, RaftMsgFullPolicy};
+use server::raft_client::{
+    log_held_up_commands, FlushCallback, PingCallback, RaftClient, SendOutcome,
+};
+use server::region_quota::RegionQuota;
+use server::read_shedder::ReadShedder;
+use server::{Error, Result};
+use util::collections::{HashMap, HashSet};
+use util::time::duration_to_sec;
+use util::timer::GLOBAL_TIMER_HANDLE;
+use util::transport::{Error as TransportError, NotifyError, SendCh};
+use util::worker::{ScheduleError, Scheduler};
 use util::HandyRwLock;
+use uuid::Uuid;
+
+// How long a store is taken as removed before its address is resolved again.
+const TOMBSTONE_STORE_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+// The peers reported unreachable are pruned once there are so many.
+const MAX_UNREACHABLE_PEERS: usize = 4096;
+// How long to wait before resending a command when the raftstore channel is full.
+const CMD_SEND_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    // Resends the commands and raft messages on the timer when the raftstore channel is full, so
+    // that the senders, e.g. futures and gRPC streams, are not blocked.
+    static ref RESEND_POOL: CpuPool = CpuPoolBuilder::new()
+        .name_prefix(thd_name!("store-resend"))
+        .pool_size(1)
+        .create();
+    // The counters of the raft messages sent by type, so that the type labels are not
+    // formatted on every send.
+    static ref SENT_RAFT_MSG_COUNTERS: HashMap<MessageType, IntCounter> = MessageType::values()
+        .iter()
+        .map(|t| {
+            let counter = SENT_RAFT_MSG_BY_TYPE_COUNTER.with_label_values(&[&format!("{:?}", t)]);
+            (*t, counter)
+        })
+        .collect();
+}
 
 pub trait RaftStoreRouter: Send + Clone {
     /// Send StoreMsg, retry if failed. Try times may vary from implementation.
@@ -47,14 +95,48 @@ pub trait RaftStoreRouter: Send + Clone {
         self.try_send(StoreMsg::new_raft_cmd(req, cb))
     }
 
+    // Send RaftCmdRequest to local store. Reads in it may skip the lease check, and be served
+    // from local data that lag behind the leader for at most `max_staleness`.
+    fn send_command_with_max_staleness(
+        &self,
+        req: RaftCmdRequest,
+        cb: Callback,
+        _: Duration,
+    ) -> RaftStoreResult<()> {
+        self.send_command(req, cb)
+    }
+
     // Send significant message. We should guarantee that the message can't be dropped.
     fn significant_send(&self, msg: SignificantMsg) -> RaftStoreResult<()>;
 
+    // Send significant messages of the given regions, with the same guarantee as
+    // `significant_send`. Messages of the same region are sent together and keep their order.
+    // Returns the result of each message in the order they are given.
+    fn significant_send_batch(
+        &self,
+        msgs: Vec<(u64, SignificantMsg)>,
+    ) -> Vec<RaftStoreResult<()>> {
+        msgs.into_iter()
+            .map(|(_, msg)| self.significant_send(msg))
+            .collect()
+    }
+
     // Report the peer of the region is unreachable.
     fn report_unreachable(&self, region_id: u64, to_peer_id: u64) -> RaftStoreResult<()> {
+        self.report_unreachable_with_reason(region_id, to_peer_id, UnreachableReason::default())
+    }
+
+    // Report the peer of the region is unreachable because of `reason`.
+    fn report_unreachable_with_reason(
+        &self,
+        region_id: u64,
+        to_peer_id: u64,
+        reason: UnreachableReason,
+    ) -> RaftStoreResult<()> {
         self.significant_send(SignificantMsg::Unreachable {
             region_id,
             to_peer_id,
+            reason,
         })
     }
 
@@ -71,6 +153,77 @@ pub trait RaftStoreRouter: Send + Clone {
             status,
         })
     }
+
+    // Propose `change` to the region through its leader `peer`, and call `cb` with the region
+    // after the change is applied. A malformed change is refused without being sent.
+    fn async_conf_change(
+        &self,
+        region_id: u64,
+        peer: metapb::Peer,
+        epoch: RegionEpoch,
+        change: ConfChange,
+        cb: ConfChangeCallback,
+    ) -> RaftStoreResult<()> {
+        change.validate()?;
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(region_id);
+        req.mut_header().set_peer(peer);
+        req.mut_header().set_region_epoch(epoch);
+        let mut admin = AdminRequest::new();
+        admin.set_cmd_type(AdminCmdType::ChangePeer);
+        admin.mut_change_peer().set_change_type(change.change_type);
+        admin.mut_change_peer().set_peer(change.peer);
+        req.set_admin_request(admin);
+        let cb = Callback::Write(box move |resp: WriteResponse| {
+            let mut resp = resp.response;
+            if resp.get_header().has_error() {
+                return cb(Err(resp.mut_header().take_error()));
+            }
+            cb(Ok(resp.mut_admin_response().mut_change_peer().take_region()))
+        });
+        self.send_command(req, cb)
+    }
+
+    // Query the committed and applied index and the leader of the local peer of the region.
+    fn region_read_progress(
+        &self,
+        region_id: u64,
+        cb: RegionReadProgressCallback,
+    ) -> RaftStoreResult<()> {
+        self.try_send(StoreMsg::RegionReadProgress {
+            region_id,
+            callback: cb,
+        })
+    }
+
+    // Register `cb` to be called with whether the local peer of the region is leader each time
+    // its role changes. Returns the id to unsubscribe with.
+    fn subscribe_leader_change(
+        &self,
+        region_id: u64,
+        _: LeaderChangeCallback,
+    ) -> RaftStoreResult<u64> {
+        Err(box_err!("leader changes of region {} can't be subscribed", region_id))
+    }
+
+    // Unregister the callback of the subscription `id` to the region.
+    fn unsubscribe_leader_change(&self, _: u64, _: u64) {}
+
+    // Check whether the snapshot carried by `msg` is no longer needed, because the local
+    // peer of its region has been destroyed, by a merge for example, since it's generated.
+    fn is_snapshot_stale(&self, _: &RaftMessage) -> bool {
+        false
+    }
+}
+
+// Decreases the outstanding callbacks when the callback is invoked or dropped.
+struct CallbackGuard(Arc<AtomicUsize>);
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+        RAFT_OUTSTANDING_CALLBACKS_GAUGE.dec();
+    }
 }
 
 #[derive(Clone)]
@@ -78,6 +231,22 @@ pub struct ServerRaftStoreRouter {
     pub ch: SendCh<StoreMsg>,
     pub significant_msg_sender: Sender<SignificantMsg>,
     local_reader_ch: Scheduler<ReadTask>,
+    hosted_regions: HostedRegions,
+    // Callbacks of commands sent but not finished by raftstore yet.
+    outstanding_callbacks: Arc<AtomicUsize>,
+    max_outstanding_callbacks: usize,
+    cmd_send_max_retry: usize,
+    raft_msg_full_policy: Arc<HashMap<MessageType, RaftMsgFullPolicy>>,
+    raft_msg_send_max_retry: usize,
+    raft_msg_send_retry_backoff: Duration,
+    read_shedder: Arc<ReadShedder>,
+    read_quota: Arc<RegionQuota>,
+    write_quota: Arc<RegionQuota>,
+    // Commands whose peers are not on the local store are rejected if it's set. The local
+    // store id is 0 until it's known.
+    strict_peer_store_check: bool,
+    local_store_id: Arc<AtomicU64>,
+    epoch_cache: Arc<EpochCache>,
 }
 
 impl ServerRaftStoreRouter {
@@ -85,21 +254,320 @@ impl ServerRaftStoreRouter {
         raftstore_ch: SendCh<StoreMsg>,
         significant_msg_sender: Sender<SignificantMsg>,
         local_reader_ch: Scheduler<ReadTask>,
+        hosted_regions: HostedRegions,
+        cfg: &Config,
     ) -> ServerRaftStoreRouter {
         ServerRaftStoreRouter {
             ch: raftstore_ch,
             significant_msg_sender,
             local_reader_ch,
+            hosted_regions,
+            outstanding_callbacks: Arc::new(AtomicUsize::new(0)),
+            max_outstanding_callbacks: cfg.max_outstanding_callbacks,
+            cmd_send_max_retry: cfg.cmd_send_max_retry,
+            raft_msg_full_policy: Arc::new(cfg.raft_msg_full_policies()),
+            raft_msg_send_max_retry: cfg.raft_msg_send_max_retry,
+            raft_msg_send_retry_backoff: cfg.raft_msg_send_retry_backoff.0,
+            read_shedder: Arc::new(ReadShedder::new(
+                cfg.local_read_shed_threshold,
+                cfg.local_read_shed_window.0,
+            )),
+            read_quota: Arc::new(RegionQuota::new(
+                cfg.region_read_quota,
+                &REGION_READ_THROTTLED_COUNTER_VEC,
+            )),
+            write_quota: Arc::new(RegionQuota::new(
+                cfg.region_write_quota,
+                &REGION_WRITE_THROTTLED_COUNTER_VEC,
+            )),
+            strict_peer_store_check: cfg.strict_peer_store_check,
+            local_store_id: Arc::new(AtomicU64::new(0)),
+            epoch_cache: Arc::new(EpochCache::default()),
+        }
+    }
+
+    /// Overrides the read quota of the region, or restores the one of the config if `quota`
+    /// is `None`. A quota of 0 means no limit.
+    pub fn set_region_read_quota(&self, region_id: u64, quota: Option<u64>) {
+        self.read_quota.set_region_quota(region_id, quota);
+    }
+
+    /// Overrides the write quota of the region, or restores the one of the config if `quota`
+    /// is `None`. A quota of 0 means no limit.
+    pub fn set_region_write_quota(&self, region_id: u64, quota: Option<u64>) {
+        self.write_quota.set_region_quota(region_id, quota);
+    }
+
+    /// Sets the id of the local store once it's bootstrapped. It's shared by all clones.
+    pub fn set_local_store_id(&self, store_id: u64) {
+        self.local_store_id.store(store_id, Ordering::SeqCst);
+    }
+
+    /// Checks whether the local store has a peer of the region.
+    pub fn has_region(&self, region_id: u64) -> bool {
+        self.hosted_regions.contains(region_id)
+    }
+
+    /// The number of callbacks of commands that are sent but not finished yet.
+    pub fn outstanding_callbacks(&self) -> usize {
+        self.outstanding_callbacks.load(Ordering::SeqCst)
+    }
+
+    // Wraps `cb` so that it's counted until it's invoked or dropped. Returns an error
+    // if there are too many outstanding callbacks.
+    fn track_callback(&self, cb: Callback) -> RaftStoreResult<Callback> {
+        if let Callback::None = cb {
+            return Ok(cb);
+        }
+        let count = self.outstanding_callbacks.fetch_add(1, Ordering::SeqCst);
+        RAFT_OUTSTANDING_CALLBACKS_GAUGE.inc();
+        let guard = CallbackGuard(Arc::clone(&self.outstanding_callbacks));
+        if self.max_outstanding_callbacks > 0 && count >= self.max_outstanding_callbacks {
+            return Err(RaftStoreError::Transport(TransportError::Busy(format!(
+                "more than {} outstanding callbacks",
+                self.max_outstanding_callbacks
+            ))));
+        }
+        let cb = match cb {
+            Callback::Read(read) => Callback::Read(box move |resp| {
+                let _guard = guard;
+                read(resp)
+            }),
+            Callback::Write(write) => Callback::Write(box move |resp| {
+                let _guard = guard;
+                write(resp)
+            }),
+            Callback::None => unreachable!(),
+        };
+        Ok(cb)
+    }
+
+    // Wraps `cb` so that the epoch cache learns from the stale epoch errors of the responses.
+    fn observe_epoch(&self, cb: Callback) -> Callback {
+        let epoch_cache = Arc::clone(&self.epoch_cache);
+        match cb {
+            Callback::Read(read) => Callback::Read(box move |resp| {
+                epoch_cache.on_response(&resp.response);
+                read(resp)
+            }),
+            Callback::Write(write) => Callback::Write(box move |resp| {
+                epoch_cache.on_response(&resp.response);
+                write(resp)
+            }),
+            Callback::None => Callback::None,
+        }
+    }
+
+    /// Like `send_command`, but resends the command at most `cmd_send_max_retry` times with a
+    /// short backoff if the raftstore channel is full. The resends are scheduled on a timer, so
+    /// this returns without waiting for them. `cb` is invoked at most once, and is dropped
+    /// without being invoked if the command is not sent eventually.
+    pub fn send_command_with_retry(
+        &self,
+        req: RaftCmdRequest,
+        cb: Callback,
+    ) -> RaftStoreResult<()> {
+        self.send_command_with_try_times(req, cb, self.cmd_send_max_retry + 1, None)
+    }
+
+    // Commands to regions that are not on the store are rejected here, without invoking `cb`,
+    // rather than after a round trip through the store. So are commands beyond the max
+    // outstanding callbacks, which makes the client back off as if the store is busy, and
+    // local reads of the hottest region when the local reader is saturated.
+    //
+    // Commands whose epochs are older than the ones known from earlier responses are
+    // responded with stale epoch errors right away, so that clients refresh their region
+    // caches without a round trip through the store.
+    //
+    // Commands are traced by the uuids in their headers, which are also set in the responses.
+    // Commands without one are given a new one here.
+    //
+    // `max_staleness` only applies to the commands handled by the local reader. Reads with
+    // `read_quorum` set in their headers skip the local reader, and are read through Raft by
+    // raftstore.
+    fn send_command_with_try_times(
+        &self,
+        mut req: RaftCmdRequest,
+        cb: Callback,
+        try_times: usize,
+        max_staleness: Option<Duration>,
+    ) -> RaftStoreResult<()> {
+        if req.get_header().get_uuid().is_empty() {
+            req.mut_header().set_uuid(Uuid::new_v4().as_bytes().to_vec());
+        }
+        let trace_id = format_trace_id(req.get_header().get_uuid());
+        let region_id = req.get_header().get_region_id();
+        if self.strict_peer_store_check {
+            let local_store_id = self.local_store_id.load(Ordering::SeqCst);
+            let store_id = req.get_header().get_peer().get_store_id();
+            if local_store_id != 0 && store_id != local_store_id {
+                warn!(
+                    "[region {}] reject command {}, peer {:?} is not on store {}",
+                    region_id,
+                    trace_id,
+                    req.get_header().get_peer(),
+                    local_store_id
+                );
+                return Err(RaftStoreError::StoreNotMatch(store_id, local_store_id));
+            }
+        }
+        if !self.has_region(region_id) {
+            debug!("[region {}] reject command {}, region not found", region_id, trace_id);
+            return Err(RaftStoreError::RegionNotFound(region_id));
+        }
+        if let Err(e) = self.epoch_cache.check(&req) {
+            debug!("[region {}] reject command {}: {:?}", region_id, trace_id, e);
+            STALE_EPOCH_FAST_REJECT_COUNTER.inc();
+            let mut resp = cmd_resp::new_error(e);
+            resp.mut_header().set_uuid(req.take_header().take_uuid());
+            cb.invoke_with_response(resp);
+            return Ok(());
+        }
+        let is_write = is_write_command(&req);
+        let cb = self.observe_epoch(cb);
+        let cb = match self.track_callback(cb) {
+            Ok(cb) => cb,
+            Err(e) => {
+                debug!("[region {}] reject command {}: {:?}", region_id, trace_id, e);
+                return Err(e);
+            }
+        };
+        let read_quorum = req.get_header().get_read_quorum();
+        let msg = StoreMsg::new_raft_cmd(req, cb);
+        if !read_quorum && ReadTask::acceptable(&msg) {
+            if self.read_quota.on_request(region_id) {
+                return Err(RaftStoreError::Transport(TransportError::Throttled(format!(
+                    "region {} exceeds the read quota, throttle read {}",
+                    region_id, trace_id
+                ))));
+            }
+            let pending_tasks = self.local_reader_ch.pending_tasks();
+            if self.read_shedder.on_read(region_id, pending_tasks) {
+                LOCAL_READ_SHED_COUNTER.inc();
+                return Err(RaftStoreError::Transport(TransportError::Busy(format!(
+                    "local reader is busy with region {}, shed read {}",
+                    region_id, trace_id
+                ))));
+            }
+            let task = match max_staleness {
+                Some(max_staleness) => ReadTask::stale_read(msg, max_staleness),
+                None => ReadTask::read(msg),
+            };
+            self.schedule_read(task)
+        } else {
+            // Throttled before being proposed, so that the writes don't pile up in the raft log.
+            if is_write && self.write_quota.on_request(region_id) {
+                return Err(RaftStoreError::Transport(TransportError::Throttled(format!(
+                    "region {} exceeds the write quota, throttle write {}",
+                    region_id, trace_id
+                ))));
+            }
+            if try_times <= 1 {
+                return self.ch.try_send(msg).map_err(RaftStoreError::Transport);
+            }
+            match self.ch.try_send_or_return(msg) {
+                Err(NotifyError::Full(msg)) => {
+                    let backoff = CMD_SEND_RETRY_BACKOFF;
+                    resend_later(self.ch.clone(), msg, try_times - 1, backoff, move |sent| {
+                        if !sent {
+                            warn!("[region {}] failed to resend command {}", region_id, trace_id);
+                        }
+                    });
+                    Ok(())
+                }
+                res => res.map_err(|e| RaftStoreError::Transport(e.into())),
+            }
         }
     }
+
+    fn schedule_read(&self, task: ReadTask) -> RaftStoreResult<()> {
+        self.local_reader_ch
+            .schedule(task)
+            .map_err(local_reader_error)
+    }
+
+    // Sends the raft message, and resends it on the timer for at most `retries` times if the
+    // channel is full, so that the gRPC stream receiving it is not blocked. Only the type of
+    // the message is logged if it's dropped eventually, as the entries in it may be sensitive.
+    fn send_raft_msg_with_retry(
+        &self,
+        msg: RaftMessage,
+        retries: usize,
+        backoff: Duration,
+    ) -> RaftStoreResult<()> {
+        let region_id = msg.get_region_id();
+        let to_peer_id = msg.get_to_peer().get_id();
+        let msg_type = msg.get_message().get_msg_type();
+        let start = Instant::now();
+        match self.ch.try_send_or_return(StoreMsg::RaftMessage(msg)) {
+            Err(NotifyError::Full(msg)) => {
+                resend_later(self.ch.clone(), msg, retries, backoff, move |sent| {
+                    RAFT_MSG_SEND_RETRY_HISTOGRAM.observe(duration_to_sec(start.elapsed()));
+                    if !sent {
+                        warn!(
+                            "[region {}] drop {:?} to peer {}, raftstore channel is full",
+                            region_id, msg_type, to_peer_id
+                        );
+                    }
+                });
+                Ok(())
+            }
+            res => res.map_err(|e| RaftStoreError::Transport(e.into())),
+        }
+    }
+}
+
+// Resends `msg` on the timer, waiting `backoff` before each try, for at most `try_times` times,
+// and then calls `on_done` with whether it's sent. The callback of a command is dropped without
+// being invoked if the command is not sent eventually.
+fn resend_later<F>(
+    ch: SendCh<StoreMsg>,
+    msg: StoreMsg,
+    try_times: usize,
+    backoff: Duration,
+    on_done: F,
+) where
+    F: FnOnce(bool) + Send + 'static,
+{
+    let f = future::loop_fn((msg, try_times), move |(msg, try_times)| {
+        let ch = ch.clone();
+        let delay = GLOBAL_TIMER_HANDLE.delay(Instant::now() + backoff);
+        delay.then(move |_| -> result::Result<Loop<bool, (StoreMsg, usize)>, ()> {
+            if try_times <= 1 {
+                // The channel is only counted as full when the message is given up.
+                return Ok(Loop::Break(ch.try_send(msg).is_ok()));
+            }
+            match ch.try_send_or_return(msg) {
+                Ok(()) => Ok(Loop::Break(true)),
+                Err(NotifyError::Full(msg)) => Ok(Loop::Continue((msg, try_times - 1))),
+                Err(_) => Ok(Loop::Break(false)),
+            }
+        })
+    });
+    RESEND_POOL.spawn(f.map(on_done)).forget();
+}
+
+// A full local reader is reported like a full raftstore channel, so that clients back off as
+// if the store is busy.
+fn local_reader_error(e: ScheduleError<ReadTask>) -> RaftStoreError {
+    RaftStoreError::Transport(match e {
+        ScheduleError::Full(_) => TransportError::Discard("local reader is full".to_owned()),
+        ScheduleError::Stopped(_) => TransportError::Closed,
+    })
+}
+
+// Whether `req` writes data. Admin commands are never throttled.
+fn is_write_command(req: &RaftCmdRequest) -> bool {
+    !req.has_admin_request() && req.get_requests().iter().any(|r| match r.get_cmd_type() {
+        CmdType::Put | CmdType::Delete | CmdType::DeleteRange | CmdType::IngestSST => true,
+        _ => false,
+    })
 }
 
 impl RaftStoreRouter for ServerRaftStoreRouter {
     fn try_send(&self, msg: StoreMsg) -> RaftStoreResult<()> {
         if ReadTask::acceptable(&msg) {
-            self.local_reader_ch
-                .schedule(ReadTask::read(msg))
-                .map_err(|e| box_err!(e))
+            self.schedule_read(ReadTask::read(msg))
         } else {
             self.ch.try_send(msg).map_err(RaftStoreError::Transport)
         }
@@ -107,20 +575,52 @@ impl RaftStoreRouter for ServerRaftStoreRouter {
 
     fn send(&self, msg: StoreMsg) -> RaftStoreResult<()> {
         if ReadTask::acceptable(&msg) {
-            self.local_reader_ch
-                .schedule(ReadTask::read(msg))
-                .map_err(|e| box_err!(e))
+            self.schedule_read(ReadTask::read(msg))
         } else {
             self.ch.send(msg).map_err(RaftStoreError::Transport)
         }
     }
 
+    // Raft messages are dropped if the channel is full, unless their types are configured
+    // with other policies, as retransmitting them may take too long. Dropped messages may be
+    // retried a few times first, in case the channel is full only briefly.
     fn send_raft_msg(&self, msg: RaftMessage) -> RaftStoreResult<()> {
-        self.try_send(StoreMsg::RaftMessage(msg))
+        let msg_type = msg.get_message().get_msg_type();
+        match self.raft_msg_full_policy.get(&msg_type) {
+            None | Some(&RaftMsgFullPolicy::Drop) if self.raft_msg_send_max_retry == 0 => {
+                self.try_send(StoreMsg::RaftMessage(msg))
+            }
+            None | Some(&RaftMsgFullPolicy::Drop) => self.send_raft_msg_with_retry(
+                msg,
+                self.raft_msg_send_max_retry,
+                self.raft_msg_send_retry_backoff,
+            ),
+            Some(&RaftMsgFullPolicy::Block) => {
+                self.send_raft_msg_with_retry(msg, self.cmd_send_max_retry, CMD_SEND_RETRY_BACKOFF)
+            }
+            Some(&RaftMsgFullPolicy::ForceSend) => {
+                let msg1 = msg.clone();
+                match self.ch.try_send(StoreMsg::RaftMessage(msg)) {
+                    Err(TransportError::Discard(_)) => {
+                        self.significant_send(SignificantMsg::RaftMessage(msg1))
+                    }
+                    res => res.map_err(RaftStoreError::Transport),
+                }
+            }
+        }
     }
 
     fn send_command(&self, req: RaftCmdRequest, cb: Callback) -> RaftStoreResult<()> {
-        self.try_send(StoreMsg::new_raft_cmd(req, cb))
+        self.send_command_with_try_times(req, cb, 1, None)
+    }
+
+    fn send_command_with_max_staleness(
+        &self,
+        req: RaftCmdRequest,
+        cb: Callback,
+        max_staleness: Duration,
+    ) -> RaftStoreResult<()> {
+        self.send_command_with_try_times(req, cb, 1, Some(max_staleness))
     }
 
     fn significant_send(&self, msg: SignificantMsg) -> RaftStoreResult<()> {
@@ -130,6 +630,65 @@ impl RaftStoreRouter for ServerRaftStoreRouter {
 
         Ok(())
     }
+
+    fn significant_send_batch(
+        &self,
+        msgs: Vec<(u64, SignificantMsg)>,
+    ) -> Vec<RaftStoreResult<()>> {
+        let mut results: Vec<_> = (0..msgs.len()).map(|_| Ok(())).collect();
+        let mut msgs: Vec<_> = msgs.into_iter().enumerate().collect();
+        // The sort is stable, so messages of a region keep their order.
+        msgs.sort_by_key(|&(_, (region_id, _))| region_id);
+        let mut disconnected = false;
+        for (i, (region_id, msg)) in msgs {
+            if disconnected {
+                results[i] = Err(box_err!(
+                    "failed to send significant msg of region {}",
+                    region_id
+                ));
+                continue;
+            }
+            if let Err(e) = self.significant_msg_sender.send(msg) {
+                // The receiver is gone, and so will be for the rest of the messages.
+                disconnected = true;
+                results[i] = Err(box_err!("failed to send significant msg {:?}", e));
+            }
+        }
+        results
+    }
+
+    fn region_read_progress(
+        &self,
+        region_id: u64,
+        cb: RegionReadProgressCallback,
+    ) -> RaftStoreResult<()> {
+        if !self.has_region(region_id) {
+            return Err(RaftStoreError::RegionNotFound(region_id));
+        }
+        self.try_send(StoreMsg::RegionReadProgress {
+            region_id,
+            callback: cb,
+        })
+    }
+
+    fn is_snapshot_stale(&self, msg: &RaftMessage) -> bool {
+        !self.has_region(msg.get_region_id())
+    }
+
+    // The subscriptions are dropped when the peer of the region is destroyed.
+    fn subscribe_leader_change(
+        &self,
+        region_id: u64,
+        cb: LeaderChangeCallback,
+    ) -> RaftStoreResult<u64> {
+        self.hosted_regions
+            .subscribe_leader_change(region_id, cb)
+            .ok_or(RaftStoreError::RegionNotFound(region_id))
+    }
+
+    fn unsubscribe_leader_change(&self, region_id: u64, id: u64) {
+        self.hosted_regions.unsubscribe_leader_change(region_id, id);
+    }
 }
 
 pub struct ServerTransport<T, S>
@@ -141,7 +700,24 @@ where
     snap_scheduler: Scheduler<SnapTask>,
     pub raft_router: T,
     resolving: Arc<RwLock<HashSet<u64>>>,
-    resolver: S,
+    // store id -> when the store is found to be tombstone.
+    tombstone_stores: Arc<RwLock<HashMap<u64, Instant>>>,
+    // store id -> when resolving the store address fails.
+    failed_stores: Arc<RwLock<HashMap<u64, Instant>>>,
+    resolve_negative_cache_ttl: Duration,
+    resolver: Arc<Mutex<S>>,
+    raft_msg_log_sample_interval: usize,
+    // Messages sent through all the clones, for sampling the logged ones.
+    sent_raft_msgs: Arc<AtomicUsize>,
+    unreachable_report_dedup_interval: Duration,
+    // store id -> (region id, peer id) -> when the peer is last reported unreachable.
+    reported_unreachable: Arc<RwLock<HashMap<u64, HashMap<(u64, u64), Instant>>>>,
+    snap_send_unreachable_window: Duration,
+    // (region id, peer id) -> when the peer is last reported unreachable, for failing the
+    // snapshots to it in the window.
+    unreachable_peers: Arc<RwLock<HashMap<(u64, u64), Instant>>>,
+    // 0 until the local store is bootstrapped.
+    local_store_id: Arc<AtomicU64>,
 }
 
 impl<T, S> Clone for ServerTransport<T, S>
@@ -155,7 +731,17 @@ where
             snap_scheduler: self.snap_scheduler.clone(),
             raft_router: self.raft_router.clone(),
             resolving: Arc::clone(&self.resolving),
-            resolver: self.resolver.clone(),
+            tombstone_stores: Arc::clone(&self.tombstone_stores),
+            failed_stores: Arc::clone(&self.failed_stores),
+            resolve_negative_cache_ttl: self.resolve_negative_cache_ttl,
+            resolver: Arc::clone(&self.resolver),
+            raft_msg_log_sample_interval: self.raft_msg_log_sample_interval,
+            sent_raft_msgs: Arc::clone(&self.sent_raft_msgs),
+            unreachable_report_dedup_interval: self.unreachable_report_dedup_interval,
+            reported_unreachable: Arc::clone(&self.reported_unreachable),
+            snap_send_unreachable_window: self.snap_send_unreachable_window,
+            unreachable_peers: Arc::clone(&self.unreachable_peers),
+            local_store_id: Arc::clone(&self.local_store_id),
         }
     }
 }
@@ -166,17 +752,64 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
         snap_scheduler: Scheduler<SnapTask>,
         raft_router: T,
         resolver: S,
+        cfg: &Config,
     ) -> ServerTransport<T, S> {
         ServerTransport {
             raft_client,
             snap_scheduler,
             raft_router,
             resolving: Arc::new(RwLock::new(Default::default())),
-            resolver,
+            tombstone_stores: Arc::new(RwLock::new(Default::default())),
+            failed_stores: Arc::new(RwLock::new(Default::default())),
+            resolve_negative_cache_ttl: cfg.resolve_negative_cache_ttl.0,
+            resolver: Arc::new(Mutex::new(resolver)),
+            raft_msg_log_sample_interval: cfg.raft_msg_log_sample_interval,
+            sent_raft_msgs: Arc::new(AtomicUsize::new(0)),
+            unreachable_report_dedup_interval: cfg.unreachable_report_dedup_interval.0,
+            reported_unreachable: Arc::new(RwLock::new(Default::default())),
+            snap_send_unreachable_window: cfg.snap_send_unreachable_window.0,
+            unreachable_peers: Arc::new(RwLock::new(Default::default())),
+            local_store_id: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    fn send_store(&self, store_id: u64, msg: RaftMessage) {
+    /// Sets the id of the local store once it's bootstrapped, so that messages to it are
+    /// delivered to the local raftstore instead of going through the network, in case the
+    /// address of another store is misconfigured to be the local one. It's shared by all
+    /// clones.
+    pub fn set_local_store_id(&self, store_id: u64) {
+        self.local_store_id.store(store_id, Ordering::SeqCst);
+    }
+
+    // Counts `msg` by its type, and logs it if it's sampled.
+    fn observe_sent_msg(&self, msg: &RaftMessage) {
+        let msg_type = msg.get_message().get_msg_type();
+        SENT_RAFT_MSG_COUNTERS[&msg_type].inc();
+        if self.raft_msg_log_sample_interval == 0 {
+            return;
+        }
+        let sent = self.sent_raft_msgs.fetch_add(1, Ordering::Relaxed);
+        if sent % self.raft_msg_log_sample_interval == 0 {
+            info!(
+                "[region {}] send {:?} to peer {} at store {}, term {}, index {}, {} entries",
+                msg.get_region_id(),
+                msg_type,
+                msg.get_to_peer().get_id(),
+                msg.get_to_peer().get_store_id(),
+                msg.get_message().get_term(),
+                msg.get_message().get_index(),
+                msg.get_message().get_entries().len()
+            );
+        }
+    }
+
+    /// Replaces the resolver for all the clones of the transport. Resolutions that have
+    /// been started finish with the old resolver.
+    pub fn set_resolver(&self, resolver: S) {
+        *self.resolver.lock().unwrap() = resolver;
+    }
+
+    fn send_store(&self, store_id: u64, msg: RaftMessage, enqueue_time: Instant) {
         // Wrapping the fail point in a closure, so we can modify
         // local variables without return,
         let transport_on_send_store_fp = || {
@@ -188,11 +821,51 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
             })
         };
         transport_on_send_store_fp();
+        let local_store_id = self.local_store_id.load(Ordering::SeqCst);
+        if local_store_id != 0 && store_id == local_store_id {
+            RAFT_MESSAGE_LOCAL_DELIVERY_COUNTER.inc();
+            debug!("deliver msg {:?} to the local store {}", msg, store_id);
+            if let Err(e) = self.raft_router.send_raft_msg(msg) {
+                error!("failed to deliver raft msg to the local store: {:?}", e);
+            }
+            return;
+        }
         // check the corresponding token for store.
         // TODO: avoid clone
         let addr = self.raft_client.rl().addrs.get(&store_id).cloned();
         if let Some(addr) = addr {
-            self.write_data(store_id, &addr, msg);
+            self.write_data(store_id, &addr, msg, enqueue_time);
+            return;
+        }
+
+        // Messages to a tombstone store are dropped right away, but the store is resolved
+        // again once in a while in case it comes back.
+        let is_tombstone = self
+            .tombstone_stores
+            .rl()
+            .get(&store_id)
+            .map_or(false, |t| t.elapsed() < TOMBSTONE_STORE_RECHECK_INTERVAL);
+        if is_tombstone {
+            RESOLVE_STORE_COUNTER
+                .with_label_values(&["tombstone"])
+                .inc();
+            debug!("store {} has been removed, drop msg {:?}", store_id, msg);
+            self.report_unreachable(msg, UnreachableReason::StoreTombstone);
+            return;
+        }
+
+        // Resolving the store address has failed just now, it most likely fails again.
+        let recently_failed = self
+            .failed_stores
+            .rl()
+            .get(&store_id)
+            .map_or(false, |t| t.elapsed() < self.resolve_negative_cache_ttl);
+        if recently_failed {
+            RESOLVE_STORE_COUNTER
+                .with_label_values(&["neg_cache_hit"])
+                .inc();
+            debug!("store {} address failed to resolve, drop msg {:?}", store_id, msg);
+            self.report_unreachable(msg, UnreachableReason::ResolveFailed);
             return;
         }
 
@@ -206,7 +879,7 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
                 "store {} address is being resolved, drop msg {:?}",
                 store_id, msg
             );
-            self.report_unreachable(msg);
+            self.report_unreachable(msg, UnreachableReason::Resolving);
             return;
         }
 
@@ -214,16 +887,17 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
         RESOLVE_STORE_COUNTER.with_label_values(&["resolve"]).inc();
 
         self.resolving.wl().insert(store_id);
-        self.resolve(store_id, msg);
+        self.resolve(store_id, msg, enqueue_time);
     }
 
     // TODO: remove allow unused mut.
     // Compiler warns `mut addr ` and `mut transport_on_resolve_fp`, when we enable
     // the `no-fail` feature.
     #[allow(unused_mut)]
-    fn resolve(&self, store_id: u64, msg: RaftMessage) {
+    fn resolve(&self, store_id: u64, msg: RaftMessage, enqueue_time: Instant) {
         let trans = self.clone();
         let msg1 = msg.clone();
+        let start = Instant::now();
         let cb = box move |mut addr: Result<String>| {
             {
                 // Wrapping the fail point in a closure, so we can modify
@@ -246,40 +920,168 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
 
             // clear resolving.
             trans.resolving.wl().remove(&store_id);
-            if let Err(e) = addr {
-                RESOLVE_STORE_COUNTER.with_label_values(&["failed"]).inc();
-                error!("resolve store {} address failed {:?}", store_id, e);
-                trans.report_unreachable(msg);
-                return;
-            }
+            let outcome = match addr {
+                Ok(_) => "success",
+                Err(Error::StoreTombstone(_)) => "tombstone",
+                Err(_) => "failed",
+            };
+            RESOLVE_STORE_DURATION
+                .with_label_values(&[outcome])
+                .observe(duration_to_sec(start.elapsed()));
+            let addr = match addr {
+                Ok(addr) => addr,
+                Err(Error::StoreTombstone(_)) => {
+                    RESOLVE_STORE_COUNTER
+                        .with_label_values(&["tombstone"])
+                        .inc();
+                    info!("store {} has been removed", store_id);
+                    trans.tombstone_stores.wl().insert(store_id, Instant::now());
+                    trans.report_unreachable(msg, UnreachableReason::StoreTombstone);
+                    return;
+                }
+                Err(e) => {
+                    RESOLVE_STORE_COUNTER.with_label_values(&["failed"]).inc();
+                    error!("resolve store {} address failed {:?}", store_id, e);
+                    trans.record_failed_store(store_id);
+                    trans.report_unreachable(msg, UnreachableReason::ResolveFailed);
+                    return;
+                }
+            };
 
             RESOLVE_STORE_COUNTER.with_label_values(&["success"]).inc();
-            let addr = addr.unwrap();
+            trans.tombstone_stores.wl().remove(&store_id);
+            trans.failed_stores.wl().remove(&store_id);
             info!("resolve store {} address ok, addr {}", store_id, addr);
             trans.raft_client.wl().addrs.insert(store_id, addr.clone());
-            trans.write_data(store_id, &addr, msg);
+            trans.write_data(store_id, &addr, msg, enqueue_time);
             // There may be no messages in the near future, so flush it immediately.
             trans.raft_client.wl().flush();
         };
-        if let Err(e) = self.resolver.resolve(store_id, cb) {
+        // Don't hold the lock during resolving, the callback may be invoked in place.
+        let resolver = self.resolver.lock().unwrap().clone();
+        if let Err(e) = resolver.resolve(store_id, cb) {
             error!("resolve store {} address failed {:?}", store_id, e);
             self.resolving.wl().remove(&store_id);
             RESOLVE_STORE_COUNTER.with_label_values(&["failed"]).inc();
-            self.report_unreachable(msg1);
+            RESOLVE_STORE_DURATION
+                .with_label_values(&["failed"])
+                .observe(duration_to_sec(start.elapsed()));
+            self.report_unreachable(msg1, UnreachableReason::ResolveFailed);
+        }
+    }
+
+    /// Drops the addresses and connections of the stores not in `live`, e.g. the stores
+    /// removed from the cluster.
+    pub fn retain_stores(&self, live: &HashSet<u64>) {
+        let dropped = self.raft_client.wl().retain_stores(live);
+        if dropped > 0 {
+            info!("dropped {} stores not in the cluster", dropped);
+        }
+    }
+
+    /// Reports how many messages are held by the raft client for each store.
+    pub fn update_pending_gauge(&self) {
+        self.raft_client.rl().update_pending_gauge();
+    }
+
+    /// Drops the messages that have been held by the raft client for longer than `timeout`,
+    /// and reports their peers unreachable, so that raft sends them again.
+    pub fn expire_msgs(&self, timeout: Duration) {
+        let msgs = self
+            .raft_client
+            .wl()
+            .take_expired_msgs(timeout, Instant::now());
+        for msg in msgs {
+            let store = msg.get_to_peer().get_store_id().to_string();
+            REPORT_FAILURE_MSG_COUNTER
+                .with_label_values(&["send_timeout", &*store])
+                .inc();
+            warn!(
+                "[region {}] {:?} to peer {} is not sent in {:?}, drop it",
+                msg.get_region_id(),
+                msg.get_message().get_msg_type(),
+                msg.get_to_peer().get_id(),
+                timeout
+            );
+            self.report_unreachable(msg, UnreachableReason::SendTimeout);
         }
     }
 
-    fn write_data(&self, store_id: u64, addr: &str, msg: RaftMessage) {
+    /// Measures the round trip time of the transport path to `store_id`, independent of
+    /// the raft messages being sent. The store address must have been resolved already.
+    pub fn ping_store(&self, store_id: u64, cb: PingCallback) {
+        let addr = self.raft_client.rl().addrs.get(&store_id).cloned();
+        match addr {
+            Some(addr) => self.raft_client.wl().ping(store_id, &addr, cb),
+            None => cb(Err(box_err!("store {} address is not resolved", store_id))),
+        }
+    }
+
+    // `enqueue_time` is when `msg` is passed to the transport, so that the time spent on
+    // resolving and batching can be told.
+    fn write_data(&self, store_id: u64, addr: &str, msg: RaftMessage, enqueue_time: Instant) {
         if msg.get_message().has_snapshot() {
+            RAFT_MSG_SEND_LATENCY
+                .with_label_values(&["snapshot"])
+                .observe(duration_to_sec(enqueue_time.elapsed()));
             return self.send_snapshot_sock(addr, msg);
         }
-        if let Err(e) = self.raft_client.wl().send(store_id, addr, msg) {
-            error!("send raft msg err {:?}", e);
+        let region_id = msg.get_region_id();
+        let from_peer_id = msg.get_from_peer().get_id();
+        let to_peer_id = msg.get_to_peer().get_id();
+        let msg_type = msg.get_message().get_msg_type();
+        let res = self
+            .raft_client
+            .wl()
+            .send_with_enqueue_time(store_id, addr, msg, enqueue_time);
+        if let Ok(outcome) = res {
+            RAFT_MESSAGE_SEND_OUTCOME_COUNTER_VEC
+                .with_label_values(&[outcome.tag()])
+                .inc();
         }
+        let reason = match res {
+            Ok(SendOutcome::Buffered) | Ok(SendOutcome::Flushed) => {
+                return self.clear_reported_unreachable(store_id)
+            }
+            Ok(SendOutcome::Dropped) => {
+                warn!(
+                    "[region {}] {:?} from peer {} to peer {} is dropped, the connection to \
+                     store {} is closed",
+                    region_id, msg_type, from_peer_id, to_peer_id, store_id
+                );
+                UnreachableReason::SendFailed
+            }
+            Err(Error::RaftMessageTooLarge(size, limit)) => {
+                // The limit may differ between stores during rolling upgrades.
+                error!(
+                    "[region {}] {:?} from peer {} to peer {} on store {} is too large, \
+                     size {} exceeds server.max-raft-msg-size {}",
+                    region_id, msg_type, from_peer_id, to_peer_id, store_id, size, limit
+                );
+                UnreachableReason::MessageTooLarge
+            }
+            Err(e) => {
+                error!("send raft msg err {:?}", e);
+                UnreachableReason::SendFailed
+            }
+        };
+        self.report_peer_unreachable(region_id, to_peer_id, store_id, reason);
     }
 
     fn send_snapshot_sock(&self, addr: &str, msg: RaftMessage) {
         let rep = self.new_snapshot_reporter(&msg);
+        if self.is_recently_unreachable(msg.get_region_id(), msg.get_to_peer().get_id()) {
+            let store = msg.get_to_peer().get_store_id().to_string();
+            REPORT_FAILURE_MSG_COUNTER
+                .with_label_values(&["snapshot_unreachable", &*store])
+                .inc();
+            warn!(
+                "[region {}] peer {} is reported unreachable recently, skip sending snapshot",
+                msg.get_region_id(),
+                msg.get_to_peer().get_id()
+            );
+            return rep.report(SnapshotStatus::Failure);
+        }
         let cb = box move |res: Result<()>| {
             if res.is_err() {
                 rep.report(SnapshotStatus::Failure);
@@ -315,7 +1117,7 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
         }
     }
 
-    pub fn report_unreachable(&self, msg: RaftMessage) {
+    pub fn report_unreachable(&self, msg: RaftMessage, reason: UnreachableReason) {
         let region_id = msg.get_region_id();
         let to_peer_id = msg.get_to_peer().get_id();
         let store_id = msg.get_to_peer().get_store_id();
@@ -326,10 +1128,113 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
                 .report(SnapshotStatus::Failure);
         }
 
-        if let Err(e) = self.raft_router.report_unreachable(region_id, to_peer_id) {
+        log_held_up_commands(&msg, reason);
+        self.report_peer_unreachable(region_id, to_peer_id, store_id, reason);
+    }
+
+    // Returns true if the peer has been reported unreachable in the dedup interval. Otherwise
+    // the report is recorded, and repeated reports are suppressed until the interval elapses.
+    // Once `MAX_UNREACHABLE_PEERS` peers are recorded and none has expired, reports are no
+    // longer recorded, so that they are not suppressed either.
+    fn is_reported_unreachable(&self, region_id: u64, to_peer_id: u64, store_id: u64) -> bool {
+        let interval = self.unreachable_report_dedup_interval;
+        if interval == Duration::from_secs(0) {
+            return false;
+        }
+        let now = Instant::now();
+        let mut reported = self.reported_unreachable.wl();
+        if let Some(t) = reported
+            .get(&store_id)
+            .and_then(|peers| peers.get(&(region_id, to_peer_id)))
+        {
+            if now.duration_since(*t) < interval {
+                return true;
+            }
+        }
+        if reported.values().map(|peers| peers.len()).sum::<usize>() >= MAX_UNREACHABLE_PEERS {
+            for peers in reported.values_mut() {
+                peers.retain(|_, t| now.duration_since(*t) < interval);
+            }
+            reported.retain(|_, peers| !peers.is_empty());
+            let count: usize = reported.values().map(|peers| peers.len()).sum();
+            if count >= MAX_UNREACHABLE_PEERS {
+                return false;
+            }
+        }
+        reported
+            .entry(store_id)
+            .or_insert_with(HashMap::default)
+            .insert((region_id, to_peer_id), now);
+        false
+    }
+
+    // Peers of a store that can be sent to again are reported unreachable right away.
+    fn clear_reported_unreachable(&self, store_id: u64) {
+        if self.unreachable_report_dedup_interval == Duration::from_secs(0)
+            || !self.reported_unreachable.rl().contains_key(&store_id)
+        {
+            return;
+        }
+        self.reported_unreachable.wl().remove(&store_id);
+    }
+
+    // Whether the peer is reported unreachable in the snapshot send window.
+    fn is_recently_unreachable(&self, region_id: u64, to_peer_id: u64) -> bool {
+        let window = self.snap_send_unreachable_window;
+        if window == Duration::from_secs(0) {
+            return false;
+        }
+        self.unreachable_peers
+            .rl()
+            .get(&(region_id, to_peer_id))
+            .map_or(false, |t| t.elapsed() < window)
+    }
+
+    // Remembers that the address of `store_id` failed to resolve, so that it's not resolved
+    // again until `resolve_negative_cache_ttl` passes.
+    fn record_failed_store(&self, store_id: u64) {
+        let ttl = self.resolve_negative_cache_ttl;
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+        let mut stores = self.failed_stores.wl();
+        stores.retain(|_, t| t.elapsed() < ttl);
+        stores.insert(store_id, Instant::now());
+    }
+
+    fn record_unreachable_peer(&self, region_id: u64, to_peer_id: u64) {
+        let window = self.snap_send_unreachable_window;
+        if window == Duration::from_secs(0) {
+            return;
+        }
+        let mut peers = self.unreachable_peers.wl();
+        if peers.len() >= MAX_UNREACHABLE_PEERS {
+            peers.retain(|_, t| t.elapsed() < window);
+        }
+        peers.insert((region_id, to_peer_id), Instant::now());
+    }
+
+    fn report_peer_unreachable(
+        &self,
+        region_id: u64,
+        to_peer_id: u64,
+        store_id: u64,
+        reason: UnreachableReason,
+    ) {
+        // Messages too large say nothing about whether the peer can be sent to.
+        if reason != UnreachableReason::MessageTooLarge {
+            self.record_unreachable_peer(region_id, to_peer_id);
+        }
+        if self.is_reported_unreachable(region_id, to_peer_id, store_id) {
+            return;
+        }
+        if let Err(e) = self
+            .raft_router
+            .report_unreachable_with_reason(region_id, to_peer_id, reason)
+        {
             error!(
-                "report peer {} on store {} unreachable for region {} failed {:?}",
-                to_peer_id, store_id, region_id, e
+                "report peer {} on store {} unreachable for region {} failed {:?}, reason {:?}",
+                to_peer_id, store_id, region_id, e, reason
             );
         }
     }
@@ -337,6 +1242,12 @@ impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> ServerTranspo
     pub fn flush_raft_client(&mut self) {
         self.raft_client.wl().flush();
     }
+
+    /// Flushes the raft client, and invokes `cb` once the messages are written to the
+    /// connections. See `RaftClient::flush_with_ack`.
+    pub fn flush_raft_client_with_ack(&self, cb: FlushCallback) {
+        self.raft_client.wl().flush_with_ack(cb);
+    }
 }
 
 impl<T, S> Transport for ServerTransport<T, S>
@@ -345,8 +1256,9 @@ where
     S: StoreAddrResolver + 'static,
 {
     fn send(&self, msg: RaftMessage) -> RaftStoreResult<()> {
+        self.observe_sent_msg(&msg);
         let to_store_id = msg.get_to_peer().get_store_id();
-        self.send_store(to_store_id, msg);
+        self.send_store(to_store_id, msg, Instant::now());
         Ok(())
     }
 
@@ -387,3 +1299,1118 @@ impl<T: RaftStoreRouter + 'static> SnapshotReporter<T> {
         }
     }
 }
+
+/// Test doubles of the transport layer.
+#[cfg(test)]
+pub mod testing {
+    use std::sync::{Arc, Mutex};
+
+    use kvproto::raft_cmdpb::RaftCmdRequest;
+    use kvproto::raft_serverpb::RaftMessage;
+
+    use super::RaftStoreRouter;
+    use raftstore::store::{Callback, Msg as StoreMsg, SignificantMsg, Transport, UnreachableReason};
+    use raftstore::Result as RaftStoreResult;
+    use util::collections::HashSet;
+
+    #[derive(Default)]
+    struct Records {
+        raft_msgs: Vec<RaftMessage>,
+        store_msgs: Vec<StoreMsg>,
+        significant_msgs: Vec<SignificantMsg>,
+        flush_count: usize,
+        unresolvable_stores: HashSet<u64>,
+        destroyed_regions: HashSet<u64>,
+    }
+
+    /// `RecordingTransport` keeps everything sent through it in memory instead of
+    /// delivering it, so tests can inspect what was sent.
+    ///
+    /// It's both a `Transport` and a `RaftStoreRouter`, which both have a `send` method,
+    /// so call them like `Transport::send(&trans, msg)`. Like `ServerTransport`, raft
+    /// messages to a store that fails to resolve are dropped, and the target peer is
+    /// reported unreachable through the router side.
+    #[derive(Clone, Default)]
+    pub struct RecordingTransport {
+        records: Arc<Mutex<Records>>,
+    }
+
+    impl RecordingTransport {
+        pub fn new() -> RecordingTransport {
+            RecordingTransport::default()
+        }
+
+        /// Makes resolving `store_id` fail if `fail` is true, or succeed again otherwise.
+        pub fn fail_resolve(&self, store_id: u64, fail: bool) {
+            let mut records = self.records.lock().unwrap();
+            if fail {
+                records.unresolvable_stores.insert(store_id);
+            } else {
+                records.unresolvable_stores.remove(&store_id);
+            }
+        }
+
+        /// Makes the snapshots of `region_id` stale, as if its local peer is destroyed.
+        pub fn destroy_region(&self, region_id: u64) {
+            let mut records = self.records.lock().unwrap();
+            records.destroyed_regions.insert(region_id);
+        }
+
+        /// Takes the raft messages sent so far.
+        pub fn take_raft_msgs(&self) -> Vec<RaftMessage> {
+            self.records.lock().unwrap().raft_msgs.drain(..).collect()
+        }
+
+        /// Takes the store messages sent so far, including commands.
+        pub fn take_store_msgs(&self) -> Vec<StoreMsg> {
+            self.records.lock().unwrap().store_msgs.drain(..).collect()
+        }
+
+        /// Takes the commands sent so far along with their callbacks, and leaves other
+        /// store messages untouched.
+        pub fn take_commands(&self) -> Vec<(RaftCmdRequest, Callback)> {
+            let mut records = self.records.lock().unwrap();
+            let (mut cmds, mut others) = (vec![], vec![]);
+            for msg in records.store_msgs.drain(..) {
+                match msg {
+                    StoreMsg::RaftCmd { request, callback, .. } => cmds.push((request, callback)),
+                    msg => others.push(msg),
+                }
+            }
+            records.store_msgs = others;
+            cmds
+        }
+
+        /// Takes the significant messages sent so far.
+        pub fn take_significant_msgs(&self) -> Vec<SignificantMsg> {
+            self.records.lock().unwrap().significant_msgs.drain(..).collect()
+        }
+
+        /// How many times the transport has been flushed.
+        pub fn flush_count(&self) -> usize {
+            self.records.lock().unwrap().flush_count
+        }
+
+        /// Asserts that the raft messages sent so far are to the peers `to_peer_ids` in order,
+        /// and clears them.
+        pub fn must_sent_to(&self, to_peer_ids: &[u64]) {
+            let msgs = self.take_raft_msgs();
+            let sent_to: Vec<_> = msgs.iter().map(|m| m.get_to_peer().get_id()).collect();
+            assert_eq!(sent_to, to_peer_ids, "{:?}", msgs);
+        }
+    }
+
+    impl Transport for RecordingTransport {
+        fn send(&self, msg: RaftMessage) -> RaftStoreResult<()> {
+            let store_id = msg.get_to_peer().get_store_id();
+            let unresolvable = self.records.lock().unwrap().unresolvable_stores.contains(&store_id);
+            if unresolvable {
+                return self.report_unreachable_with_reason(
+                    msg.get_region_id(),
+                    msg.get_to_peer().get_id(),
+                    UnreachableReason::ResolveFailed,
+                );
+            }
+            self.records.lock().unwrap().raft_msgs.push(msg);
+            Ok(())
+        }
+
+        fn flush(&mut self) {
+            self.records.lock().unwrap().flush_count += 1;
+        }
+    }
+
+    impl RaftStoreRouter for RecordingTransport {
+        fn send(&self, msg: StoreMsg) -> RaftStoreResult<()> {
+            self.try_send(msg)
+        }
+
+        fn try_send(&self, msg: StoreMsg) -> RaftStoreResult<()> {
+            self.records.lock().unwrap().store_msgs.push(msg);
+            Ok(())
+        }
+
+        fn significant_send(&self, msg: SignificantMsg) -> RaftStoreResult<()> {
+            self.records.lock().unwrap().significant_msgs.push(msg);
+            Ok(())
+        }
+
+        fn is_snapshot_stale(&self, msg: &RaftMessage) -> bool {
+            let records = self.records.lock().unwrap();
+            records.destroyed_regions.contains(&msg.get_region_id())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::result;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use grpc::Environment;
+    use kvproto::errorpb;
+    use kvproto::metapb;
+    use kvproto::raft_cmdpb::{AdminCmdType, CmdType, RaftCmdRequest, RaftCmdResponse, Request};
+    use mio::{EventLoop, EventLoopConfig, Handler};
+    use raft::eraftpb::ConfChangeType;
+
+    use super::testing::RecordingTransport;
+    use super::*;
+    use raftstore::errors::RAFTSTORE_IS_BUSY;
+    use raftstore::store::RegionReadProgress;
+    use server::resolve::Callback as ResolveCallback;
+    use util::config::ReadableDuration;
+    use util::security::SecurityManager;
+    use util::worker::Worker;
+
+    struct NoopHandler;
+
+    impl Handler for NoopHandler {
+        type Timeout = ();
+        type Message = StoreMsg;
+    }
+
+    // Responds to the commands and stops after handling `remaining` messages.
+    struct CmdHandler {
+        remaining: usize,
+    }
+
+    impl Handler for CmdHandler {
+        type Timeout = ();
+        type Message = StoreMsg;
+
+        fn notify(&mut self, event_loop: &mut EventLoop<CmdHandler>, msg: StoreMsg) {
+            if let StoreMsg::RaftCmd { callback, .. } = msg {
+                callback.invoke_with_response(RaftCmdResponse::new());
+            }
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                event_loop.shutdown();
+            }
+        }
+    }
+
+    // What the router of the tests sends to, standing in for the raftstore.
+    struct TestStore<H: Handler> {
+        // Runs the handler of the test, if any, on the raft messages and commands.
+        event_loop: EventLoop<H>,
+        significant_msgs: mpsc::Receiver<SignificantMsg>,
+        // Not started, so the reads scheduled to it keep pending.
+        local_reader: Worker<ReadTask>,
+        hosted_regions: HostedRegions,
+    }
+
+    // Creates a router hosting `regions`. The store should be kept alive while the router is
+    // used.
+    fn new_test_router<H>(cfg: &Config, regions: &[u64]) -> (ServerRaftStoreRouter, TestStore<H>)
+    where
+        H: Handler<Message = StoreMsg>,
+    {
+        new_test_router_on(EventLoop::new().unwrap(), cfg, regions)
+    }
+
+    // Like `new_test_router`, but the raftstore channel holds at most `capacity` messages, so
+    // that the tests can fill it up.
+    fn new_test_router_with_capacity<H>(
+        capacity: usize,
+        cfg: &Config,
+        regions: &[u64],
+    ) -> (ServerRaftStoreRouter, TestStore<H>)
+    where
+        H: Handler<Message = StoreMsg>,
+    {
+        let mut config = EventLoopConfig::new();
+        config.notify_capacity(capacity);
+        new_test_router_on(EventLoop::configured(config).unwrap(), cfg, regions)
+    }
+
+    fn new_test_router_on<H>(
+        event_loop: EventLoop<H>,
+        cfg: &Config,
+        regions: &[u64],
+    ) -> (ServerRaftStoreRouter, TestStore<H>)
+    where
+        H: Handler<Message = StoreMsg>,
+    {
+        let ch = SendCh::new(event_loop.channel(), "test");
+        let (significant_msg_sender, significant_msgs) = mpsc::channel();
+        let local_reader = Worker::new("test-local-reader");
+        let hosted_regions = HostedRegions::default();
+        for &region_id in regions {
+            hosted_regions.insert(region_id);
+        }
+        let router = ServerRaftStoreRouter::new(
+            ch,
+            significant_msg_sender,
+            local_reader.scheduler(),
+            hosted_regions.clone(),
+            cfg,
+        );
+        let store = TestStore {
+            event_loop,
+            significant_msgs,
+            local_reader,
+            hosted_regions,
+        };
+        (router, store)
+    }
+
+    #[test]
+    fn test_send_command_to_absent_region() {
+        let (router, store) = new_test_router::<NoopHandler>(&Config::default(), &[]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        let mut put = Request::new();
+        put.set_cmd_type(CmdType::Put);
+        req.mut_requests().push(put);
+
+        let (tx, rx) = mpsc::channel();
+        let cb = Callback::Write(box move |_| tx.send(()).unwrap());
+        match router.send_command(req.clone(), cb) {
+            Err(RaftStoreError::RegionNotFound(1)) => {}
+            res => panic!("expect region not found, but got {:?}", res),
+        }
+        // The callback is dropped without being invoked.
+        assert!(rx.recv().is_err());
+
+        store.hosted_regions.insert(1);
+        assert!(router.has_region(1));
+        let cb = Callback::Write(box |_| {});
+        router.send_command(req, cb).unwrap();
+
+        store.hosted_regions.remove(1);
+        assert!(!router.has_region(1));
+    }
+
+    // Responds to a command with a stale epoch error carrying `region`, and stops.
+    struct StaleEpochHandler {
+        region: metapb::Region,
+    }
+
+    impl Handler for StaleEpochHandler {
+        type Timeout = ();
+        type Message = StoreMsg;
+
+        fn notify(&mut self, event_loop: &mut EventLoop<StaleEpochHandler>, msg: StoreMsg) {
+            if let StoreMsg::RaftCmd { callback, .. } = msg {
+                let e = RaftStoreError::StaleEpoch("stale".to_owned(), vec![self.region.clone()]);
+                callback.invoke_with_response(cmd_resp::new_error(e));
+            }
+            event_loop.shutdown();
+        }
+    }
+
+    #[test]
+    fn test_stale_epoch_fast_path() {
+        let (router, mut store) = new_test_router::<StaleEpochHandler>(&Config::default(), &[1]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        req.mut_header().mut_region_epoch().set_version(2);
+        let mut put = Request::new();
+        put.set_cmd_type(CmdType::Put);
+        req.mut_requests().push(put);
+
+        let mut region = metapb::Region::new();
+        region.set_id(1);
+        region.mut_region_epoch().set_version(3);
+        let (tx, rx) = mpsc::channel();
+        let tx1 = tx.clone();
+        let cb = Callback::Write(box move |resp| tx1.send(resp.response).unwrap());
+        router.send_command(req.clone(), cb).unwrap();
+        let mut handler = StaleEpochHandler {
+            region: region.clone(),
+        };
+        store.event_loop.run(&mut handler).unwrap();
+        assert!(rx.recv().unwrap().get_header().get_error().has_stale_epoch());
+
+        // The event loop has stopped, so the callback must be invoked by the router.
+        let tx1 = tx.clone();
+        let cb = Callback::Write(box move |resp| tx1.send(resp.response).unwrap());
+        req.mut_header().set_uuid(b"stale".to_vec());
+        router.send_command(req.clone(), cb).unwrap();
+        let resp = rx.try_recv().unwrap();
+        assert_eq!(resp.get_header().get_uuid(), b"stale");
+        let err = resp.get_header().get_error();
+        assert_eq!(err.get_stale_epoch().get_new_regions(), &[region]);
+        assert_eq!(router.outstanding_callbacks(), 0);
+
+        // Commands carrying the latest epoch are still sent to the raftstore.
+        req.mut_header().mut_region_epoch().set_version(3);
+        let cb = Callback::Write(box move |resp| tx.send(resp.response).unwrap());
+        router.send_command(req, cb).unwrap();
+        assert!(rx.try_recv().is_err());
+        assert_eq!(router.outstanding_callbacks(), 1);
+    }
+
+    #[test]
+    fn test_strict_peer_store_check() {
+        let mut cfg = Config::default();
+        cfg.strict_peer_store_check = true;
+        let (router, _store) = new_test_router::<NoopHandler>(&cfg, &[1]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        req.mut_header().mut_peer().set_store_id(2);
+        // Nothing is checked before the local store id is known.
+        router.send_command(req.clone(), Callback::None).unwrap();
+
+        router.clone().set_local_store_id(3);
+        match router.send_command(req.clone(), Callback::None) {
+            Err(RaftStoreError::StoreNotMatch(2, 3)) => {}
+            res => panic!("expect store not match, but got {:?}", res),
+        }
+        req.mut_header().mut_peer().set_store_id(3);
+        router.send_command(req, Callback::None).unwrap();
+    }
+
+    #[test]
+    fn test_significant_send_batch() {
+        let (router, store) = new_test_router::<NoopHandler>(&Config::default(), &[]);
+        let unreachable = |region_id, to_peer_id| {
+            let msg = SignificantMsg::Unreachable {
+                region_id,
+                to_peer_id,
+                reason: UnreachableReason::default(),
+            };
+            (region_id, msg)
+        };
+
+        let msgs = vec![unreachable(2, 1), unreachable(1, 1), unreachable(2, 2)];
+        let results = router.significant_send_batch(msgs);
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|r| r.is_ok()));
+        let sent: Vec<_> = store.significant_msgs.try_iter().collect();
+        assert_eq!(sent, vec![unreachable(1, 1).1, unreachable(2, 1).1, unreachable(2, 2).1]);
+
+        // Every message fails once the receiver is gone.
+        drop(store.significant_msgs);
+        let results = router.significant_send_batch(vec![unreachable(1, 1), unreachable(2, 1)]);
+        assert!(results.iter().all(|r| r.is_err()));
+    }
+
+    #[test]
+    fn test_max_outstanding_callbacks() {
+        let mut cfg = Config::default();
+        cfg.max_outstanding_callbacks = 1;
+        let (router, _store) = new_test_router::<NoopHandler>(&cfg, &[1]);
+
+        let (tx, rx) = mpsc::channel();
+        let cb = Callback::Write(box move |_| tx.send(()).unwrap());
+        let cb = router.track_callback(cb).unwrap();
+        assert_eq!(router.outstanding_callbacks(), 1);
+        // Callbacks beyond the limit are refused, while `Callback::None` is not counted.
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        match router.send_command(req, Callback::Read(box |_| {})) {
+            Err(RaftStoreError::Transport(TransportError::Busy(_))) => {}
+            res => panic!("expect busy, but got {:?}", res),
+        }
+        router.track_callback(Callback::None).unwrap();
+        assert_eq!(router.outstanding_callbacks(), 1);
+
+        cb.invoke_with_response(RaftCmdResponse::new());
+        rx.recv().unwrap();
+        assert_eq!(router.outstanding_callbacks(), 0);
+
+        let cb = router.track_callback(Callback::Read(box |_| {})).unwrap();
+        assert_eq!(router.outstanding_callbacks(), 1);
+        drop(cb);
+        assert_eq!(router.outstanding_callbacks(), 0);
+    }
+
+    #[test]
+    fn test_send_command_with_retry() {
+        let mut cfg = Config::default();
+        cfg.cmd_send_max_retry = 100;
+        let (router, store) = new_test_router_with_capacity::<CmdHandler>(1, &cfg, &[1]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        let mut put = Request::new();
+        put.set_cmd_type(CmdType::Put);
+        req.mut_requests().push(put);
+
+        // Fill the channel before the event loop is running.
+        router.ch.try_send(StoreMsg::Quit).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx1 = tx.clone();
+        let cb = Callback::Write(box move |_| tx1.send(()).unwrap());
+        match router.send_command(req.clone(), cb) {
+            Err(RaftStoreError::Transport(TransportError::Discard(_))) => {}
+            res => panic!("expect discarded, but got {:?}", res),
+        }
+        assert_eq!(router.outstanding_callbacks(), 0);
+
+        let mut event_loop = store.event_loop;
+        let h = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut handler = CmdHandler { remaining: 2 };
+            event_loop.run(&mut handler).unwrap();
+        });
+        let cb = Callback::Write(box move |_| tx.send(()).unwrap());
+        router.send_command_with_retry(req, cb).unwrap();
+        // The command is resent on the timer rather than by blocking the sender.
+        assert_eq!(router.outstanding_callbacks(), 1);
+        h.join().unwrap();
+
+        // Only the command sent eventually is responded, and only once.
+        rx.recv().unwrap();
+        assert!(rx.recv().is_err());
+        assert_eq!(router.outstanding_callbacks(), 0);
+    }
+
+    #[test]
+    fn test_raft_msg_full_policy() {
+        let mut cfg = Config::default();
+        cfg.cmd_send_max_retry = 1;
+        cfg.raft_msg_full_policy = map![
+            "MsgRequestVote".to_owned() => RaftMsgFullPolicy::ForceSend,
+            "MsgHeartbeat".to_owned() => RaftMsgFullPolicy::Block
+        ];
+        let (router, store) = new_test_router_with_capacity::<NoopHandler>(1, &cfg, &[]);
+        let new_msg = |msg_type| {
+            let mut msg = RaftMessage::new();
+            msg.set_region_id(1);
+            msg.mut_message().set_msg_type(msg_type);
+            msg
+        };
+
+        router.ch.try_send(StoreMsg::Quit).unwrap();
+        match router.send_raft_msg(new_msg(MessageType::MsgAppend)) {
+            Err(RaftStoreError::Transport(TransportError::Discard(_))) => {}
+            res => panic!("expect discarded, but got {:?}", res),
+        }
+        // Resent on the timer, without blocking the sender.
+        let start = Instant::now();
+        router.send_raft_msg(new_msg(MessageType::MsgHeartbeat)).unwrap();
+        assert!(start.elapsed() < CMD_SEND_RETRY_BACKOFF);
+        assert!(store.significant_msgs.try_recv().is_err());
+
+        let vote = new_msg(MessageType::MsgRequestVote);
+        router.send_raft_msg(vote.clone()).unwrap();
+        assert_eq!(
+            store.significant_msgs.try_recv().unwrap(),
+            SignificantMsg::RaftMessage(vote)
+        );
+    }
+
+    #[test]
+    fn test_raft_msg_send_retry() {
+        let mut cfg = Config::default();
+        cfg.raft_msg_send_max_retry = 2;
+        cfg.raft_msg_send_retry_backoff = ReadableDuration::millis(50);
+        let (router, _store) = new_test_router_with_capacity::<NoopHandler>(1, &cfg, &[]);
+
+        router.ch.try_send(StoreMsg::Quit).unwrap();
+        let count = RAFT_MSG_SEND_RETRY_HISTOGRAM.get_sample_count();
+        let mut msg = RaftMessage::new();
+        msg.mut_message().set_msg_type(MessageType::MsgAppend);
+        let start = Instant::now();
+        // The message is resent on the timer instead of blocking the sender.
+        router.send_raft_msg(msg).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        for _ in 0..100 {
+            if RAFT_MSG_SEND_RETRY_HISTOGRAM.get_sample_count() > count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(RAFT_MSG_SEND_RETRY_HISTOGRAM.get_sample_count() > count);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    struct TraceHandler {
+        uuids: Sender<Vec<u8>>,
+        remaining: usize,
+    }
+
+    impl Handler for TraceHandler {
+        type Timeout = ();
+        type Message = StoreMsg;
+
+        fn notify(&mut self, event_loop: &mut EventLoop<TraceHandler>, msg: StoreMsg) {
+            if let StoreMsg::RaftCmd { request, .. } = msg {
+                self.uuids.send(request.get_header().get_uuid().to_vec()).unwrap();
+            }
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                event_loop.shutdown();
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_command_trace_id() {
+        let (router, mut store) = new_test_router::<TraceHandler>(&Config::default(), &[1]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        let mut put = Request::new();
+        put.set_cmd_type(CmdType::Put);
+        req.mut_requests().push(put);
+
+        // A trace id is generated for the command without one, and the given one is kept.
+        router.send_command(req.clone(), Callback::None).unwrap();
+        let uuid = Uuid::new_v4().as_bytes().to_vec();
+        req.mut_header().set_uuid(uuid.clone());
+        router.send_command(req, Callback::None).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut handler = TraceHandler {
+            uuids: tx,
+            remaining: 2,
+        };
+        store.event_loop.run(&mut handler).unwrap();
+        let generated = rx.recv().unwrap();
+        assert!(Uuid::from_bytes(&generated).is_ok());
+        assert_ne!(generated, uuid);
+        assert_eq!(rx.recv().unwrap(), uuid);
+    }
+
+    #[test]
+    fn test_send_read_quorum_command() {
+        let (router, store) =
+            new_test_router_with_capacity::<NoopHandler>(1, &Config::default(), &[1]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        let mut get = Request::new();
+        get.set_cmd_type(CmdType::Get);
+        req.mut_requests().push(get);
+
+        // Reads that require quorum go to raftstore directly, which fills up the channel.
+        let mut read_quorum = req.clone();
+        read_quorum.mut_header().set_read_quorum(true);
+        router.send_command(read_quorum, Callback::None).unwrap();
+        assert!(!store.local_reader.is_busy());
+        match router.ch.try_send(StoreMsg::Quit) {
+            Err(TransportError::Discard(_)) => {}
+            res => panic!("expect discarded, but got {:?}", res),
+        }
+
+        // Other reads go to the local reader.
+        router.send_command(req, Callback::None).unwrap();
+        assert!(store.local_reader.is_busy());
+    }
+
+    #[test]
+    fn test_shed_local_reads() {
+        let mut cfg = Config::default();
+        cfg.local_read_shed_threshold = 2;
+        cfg.local_read_shed_window = ReadableDuration::millis(10);
+        let (router, _store) = new_test_router::<NoopHandler>(&cfg, &[1, 2]);
+        let new_read = |region_id| {
+            let mut req = RaftCmdRequest::new();
+            req.mut_header().set_region_id(region_id);
+            let mut get = Request::new();
+            get.set_cmd_type(CmdType::Get);
+            req.mut_requests().push(get);
+            req
+        };
+
+        // The local reader is not started, so the reads keep pending.
+        router.send_command(new_read(1), Callback::None).unwrap();
+        router.send_command(new_read(1), Callback::None).unwrap();
+        router.send_command(new_read(2), Callback::None).unwrap();
+
+        // Region 1 is the hottest region of the last window.
+        thread::sleep(Duration::from_millis(10));
+        match router.send_command(new_read(1), Callback::None) {
+            Err(RaftStoreError::Transport(TransportError::Busy(_))) => {}
+            res => panic!("expect busy, but got {:?}", res),
+        }
+        router.send_command(new_read(2), Callback::None).unwrap();
+    }
+
+    #[test]
+    fn test_region_read_quota() {
+        let mut cfg = Config::default();
+        cfg.region_read_quota = 10;
+        let (router, _store) = new_test_router::<NoopHandler>(&cfg, &[1, 2]);
+        let new_read = |region_id| {
+            let mut req = RaftCmdRequest::new();
+            req.mut_header().set_region_id(region_id);
+            let mut get = Request::new();
+            get.set_cmd_type(CmdType::Get);
+            req.mut_requests().push(get);
+            req
+        };
+
+        // Region 1 is flooded, while region 2 is not affected.
+        let throttled = (0..20)
+            .filter(|_| router.send_command(new_read(1), Callback::None).is_err())
+            .count();
+        assert!(throttled >= 9);
+        match router.send_command(new_read(1), Callback::None) {
+            Err(RaftStoreError::Transport(TransportError::Throttled(_))) => {}
+            res => panic!("expect throttled, but got {:?}", res),
+        }
+        for _ in 0..5 {
+            router.send_command(new_read(2), Callback::None).unwrap();
+        }
+
+        router.set_region_read_quota(1, Some(0));
+        router.send_command(new_read(1), Callback::None).unwrap();
+    }
+
+    #[test]
+    fn test_region_write_quota() {
+        let mut cfg = Config::default();
+        cfg.region_write_quota = 10;
+        let (router, _store) = new_test_router::<NoopHandler>(&cfg, &[1, 2]);
+        let new_put = |region_id| {
+            let mut req = RaftCmdRequest::new();
+            req.mut_header().set_region_id(region_id);
+            let mut put = Request::new();
+            put.set_cmd_type(CmdType::Put);
+            req.mut_requests().push(put);
+            req
+        };
+
+        // Region 1 is flooded, while region 2 is not affected.
+        let throttled = (0..20)
+            .filter(|_| router.send_command(new_put(1), Callback::None).is_err())
+            .count();
+        assert!(throttled >= 9);
+        match router.send_command(new_put(1), Callback::None) {
+            Err(RaftStoreError::Transport(TransportError::Throttled(_))) => {}
+            res => panic!("expect throttled, but got {:?}", res),
+        }
+        for _ in 0..5 {
+            router.send_command(new_put(2), Callback::None).unwrap();
+        }
+        let throttled = REGION_WRITE_THROTTLED_COUNTER_VEC.with_label_values(&["1"]);
+        assert!(throttled.get() >= 10);
+        assert_eq!(
+            REGION_WRITE_THROTTLED_COUNTER_VEC
+                .with_label_values(&["2"])
+                .get(),
+            0
+        );
+
+        // Admin commands are never throttled.
+        let mut compact = RaftCmdRequest::new();
+        compact.mut_header().set_region_id(1);
+        compact
+            .mut_admin_request()
+            .set_cmd_type(AdminCmdType::CompactLog);
+        router.send_command(compact, Callback::None).unwrap();
+
+        router.set_region_write_quota(1, Some(0));
+        router.send_command(new_put(1), Callback::None).unwrap();
+    }
+
+    #[test]
+    fn test_router_errors() {
+        let new_task = || {
+            let msg = StoreMsg::new_raft_cmd(RaftCmdRequest::new(), Callback::None);
+            ReadTask::read(msg)
+        };
+        match local_reader_error(ScheduleError::Full(new_task())) {
+            RaftStoreError::Transport(TransportError::Discard(_)) => {}
+            e => panic!("expect discarded, but got {:?}", e),
+        }
+        match local_reader_error(ScheduleError::Stopped(new_task())) {
+            RaftStoreError::Transport(TransportError::Closed) => {}
+            e => panic!("expect closed, but got {:?}", e),
+        }
+
+        // Clients are told why the store is busy.
+        let cases = vec![
+            (TransportError::Discard("full".to_owned()), RAFTSTORE_IS_BUSY),
+            (TransportError::Busy("busy".to_owned()), "busy"),
+            (TransportError::Throttled("throttled".to_owned()), "throttled"),
+        ];
+        for (e, reason) in cases {
+            let err: errorpb::Error = RaftStoreError::Transport(e).into();
+            assert_eq!(err.get_server_is_busy().get_reason(), reason);
+        }
+        let err: errorpb::Error = RaftStoreError::Transport(TransportError::Closed).into();
+        assert!(!err.has_server_is_busy());
+    }
+
+    #[test]
+    fn test_async_conf_change() {
+        let router = RecordingTransport::new();
+        let mut leader = metapb::Peer::new();
+        leader.set_id(1);
+        leader.set_store_id(1);
+        let mut epoch = metapb::RegionEpoch::new();
+        epoch.set_conf_ver(2);
+        let mut peer = metapb::Peer::new();
+        peer.set_id(2);
+        peer.set_store_id(2);
+        let (tx, rx) = mpsc::channel();
+        let new_cb = || -> ConfChangeCallback {
+            let tx = tx.clone();
+            box move |res: result::Result<metapb::Region, errorpb::Error>| tx.send(res).unwrap()
+        };
+
+        // Malformed changes are refused before being sent.
+        let mut no_store = peer.clone();
+        no_store.set_store_id(0);
+        let learner = ConfChange::new(ConfChangeType::AddLearnerNode, peer.clone());
+        for change in vec![ConfChange::new(ConfChangeType::AddNode, no_store), learner] {
+            router
+                .async_conf_change(1, leader.clone(), epoch.clone(), change, new_cb())
+                .unwrap_err();
+        }
+        assert!(router.take_commands().is_empty());
+
+        let change = ConfChange::new(ConfChangeType::AddNode, peer.clone());
+        router
+            .async_conf_change(1, leader.clone(), epoch.clone(), change, new_cb())
+            .unwrap();
+        let (req, cb) = router.take_commands().pop().unwrap();
+        assert_eq!(req.get_header().get_region_id(), 1);
+        assert_eq!(*req.get_header().get_peer(), leader);
+        assert_eq!(*req.get_header().get_region_epoch(), epoch);
+        let admin = req.get_admin_request();
+        assert_eq!(admin.get_cmd_type(), AdminCmdType::ChangePeer);
+        assert_eq!(
+            admin.get_change_peer().get_change_type(),
+            ConfChangeType::AddNode
+        );
+        assert_eq!(*admin.get_change_peer().get_peer(), peer);
+        let mut resp = RaftCmdResponse::new();
+        let mut region = metapb::Region::new();
+        region.set_id(1);
+        region.mut_peers().push(peer.clone());
+        resp.mut_admin_response()
+            .mut_change_peer()
+            .set_region(region.clone());
+        cb.invoke_with_response(resp);
+        assert_eq!(rx.recv().unwrap(), Ok(region));
+
+        let change = ConfChange::new(ConfChangeType::RemoveNode, peer);
+        router
+            .async_conf_change(1, leader, epoch, change, new_cb())
+            .unwrap();
+        let (_, cb) = router.take_commands().pop().unwrap();
+        cb.invoke_with_response(cmd_resp::new_error(RaftStoreError::RegionNotFound(1)));
+        assert!(rx.recv().unwrap().unwrap_err().has_region_not_found());
+    }
+
+    #[test]
+    fn test_region_read_progress() {
+        let (router, _store) = new_test_router::<NoopHandler>(&Config::default(), &[1]);
+
+        match router.region_read_progress(2, box |_: RaftStoreResult<RegionReadProgress>| {}) {
+            Err(RaftStoreError::RegionNotFound(2)) => {}
+            res => panic!("expect region not found, but got {:?}", res),
+        }
+        router
+            .region_read_progress(1, box |_: RaftStoreResult<RegionReadProgress>| {})
+            .unwrap();
+    }
+
+    #[test]
+    fn test_subscribe_leader_change() {
+        let (router, store) = new_test_router::<NoopHandler>(&Config::default(), &[1]);
+
+        match router.subscribe_leader_change(2, box |_: bool| {}) {
+            Err(RaftStoreError::RegionNotFound(2)) => {}
+            res => panic!("expect region not found, but got {:?}", res),
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let id = router
+            .subscribe_leader_change(1, box move |is_leader: bool| {
+                tx.lock().unwrap().send(is_leader).unwrap()
+            })
+            .unwrap();
+        store.hosted_regions.notify_leader_change(1, true);
+        store.hosted_regions.notify_leader_change(1, false);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![true, false]);
+
+        // The callback is dropped after unsubscribing.
+        router.unsubscribe_leader_change(1, id);
+        store.hosted_regions.notify_leader_change(1, true);
+        assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+
+        // Subscriptions are dropped along with the region.
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        router
+            .subscribe_leader_change(1, box move |is_leader: bool| {
+                tx.lock().unwrap().send(is_leader).unwrap()
+            })
+            .unwrap();
+        store.hosted_regions.remove(1);
+        store.hosted_regions.notify_leader_change(1, true);
+        assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    }
+
+    #[derive(Clone)]
+    struct NoopResolver;
+
+    impl StoreAddrResolver for NoopResolver {
+        fn resolve(&self, _: u64, _: ResolveCallback) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Creates a transport resolving the stores with `resolver`, and sending to the returned
+    // router. The snapshots are scheduled to the returned worker, which is not started.
+    fn new_test_transport<S>(
+        cfg: Config,
+        resolver: S,
+    ) -> (
+        ServerTransport<RecordingTransport, S>,
+        RecordingTransport,
+        Worker<SnapTask>,
+    )
+    where
+        S: StoreAddrResolver + 'static,
+    {
+        let env = Arc::new(Environment::new(1));
+        let cfg = Arc::new(cfg);
+        let security_mgr = Arc::new(SecurityManager::default());
+        let raft_client = RaftClient::new(env, Arc::clone(&cfg), security_mgr);
+        let snap_worker = Worker::new("test-snap");
+        let router = RecordingTransport::new();
+        let trans = ServerTransport::new(
+            Arc::new(RwLock::new(raft_client)),
+            snap_worker.scheduler(),
+            router.clone(),
+            resolver,
+            &cfg,
+        );
+        (trans, router, snap_worker)
+    }
+
+    #[test]
+    fn test_dedup_unreachable_reports() {
+        let mut cfg = Config::default();
+        cfg.unreachable_report_dedup_interval = ReadableDuration::millis(20);
+        let (trans, router, _snap_worker) = new_test_transport(cfg, NoopResolver);
+
+        let reason = UnreachableReason::SendFailed;
+        for _ in 0..100 {
+            trans.report_peer_unreachable(1, 2, 3, reason);
+            trans.report_peer_unreachable(4, 5, 3, reason);
+        }
+        // Only the first report of each peer goes through.
+        assert_eq!(router.take_significant_msgs().len(), 2);
+
+        // Reports go through again after the interval.
+        thread::sleep(Duration::from_millis(20));
+        for _ in 0..100 {
+            trans.report_peer_unreachable(1, 2, 3, reason);
+        }
+        assert_eq!(router.take_significant_msgs().len(), 1);
+
+        // Or once a message is sent to the store.
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        trans.write_data(3, "127.0.0.1:0", msg, Instant::now());
+        trans.report_peer_unreachable(1, 2, 3, reason);
+        trans.report_peer_unreachable(4, 5, 3, reason);
+        assert_eq!(router.take_significant_msgs().len(), 2);
+    }
+
+    #[test]
+    fn test_dedup_unreachable_reports_bounded() {
+        let mut cfg = Config::default();
+        cfg.unreachable_report_dedup_interval = ReadableDuration::secs(60);
+        let (trans, router, _snap_worker) = new_test_transport(cfg, NoopResolver);
+
+        let reason = UnreachableReason::SendFailed;
+        let count = MAX_UNREACHABLE_PEERS as u64;
+        for region_id in 0..count + 10 {
+            trans.report_peer_unreachable(region_id, 1, region_id % 3, reason);
+        }
+        assert_eq!(router.take_significant_msgs().len(), count as usize + 10);
+        let recorded: usize = trans.reported_unreachable.rl().values().map(|p| p.len()).sum();
+        assert_eq!(recorded, MAX_UNREACHABLE_PEERS);
+
+        // The recorded peers are still suppressed, while the others are not.
+        trans.report_peer_unreachable(0, 1, 0, reason);
+        assert!(router.take_significant_msgs().is_empty());
+        trans.report_peer_unreachable(count, 1, count % 3, reason);
+        assert_eq!(router.take_significant_msgs().len(), 1);
+    }
+
+    #[test]
+    fn test_skip_snapshot_to_unreachable_peer() {
+        let mut cfg = Config::default();
+        cfg.snap_send_unreachable_window = ReadableDuration::millis(20);
+        let (trans, router, snap_worker) = new_test_transport(cfg, NoopResolver);
+        let snap_scheduler = snap_worker.scheduler();
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        msg.mut_to_peer().set_id(2);
+        msg.mut_to_peer().set_store_id(3);
+        msg.mut_message().set_msg_type(MessageType::MsgSnapshot);
+        msg.mut_message().mut_snapshot();
+        trans.report_peer_unreachable(1, 2, 3, UnreachableReason::SendFailed);
+        // Other peers are not affected, and too large messages don't count.
+        trans.report_peer_unreachable(1, 4, 3, UnreachableReason::MessageTooLarge);
+        router.take_significant_msgs();
+
+        trans.write_data(3, "127.0.0.1:0", msg.clone(), Instant::now());
+        assert_eq!(
+            router.take_significant_msgs(),
+            vec![SignificantMsg::SnapshotStatus {
+                region_id: 1,
+                to_peer_id: 2,
+                status: SnapshotStatus::Failure,
+            }]
+        );
+        assert_eq!(snap_scheduler.pending_tasks(), 0);
+        let mut other = msg.clone();
+        other.mut_to_peer().set_id(4);
+        trans.write_data(3, "127.0.0.1:0", other, Instant::now());
+        assert_eq!(snap_scheduler.pending_tasks(), 1);
+
+        // Snapshots are sent again after the window.
+        thread::sleep(Duration::from_millis(20));
+        trans.write_data(3, "127.0.0.1:0", msg, Instant::now());
+        assert_eq!(snap_scheduler.pending_tasks(), 2);
+        assert!(router.take_significant_msgs().is_empty());
+    }
+
+    #[derive(Clone)]
+    struct FailingResolver {
+        count: Arc<AtomicUsize>,
+    }
+
+    impl StoreAddrResolver for FailingResolver {
+        fn resolve(&self, store_id: u64, cb: ResolveCallback) -> Result<()> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            cb(Err(box_err!("store {} is not registered", store_id)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_resolve_negative_cache() {
+        let mut cfg = Config::default();
+        cfg.resolve_negative_cache_ttl = ReadableDuration::millis(20);
+        let count = Arc::new(AtomicUsize::new(0));
+        let (trans, router, _snap_worker) = new_test_transport(
+            cfg,
+            FailingResolver {
+                count: Arc::clone(&count),
+            },
+        );
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        msg.mut_to_peer().set_id(2);
+        msg.mut_to_peer().set_store_id(3);
+        let hits = RESOLVE_STORE_COUNTER.with_label_values(&["neg_cache_hit"]);
+        let hit_count = hits.get();
+        for _ in 0..10 {
+            Transport::send(&trans, msg.clone()).unwrap();
+        }
+        // The store is resolved only once in the TTL, and every message is still reported.
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(hits.get(), hit_count + 9);
+        assert_eq!(router.take_significant_msgs().len(), 10);
+
+        // It's resolved again after the TTL.
+        thread::sleep(Duration::from_millis(20));
+        Transport::send(&trans, msg.clone()).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        Transport::send(&trans, msg.clone()).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Expired failures are pruned when another store fails.
+        thread::sleep(Duration::from_millis(20));
+        msg.mut_to_peer().set_store_id(4);
+        Transport::send(&trans, msg).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        let failed: Vec<_> = trans.failed_stores.rl().keys().cloned().collect();
+        assert_eq!(failed, vec![4]);
+    }
+
+    #[derive(Clone)]
+    struct PanicResolver;
+
+    impl StoreAddrResolver for PanicResolver {
+        fn resolve(&self, store_id: u64, _: ResolveCallback) -> Result<()> {
+            panic!("store {} should not be resolved", store_id)
+        }
+    }
+
+    #[test]
+    fn test_send_to_local_store() {
+        let (trans, router, _snap_worker) = new_test_transport(Config::default(), PanicResolver);
+        trans.clone().set_local_store_id(1);
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(2);
+        msg.mut_to_peer().set_store_id(1);
+        Transport::send(&trans, msg).unwrap();
+        let msgs = router.take_store_msgs();
+        assert_eq!(msgs.len(), 1);
+        match msgs[0] {
+            StoreMsg::RaftMessage(ref msg) => assert_eq!(msg.get_region_id(), 2),
+            ref msg => panic!("expect the raft message, but got {:?}", msg),
+        }
+        assert!(trans.raft_client.rl().addrs.is_empty());
+    }
+
+    #[test]
+    fn test_count_sent_raft_msgs() {
+        let (trans, router, _snap_worker) = new_test_transport(Config::default(), PanicResolver);
+        trans.clone().set_local_store_id(1);
+        let votes = SENT_RAFT_MSG_BY_TYPE_COUNTER.with_label_values(&["MsgRequestVote"]);
+        let count = votes.get();
+
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(2);
+        msg.mut_to_peer().set_store_id(1);
+        msg.mut_message().set_msg_type(MessageType::MsgRequestVote);
+        Transport::send(&trans, msg).unwrap();
+        assert!(votes.get() > count);
+        // Every message type has a counter.
+        assert_eq!(SENT_RAFT_MSG_COUNTERS.len(), MessageType::values().len());
+    }
+
+    #[test]
+    fn test_recording_transport() {
+        let mut trans = RecordingTransport::new();
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(1);
+        msg.mut_to_peer().set_id(2);
+        msg.mut_to_peer().set_store_id(2);
+        Transport::send(&trans, msg.clone()).unwrap();
+        msg.mut_to_peer().set_id(3);
+        msg.mut_to_peer().set_store_id(3);
+        Transport::send(&trans, msg.clone()).unwrap();
+        trans.flush();
+        trans.must_sent_to(&[2, 3]);
+        assert_eq!(trans.flush_count(), 1);
+
+        trans.fail_resolve(3, true);
+        Transport::send(&trans, msg.clone()).unwrap();
+        trans.must_sent_to(&[]);
+        assert_eq!(
+            trans.take_significant_msgs(),
+            vec![SignificantMsg::Unreachable {
+                region_id: 1,
+                to_peer_id: 3,
+                reason: UnreachableReason::ResolveFailed,
+            }]
+        );
+        trans.fail_resolve(3, false);
+        Transport::send(&trans, msg).unwrap();
+        trans.must_sent_to(&[3]);
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_header().set_region_id(1);
+        trans.send_command(req, Callback::None).unwrap();
+        trans.try_send(StoreMsg::Quit).unwrap();
+        let cmds = trans.take_commands();
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].0.get_header().get_region_id(), 1);
+        assert_eq!(trans.take_store_msgs().len(), 1);
+    }
+}
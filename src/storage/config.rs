@@ -23,6 +23,8 @@ const DEFAULT_GC_RATIO_THRESHOLD: f64 = 1.1;
 const DEFAULT_MAX_KEY_SIZE: usize = 4 * 1024;
 const DEFAULT_SCHED_CAPACITY: usize = 10240;
 const DEFAULT_SCHED_CONCURRENCY: usize = 2048000;
+const DEFAULT_REGION_METRICS_TOP_N: usize = 32;
+const DEFAULT_WRITE_LIMIT_BYPASS_THRESHOLD_KB: u64 = 16;
 
 // According to "Little's law", assuming you can write 100MB per
 // second, and it takes about 100ms to process the write requests
@@ -41,6 +43,12 @@ pub struct Config {
     pub scheduler_concurrency: usize,
     pub scheduler_worker_pool_size: usize,
     pub scheduler_pending_write_threshold: ReadableSize,
+    // The number of busiest regions that have their own request metrics, 0 means disabled.
+    pub region_metrics_top_n: usize,
+    // The bytes written per second through raft, 0 means unlimited.
+    pub write_limit_bytes_per_sec: ReadableSize,
+    // Writes smaller than it are not limited.
+    pub write_limit_bypass_threshold: ReadableSize,
 }
 
 impl Default for Config {
@@ -54,6 +62,9 @@ impl Default for Config {
             scheduler_concurrency: DEFAULT_SCHED_CONCURRENCY,
             scheduler_worker_pool_size: if total_cpu >= 16 { 8 } else { 4 },
             scheduler_pending_write_threshold: ReadableSize::mb(DEFAULT_SCHED_PENDING_WRITE_MB),
+            region_metrics_top_n: DEFAULT_REGION_METRICS_TOP_N,
+            write_limit_bytes_per_sec: ReadableSize(0),
+            write_limit_bypass_threshold: ReadableSize::kb(DEFAULT_WRITE_LIMIT_BYPASS_THRESHOLD_KB),
         }
     }
 }
@@ -11,7 +11,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::exponential_buckets;
+use prometheus::{
+    exponential_buckets, Gauge, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
 use prometheus_static_metric::*;
 
 use storage::ErrorHeaderKind;
@@ -87,4 +89,32 @@ lazy_static! {
             exponential_buckets(0.0005, 2.0, 20).unwrap()
         ).unwrap()
     };
+    pub static ref ENGINE_BLOCK_CACHE_HIT_RATE_GAUGE: Gauge = register_gauge!(
+        "tikv_storage_engine_block_cache_hit_rate",
+        "Block cache hit rate of the storage engine"
+    ).unwrap();
+    pub static ref ENGINE_PENDING_COMPACTION_BYTES_GAUGE: IntGauge = register_int_gauge!(
+        "tikv_storage_engine_pending_compaction_bytes",
+        "Pending compaction bytes of the storage engine"
+    ).unwrap();
+    pub static ref ENGINE_NUM_FILES_AT_LEVEL_GAUGE_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "tikv_storage_engine_num_files_at_level",
+        "Number of SST files at each level of the storage engine",
+        &["level"]
+    ).unwrap();
+    pub static ref REGION_REQUESTS_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_storage_engine_region_request_total",
+        "Total number of engine requests of the busiest regions",
+        &["region", "type"]
+    ).unwrap();
+    pub static ref REGION_REQUESTS_DURATIONS_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_storage_engine_region_request_duration_seconds",
+        "Bucketed histogram of engine requests duration of the busiest regions",
+        &["region", "type"],
+        exponential_buckets(0.0005, 2.0, 20).unwrap()
+    ).unwrap();
+    pub static ref ASYNC_WRITE_COALESCED_COUNTER: IntCounter = register_int_counter!(
+        "tikv_storage_engine_async_write_coalesced_total",
+        "Total number of modifications dropped as overwritten by later ones in the same write"
+    ).unwrap();
 }
@@ -15,27 +15,40 @@ use std::boxed::FnBox;
 use std::cell::Cell;
 use std::cmp::Ordering;
 use std::fmt::{Debug, Display};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{error, result};
 
+use futures::{future, Future};
 use kvproto::errorpb::Error as ErrorHeader;
 use kvproto::kvrpcpb::{Context, ScanDetail, ScanInfo};
 use raftstore::store::engine::IterOption;
 use raftstore::store::{SeekRegionFilter, SeekRegionResult};
 use rocksdb::TablePropertiesCollection;
 use storage::{CfName, Key, Value, CF_DEFAULT, CF_LOCK, CF_WRITE};
+use util::future::paired_future_callback;
 
 mod btree_engine;
 mod cursor_builder;
 mod metrics;
+mod pending_batch;
 mod perf_context;
 pub mod raftkv;
+mod region_metrics;
 mod rocksdb;
+mod size_cache;
+mod write_limiter;
 
 pub use self::btree_engine::{BTreeEngine, BTreeEngineIterator, BTreeEngineSnapshot};
 pub use self::cursor_builder::CursorBuilder;
+pub use self::pending_batch::{pending_batch_reads, PendingBatchRead};
 pub use self::perf_context::{PerfStatisticsDelta, PerfStatisticsInstant};
-pub use self::rocksdb::{RocksEngine, RocksSnapshot, TestEngineBuilder};
+pub use self::rocksdb::{get_engine_stats, RocksEngine, RocksSnapshot, TestEngineBuilder};
+
+use self::metrics::{
+    ENGINE_BLOCK_CACHE_HIT_RATE_GAUGE, ENGINE_NUM_FILES_AT_LEVEL_GAUGE_VEC,
+    ENGINE_PENDING_COMPACTION_BYTES_GAUGE,
+};
 
 pub const SEEK_BOUND: u64 = 8;
 
@@ -51,6 +64,23 @@ const STAT_SEEK_FOR_PREV: &str = "seek_for_prev";
 const STAT_OVER_SEEK_BOUND: &str = "over_seek_bound";
 
 pub type Callback<T> = Box<FnBox((CbContext, Result<T>)) + Send>;
+pub type BatchCallback<T> = Box<FnBox(Vec<(CbContext, Result<T>)>) + Send>;
+
+/// The result of a write to the engine.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WriteResult {
+    /// The raft log index the write is applied at, 0 for engines that don't replicate
+    /// writes through raft. It only grows across the writes to a region.
+    pub applied_index: u64,
+}
+
+/// The estimated size of a region, taken by `Engine::async_approximate_size`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ApproximateSize {
+    /// The bytes of the data of the region.
+    pub size: u64,
+    pub keys: u64,
+}
 
 #[derive(Debug)]
 pub struct CbContext {
@@ -63,10 +93,110 @@ impl CbContext {
     }
 }
 
-#[derive(Debug)]
+struct BatchCollectorCore<T> {
+    results: Vec<Option<(CbContext, Result<T>)>>,
+    remain: usize,
+    callback: Option<BatchCallback<T>>,
+    // The id of the batch in `pending_batch_reads`.
+    batch_id: Option<usize>,
+}
+
+impl<T> Drop for BatchCollectorCore<T> {
+    fn drop(&mut self) {
+        // The results are given up if the callbacks are dropped before being invoked.
+        if let Some(id) = self.batch_id.take() {
+            pending_batch::unregister(id);
+        }
+    }
+}
+
+/// Gathers the results of a batch of async operations and invokes the
+/// `BatchCallback` once every slot is filled.
+struct BatchCollector<T> {
+    core: Arc<Mutex<BatchCollectorCore<T>>>,
+}
+
+impl<T> Clone for BatchCollector<T> {
+    fn clone(&self) -> Self {
+        BatchCollector {
+            core: Arc::clone(&self.core),
+        }
+    }
+}
+
+impl<T> BatchCollector<T> {
+    /// `regions` are the regions of the operations in the batch, reported by
+    /// `pending_batch_reads` until the results are collected.
+    fn new(regions: Vec<u64>, callback: BatchCallback<T>) -> BatchCollector<T> {
+        let size = regions.len();
+        if size == 0 {
+            callback(vec![]);
+            return BatchCollector {
+                core: Arc::new(Mutex::new(BatchCollectorCore {
+                    results: vec![],
+                    remain: 0,
+                    callback: None,
+                    batch_id: None,
+                })),
+            };
+        }
+        BatchCollector {
+            core: Arc::new(Mutex::new(BatchCollectorCore {
+                results: (0..size).map(|_| None).collect(),
+                remain: size,
+                callback: Some(callback),
+                batch_id: Some(pending_batch::register(regions)),
+            })),
+        }
+    }
+
+    /// Fills the slot `index`. Results for filled slots, or coming after the callback is
+    /// invoked, are dropped.
+    fn collect(&self, index: usize, cb_ctx: CbContext, res: Result<T>) {
+        let (callback, results) = {
+            let mut core = self.core.lock().unwrap();
+            match core.results.get(index) {
+                Some(&None) => {}
+                _ => return,
+            }
+            core.results[index] = Some((cb_ctx, res));
+            core.remain -= 1;
+            if let Some(id) = core.batch_id {
+                pending_batch::finish_slot(id, index);
+            }
+            if core.remain > 0 {
+                return;
+            }
+            if let Some(id) = core.batch_id.take() {
+                pending_batch::unregister(id);
+            }
+            let results = core.results.drain(..).map(Option::unwrap).collect();
+            (core.callback.take().unwrap(), results)
+        };
+        callback(results);
+    }
+}
+
+impl<T: Clone> BatchCollector<T> {
+    /// Fills every slot in `indices` with the same result.
+    fn collect_shared(&self, indices: &[usize], cb_ctx: CbContext, res: Result<T>) {
+        for &i in &indices[1..] {
+            let res = match res {
+                Ok(ref t) => Ok(t.clone()),
+                Err(ref e) => Err(e.maybe_clone().unwrap_or_else(|| box_err!("{:?}", e))),
+            };
+            self.collect(i, CbContext { term: cb_ctx.term }, res);
+        }
+        self.collect(indices[0], cb_ctx, res);
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Modify {
     Delete(CfName, Key),
     Put(CfName, Key, Value),
+    /// Deletes the keys in `[start_key, end_key)` of the column family. `RaftKv` clamps the
+    /// range to the region of the context, and an empty end key means the end of the region.
     DeleteRange(CfName, Key, Key),
 }
 
@@ -77,6 +207,64 @@ pub trait Engine: Send + Display + Debug + Clone + Sized + 'static {
     fn async_write(&self, ctx: &Context, batch: Vec<Modify>, callback: Callback<()>) -> Result<()>;
     fn async_snapshot(&self, ctx: &Context, callback: Callback<Self::Snap>) -> Result<()>;
 
+    /// Same as `async_write`, but the callback is given the `WriteResult`, for clients
+    /// that track how fresh a follower is. Engines that don't replicate writes through
+    /// raft report the default.
+    fn async_write_with_result(
+        &self,
+        ctx: &Context,
+        batch: Vec<Modify>,
+        callback: Callback<WriteResult>,
+    ) -> Result<()> {
+        self.async_write(ctx, batch, box move |(cb_ctx, res): (_, Result<()>)| {
+            callback((cb_ctx, res.map(|()| WriteResult::default())))
+        })
+    }
+
+    /// Takes a snapshot for every context in `batch`. The callback is invoked once all
+    /// snapshots are ready, with results in the same order as `batch`.
+    fn async_batch_snapshot(
+        &self,
+        batch: Vec<Context>,
+        callback: BatchCallback<Self::Snap>,
+    ) -> Result<()> {
+        let regions = batch.iter().map(|ctx| ctx.get_region_id()).collect();
+        let collector = BatchCollector::new(regions, callback);
+        for (i, ctx) in batch.iter().enumerate() {
+            let c = collector.clone();
+            let cb = box move |(cb_ctx, res)| c.collect(i, cb_ctx, res);
+            if let Err(e) = self.async_snapshot(ctx, cb) {
+                collector.collect(i, CbContext::new(), Err(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Invokes the callback once all the writes to the region of `ctx` finished before are
+    /// applied and persisted, as a durability checkpoint after bulk writes. Engines that
+    /// finish writes synchronously have nothing to wait for.
+    fn async_flush(&self, _: &Context, callback: Callback<()>) -> Result<()> {
+        callback((CbContext::new(), Ok(())));
+        Ok(())
+    }
+
+    /// Estimates the bytes and the keys of the region of `ctx`, for planning like balancing
+    /// that only needs rough figures. Engines that can't estimate report zeros.
+    fn async_approximate_size(
+        &self,
+        _: &Context,
+        callback: Callback<ApproximateSize>,
+    ) -> Result<()> {
+        callback((CbContext::new(), Ok(ApproximateSize::default())));
+        Ok(())
+    }
+
+    /// Gets a point-in-time summary of the underlying storage engine. Engines that
+    /// can't provide these figures report zeros.
+    fn get_statistics(&self) -> EngineStats {
+        EngineStats::default()
+    }
+
     fn write(&self, ctx: &Context, batch: Vec<Modify>) -> Result<()> {
         let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
         match wait_op!(|cb| self.async_write(ctx, batch, cb), timeout) {
@@ -85,6 +273,30 @@ pub trait Engine: Send + Display + Debug + Clone + Sized + 'static {
         }
     }
 
+    fn write_with_result(&self, ctx: &Context, batch: Vec<Modify>) -> Result<WriteResult> {
+        let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+        match wait_op!(|cb| self.async_write_with_result(ctx, batch, cb), timeout) {
+            Some((_, res)) => res,
+            None => Err(Error::Timeout(timeout)),
+        }
+    }
+
+    fn flush(&self, ctx: &Context) -> Result<()> {
+        let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+        match wait_op!(|cb| self.async_flush(ctx, cb), timeout) {
+            Some((_, res)) => res,
+            None => Err(Error::Timeout(timeout)),
+        }
+    }
+
+    fn approximate_size(&self, ctx: &Context) -> Result<ApproximateSize> {
+        let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+        match wait_op!(|cb| self.async_approximate_size(ctx, cb), timeout) {
+            Some((_, res)) => res,
+            None => Err(Error::Timeout(timeout)),
+        }
+    }
+
     fn snapshot(&self, ctx: &Context) -> Result<Self::Snap> {
         let timeout = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
         match wait_op!(|cb| self.async_snapshot(ctx, cb), timeout) {
@@ -93,6 +305,21 @@ pub trait Engine: Send + Display + Debug + Clone + Sized + 'static {
         }
     }
 
+    /// Takes a snapshot like `async_snapshot`, but resolves the returned future with it
+    /// instead of invoking a callback. Nothing blocks while waiting for the snapshot, and
+    /// the future fails if the engine drops the callback without invoking it.
+    fn snapshot_future(
+        &self,
+        ctx: &Context,
+    ) -> Box<Future<Item = Self::Snap, Error = Error> + Send> {
+        let (cb, rx) = paired_future_callback();
+        let res = self.async_snapshot(ctx, cb);
+        let f = future::result(res)
+            .and_then(|_| rx.map_err(|cancel| Error::Other(box_err!(cancel))))
+            .and_then(|(_, res)| res);
+        box f
+    }
+
     fn put(&self, ctx: &Context, key: Key, value: Value) -> Result<()> {
         self.put_cf(ctx, CF_DEFAULT, key, value)
     }
@@ -128,6 +355,11 @@ pub trait Snapshot: Send + Debug + Clone + Sized {
     fn get_properties_cf(&self, _: CfName) -> Result<TablePropertiesCollection> {
         Err(Error::RocksDb("no user properties".to_owned()))
     }
+    /// Returns the applied index of the region that the snapshot is taken on, or `None` if
+    /// the snapshot is not of a region.
+    fn applied_index(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub trait Iterator: Send + Sized {
@@ -308,6 +540,42 @@ impl StatisticsSummary {
     }
 }
 
+/// `EngineStats` is a summary of the underlying storage engine, taken by
+/// `Engine::get_statistics`.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct EngineStats {
+    pub block_cache_hit: u64,
+    pub block_cache_miss: u64,
+    pub pending_compaction_bytes: u64,
+    // SST file count of every level, summed over all column families.
+    pub num_files_at_level: Vec<u64>,
+}
+
+impl EngineStats {
+    pub fn block_cache_hit_rate(&self) -> f64 {
+        let total = self.block_cache_hit + self.block_cache_miss;
+        if total == 0 {
+            return 0.0;
+        }
+        self.block_cache_hit as f64 / total as f64
+    }
+
+    pub fn total_sst_files(&self) -> u64 {
+        self.num_files_at_level.iter().sum()
+    }
+
+    /// Exports the statistics as prometheus metrics.
+    pub fn flush_metrics(&self) {
+        ENGINE_BLOCK_CACHE_HIT_RATE_GAUGE.set(self.block_cache_hit_rate());
+        ENGINE_PENDING_COMPACTION_BYTES_GAUGE.set(self.pending_compaction_bytes as i64);
+        for (level, n) in self.num_files_at_level.iter().enumerate() {
+            ENGINE_NUM_FILES_AT_LEVEL_GAUGE_VEC
+                .with_label_values(&[&level.to_string()])
+                .set(*n as i64);
+        }
+    }
+}
+
 pub struct Cursor<I: Iterator> {
     iter: I,
     scan_mode: ScanMode,
@@ -784,6 +1052,8 @@ pub mod tests {
         test_near_seek(engine);
         test_cf(engine);
         test_empty_write(engine);
+        test_batch_snapshot(engine);
+        test_snapshot_future(engine);
     }
 
     fn test_get_put<E: Engine>(engine: &E) {
@@ -1082,6 +1352,50 @@ pub mod tests {
         engine.write(&Context::new(), vec![]).unwrap_err();
     }
 
+    fn test_batch_snapshot<E: Engine>(engine: &E) {
+        must_put(engine, b"z", b"1");
+        let batch: Vec<_> = (1..4u64)
+            .map(|id| {
+                let mut ctx = Context::new();
+                ctx.set_region_id(id);
+                ctx
+            })
+            .collect();
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        engine
+            .async_batch_snapshot(batch, box move |res| tx.send(res).unwrap())
+            .unwrap();
+        let snaps = rx.recv().unwrap();
+        assert_eq!(snaps.len(), 3);
+        for (_, snap) in snaps {
+            let v = snap.unwrap().get(&Key::from_raw(b"z")).unwrap();
+            assert_eq!(v.unwrap(), b"1");
+        }
+
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        engine
+            .async_batch_snapshot(vec![], box move |res| tx.send(res).unwrap())
+            .unwrap();
+        assert!(rx.recv().unwrap().is_empty());
+        must_delete(engine, b"z");
+    }
+
+    fn test_snapshot_future<E: Engine>(engine: &E) {
+        must_put(engine, b"y", b"1");
+        let key = Key::from_raw(b"y");
+        let snap = engine.snapshot_future(&Context::new()).wait().unwrap();
+        let expected = engine.snapshot(&Context::new()).unwrap().get(&key).unwrap();
+        assert_eq!(snap.get(&key).unwrap(), expected);
+        assert_eq!(expected.unwrap(), b"1");
+
+        // The snapshot taken before is not affected by later writes.
+        must_put(engine, b"y", b"2");
+        assert_eq!(snap.get(&key).unwrap().unwrap(), b"1");
+        let snap = engine.snapshot_future(&Context::new()).wait().unwrap();
+        assert_eq!(snap.get(&key).unwrap().unwrap(), b"2");
+        must_delete(engine, b"y");
+    }
+
     pub fn test_cfs_statistics<E: Engine>(engine: &E) {
         must_put(engine, b"foo", b"bar1");
         must_put(engine, b"foo2", b"bar2");
@@ -1133,4 +1447,35 @@ pub mod tests {
         assert_eq!(iter.value(&mut statistics), b"bar1");
         assert_eq!(statistics.prev, 3);
     }
+
+    #[test]
+    fn test_batch_collector_partial_failure() {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let collector = BatchCollector::new(vec![1, 2, 3], box move |res| tx.send(res).unwrap());
+        let batch_id = collector.core.lock().unwrap().batch_id.unwrap();
+        let pending = || pending_batch::pending_regions(batch_id);
+        collector.collect(2, CbContext::new(), Ok(3));
+        collector.collect(0, CbContext::new(), Err(Error::EmptyRequest));
+        // A slot is only filled once.
+        collector.collect(0, CbContext::new(), Ok(1));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(pending(), Some(vec![2]));
+        collector.collect(1, CbContext::new(), Ok(2));
+        assert_eq!(pending(), None);
+
+        let res = rx.recv().unwrap();
+        assert_eq!(res.len(), 3);
+        let mut res = res.into_iter().map(|(_, r)| r);
+        match res.next().unwrap() {
+            Err(Error::EmptyRequest) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(res.next().unwrap().unwrap(), 2);
+        assert_eq!(res.next().unwrap().unwrap(), 3);
+
+        // Late and duplicated results after the callback is invoked are dropped.
+        collector.collect(1, CbContext::new(), Ok(4));
+        collector.collect(2, CbContext::new(), Err(Error::EmptyRequest));
+        assert!(rx.try_recv().is_err());
+    }
 }
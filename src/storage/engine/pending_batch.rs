@@ -0,0 +1,119 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use util::collections::HashMap;
+
+struct PendingBatch {
+    start: Instant,
+    // The region of each slot, `None` once the slot is filled.
+    regions: Vec<Option<u64>>,
+}
+
+// Batches are spread over the shards by their ids, so that concurrent batches rarely
+// contend on the same lock.
+const SHARD_COUNT: usize = 32;
+
+lazy_static! {
+    static ref PENDING_BATCHES: Vec<Mutex<HashMap<usize, PendingBatch>>> = (0..SHARD_COUNT)
+        .map(|_| Mutex::new(HashMap::default()))
+        .collect();
+    static ref NEXT_BATCH_ID: AtomicUsize = AtomicUsize::new(0);
+}
+
+fn shard(id: usize) -> &'static Mutex<HashMap<usize, PendingBatch>> {
+    &PENDING_BATCHES[id % SHARD_COUNT]
+}
+
+/// A batch read whose results are not all collected yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingBatchRead {
+    pub elapsed: Duration,
+    /// The regions of the reads not finished yet, one for each read.
+    pub pending_regions: Vec<u64>,
+}
+
+/// Returns the batch reads in flight, the longest running first, for finding the read
+/// that holds up a whole batch.
+pub fn pending_batch_reads() -> Vec<PendingBatchRead> {
+    let now = Instant::now();
+    let mut reads = vec![];
+    for batches in PENDING_BATCHES.iter() {
+        let batches = batches.lock().unwrap();
+        reads.extend(batches.values().map(|b| PendingBatchRead {
+            elapsed: now.duration_since(b.start),
+            pending_regions: b.regions.iter().filter_map(|r| *r).collect(),
+        }));
+    }
+    reads.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+    reads
+}
+
+/// Tracks a batch of reads to `regions`, and returns the id of the batch.
+pub fn register(regions: Vec<u64>) -> usize {
+    let id = NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed);
+    let batch = PendingBatch {
+        start: Instant::now(),
+        regions: regions.into_iter().map(Some).collect(),
+    };
+    shard(id).lock().unwrap().insert(id, batch);
+    id
+}
+
+/// Returns the regions of the reads not finished yet of the batch `id`, or `None` if the
+/// batch isn't tracked.
+pub fn pending_regions(id: usize) -> Option<Vec<u64>> {
+    let batches = shard(id).lock().unwrap();
+    batches
+        .get(&id)
+        .map(|b| b.regions.iter().filter_map(|r| *r).collect())
+}
+
+/// Marks the read at `index` of the batch `id` finished.
+pub fn finish_slot(id: usize, index: usize) {
+    if let Some(batch) = shard(id).lock().unwrap().get_mut(&id) {
+        batch.regions[index] = None;
+    }
+}
+
+/// Stops tracking the batch `id`, once all its results are collected or given up.
+pub fn unregister(id: usize) {
+    shard(id).lock().unwrap().remove(&id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_batch_reads() {
+        let id = register(vec![1, 2, 1]);
+        assert_eq!(pending_regions(id), Some(vec![1, 2, 1]));
+        finish_slot(id, 0);
+        finish_slot(id, 2);
+        assert_eq!(pending_regions(id), Some(vec![2]));
+        assert!(
+            pending_batch_reads()
+                .iter()
+                .any(|r| r.pending_regions == vec![2])
+        );
+        unregister(id);
+        assert_eq!(pending_regions(id), None);
+        // Finishing an untracked batch is a no-op.
+        finish_slot(id, 1);
+    }
+}
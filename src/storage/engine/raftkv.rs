@@ -14,11 +14,12 @@
 use std::fmt::{self, Debug, Display, Formatter};
 use std::io::Error as IoError;
 use std::result;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 
 use kvproto::errorpb;
 use kvproto::kvrpcpb::Context;
+use kvproto::metapb;
 use kvproto::raft_cmdpb::{
     CmdType, DeleteRangeRequest, DeleteRequest, PutRequest, RaftCmdRequest, RaftCmdResponse,
     RaftRequestHeader, Request, Response,
@@ -26,20 +27,26 @@ use kvproto::raft_cmdpb::{
 use protobuf::RepeatedField;
 
 use super::metrics::*;
+use super::region_metrics::RegionMetrics;
+use super::size_cache::SizeCache;
+use super::write_limiter::WriteLimiter;
 use super::{
-    Callback, CbContext, Cursor, Engine, Iterator as EngineIterator, Modify, RegionInfoProvider,
-    ScanMode, Snapshot,
+    get_engine_stats, ApproximateSize, BatchCallback, BatchCollector, Callback, CbContext, Cursor,
+    Engine, EngineStats, Iterator as EngineIterator, Modify, RegionInfoProvider, ScanMode,
+    Snapshot, WriteResult,
 };
 use raftstore::errors::Error as RaftServerError;
 use raftstore::store::engine::IterOption;
 use raftstore::store::engine::Peekable;
+use raftstore::store::util::format_trace_id;
 use raftstore::store::{Callback as StoreCallback, ReadResponse, WriteResponse};
 use raftstore::store::{
     Msg as StoreMsg, RegionIterator, RegionSnapshot, SeekRegionFilter, SeekRegionResult,
 };
-use rocksdb::TablePropertiesCollection;
+use rocksdb::{TablePropertiesCollection, DB};
 use server::transport::RaftStoreRouter;
 use storage::{self, engine, CfName, Key, Value, CF_DEFAULT};
+use util::collections::HashMap;
 
 quick_error! {
     #[derive(Debug)]
@@ -122,8 +129,16 @@ impl From<RaftServerError> for engine::Error {
 #[derive(Clone)]
 pub struct RaftKv<S: RaftStoreRouter + 'static> {
     router: S,
+    // The local kv engine, only used for collecting statistics.
+    local_engine: Option<Arc<DB>>,
+    region_metrics: Option<Arc<RegionMetrics>>,
+    write_limiter: Option<Arc<WriteLimiter>>,
+    size_cache: Arc<SizeCache>,
 }
 
+// How long the approximate size of a region is reused.
+const APPROXIMATE_SIZE_CACHE_TTL: Duration = Duration::from_secs(10);
+
 pub enum CmdRes {
     Resp(Vec<Response>),
     Snap(RegionSnapshot),
@@ -141,22 +156,28 @@ fn check_raft_cmd_response(resp: &mut RaftCmdResponse, req_cnt: usize) -> Result
     }
     if req_cnt != resp.get_responses().len() {
         return Err(Error::InvalidResponse(format!(
-            "responses count {} is not equal to requests count {}",
+            "responses count {} is not equal to requests count {} of command {}",
             resp.get_responses().len(),
-            req_cnt
+            req_cnt,
+            format_trace_id(resp.get_header().get_uuid())
         )));
     }
 
     Ok(())
 }
 
-fn on_write_result(mut write_resp: WriteResponse, req_cnt: usize) -> (CbContext, Result<CmdRes>) {
+fn on_write_result(
+    mut write_resp: WriteResponse,
+    req_cnt: usize,
+) -> (CbContext, Result<WriteResult>) {
     let cb_ctx = new_ctx(&write_resp.response);
     if let Err(e) = check_raft_cmd_response(&mut write_resp.response, req_cnt) {
         return (cb_ctx, Err(e));
     }
-    let resps = write_resp.response.take_responses();
-    (cb_ctx, Ok(CmdRes::Resp(resps.into_vec())))
+    let res = WriteResult {
+        applied_index: write_resp.applied_index,
+    };
+    (cb_ctx, Ok(res))
 }
 
 fn on_read_result(mut read_resp: ReadResponse, req_cnt: usize) -> (CbContext, Result<CmdRes>) {
@@ -166,7 +187,15 @@ fn on_read_result(mut read_resp: ReadResponse, req_cnt: usize) -> (CbContext, Re
     }
     let resps = read_resp.response.take_responses();
     if resps.len() >= 1 || resps[0].get_cmd_type() == CmdType::Snap {
-        (cb_ctx, Ok(CmdRes::Snap(read_resp.snapshot.unwrap())))
+        // Report a missing snapshot as an error of this read only, so that it
+        // doesn't abort the other entries of a batch.
+        match read_resp.snapshot {
+            Some(snapshot) => (cb_ctx, Ok(CmdRes::Snap(snapshot))),
+            None => (
+                cb_ctx,
+                Err(Error::InvalidResponse("snapshot is missing".to_owned())),
+            ),
+        }
     } else {
         (cb_ctx, Ok(CmdRes::Resp(resps.into_vec())))
     }
@@ -175,7 +204,40 @@ fn on_read_result(mut read_resp: ReadResponse, req_cnt: usize) -> (CbContext, Re
 impl<S: RaftStoreRouter> RaftKv<S> {
     /// Create a RaftKv using specified configuration.
     pub fn new(router: S) -> RaftKv<S> {
-        RaftKv { router }
+        RaftKv {
+            router,
+            local_engine: None,
+            region_metrics: None,
+            write_limiter: None,
+            size_cache: Arc::new(SizeCache::new(APPROXIMATE_SIZE_CACHE_TTL)),
+        }
+    }
+
+    /// Enables per-region request metrics for the `top_n` busiest regions.
+    pub fn with_region_metrics(mut self, top_n: usize) -> RaftKv<S> {
+        self.region_metrics = Some(Arc::new(RegionMetrics::new(top_n)));
+        self
+    }
+
+    /// Limits the bytes written per second. Writes beyond the limit are refused with
+    /// `ServerIsBusy`, except the ones smaller than `bypass_threshold`.
+    pub fn with_write_limiter(mut self, bytes_per_sec: u64, bypass_threshold: usize) -> RaftKv<S> {
+        self.write_limiter = Some(Arc::new(WriteLimiter::new(bytes_per_sec, bypass_threshold)));
+        self
+    }
+
+    /// Sets the local kv engine that `get_statistics` reads from.
+    pub fn with_local_engine(mut self, engine: Arc<DB>) -> RaftKv<S> {
+        self.local_engine = Some(engine);
+        self
+    }
+
+    /// Gets a snapshot of the region of `ctx`, blocking until it's ready or timed out.
+    /// Region errors like not leader or stale epoch are returned as errors.
+    ///
+    /// It's for tools and tests only, requests should use `async_snapshot` instead.
+    pub fn sync_snapshot(&self, ctx: &Context) -> engine::Result<RegionSnapshot> {
+        Engine::snapshot(self, ctx)
     }
 
     fn new_request_header(&self, ctx: &Context) -> RaftRequestHeader {
@@ -217,7 +279,7 @@ impl<S: RaftStoreRouter> RaftKv<S> {
         &self,
         ctx: &Context,
         reqs: Vec<Request>,
-        cb: Callback<CmdRes>,
+        cb: Callback<WriteResult>,
     ) -> Result<()> {
         fail_point!("raftkv_early_error_report", |_| Err(
             RaftServerError::RegionNotFound(ctx.get_region_id()).into()
@@ -238,47 +300,22 @@ impl<S: RaftStoreRouter> RaftKv<S> {
             )
             .map_err(From::from)
     }
-}
-
-fn invalid_resp_type(exp: CmdType, act: CmdType) -> Error {
-    Error::InvalidResponse(format!(
-        "cmd type not match, want {:?}, got {:?}!",
-        exp, act
-    ))
-}
 
-impl<S: RaftStoreRouter> Display for RaftKv<S> {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "RaftKv")
-    }
-}
-
-impl<S: RaftStoreRouter> Debug for RaftKv<S> {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "RaftKv")
-    }
-}
-
-impl<S: RaftStoreRouter> Engine for RaftKv<S> {
-    type Iter = RegionIterator;
-    type Snap = RegionSnapshot;
-
-    fn async_write(
+    /// Proposes `modifies` as a single raft command.
+    fn write_modifies(
         &self,
         ctx: &Context,
         modifies: Vec<Modify>,
-        cb: Callback<()>,
+        cb: Callback<WriteResult>,
     ) -> engine::Result<()> {
-        fail_point!("raftkv_async_write");
-        if modifies.is_empty() {
-            return Err(engine::Error::EmptyRequest);
-        }
-
+        let modifies = coalesce_modifies(modifies);
         let mut reqs = Vec::with_capacity(modifies.len());
+        let mut write_size = 0;
         for m in modifies {
             let mut req = Request::new();
             match m {
                 Modify::Delete(cf, k) => {
+                    write_size += k.as_encoded().len();
                     let mut delete = DeleteRequest::new();
                     delete.set_key(k.into_encoded());
                     if cf != CF_DEFAULT {
@@ -288,6 +325,7 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
                     req.set_delete(delete);
                 }
                 Modify::Put(cf, k, v) => {
+                    write_size += k.as_encoded().len() + v.len();
                     let mut put = PutRequest::new();
                     put.set_key(k.into_encoded());
                     put.set_value(v);
@@ -298,6 +336,7 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
                     req.set_put(put);
                 }
                 Modify::DeleteRange(cf, start_key, end_key) => {
+                    write_size += start_key.as_encoded().len() + end_key.as_encoded().len();
                     let mut delete_range = DeleteRangeRequest::new();
                     delete_range.set_cf(cf.to_string());
                     delete_range.set_start_key(start_key.into_encoded());
@@ -310,19 +349,30 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
         }
 
         ASYNC_REQUESTS_COUNTER_VEC.write.all.inc();
+        if let Some(ref limiter) = self.write_limiter {
+            if !limiter.try_acquire(write_size) {
+                let e = server_is_busy_error("write limit exceeded");
+                let status_kind = get_status_kind_from_error(&e);
+                ASYNC_REQUESTS_COUNTER_VEC.write.get(status_kind).inc();
+                return Err(e.into());
+            }
+        }
         let req_timer = ASYNC_REQUESTS_DURATIONS_VEC.write.start_coarse_timer();
+        let region_timer = self
+            .region_metrics
+            .as_ref()
+            .map(|m| m.on_request(ctx.get_region_id(), "write"));
 
         self.exec_write_requests(ctx, reqs, box move |(cb_ctx, res)| match res {
-            Ok(CmdRes::Resp(_)) => {
+            Ok(res) => {
                 req_timer.observe_duration();
+                if let Some(t) = region_timer {
+                    t.observe_duration();
+                }
                 ASYNC_REQUESTS_COUNTER_VEC.write.success.inc();
                 fail_point!("raftkv_async_write_finish");
-                cb((cb_ctx, Ok(())))
+                cb((cb_ctx, Ok(res)))
             }
-            Ok(CmdRes::Snap(_)) => cb((
-                cb_ctx,
-                Err(box_err!("unexpect snapshot, should mutate instead.")),
-            )),
             Err(e) => {
                 let status_kind = get_status_kind_from_engine_error(&e);
                 ASYNC_REQUESTS_COUNTER_VEC.write.get(status_kind).inc();
@@ -334,6 +384,187 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
             e.into()
         })
     }
+}
+
+/// Clamps the delete ranges in `modifies` to `region`. An empty end key means the end of
+/// the region. A range that doesn't overlap the region is refused with `KeyNotInRegion`.
+fn clamp_delete_ranges(
+    region: &metapb::Region,
+    modifies: Vec<Modify>,
+) -> engine::Result<Vec<Modify>> {
+    modifies
+        .into_iter()
+        .map(|m| match m {
+            Modify::DeleteRange(cf, start_key, end_key) => {
+                let (start_key, end_key) =
+                    clamp_range(region, start_key.into_encoded(), end_key.into_encoded())?;
+                Ok(Modify::DeleteRange(
+                    cf,
+                    Key::from_encoded(start_key),
+                    Key::from_encoded(end_key),
+                ))
+            }
+            m => Ok(m),
+        })
+        .collect()
+}
+
+fn clamp_range(
+    region: &metapb::Region,
+    mut start_key: Vec<u8>,
+    mut end_key: Vec<u8>,
+) -> engine::Result<(Vec<u8>, Vec<u8>)> {
+    let (region_start, region_end) = (region.get_start_key(), region.get_end_key());
+    if !region_end.is_empty() && start_key.as_slice() >= region_end {
+        return Err(RaftServerError::KeyNotInRegion(start_key, region.clone()).into());
+    }
+    if !end_key.is_empty() && end_key.as_slice() <= region_start {
+        return Err(RaftServerError::KeyNotInRegion(end_key, region.clone()).into());
+    }
+    if start_key.as_slice() < region_start {
+        start_key = region_start.to_vec();
+    }
+    if !region_end.is_empty() && (end_key.is_empty() || end_key.as_slice() > region_end) {
+        end_key = region_end.to_vec();
+    }
+    Ok((start_key, end_key))
+}
+
+fn server_is_busy_error(reason: &str) -> Error {
+    let mut err = errorpb::Error::new();
+    err.set_message(reason.to_owned());
+    let mut server_is_busy = errorpb::ServerIsBusy::new();
+    server_is_busy.set_reason(reason.to_owned());
+    err.set_server_is_busy(server_is_busy);
+    Error::RequestFailed(err)
+}
+
+fn modify_cf(m: &Modify) -> CfName {
+    match *m {
+        Modify::Delete(cf, _) | Modify::Put(cf, ..) | Modify::DeleteRange(cf, ..) => cf,
+    }
+}
+
+/// Drops the writes to keys that are overwritten by a later `Put` or `Delete` in the batch,
+/// and groups the rest by column family. Modifications to the same column family are kept
+/// in order, so applying the result is the same as applying `modifies`.
+fn coalesce_modifies(modifies: Vec<Modify>) -> Vec<Modify> {
+    let mut overwritten = vec![false; modifies.len()];
+    {
+        let mut last_writes: HashMap<(CfName, &Key), usize> = HashMap::default();
+        for (i, m) in modifies.iter().enumerate() {
+            let key = match *m {
+                Modify::Delete(cf, ref k) | Modify::Put(cf, ref k, _) => (cf, k),
+                Modify::DeleteRange(..) => continue,
+            };
+            if let Some(prev) = last_writes.insert(key, i) {
+                overwritten[prev] = true;
+            }
+        }
+    }
+    let dropped = overwritten.iter().filter(|o| **o).count();
+    if dropped > 0 {
+        ASYNC_WRITE_COALESCED_COUNTER.inc_by(dropped as i64);
+    }
+
+    let mut modifies: Vec<_> = modifies
+        .into_iter()
+        .zip(overwritten)
+        .filter(|&(_, overwritten)| !overwritten)
+        .map(|(m, _)| m)
+        .collect();
+    let mut cfs = vec![];
+    for m in &modifies {
+        let cf = modify_cf(m);
+        if !cfs.contains(&cf) {
+            cfs.push(cf);
+        }
+    }
+    // `sort_by_key` is stable.
+    modifies.sort_by_key(|m| cfs.iter().position(|cf| *cf == modify_cf(m)));
+    modifies
+}
+
+fn invalid_resp_type(exp: CmdType, act: CmdType) -> Error {
+    Error::InvalidResponse(format!(
+        "cmd type not match, want {:?}, got {:?}!",
+        exp, act
+    ))
+}
+
+impl<S: RaftStoreRouter> Display for RaftKv<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "RaftKv")
+    }
+}
+
+impl<S: RaftStoreRouter> Debug for RaftKv<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "RaftKv")
+    }
+}
+
+impl<S: RaftStoreRouter> Engine for RaftKv<S> {
+    type Iter = RegionIterator;
+    type Snap = RegionSnapshot;
+
+    fn async_write(
+        &self,
+        ctx: &Context,
+        modifies: Vec<Modify>,
+        cb: Callback<()>,
+    ) -> engine::Result<()> {
+        self.async_write_with_result(ctx, modifies, box move |(cb_ctx, res)| {
+            cb((cb_ctx, res.map(|_| ())))
+        })
+    }
+
+    fn async_write_with_result(
+        &self,
+        ctx: &Context,
+        modifies: Vec<Modify>,
+        cb: Callback<WriteResult>,
+    ) -> engine::Result<()> {
+        fail_point!("raftkv_async_write");
+        if modifies.is_empty() {
+            return Err(engine::Error::EmptyRequest);
+        }
+
+        let has_range = modifies.iter().any(|m| match *m {
+            Modify::DeleteRange(..) => true,
+            _ => false,
+        });
+        if !has_range {
+            return self.write_modifies(ctx, modifies, cb);
+        }
+
+        // Delete ranges are clamped to the region, so the region has to be known, and
+        // a snapshot is the way to get it checked against the context.
+        let kv = self.clone();
+        let write_ctx = ctx.clone();
+        self.async_snapshot(
+            ctx,
+            box move |(cb_ctx, res): (_, engine::Result<Self::Snap>)| {
+                // `write_modifies` drops the callback if it fails before proposing.
+                let cb = Arc::new(Mutex::new(Some(cb)));
+                let res = res
+                    .and_then(|snap| clamp_delete_ranges(snap.get_region(), modifies))
+                    .and_then(|modifies| {
+                        let cb = Arc::clone(&cb);
+                        kv.write_modifies(
+                            &write_ctx,
+                            modifies,
+                            box move |res| cb.lock().unwrap().take().unwrap()(res),
+                        )
+                    });
+                if let Err(e) = res {
+                    if let Some(cb) = cb.lock().unwrap().take() {
+                        cb((cb_ctx, Err(e)));
+                    }
+                }
+            },
+        )
+    }
 
     fn async_snapshot(&self, ctx: &Context, cb: Callback<Self::Snap>) -> engine::Result<()> {
         fail_point!("raftkv_async_snapshot");
@@ -342,6 +573,10 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
 
         ASYNC_REQUESTS_COUNTER_VEC.snapshot.all.inc();
         let req_timer = ASYNC_REQUESTS_DURATIONS_VEC.snapshot.start_coarse_timer();
+        let region_timer = self
+            .region_metrics
+            .as_ref()
+            .map(|m| m.on_request(ctx.get_region_id(), "snapshot"));
 
         self.exec_read_requests(ctx, vec![req], box move |(cb_ctx, res)| match res {
             Ok(CmdRes::Resp(r)) => cb((
@@ -350,6 +585,9 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
             )),
             Ok(CmdRes::Snap(s)) => {
                 req_timer.observe_duration();
+                if let Some(t) = region_timer {
+                    t.observe_duration();
+                }
                 ASYNC_REQUESTS_COUNTER_VEC.snapshot.success.inc();
                 cb((cb_ctx, Ok(s)))
             }
@@ -364,6 +602,111 @@ impl<S: RaftStoreRouter> Engine for RaftKv<S> {
             e.into()
         })
     }
+
+    /// Proposes a read index request, which finishes only after all the writes committed
+    /// before it are applied, and then syncs the WAL of the local engine if it's set.
+    fn async_flush(&self, ctx: &Context, cb: Callback<()>) -> engine::Result<()> {
+        let mut req = Request::new();
+        req.set_cmd_type(CmdType::Snap);
+        let mut header = self.new_request_header(ctx);
+        header.set_read_quorum(true);
+        let mut cmd = RaftCmdRequest::new();
+        cmd.set_header(header);
+        cmd.set_requests(RepeatedField::from_vec(vec![req]));
+
+        let local_engine = self.local_engine.clone();
+        self.router
+            .send_command(
+                cmd,
+                StoreCallback::Read(box move |resp| {
+                    let (cb_ctx, res) = on_read_result(resp, 1);
+                    let res = res.map_err(Error::into).and_then(|_| match local_engine {
+                        Some(db) => db.sync_wal().map_err(|e| box_err!(e)),
+                        None => Ok(()),
+                    });
+                    cb((cb_ctx, res));
+                }),
+            )
+            .map_err(|e| Error::from(e).into())
+    }
+
+    /// Contexts of the same region, epoch and term share a single snapshot.
+    fn async_batch_snapshot(
+        &self,
+        batch: Vec<Context>,
+        callback: BatchCallback<Self::Snap>,
+    ) -> engine::Result<()> {
+        let mut groups: Vec<(&Context, Vec<usize>)> = vec![];
+        let mut group_ids = HashMap::default();
+        for (i, ctx) in batch.iter().enumerate() {
+            let epoch = ctx.get_region_epoch();
+            let key = (
+                ctx.get_region_id(),
+                epoch.get_conf_ver(),
+                epoch.get_version(),
+                ctx.get_term(),
+            );
+            let id = *group_ids.entry(key).or_insert_with(|| {
+                groups.push((ctx, vec![]));
+                groups.len() - 1
+            });
+            groups[id].1.push(i);
+        }
+
+        let regions = batch.iter().map(|ctx| ctx.get_region_id()).collect();
+        let collector = BatchCollector::new(regions, callback);
+        for (ctx, indices) in groups {
+            let c = collector.clone();
+            let cb_indices = indices.clone();
+            let cb = box move |(cb_ctx, res)| c.collect_shared(&cb_indices, cb_ctx, res);
+            if let Err(e) = self.async_snapshot(ctx, cb) {
+                collector.collect_shared(&indices, CbContext::new(), Err(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimates the size from the table properties over the range of the region, which is
+    /// taken from a snapshot, so that the region is checked like reads. The estimate is
+    /// reused for a while as long as the region isn't split or merged.
+    fn async_approximate_size(
+        &self,
+        ctx: &Context,
+        cb: Callback<ApproximateSize>,
+    ) -> engine::Result<()> {
+        let region_id = ctx.get_region_id();
+        let size_cache = Arc::clone(&self.size_cache);
+        // The snapshot checks the epoch and the leadership like reads, and the cache is only
+        // consulted for the epoch of the region the snapshot is taken on.
+        self.async_snapshot(ctx, box move |(cb_ctx, res)| {
+            let res = res.and_then(|snap| {
+                let version = snap.get_region().get_region_epoch().get_version();
+                if let Some(size) = size_cache.get(region_id, version) {
+                    return Ok(size);
+                }
+                match snap.get_approximate_size() {
+                    Ok((size, keys)) => {
+                        let size = ApproximateSize { size, keys };
+                        size_cache.put(region_id, version, size);
+                        Ok(size)
+                    }
+                    Err(e) => Err(box_err!(
+                        "failed to estimate the size of region {}: {:?}",
+                        region_id,
+                        e
+                    )),
+                }
+            });
+            cb((cb_ctx, res))
+        })
+    }
+
+    fn get_statistics(&self) -> EngineStats {
+        match self.local_engine {
+            Some(ref db) => get_engine_stats(db),
+            None => EngineStats::default(),
+        }
+    }
 }
 
 impl<S: RaftStoreRouter> RegionInfoProvider for RaftKv<S> {
@@ -443,6 +786,10 @@ impl Snapshot for RegionSnapshot {
     fn get_properties_cf(&self, cf: CfName) -> engine::Result<TablePropertiesCollection> {
         RegionSnapshot::get_properties_cf(self, cf).map_err(|e| e.into())
     }
+
+    fn applied_index(&self) -> Option<u64> {
+        RegionSnapshot::get_applied_index(self).ok()
+    }
 }
 
 impl EngineIterator for RegionIterator {
@@ -492,3 +839,104 @@ impl EngineIterator for RegionIterator {
         RegionIterator::value(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::{CF_LOCK, CF_WRITE};
+
+    #[test]
+    fn test_coalesce_modifies() {
+        let key = |k: &[u8]| Key::from_raw(k);
+        let modifies = vec![
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v1".to_vec()),
+            Modify::Put(CF_LOCK, key(b"a"), b"lock".to_vec()),
+            Modify::Put(CF_WRITE, key(b"a"), b"write".to_vec()),
+            Modify::Delete(CF_DEFAULT, key(b"a")),
+            Modify::Delete(CF_LOCK, key(b"b")),
+            Modify::DeleteRange(CF_DEFAULT, key(b"b"), key(b"c")),
+            Modify::Put(CF_DEFAULT, key(b"b"), b"v2".to_vec()),
+            Modify::Delete(CF_LOCK, key(b"a")),
+            Modify::Put(CF_DEFAULT, key(b"c"), b"v3".to_vec()),
+        ];
+        let expected = vec![
+            Modify::Put(CF_WRITE, key(b"a"), b"write".to_vec()),
+            Modify::Delete(CF_DEFAULT, key(b"a")),
+            Modify::DeleteRange(CF_DEFAULT, key(b"b"), key(b"c")),
+            Modify::Put(CF_DEFAULT, key(b"b"), b"v2".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"c"), b"v3".to_vec()),
+            Modify::Delete(CF_LOCK, key(b"b")),
+            Modify::Delete(CF_LOCK, key(b"a")),
+        ];
+        assert_eq!(coalesce_modifies(modifies), expected);
+    }
+
+    #[test]
+    fn test_coalesce_same_key_modifies() {
+        let key = |k: &[u8]| Key::from_raw(k);
+        // Put then Delete.
+        let modifies = vec![
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v1".to_vec()),
+            Modify::Delete(CF_DEFAULT, key(b"a")),
+        ];
+        assert_eq!(
+            coalesce_modifies(modifies),
+            vec![Modify::Delete(CF_DEFAULT, key(b"a"))]
+        );
+
+        // Delete then Put.
+        let modifies = vec![
+            Modify::Delete(CF_DEFAULT, key(b"a")),
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v1".to_vec()),
+        ];
+        assert_eq!(
+            coalesce_modifies(modifies),
+            vec![Modify::Put(CF_DEFAULT, key(b"a"), b"v1".to_vec())]
+        );
+
+        // Duplicate Puts, while the keys written once keep their order.
+        let modifies = vec![
+            Modify::Put(CF_DEFAULT, key(b"c"), b"v1".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v1".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"b"), b"v1".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v2".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v3".to_vec()),
+        ];
+        let expected = vec![
+            Modify::Put(CF_DEFAULT, key(b"c"), b"v1".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"b"), b"v1".to_vec()),
+            Modify::Put(CF_DEFAULT, key(b"a"), b"v3".to_vec()),
+        ];
+        assert_eq!(coalesce_modifies(modifies), expected);
+    }
+
+    #[test]
+    fn test_clamp_range() {
+        fn clamp(region: &metapb::Region, start: &[u8], end: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+            match clamp_range(region, start.to_vec(), end.to_vec()) {
+                Ok(range) => Some(range),
+                Err(engine::Error::Request(ref e)) if e.has_key_not_in_region() => None,
+                Err(e) => panic!("expect key not in region, but got {:?}", e),
+            }
+        }
+        let range = |start: &[u8], end: &[u8]| Some((start.to_vec(), end.to_vec()));
+
+        let mut region = metapb::Region::new();
+        region.set_start_key(b"b".to_vec());
+        region.set_end_key(b"d".to_vec());
+        assert_eq!(clamp(&region, b"b1", b"c"), range(b"b1", b"c"));
+        assert_eq!(clamp(&region, b"a", b"c"), range(b"b", b"c"));
+        assert_eq!(clamp(&region, b"c", b"e"), range(b"c", b"d"));
+        assert_eq!(clamp(&region, b"", b""), range(b"b", b"d"));
+        assert_eq!(clamp(&region, b"c", b""), range(b"c", b"d"));
+        // Ranges out of the region are refused.
+        assert_eq!(clamp(&region, b"d", b"e"), None);
+        assert_eq!(clamp(&region, b"e", b""), None);
+        assert_eq!(clamp(&region, b"a", b"b"), None);
+
+        // The last region keeps the empty end key.
+        region.set_end_key(vec![]);
+        assert_eq!(clamp(&region, b"c", b""), range(b"c", b""));
+        assert_eq!(clamp(&region, b"a", b"e"), range(b"b", b"e"));
+    }
+}
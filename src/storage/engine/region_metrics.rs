@@ -0,0 +1,253 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use prometheus::{Histogram, IntCounter};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use util::collections::HashMap;
+use util::time::duration_to_sec;
+
+use super::metrics::*;
+
+/// The label for requests to regions that are not tracked.
+pub const OTHER_REGIONS_LABEL: &str = "other";
+
+// Regions are tracked in shards by their ids, so that requests to different regions seldom
+// contend for the same lock.
+const SHARD_COUNT: usize = 16;
+
+/// `RegionMetrics` records request counts and latencies labeled by region id.
+///
+/// To keep the label cardinality bounded, only the `capacity` busiest regions have their
+/// own label, and the rest are folded into `OTHER_REGIONS_LABEL`. The busiest regions
+/// are found with the Misra-Gries frequent items sketch: when a new region arrives and
+/// the sketch is full, every counter is decremented and the regions whose counter drops
+/// to zero are evicted along with their metrics, making room for the new one. Each shard
+/// of the regions keeps its own sketch, with an even share of `capacity`.
+pub struct RegionMetrics {
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl RegionMetrics {
+    pub fn new(capacity: usize) -> RegionMetrics {
+        RegionMetrics::with_shards(capacity, SHARD_COUNT)
+    }
+
+    fn with_shards(capacity: usize, shard_count: usize) -> RegionMetrics {
+        // Every shard tracks one region at least.
+        let shard_count = shard_count.min(capacity).max(1);
+        let shards = (0..shard_count)
+            .map(|i| {
+                // The remainder of `capacity` goes to the first shards.
+                let capacity = capacity / shard_count + (i < capacity % shard_count) as usize;
+                Mutex::new(Shard {
+                    capacity,
+                    regions: HashMap::with_capacity_and_hasher(capacity, Default::default()),
+                    other: vec![],
+                })
+            })
+            .collect();
+        RegionMetrics { shards }
+    }
+
+    /// Records a request of type `tp` to region `region_id`. The returned timer
+    /// should be observed when the request finishes.
+    pub fn on_request(&self, region_id: u64, tp: &'static str) -> RegionTimer {
+        let shard = &self.shards[region_id as usize % self.shards.len()];
+        let (metrics, tracked, evicted) = shard.lock().unwrap().track(region_id, tp);
+        // Removing the metrics of the evicted regions takes the lock of the vectors, so
+        // it's done out of the lock of the shard.
+        for (id, tps) in evicted {
+            let id = id.to_string();
+            for tp in tps {
+                let _ = REGION_REQUESTS_COUNTER_VEC.remove_label_values(&[&id, tp]);
+                let _ = REGION_REQUESTS_DURATIONS_VEC.remove_label_values(&[&id, tp]);
+            }
+        }
+        metrics.counter.inc();
+        RegionTimer {
+            region_id: if tracked { Some(region_id) } else { None },
+            duration: metrics.duration,
+            start: Instant::now(),
+        }
+    }
+}
+
+// The metrics of a type of requests, cached so that the labels are not formatted and looked
+// up on every request.
+#[derive(Clone)]
+struct RequestMetrics {
+    counter: IntCounter,
+    duration: Histogram,
+}
+
+// Returns the cached metrics of `tp`, or caches them first with `label`.
+fn get_or_cache(
+    cache: &mut Vec<(&'static str, RequestMetrics)>,
+    label: &str,
+    tp: &'static str,
+) -> RequestMetrics {
+    if let Some(&(_, ref metrics)) = cache.iter().find(|&&(t, _)| t == tp) {
+        return metrics.clone();
+    }
+    let metrics = RequestMetrics {
+        counter: REGION_REQUESTS_COUNTER_VEC.with_label_values(&[label, tp]),
+        duration: REGION_REQUESTS_DURATIONS_VEC.with_label_values(&[label, tp]),
+    };
+    cache.push((tp, metrics.clone()));
+    metrics
+}
+
+struct TrackedRegion {
+    count: u64,
+    // There are a few types of requests, so they are looked up linearly.
+    metrics: Vec<(&'static str, RequestMetrics)>,
+}
+
+struct Shard {
+    capacity: usize,
+    regions: HashMap<u64, TrackedRegion>,
+    // The metrics of the regions that are not tracked.
+    other: Vec<(&'static str, RequestMetrics)>,
+}
+
+type EvictedRegion = (u64, Vec<&'static str>);
+
+impl Shard {
+    // Returns the metrics to record the request with, whether the region is tracked, and the
+    // regions evicted to make room for it along with the types of their metrics.
+    fn track(
+        &mut self,
+        region_id: u64,
+        tp: &'static str,
+    ) -> (RequestMetrics, bool, Vec<EvictedRegion>) {
+        if let Some(region) = self.regions.get_mut(&region_id) {
+            region.count += 1;
+            let metrics = get_or_cache(&mut region.metrics, &region_id.to_string(), tp);
+            return (metrics, true, vec![]);
+        }
+        let mut evicted = vec![];
+        if self.regions.len() >= self.capacity {
+            for (id, region) in self.regions.iter_mut() {
+                region.count -= 1;
+                if region.count == 0 {
+                    evicted.push(*id);
+                }
+            }
+        }
+        let evicted: Vec<_> = evicted
+            .into_iter()
+            .map(|id| {
+                let region = self.regions.remove(&id).unwrap();
+                (id, region.metrics.into_iter().map(|(tp, _)| tp).collect())
+            })
+            .collect();
+        if self.regions.len() < self.capacity {
+            let mut region = TrackedRegion {
+                count: 1,
+                metrics: vec![],
+            };
+            let metrics = get_or_cache(&mut region.metrics, &region_id.to_string(), tp);
+            self.regions.insert(region_id, region);
+            (metrics, true, evicted)
+        } else {
+            let metrics = get_or_cache(&mut self.other, OTHER_REGIONS_LABEL, tp);
+            (metrics, false, evicted)
+        }
+    }
+}
+
+/// `RegionTimer` measures the latency of a request recorded by `RegionMetrics`.
+pub struct RegionTimer {
+    // `None` if the region is not tracked.
+    region_id: Option<u64>,
+    duration: Histogram,
+    start: Instant,
+}
+
+impl RegionTimer {
+    /// The label that the request is reported with.
+    pub fn label(&self) -> String {
+        match self.region_id {
+            Some(id) => id.to_string(),
+            None => OTHER_REGIONS_LABEL.to_owned(),
+        }
+    }
+
+    pub fn observe_duration(self) {
+        self.duration.observe(duration_to_sec(self.start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_metrics_top_n() {
+        let metrics = RegionMetrics::with_shards(2, 1);
+        let on_request = |region_id, tp| {
+            let timer = metrics.on_request(region_id, tp);
+            let label = timer.label();
+            timer.observe_duration();
+            label
+        };
+        for _ in 0..100 {
+            assert_eq!(on_request(1, "write"), "1");
+            assert_eq!(on_request(2, "snapshot"), "2");
+        }
+
+        // The sketch is full, so an idle region is folded into "other".
+        assert_eq!(on_request(3, "write"), OTHER_REGIONS_LABEL);
+        assert_eq!(on_request(1, "write"), "1");
+        assert_eq!(on_request(2, "snapshot"), "2");
+
+        // Region 1 keeps being busy while region 2 turns idle, so region 3
+        // finally takes the place of region 2.
+        for _ in 0..100 {
+            assert_eq!(on_request(1, "write"), "1");
+        }
+        for _ in 0..99 {
+            assert_eq!(on_request(3, "write"), OTHER_REGIONS_LABEL);
+        }
+        assert_eq!(on_request(3, "write"), "3");
+        assert_eq!(on_request(1, "write"), "1");
+
+        let counter = REGION_REQUESTS_COUNTER_VEC.with_label_values(&["1", "write"]);
+        assert!(counter.get() >= 202);
+        let histogram = REGION_REQUESTS_DURATIONS_VEC.with_label_values(&["1", "write"]);
+        assert!(histogram.get_sample_count() >= 202);
+    }
+
+    #[test]
+    fn test_region_metrics_shards() {
+        let metrics = RegionMetrics::with_shards(2, 16);
+        assert_eq!(metrics.shards.len(), 2);
+        let on_request = |region_id| {
+            let timer = metrics.on_request(region_id, "write");
+            let label = timer.label();
+            timer.observe_duration();
+            label
+        };
+        for _ in 0..10 {
+            assert_eq!(on_request(11), "11");
+            assert_eq!(on_request(12), "12");
+        }
+
+        // Each shard has room for one region, which is taken by the busy one.
+        assert_eq!(on_request(13), OTHER_REGIONS_LABEL);
+        assert_eq!(on_request(14), OTHER_REGIONS_LABEL);
+    }
+}
@@ -20,17 +20,18 @@ use kvproto::kvrpcpb::Context;
 use tempdir::TempDir;
 
 use raftstore::store::engine::{IterOption, Peekable};
-use rocksdb::{DBIterator, SeekKey, Writable, WriteBatch, DB};
+use rocksdb::{DBIterator, SeekKey, TickerType, Writable, WriteBatch, DB};
 use storage::{CfName, Key, Value, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 
 use util::escape;
 use util::rocksdb;
+use util::rocksdb::engine_metrics::ROCKSDB_PENDING_COMPACTION_BYTES;
 use util::rocksdb::CFOptions;
 use util::worker::{Runnable, Scheduler, Worker};
 
 use super::{
-    Callback, CbContext, Cursor, Engine, Error, Iterator as EngineIterator, Modify, Result,
-    ScanMode, Snapshot,
+    Callback, CbContext, Cursor, Engine, EngineStats, Error, Iterator as EngineIterator, Modify,
+    Result, ScanMode, Snapshot,
 };
 
 pub use raftstore::store::engine::SyncSnapshot as RocksSnapshot;
@@ -234,6 +235,30 @@ fn write_modifies(db: &DB, modifies: Vec<Modify>) -> Result<()> {
     Ok(())
 }
 
+/// Collects an `EngineStats` from the tickers and properties of `db`. Properties
+/// that are not available are counted as zero.
+pub fn get_engine_stats(db: &DB) -> EngineStats {
+    let mut stats = EngineStats::default();
+    stats.block_cache_hit = db.get_statistics_ticker_count(TickerType::BlockCacheHit);
+    stats.block_cache_miss = db.get_statistics_ticker_count(TickerType::BlockCacheMiss);
+    for cf in db.cf_names() {
+        let handle = rocksdb::get_cf_handle(db, cf).unwrap();
+        stats.pending_compaction_bytes += db
+            .get_property_int_cf(handle, ROCKSDB_PENDING_COMPACTION_BYTES)
+            .unwrap_or(0);
+
+        let num_levels = db.get_options_cf(handle).get_num_levels();
+        if stats.num_files_at_level.len() < num_levels {
+            stats.num_files_at_level.resize(num_levels, 0);
+        }
+        for level in 0..num_levels {
+            stats.num_files_at_level[level] +=
+                rocksdb::get_cf_num_files_at_level(db, handle, level).unwrap_or(0);
+        }
+    }
+    stats
+}
+
 impl Engine for RocksEngine {
     type Iter = DBIterator<Arc<DB>>;
     type Snap = RocksSnapshot;
@@ -250,6 +275,10 @@ impl Engine for RocksEngine {
         box_try!(self.sched.schedule(Task::Snapshot(cb)));
         Ok(())
     }
+
+    fn get_statistics(&self) -> EngineStats {
+        get_engine_stats(&self.db)
+    }
 }
 
 impl Snapshot for RocksSnapshot {
@@ -361,6 +390,33 @@ mod tests {
         test_cfs_statistics(&engine);
     }
 
+    #[test]
+    fn test_rocksdb_engine_stats() {
+        let engine = TestEngineBuilder::new()
+            .cfs(TEST_ENGINE_CFS)
+            .build()
+            .unwrap();
+        let stats = engine.get_statistics();
+        assert_eq!(stats.total_sst_files(), 0);
+
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            must_put(&engine, key.as_bytes(), b"value");
+        }
+        engine.get_rocksdb().flush(true).unwrap();
+        for _ in 0..2 {
+            assert_has(&engine, b"key1", b"value");
+        }
+
+        let stats = engine.get_statistics();
+        assert!(!stats.num_files_at_level.is_empty());
+        assert_eq!(stats.num_files_at_level[0], 1);
+        assert_eq!(stats.total_sst_files(), 1);
+        assert!(stats.block_cache_miss > 0);
+        assert!(stats.block_cache_hit > 0);
+        assert!(stats.block_cache_hit_rate() > 0.0);
+    }
+
     #[test]
     fn rocksdb_reopen() {
         let dir = TempDir::new("rocksdb_test").unwrap();
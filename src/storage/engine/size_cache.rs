@@ -0,0 +1,103 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use util::collections::HashMap;
+
+use super::ApproximateSize;
+
+// The cache is simply cleared once it grows so large, most of the entries are expired
+// by then anyway.
+const MAX_CACHED_REGIONS: usize = 64 * 1024;
+
+struct Entry {
+    version: u64,
+    size: ApproximateSize,
+    insert_time: Instant,
+}
+
+/// `SizeCache` remembers the approximate sizes of regions for `ttl`, so that planners
+/// asking for the sizes over and over don't read the table properties every time.
+///
+/// A size is only served for the region epoch version it's estimated at, as splits and
+/// merges change the range of the region.
+pub struct SizeCache {
+    ttl: Duration,
+    regions: Mutex<HashMap<u64, Entry>>,
+}
+
+impl SizeCache {
+    pub fn new(ttl: Duration) -> SizeCache {
+        SizeCache {
+            ttl,
+            regions: Mutex::new(HashMap::default()),
+        }
+    }
+
+    pub fn get(&self, region_id: u64, version: u64) -> Option<ApproximateSize> {
+        self.get_at(region_id, version, Instant::now())
+    }
+
+    fn get_at(&self, region_id: u64, version: u64, now: Instant) -> Option<ApproximateSize> {
+        let regions = self.regions.lock().unwrap();
+        regions
+            .get(&region_id)
+            .filter(|e| e.version == version && now.duration_since(e.insert_time) < self.ttl)
+            .map(|e| e.size)
+    }
+
+    pub fn put(&self, region_id: u64, version: u64, size: ApproximateSize) {
+        self.put_at(region_id, version, size, Instant::now())
+    }
+
+    fn put_at(&self, region_id: u64, version: u64, size: ApproximateSize, now: Instant) {
+        let mut regions = self.regions.lock().unwrap();
+        if regions.len() >= MAX_CACHED_REGIONS {
+            regions.clear();
+        }
+        let entry = Entry {
+            version,
+            size,
+            insert_time: now,
+        };
+        regions.insert(region_id, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_cache() {
+        let ttl = Duration::from_secs(10);
+        let cache = SizeCache::new(ttl);
+        let size = ApproximateSize {
+            size: 1024,
+            keys: 10,
+        };
+        let now = Instant::now();
+        assert_eq!(cache.get_at(1, 1, now), None);
+        cache.put_at(1, 1, size, now);
+        assert_eq!(cache.get_at(1, 1, now), Some(size));
+        // The region has been split or merged since then.
+        assert_eq!(cache.get_at(1, 2, now), None);
+        assert_eq!(cache.get_at(2, 1, now), None);
+
+        assert_eq!(cache.get_at(1, 1, now + ttl / 2), Some(size));
+        assert_eq!(cache.get_at(1, 1, now + ttl), None);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use util::time::duration_to_sec;
+
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+/// `WriteLimiter` limits the bytes written through an engine per second.
+///
+/// It's a token bucket that holds at most one second of quota. A write is admitted as long
+/// as there is some quota left, and may push the bucket into debt, so that a write larger than
+/// the quota can still go through once the bucket is refilled. Writes smaller than
+/// `bypass_threshold` are always admitted and don't consume quota.
+pub struct WriteLimiter {
+    bytes_per_sec: f64,
+    bypass_threshold: usize,
+    bucket: Mutex<Bucket>,
+}
+
+impl WriteLimiter {
+    pub fn new(bytes_per_sec: u64, bypass_threshold: usize) -> WriteLimiter {
+        WriteLimiter {
+            bytes_per_sec: bytes_per_sec as f64,
+            bypass_threshold,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns false if the write of `bytes` should back off.
+    pub fn try_acquire(&self, bytes: usize) -> bool {
+        self.try_acquire_at(bytes, Instant::now())
+    }
+
+    fn try_acquire_at(&self, bytes: usize, now: Instant) -> bool {
+        if bytes < self.bypass_threshold {
+            return true;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let refill = duration_to_sec(now.duration_since(bucket.last_refill)) * self.bytes_per_sec;
+        bucket.available = (bucket.available + refill).min(self.bytes_per_sec);
+        bucket.last_refill = now;
+        if bucket.available <= 0.0 {
+            return false;
+        }
+        bucket.available -= bytes as f64;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_write_limiter() {
+        let limiter = WriteLimiter::new(1000, 10);
+        // Writes in the same instant get no refill.
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at(600, now));
+        assert!(limiter.try_acquire_at(600, now));
+        // The bucket is in debt now.
+        assert!(!limiter.try_acquire_at(100, now));
+        // Small writes are never limited.
+        assert!(limiter.try_acquire_at(9, now));
+
+        // Half a second pays the debt of 200 bytes back and refills 300 bytes.
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at(2000, now));
+        assert!(!limiter.try_acquire_at(10, now));
+    }
+}
@@ -631,6 +631,26 @@ impl<E: Engine> Storage<E> {
             .map_err(Error::from)
     }
 
+    fn async_batch_snapshot(
+        engine: E,
+        batch: Vec<Context>,
+    ) -> impl Future<Item = Vec<Result<E::Snap>>, Error = Error> {
+        let (callback, future) = util::future::paired_future_callback();
+        let val = engine.async_batch_snapshot(batch, callback);
+
+        future::result(val)
+            .and_then(|_| future.map_err(|cancel| EngineError::Other(box_err!(cancel))))
+            .map_err(txn::Error::from)
+            .map_err(Error::from)
+            .map(|results| {
+                results
+                    .into_iter()
+                    // map storage::engine::Error -> storage::txn::Error -> storage::Error
+                    .map(|(_ctx, result)| result.map_err(txn::Error::from).map_err(Error::from))
+                    .collect()
+            })
+    }
+
     /// Get from the snapshot.
     pub fn async_get(
         &self,
@@ -716,17 +736,7 @@ impl<E: Engine> Storage<E> {
                         ctx.get_isolation_level(),
                         !ctx.get_not_fill_cache(),
                     );
-                    let kv_pairs: Vec<_> = snap_store
-                        .batch_get(&keys, &mut statistics)
-                        .into_iter()
-                        .zip(keys)
-                        .filter(|&(ref v, ref _k)| !(v.is_ok() && v.as_ref().unwrap().is_none()))
-                        .map(|(v, k)| match v {
-                            Ok(Some(x)) => Ok((k.into_raw().unwrap(), x)),
-                            Err(e) => Err(Error::from(e)),
-                            _ => unreachable!(),
-                        })
-                        .collect();
+                    let kv_pairs = batch_get_from_store(&snap_store, keys, &mut statistics);
 
                     thread_ctx.collect_key_reads(CMD, kv_pairs.len() as u64);
                     thread_ctx.collect_scan_count(CMD, &statistics);
@@ -745,6 +755,82 @@ impl<E: Engine> Storage<E> {
             .flatten()
     }
 
+    /// Batch get keys that belong to different regions.
+    ///
+    /// `keys[i]` is read from the region described by `ctxs[i]`. Snapshots of all regions are
+    /// acquired in one `async_batch_snapshot` call. The outer result vector follows the order of
+    /// `ctxs`, so a region error (not leader, stale epoch, etc.) only fails the keys of that
+    /// region and the client can retry them alone. The batch runs with the highest priority of
+    /// `ctxs`.
+    pub fn async_batch_get_multi_region(
+        &self,
+        ctxs: Vec<Context>,
+        keys: Vec<Vec<Key>>,
+        start_ts: u64,
+    ) -> impl Future<Item = Vec<Result<Vec<Result<KvPair>>>>, Error = Error> {
+        const CMD: &str = "batch_get_multi_region";
+        let engine = self.get_engine();
+        let priority = batch_priority(&ctxs);
+
+        let res = if ctxs.len() != keys.len() {
+            Err(box_err!(
+                "contexts count {} is not equal to key groups count {}",
+                ctxs.len(),
+                keys.len()
+            ))
+        } else {
+            self.read_pool
+                .future_execute(priority, move |ctxd| {
+                    let mut _timer = {
+                        let ctxd = ctxd.clone();
+                        let mut thread_ctx = ctxd.current_thread_context_mut();
+                        thread_ctx.start_command_duration_timer(CMD, priority)
+                    };
+
+                    Self::async_batch_snapshot(engine, ctxs.clone())
+                        .map(move |snapshots| {
+                            let mut thread_ctx = ctxd.current_thread_context_mut();
+                            let _t_process = thread_ctx.start_processing_read_duration_timer(CMD);
+
+                            snapshots
+                                .into_iter()
+                                .zip(ctxs)
+                                .zip(keys)
+                                .map(|((snapshot, ctx), keys)| {
+                                    snapshot.map(|snapshot| {
+                                        let mut statistics = Statistics::default();
+                                        let snap_store = SnapshotStore::new(
+                                            snapshot,
+                                            start_ts,
+                                            ctx.get_isolation_level(),
+                                            !ctx.get_not_fill_cache(),
+                                        );
+                                        let kv_pairs = batch_get_from_store(
+                                            &snap_store,
+                                            keys,
+                                            &mut statistics,
+                                        );
+
+                                        let region_id = ctx.get_region_id();
+                                        thread_ctx.collect_key_reads(CMD, kv_pairs.len() as u64);
+                                        thread_ctx.collect_scan_count(CMD, &statistics);
+                                        thread_ctx.collect_read_flow(region_id, &statistics);
+                                        kv_pairs
+                                    })
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .then(move |r| {
+                            _timer.observe_duration();
+                            r
+                        })
+                })
+                .map_err(|_| Error::SchedTooBusy)
+        };
+
+        future::result(res).flatten()
+    }
+
     /// Scan a range starting with `start_key` up to `limit` rows from the snapshot.
     pub fn async_scan(
         &self,
@@ -811,6 +897,21 @@ impl<E: Engine> Storage<E> {
             .flatten()
     }
 
+    /// Scan up to `limit` rows in descending order, from the key right before `start_key`
+    /// down to `end_key`, which is inclusive. The scan stops at the start of the region
+    /// even if `end_key` is beyond it, as the region snapshot bounds its iterators.
+    pub fn async_reverse_scan(
+        &self,
+        ctx: Context,
+        start_key: Key,
+        end_key: Option<Key>,
+        limit: usize,
+        start_ts: u64,
+        options: Options,
+    ) -> impl Future<Item = Vec<Result<KvPair>>, Error = Error> {
+        self.async_scan(ctx, start_key, end_key, limit, start_ts, options.reverse_scan())
+    }
+
     pub fn async_pause(
         &self,
         ctx: Context,
@@ -1524,6 +1625,38 @@ impl<E: Engine> Storage<E> {
     }
 }
 
+/// Gets the highest priority of `ctxs`, so that a request of a high priority isn't held back
+/// by the other requests of its batch.
+fn batch_priority(ctxs: &[Context]) -> readpool::Priority {
+    let pris: Vec<_> = ctxs.iter().map(|ctx| ctx.get_priority()).collect();
+    if pris.contains(&CommandPri::High) {
+        readpool::Priority::High
+    } else if pris.contains(&CommandPri::Normal) || pris.is_empty() {
+        readpool::Priority::Normal
+    } else {
+        readpool::Priority::Low
+    }
+}
+
+/// Gets `keys` from `snap_store`, skipping the keys that don't exist.
+fn batch_get_from_store<S: Snapshot>(
+    snap_store: &SnapshotStore<S>,
+    keys: Vec<Key>,
+    statistics: &mut Statistics,
+) -> Vec<Result<KvPair>> {
+    snap_store
+        .batch_get(&keys, statistics)
+        .into_iter()
+        .zip(keys)
+        .filter(|&(ref v, ref _k)| !(v.is_ok() && v.as_ref().unwrap().is_none()))
+        .map(|(v, k)| match v {
+            Ok(Some(x)) => Ok((k.into_raw().unwrap(), x)),
+            Err(e) => Err(Error::from(e)),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum Error {
@@ -2047,6 +2180,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reverse_scan() {
+        let storage = TestStorageBuilder::new().build().unwrap();
+        let (tx, rx) = channel();
+        let keys: Vec<_> = [b"a", b"b", b"c", b"d", b"e"]
+            .iter()
+            .map(|k| Key::from_raw(*k))
+            .collect();
+        let mutations = keys
+            .iter()
+            .map(|k| Mutation::Put((k.clone(), k.to_raw().unwrap())))
+            .collect();
+        storage
+            .async_prewrite(
+                Context::new(),
+                mutations,
+                b"a".to_vec(),
+                1,
+                Options::default(),
+                expect_ok_callback(tx.clone(), 0),
+            )
+            .unwrap();
+        rx.recv().unwrap();
+        storage
+            .async_commit(Context::new(), keys, 1, 2, expect_ok_callback(tx.clone(), 1))
+            .unwrap();
+        rx.recv().unwrap();
+
+        let scan = |start: &[u8], end: Option<&[u8]>, limit, reverse| {
+            let (start, end) = (Key::from_raw(start), end.map(Key::from_raw));
+            let res = if reverse {
+                storage.async_reverse_scan(Context::new(), start, end, limit, 5, Options::default())
+            } else {
+                storage.async_scan(Context::new(), start, end, limit, 5, Options::default())
+            };
+            res.wait()
+                .unwrap()
+                .into_iter()
+                .map(|r| r.unwrap())
+                .collect::<Vec<_>>()
+        };
+        // A reverse scan from `start` down to `end` yields what a forward scan from
+        // `end` up to `start` does, in the reversed order.
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (b"\xff", b""),
+            (b"e", b"b"),
+            (b"d", b"d"),
+            (b"c", b"a"),
+            (b"\xff", b"\xfe"),
+        ];
+        for (start, end) in cases {
+            let mut expected = scan(end, Some(start), 1000, false);
+            expected.reverse();
+            assert_eq!(scan(start, Some(end), 1000, true), expected);
+            expected.truncate(2);
+            assert_eq!(scan(start, Some(end), 2, true), expected);
+        }
+        let pairs = scan(b"\xff", None, 1000, true);
+        let keys: Vec<_> = pairs.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"e", b"d", b"c", b"b", b"a"]);
+    }
+
     #[test]
     fn test_batch_get() {
         let storage = TestStorageBuilder::new().build().unwrap();
@@ -2111,6 +2306,213 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_batch_get_multi_region() {
+        let storage = TestStorageBuilder::new().build().unwrap();
+        let (tx, rx) = channel();
+        storage
+            .async_prewrite(
+                Context::new(),
+                vec![
+                    Mutation::Put((Key::from_raw(b"a"), b"aa".to_vec())),
+                    Mutation::Put((Key::from_raw(b"m"), b"mm".to_vec())),
+                    Mutation::Put((Key::from_raw(b"x"), b"xx".to_vec())),
+                ],
+                b"a".to_vec(),
+                1,
+                Options::default(),
+                expect_ok_callback(tx.clone(), 0),
+            )
+            .unwrap();
+        rx.recv().unwrap();
+        storage
+            .async_commit(
+                Context::new(),
+                vec![
+                    Key::from_raw(b"a"),
+                    Key::from_raw(b"m"),
+                    Key::from_raw(b"x"),
+                ],
+                1,
+                2,
+                expect_ok_callback(tx.clone(), 1),
+            )
+            .unwrap();
+        rx.recv().unwrap();
+
+        let ctxs: Vec<_> = (1..4)
+            .map(|region_id| {
+                let mut ctx = Context::new();
+                ctx.set_region_id(region_id);
+                ctx
+            })
+            .collect();
+        let keys = vec![
+            vec![Key::from_raw(b"a"), Key::from_raw(b"b")],
+            vec![Key::from_raw(b"m")],
+            vec![Key::from_raw(b"y"), Key::from_raw(b"x")],
+        ];
+        let results = storage
+            .async_batch_get_multi_region(ctxs.clone(), keys, 3)
+            .wait()
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        let mut results = results.into_iter();
+        expect_multi_values(
+            vec![Some((b"a".to_vec(), b"aa".to_vec()))],
+            results.next().unwrap(),
+        );
+        expect_multi_values(
+            vec![Some((b"m".to_vec(), b"mm".to_vec()))],
+            results.next().unwrap(),
+        );
+        expect_multi_values(
+            vec![Some((b"x".to_vec(), b"xx".to_vec()))],
+            results.next().unwrap(),
+        );
+
+        // Mismatched contexts and key groups.
+        storage
+            .async_batch_get_multi_region(ctxs, vec![vec![Key::from_raw(b"a")]], 3)
+            .wait()
+            .unwrap_err();
+    }
+
+    /// Fails the snapshots of one region with not leader.
+    #[derive(Clone)]
+    struct NotLeaderEngine {
+        engine: RocksEngine,
+        region_id: u64,
+    }
+
+    impl Display for NotLeaderEngine {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "NotLeaderEngine")
+        }
+    }
+
+    impl Debug for NotLeaderEngine {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "NotLeaderEngine")
+        }
+    }
+
+    impl Engine for NotLeaderEngine {
+        type Iter = <RocksEngine as Engine>::Iter;
+        type Snap = <RocksEngine as Engine>::Snap;
+
+        fn async_write(
+            &self,
+            ctx: &Context,
+            batch: Vec<Modify>,
+            callback: engine::Callback<()>,
+        ) -> engine::Result<()> {
+            self.engine.async_write(ctx, batch, callback)
+        }
+
+        fn async_snapshot(
+            &self,
+            ctx: &Context,
+            callback: engine::Callback<Self::Snap>,
+        ) -> engine::Result<()> {
+            if ctx.get_region_id() != self.region_id {
+                return self.engine.async_snapshot(ctx, callback);
+            }
+            let mut err = errorpb::Error::new();
+            err.mut_not_leader().set_region_id(self.region_id);
+            callback((engine::CbContext::new(), Err(EngineError::Request(err))));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_batch_get_multi_region_error() {
+        let engine = NotLeaderEngine {
+            engine: TestEngineBuilder::new().build().unwrap(),
+            region_id: 2,
+        };
+        let storage = TestStorageBuilder::from_engine(engine).build().unwrap();
+        let (tx, rx) = channel();
+        storage
+            .async_prewrite(
+                Context::new(),
+                vec![
+                    Mutation::Put((Key::from_raw(b"a"), b"aa".to_vec())),
+                    Mutation::Put((Key::from_raw(b"x"), b"xx".to_vec())),
+                ],
+                b"a".to_vec(),
+                1,
+                Options::default(),
+                expect_ok_callback(tx.clone(), 0),
+            )
+            .unwrap();
+        rx.recv().unwrap();
+        storage
+            .async_commit(
+                Context::new(),
+                vec![Key::from_raw(b"a"), Key::from_raw(b"x")],
+                1,
+                2,
+                expect_ok_callback(tx.clone(), 1),
+            )
+            .unwrap();
+        rx.recv().unwrap();
+
+        let ctxs: Vec<_> = (1..4)
+            .map(|region_id| {
+                let mut ctx = Context::new();
+                ctx.set_region_id(region_id);
+                ctx
+            })
+            .collect();
+        let keys = vec![
+            vec![Key::from_raw(b"a")],
+            vec![Key::from_raw(b"m")],
+            vec![Key::from_raw(b"x")],
+        ];
+        let results = storage
+            .async_batch_get_multi_region(ctxs, keys, 3)
+            .wait()
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        let mut results = results.into_iter();
+        expect_multi_values(
+            vec![Some((b"a".to_vec(), b"aa".to_vec()))],
+            results.next().unwrap(),
+        );
+        // Only the keys of the failed region fail.
+        match results.next().unwrap() {
+            Err(Error::Txn(txn::Error::Engine(EngineError::Request(ref e))))
+                if e.has_not_leader() => {}
+            res => panic!("expect not leader, but got {:?}", res),
+        }
+        expect_multi_values(
+            vec![Some((b"x".to_vec(), b"xx".to_vec()))],
+            results.next().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_batch_priority() {
+        let ctx = |pri| {
+            let mut ctx = Context::new();
+            ctx.set_priority(pri);
+            ctx
+        };
+        match batch_priority(&[ctx(CommandPri::Low), ctx(CommandPri::High)]) {
+            readpool::Priority::High => {}
+            p => panic!("expect high, but got {:?}", p),
+        }
+        match batch_priority(&[ctx(CommandPri::Low), ctx(CommandPri::Normal)]) {
+            readpool::Priority::Normal => {}
+            p => panic!("expect normal, but got {:?}", p),
+        }
+        match batch_priority(&[ctx(CommandPri::Low)]) {
+            readpool::Priority::Low => {}
+            p => panic!("expect low, but got {:?}", p),
+        }
+    }
+
     #[test]
     fn test_txn() {
         let storage = TestStorageBuilder::new().build().unwrap();
@@ -12,7 +12,7 @@
 // limitations under the License.
 
 use futures::sync::oneshot;
-use futures::{Async, Future, IntoFuture, Poll};
+use futures::{Async, AsyncSink, Future, IntoFuture, Poll, Sink, Stream};
 use std::boxed;
 use util::Either;
 
@@ -90,3 +90,123 @@ where
         }
     }
 }
+
+/// Sends all the items of `stream` to `sink` like `Sink::send_all`, but pulls the next item
+/// only once the previous one is flushed. A sink applying flow control, like a gRPC stream
+/// written to a slow client, then holds back the stream, instead of taking its items as fast
+/// as they are produced.
+pub fn send_all_flushed<S, St>(sink: S, stream: St) -> SendAllFlushed<S, St>
+where
+    S: Sink,
+    St: Stream<Item = S::SinkItem>,
+    S::SinkError: From<St::Error>,
+{
+    SendAllFlushed {
+        sink: Some(sink),
+        stream,
+        buffered: None,
+    }
+}
+
+/// The future returned by `send_all_flushed`, resolving to the sink once the stream ends.
+pub struct SendAllFlushed<S: Sink, St> {
+    sink: Option<S>,
+    stream: St,
+    buffered: Option<S::SinkItem>,
+}
+
+impl<S, St> Future for SendAllFlushed<S, St>
+where
+    S: Sink,
+    St: Stream<Item = S::SinkItem>,
+    S::SinkError: From<St::Error>,
+{
+    type Item = S;
+    type Error = S::SinkError;
+
+    fn poll(&mut self) -> Poll<S, S::SinkError> {
+        loop {
+            {
+                let sink = self.sink.as_mut().expect("polled after completion");
+                if let Some(item) = self.buffered.take() {
+                    if let AsyncSink::NotReady(item) = sink.start_send(item)? {
+                        self.buffered = Some(item);
+                        return Ok(Async::NotReady);
+                    }
+                }
+                try_ready!(sink.poll_complete());
+            }
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => self.buffered = Some(item),
+                Async::Ready(None) => {
+                    try_ready!(self.sink.as_mut().unwrap().close());
+                    return Ok(Async::Ready(self.sink.take().unwrap()));
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, StartSend};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    // A sink flushing no more than `flushable` items.
+    struct SlowSink {
+        items: Vec<u64>,
+        flushable: Arc<AtomicUsize>,
+    }
+
+    impl Sink for SlowSink {
+        type SinkItem = u64;
+        type SinkError = ();
+
+        fn start_send(&mut self, item: u64) -> StartSend<u64, ()> {
+            self.items.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), ()> {
+            if self.items.len() <= self.flushable.load(Ordering::SeqCst) {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_all_flushed() {
+        let flushable = Arc::new(AtomicUsize::new(0));
+        let sink = SlowSink {
+            items: vec![],
+            flushable: Arc::clone(&flushable),
+        };
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let pulled1 = Arc::clone(&pulled);
+        let stream = stream::iter_ok(0..5).inspect(move |_| {
+            pulled1.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut future = send_all_flushed(sink, stream);
+
+        // Not a single item is flushed, so only the first one is pulled.
+        for _ in 0..3 {
+            assert!(future.poll().unwrap().is_not_ready());
+            assert_eq!(pulled.load(Ordering::SeqCst), 1);
+        }
+        flushable.store(2, Ordering::SeqCst);
+        assert!(future.poll().unwrap().is_not_ready());
+        assert_eq!(pulled.load(Ordering::SeqCst), 3);
+
+        flushable.store(5, Ordering::SeqCst);
+        match future.poll() {
+            Ok(Async::Ready(sink)) => assert_eq!(sink.items, vec![0, 1, 2, 3, 4]),
+            _ => panic!("expect all the items to be sent"),
+        }
+    }
+}
@@ -11,10 +11,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::Future;
+use futures::{future, Future, Poll};
 use futures_cpupool::{self as cpupool, CpuFuture, CpuPool};
-use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use prometheus::{Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
 use std::cell::{Cell, RefCell, RefMut};
+use std::cmp;
 /// This mod implemented a wrapped future pool that supports `on_tick()` which is driven by
 /// tasks and is invoked no less than the specific interval.
 use std::fmt;
@@ -33,11 +34,21 @@ lazy_static! {
         "Current future_pool pending + running tasks.",
         &["name"]
     ).unwrap();
+    pub static ref FUTUREPOOL_QUEUED_TASK_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "tikv_futurepool_queued_task_total",
+        "Current future_pool tasks waiting for a thread.",
+        &["name"]
+    ).unwrap();
     pub static ref FUTUREPOOL_HANDLED_TASK_VEC: IntCounterVec = register_int_counter_vec!(
         "tikv_futurepool_handled_task_total",
         "Total number of future_pool handled tasks.",
         &["name"]
     ).unwrap();
+    pub static ref FUTUREPOOL_UTILIZATION_VEC: GaugeVec = register_gauge_vec!(
+        "tikv_futurepool_utilization",
+        "Ratio of busy threads of future_pool.",
+        &["name"]
+    ).unwrap();
 }
 
 pub trait Context: fmt::Debug + Send {
@@ -127,10 +138,15 @@ impl<T: Context> ContextDelegators<T> {
 /// A future thread pool that supports `on_tick` for each thread.
 pub struct FuturePool<T: Context + 'static> {
     pool: CpuPool,
+    pool_size: usize,
     context_delegators: ContextDelegators<T>,
     running_task_count: Arc<AtomicUsize>,
+    // The threads polling tasks, shared by the pools running on the same threads.
+    busy_thread_count: Arc<AtomicUsize>,
     metrics_pending_task_count: IntGauge,
+    metrics_queued_task_count: IntGauge,
     metrics_handled_task_count: IntCounter,
+    metrics_utilization: Gauge,
 }
 
 impl<T: Context + 'static> fmt::Debug for FuturePool<T> {
@@ -146,10 +162,14 @@ impl<T: Context + 'static> Clone for FuturePool<T> {
     fn clone(&self) -> FuturePool<T> {
         FuturePool {
             pool: self.pool.clone(),
+            pool_size: self.pool_size,
             context_delegators: self.context_delegators.clone(),
             running_task_count: Arc::clone(&self.running_task_count),
+            busy_thread_count: Arc::clone(&self.busy_thread_count),
             metrics_pending_task_count: self.metrics_pending_task_count.clone(),
+            metrics_queued_task_count: self.metrics_queued_task_count.clone(),
             metrics_handled_task_count: self.metrics_handled_task_count.clone(),
+            metrics_utilization: self.metrics_utilization.clone(),
         }
     }
 }
@@ -201,14 +221,65 @@ impl<T: Context + 'static> FuturePool<T> {
                 (thread_id, context_delegator)
             })
             .collect();
+        FuturePool::with_pool(
+            pool,
+            pool_size,
+            Arc::new(AtomicUsize::new(0)),
+            contexts,
+            name_prefix,
+        )
+    }
+
+    /// Creates a pool running its tasks on the threads of `base`, so that the tasks of both
+    /// pools compete for the same threads. The pool has contexts, task counts and metrics of
+    /// its own, while its utilization is that of the threads shared.
+    pub fn new_on<U, F>(
+        base: &FuturePool<U>,
+        name_prefix: &str,
+        tick_interval: Duration,
+        context_factory: F,
+    ) -> FuturePool<T>
+    where
+        U: Context + 'static,
+        F: Factory<T>,
+    {
+        let contexts = base
+            .context_delegators
+            .delegators
+            .keys()
+            .map(|thread_id| {
+                let context = context_factory.build();
+                (*thread_id, ContextDelegator::new(context, tick_interval))
+            })
+            .collect();
+        FuturePool::with_pool(
+            base.pool.clone(),
+            base.pool_size,
+            Arc::clone(&base.busy_thread_count),
+            contexts,
+            name_prefix,
+        )
+    }
+
+    fn with_pool(
+        pool: CpuPool,
+        pool_size: usize,
+        busy_thread_count: Arc<AtomicUsize>,
+        contexts: HashMap<thread::ThreadId, ContextDelegator<T>>,
+        name_prefix: &str,
+    ) -> FuturePool<T> {
         FuturePool {
             pool,
+            pool_size,
             context_delegators: ContextDelegators::new(contexts),
             running_task_count: Arc::new(AtomicUsize::new(0)),
+            busy_thread_count,
             metrics_pending_task_count: FUTUREPOOL_PENDING_TASK_VEC
                 .with_label_values(&[name_prefix]),
+            metrics_queued_task_count: FUTUREPOOL_QUEUED_TASK_VEC.with_label_values(&[name_prefix]),
             metrics_handled_task_count: FUTUREPOOL_HANDLED_TASK_VEC
                 .with_label_values(&[name_prefix]),
+            metrics_utilization: FUTUREPOOL_UTILIZATION_VEC.with_label_values(&[name_prefix]),
         }
     }
 
@@ -218,6 +289,21 @@ impl<T: Context + 'static> FuturePool<T> {
         self.running_task_count.load(Ordering::Acquire)
     }
 
+    #[inline]
+    pub fn get_pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    /// Get the fraction of the threads that are busy running tasks. Tasks waiting for other
+    /// futures don't keep a thread busy.
+    #[inline]
+    pub fn get_utilization(&self) -> f64 {
+        utilization(
+            self.busy_thread_count.load(Ordering::Acquire),
+            self.pool_size,
+        )
+    }
+
     pub fn spawn<F, R>(&self, future_factory: R) -> CpuFuture<F::Item, F::Error>
     where
         R: FnOnce(ContextDelegators<T>) -> F + Send + 'static,
@@ -225,30 +311,76 @@ impl<T: Context + 'static> FuturePool<T> {
         F::Item: Send + 'static,
         F::Error: Send + 'static,
     {
+        let pool_size = self.pool_size;
         let running_task_count = Arc::clone(&self.running_task_count);
         let metrics_pending_task_count = self.metrics_pending_task_count.clone();
+        let metrics_queued_task_count = self.metrics_queued_task_count.clone();
         let metrics_handled_task_count = self.metrics_handled_task_count.clone();
         let delegators = self.context_delegators.clone();
         let func = move || {
             future_factory(delegators.clone()).then(move |r| {
                 let delegator = delegators.get_current_thread_delegator();
                 delegator.on_task_finish();
-                running_task_count.fetch_sub(1, Ordering::Release);
+                let running = running_task_count.fetch_sub(1, Ordering::Release) - 1;
                 metrics_pending_task_count.dec();
+                metrics_queued_task_count.set(queued(running, pool_size));
                 metrics_handled_task_count.inc();
                 r
             })
         };
 
-        self.running_task_count.fetch_add(1, Ordering::Release);
+        let running = self.running_task_count.fetch_add(1, Ordering::Release) + 1;
         self.metrics_pending_task_count.inc();
-        self.pool.spawn_fn(func)
+        self.metrics_queued_task_count
+            .set(queued(running, self.pool_size));
+        self.pool.spawn(BusyCounted {
+            future: future::lazy(func),
+            busy_thread_count: Arc::clone(&self.busy_thread_count),
+            pool_size: self.pool_size,
+            metrics_utilization: self.metrics_utilization.clone(),
+        })
     }
 }
 
+/// Counts a thread of the pool busy while it's polling `future`.
+struct BusyCounted<F> {
+    future: F,
+    busy_thread_count: Arc<AtomicUsize>,
+    pool_size: usize,
+    metrics_utilization: Gauge,
+}
+
+impl<F: Future> Future for BusyCounted<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let busy = self.busy_thread_count.fetch_add(1, Ordering::AcqRel) + 1;
+        update_utilization(&self.metrics_utilization, busy, self.pool_size);
+        let res = self.future.poll();
+        let busy = self.busy_thread_count.fetch_sub(1, Ordering::AcqRel) - 1;
+        update_utilization(&self.metrics_utilization, busy, self.pool_size);
+        res
+    }
+}
+
+fn queued(running_task_count: usize, pool_size: usize) -> i64 {
+    running_task_count.saturating_sub(pool_size) as i64
+}
+
+fn utilization(busy_thread_count: usize, pool_size: usize) -> f64 {
+    let busy = cmp::min(busy_thread_count, pool_size);
+    busy as f64 / pool_size as f64
+}
+
+fn update_utilization(gauge: &Gauge, busy_thread_count: usize, pool_size: usize) {
+    gauge.set(utilization(busy_thread_count, pool_size));
+}
+
 #[cfg(test)]
 mod tests {
     use futures::future;
+    use futures::sync::oneshot;
     use std::sync::mpsc::{channel, Sender};
     use std::thread;
     use std::time::Duration;
@@ -400,4 +532,78 @@ mod tests {
         f4.join(f5).wait().unwrap();
         assert_eq!(pool.get_running_task_count(), 0);
     }
+
+    #[test]
+    fn test_utilization() {
+        #[derive(Debug)]
+        struct MyContext;
+        impl Context for MyContext {}
+
+        let pool = FuturePool::new(
+            2,
+            1024000,
+            "test-utilization",
+            Duration::from_millis(50),
+            move || MyContext {},
+        );
+        let gauge = FUTUREPOOL_UTILIZATION_VEC.with_label_values(&["test-utilization"]);
+
+        // A task waiting for other futures keeps no thread busy.
+        let (tx, rx) = oneshot::channel::<()>();
+        let waiting = pool.spawn(move |_| rx.map_err(|_| ()));
+        let running = spawn_long_time_future(&pool, 200);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.get_running_task_count(), 2);
+        assert!((pool.get_utilization() - 0.5).abs() < 1e-6);
+        assert!((gauge.get() - 0.5).abs() < 1e-6);
+
+        tx.send(()).unwrap();
+        waiting.join(running).wait().unwrap();
+        assert_eq!(pool.get_utilization(), 0.0);
+        assert_eq!(gauge.get(), 0.0);
+    }
+
+    #[test]
+    fn test_new_on() {
+        #[derive(Debug)]
+        struct MyContext {
+            pool: &'static str,
+        }
+        impl Context for MyContext {}
+
+        let base = FuturePool::new(
+            1,
+            1024000,
+            "test-base",
+            Duration::from_millis(50),
+            move || MyContext { pool: "base" },
+        );
+        let shared = FuturePool::new_on(
+            &base,
+            "test-shared",
+            Duration::from_millis(50),
+            move || MyContext { pool: "shared" },
+        );
+        assert_eq!(shared.get_pool_size(), 1);
+
+        // Tasks of the pool run on the threads of the base, with contexts of its own.
+        let name = shared
+            .spawn(move |ctxd| {
+                assert_eq!(ctxd.current_thread_context_mut().pool, "shared");
+                future::ok::<_, ()>(thread::current().name().unwrap().to_owned())
+            })
+            .wait()
+            .unwrap();
+        assert!(name.starts_with("test-base"), "{}", name);
+
+        // A task of the base keeps the only thread busy for both.
+        let f = spawn_long_time_future(&base, 200);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(shared.get_running_task_count(), 0);
+        assert_eq!(shared.get_utilization(), 1.0);
+        let start = Instant::now();
+        spawn_long_time_future_and_wait(&shared, 0);
+        assert!(start.elapsed() > Duration::from_millis(100));
+        f.wait().unwrap();
+    }
 }
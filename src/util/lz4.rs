@@ -0,0 +1,104 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! LZ4 block compression, with the length of the original data stored in a 4 bytes
+//! little-endian header, so that the block can be decompressed on its own.
+
+use std::io::{Error, ErrorKind, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use lz4_sys;
+
+const HEADER_LEN: usize = 4;
+// The max input size supported by LZ4.
+const MAX_INPUT_SIZE: usize = 0x7E00_0000;
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() > MAX_INPUT_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} bytes are too large to compress", data.len()),
+        ));
+    }
+    unsafe {
+        let bound = lz4_sys::LZ4_compressBound(data.len() as i32) as usize;
+        let mut buf = Vec::with_capacity(HEADER_LEN + bound);
+        buf.set_len(HEADER_LEN + bound);
+        LittleEndian::write_u32(&mut buf, data.len() as u32);
+        let size = lz4_sys::LZ4_compress_default(
+            data.as_ptr() as *const _,
+            buf[HEADER_LEN..].as_mut_ptr() as *mut _,
+            data.len() as i32,
+            bound as i32,
+        );
+        if size <= 0 {
+            return Err(Error::new(ErrorKind::Other, "lz4 compression failed"));
+        }
+        buf.truncate(HEADER_LEN + size as usize);
+        Ok(buf)
+    }
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < HEADER_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "lz4 block is too short"));
+    }
+    let len = LittleEndian::read_u32(data) as usize;
+    if len > MAX_INPUT_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("lz4 block of {} bytes is too large", len),
+        ));
+    }
+    unsafe {
+        let mut buf = Vec::with_capacity(len);
+        buf.set_len(len);
+        let size = lz4_sys::LZ4_decompress_safe(
+            data[HEADER_LEN..].as_ptr() as *const _,
+            buf.as_mut_ptr() as *mut _,
+            (data.len() - HEADER_LEN) as i32,
+            len as i32,
+        );
+        if size < 0 || size as usize != len {
+            return Err(Error::new(ErrorKind::InvalidData, "corrupted lz4 block"));
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lz4() {
+        let repeated: Vec<u8> = b"abcdefg".iter().cycle().take(7000).cloned().collect();
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            b"a".to_vec(),
+            repeated.clone(),
+            (0..10000).map(|i| (i * 7 % 256) as u8).collect(),
+        ];
+        for data in cases {
+            let compressed = compress(&data).unwrap();
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+        assert!(compress(&repeated).unwrap().len() < repeated.len() / 10);
+
+        let mut corrupted = compress(&repeated).unwrap();
+        corrupted.truncate(corrupted.len() / 2);
+        assert!(decompress(&corrupted).is_err());
+        assert!(decompress(b"ab").is_err());
+    }
+}
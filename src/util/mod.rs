@@ -38,6 +38,7 @@ pub mod futurepool;
 pub mod io_limiter;
 pub mod jemalloc;
 pub mod logger;
+pub mod lz4;
 pub mod metrics;
 pub mod mpsc;
 pub mod rocksdb;
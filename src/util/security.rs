@@ -15,6 +15,7 @@ use std::error::Error;
 use std::fs::File;
 use std::io::Read;
 use std::ptr;
+use std::sync::RwLock;
 
 use grpc::{
     Channel, ChannelBuilder, ChannelCredentialsBuilder, ServerBuilder, ServerCredentialsBuilder,
@@ -82,14 +83,23 @@ impl SecurityConfig {
 }
 
 #[derive(Default)]
-pub struct SecurityManager {
+struct Credentials {
     ca: Vec<u8>,
     cert: Vec<u8>,
     key: Vec<u8>,
-    override_ssl_target: String,
 }
 
-impl Drop for SecurityManager {
+impl Credentials {
+    fn load(cfg: &SecurityConfig) -> Result<Credentials, Box<Error>> {
+        Ok(Credentials {
+            ca: load_key("CA", &cfg.ca_path)?,
+            cert: load_key("certificate", &cfg.cert_path)?,
+            key: load_key("private key", &cfg.key_path)?,
+        })
+    }
+}
+
+impl Drop for Credentials {
     fn drop(&mut self) {
         unsafe {
             for b in &mut self.key {
@@ -99,38 +109,60 @@ impl Drop for SecurityManager {
     }
 }
 
+#[derive(Default)]
+pub struct SecurityManager {
+    cfg: SecurityConfig,
+    creds: RwLock<Credentials>,
+}
+
 impl SecurityManager {
     pub fn new(cfg: &SecurityConfig) -> Result<SecurityManager, Box<Error>> {
         Ok(SecurityManager {
-            ca: load_key("CA", &cfg.ca_path)?,
-            cert: load_key("certificate", &cfg.cert_path)?,
-            key: load_key("private key", &cfg.key_path)?,
-            override_ssl_target: cfg.override_ssl_target.clone(),
+            cfg: cfg.clone(),
+            creds: RwLock::new(Credentials::load(cfg)?),
         })
     }
 
+    /// Reloads the credentials from the configured paths, for rotating certificates. The
+    /// new credentials are used by the connections made by `connect` afterwards, while the
+    /// existing connections are kept. Servers already built by `bind` are not affected.
+    /// If any of them fails to load, the current ones are kept.
+    pub fn reload(&self) -> Result<(), Box<Error>> {
+        if self.creds.read().unwrap().ca.is_empty() {
+            return Err("secure connection is not enabled".into());
+        }
+        let creds = Credentials::load(&self.cfg)?;
+        if creds.ca.is_empty() || creds.cert.is_empty() || creds.key.is_empty() {
+            return Err("ca, cert and private key should not be empty".into());
+        }
+        *self.creds.write().unwrap() = creds;
+        Ok(())
+    }
+
     pub fn connect(&self, mut cb: ChannelBuilder, addr: &str) -> Channel {
-        if self.ca.is_empty() {
+        let creds = self.creds.read().unwrap();
+        if creds.ca.is_empty() {
             cb.connect(addr)
         } else {
-            if !self.override_ssl_target.is_empty() {
-                cb = cb.override_ssl_target(self.override_ssl_target.clone());
+            if !self.cfg.override_ssl_target.is_empty() {
+                cb = cb.override_ssl_target(self.cfg.override_ssl_target.clone());
             }
             let cred = ChannelCredentialsBuilder::new()
-                .root_cert(self.ca.clone())
-                .cert(self.cert.clone(), self.key.clone())
+                .root_cert(creds.ca.clone())
+                .cert(creds.cert.clone(), creds.key.clone())
                 .build();
             cb.secure_connect(addr, cred)
         }
     }
 
     pub fn bind(&self, sb: ServerBuilder, addr: &str, port: u16) -> ServerBuilder {
-        if self.ca.is_empty() {
+        let creds = self.creds.read().unwrap();
+        if creds.ca.is_empty() {
             sb.bind(addr, port)
         } else {
             let cred = ServerCredentialsBuilder::new()
-                .root_cert(self.ca.clone(), true)
-                .add_cert(self.cert.clone(), self.key.clone())
+                .root_cert(creds.ca.clone(), true)
+                .add_cert(creds.cert.clone(), creds.key.clone())
                 .build();
             sb.bind_secure(addr, port, cred)
         }
@@ -141,7 +173,7 @@ impl SecurityManager {
 mod tests {
     use super::*;
 
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Write;
 
     use tempdir::TempDir;
@@ -152,9 +184,13 @@ mod tests {
         // default is disable secure connection.
         cfg.validate().unwrap();
         let mut mgr = SecurityManager::new(&cfg).unwrap();
-        assert!(mgr.ca.is_empty());
-        assert!(mgr.cert.is_empty());
-        assert!(mgr.key.is_empty());
+        {
+            let creds = mgr.creds.read().unwrap();
+            assert!(creds.ca.is_empty());
+            assert!(creds.cert.is_empty());
+            assert!(creds.key.is_empty());
+        }
+        mgr.reload().unwrap_err();
 
         let assert_cfg = |c: fn(&mut SecurityConfig), valid: bool| {
             let mut invalid_cfg = cfg.clone();
@@ -192,8 +228,26 @@ mod tests {
         c.ca_path = format!("{}", example_ca.display());
         c.validate().unwrap();
         mgr = SecurityManager::new(&c).unwrap();
-        assert_eq!(mgr.ca, vec![0]);
-        assert_eq!(mgr.cert, vec![1]);
-        assert_eq!(mgr.key, vec![2]);
+        {
+            let creds = mgr.creds.read().unwrap();
+            assert_eq!(creds.ca, vec![0]);
+            assert_eq!(creds.cert, vec![1]);
+            assert_eq!(creds.key, vec![2]);
+        }
+
+        // The rotated credentials are reloaded.
+        File::create(&example_cert).unwrap().write_all(&[3]).unwrap();
+        mgr.reload().unwrap();
+        assert_eq!(mgr.creds.read().unwrap().cert, vec![3]);
+
+        // The current credentials are kept if the new ones fail to load.
+        File::create(&example_key).unwrap();
+        mgr.reload().unwrap_err();
+        fs::remove_file(&example_ca).unwrap();
+        mgr.reload().unwrap_err();
+        let creds = mgr.creds.read().unwrap();
+        assert_eq!(creds.ca, vec![0]);
+        assert_eq!(creds.cert, vec![3]);
+        assert_eq!(creds.key, vec![2]);
     }
 }
@@ -1,9 +1,11 @@
 pub const HIGH_PRI: i32 = -1;
+pub const LOW_PRI: i32 = 10;
 
 #[cfg(target_os = "linux")]
 pub mod thread {
     use libc;
     use std::io::Error;
+    use std::time::Duration;
 
     pub fn set_priority(pri: i32) -> Result<(), Error> {
         unsafe {
@@ -34,6 +36,19 @@ pub mod thread {
         }
     }
 
+    /// Returns the CPU time consumed by the current thread. Only the difference between two
+    /// calls on the same thread makes sense.
+    pub fn cpu_time() -> Duration {
+        let mut t = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe {
+            libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut t);
+        }
+        Duration::new(t.tv_sec as u64, t.tv_nsec as u32)
+    }
+
     #[cfg(test)]
     mod tests {
         use super::super::HIGH_PRI;
@@ -56,12 +71,28 @@ pub mod thread {
                 assert_eq!(get_priority().unwrap(), HIGH_PRI);
             }
         }
+
+        #[test]
+        fn test_cpu_time() {
+            // Spinning takes CPU time.
+            let start = cpu_time();
+            while cpu_time() - start < Duration::from_millis(10) {}
+            // Sleeping takes no CPU time.
+            let start = cpu_time();
+            ::std::thread::sleep(Duration::from_millis(50));
+            assert!(cpu_time() - start < Duration::from_millis(25));
+        }
     }
 }
 
 #[cfg(not(target_os = "linux"))]
 pub mod thread {
     use std::io::Error;
+    use std::time::{Duration, Instant};
+
+    lazy_static! {
+        static ref START_TIME: Instant = Instant::now();
+    }
 
     pub fn set_priority(_: i32) -> Result<(), Error> {
         Ok(())
@@ -70,4 +101,50 @@ pub mod thread {
     pub fn get_priority() -> Result<i32, Error> {
         Ok(0)
     }
+
+    /// Per-thread CPU clocks are not available, so returns the wall-clock time instead. Only
+    /// the difference between two calls makes sense.
+    pub fn cpu_time() -> Duration {
+        START_TIME.elapsed()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod memory {
+    use libc;
+    use std::fs;
+    use std::io::{Error, ErrorKind};
+
+    /// Returns the resident set size of the process in bytes.
+    pub fn get_rss() -> Result<u64, Error> {
+        let statm = fs::read_to_string("/proc/self/statm")?;
+        let pages: u64 = statm
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("bad statm {:?}", statm)))?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Ok(pages * page_size as u64)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_get_rss() {
+            let rss = get_rss().unwrap();
+            let buf = vec![1u8; 64 * 1024 * 1024];
+            assert!(get_rss().unwrap() >= rss + buf.len() as u64 / 2);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub mod memory {
+    use std::io::{Error, ErrorKind};
+
+    pub fn get_rss() -> Result<u64, Error> {
+        Err(Error::new(ErrorKind::Other, "unsupported platform"))
+    }
 }
@@ -37,6 +37,16 @@ quick_error! {
             description("message is discarded")
             display("{}", reason)
         }
+        /// The receiver refuses to take more, e.g. it has too many requests in flight.
+        Busy(reason: String) {
+            description("receiver is busy")
+            display("{}", reason)
+        }
+        /// The message exceeds the quota of its region.
+        Throttled(reason: String) {
+            description("message is throttled")
+            display("{}", reason)
+        }
         Closed {
             description("channel is closed")
             display("channel is closed")
@@ -124,6 +134,12 @@ impl<T: Debug, C: Sender<T>> RetryableSendCh<T, C> {
         self.send_with_try_times(t, 1)
     }
 
+    /// Try send t once. Unlike `try_send`, t is given back if the channel is full, so that
+    /// the caller can resend it later instead of blocking until the channel has room.
+    pub fn try_send_or_return(&self, t: T) -> Result<(), NotifyError<T>> {
+        self.ch.send(t)
+    }
+
     pub fn into_inner(self) -> C {
         self.ch
     }
@@ -172,6 +172,11 @@ impl<T: Display> Scheduler<T> {
     pub fn is_busy(&self) -> bool {
         self.counter.load(Ordering::SeqCst) > 0
     }
+
+    /// The number of tasks scheduled but not handled yet.
+    pub fn pending_tasks(&self) -> usize {
+        self.counter.load(Ordering::SeqCst)
+    }
 }
 
 impl<T> Clone for Scheduler<T> {
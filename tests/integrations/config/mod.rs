@@ -24,7 +24,9 @@ use tikv::import::Config as ImportConfig;
 use tikv::pd::Config as PdConfig;
 use tikv::raftstore::coprocessor::Config as CopConfig;
 use tikv::raftstore::store::Config as RaftstoreConfig;
-use tikv::server::config::GrpcCompressionType;
+use tikv::server::config::{
+    GrpcCompressionType, RaftMsgCodec, RaftMsgFullPolicy, SnapCompression,
+};
 use tikv::server::Config as ServerConfig;
 use tikv::storage::Config as StorageConfig;
 use tikv::util::config::{ReadableDuration, ReadableSize};
@@ -59,9 +61,20 @@ fn test_serde_custom_tikv_config() {
         cluster_id: 0, // KEEP IT ZERO, it is skipped by serde.
         addr: "example.com:443".to_owned(),
         labels: map!{ "a".to_owned() => "b".to_owned() },
+        raft_msg_full_policy: map!{
+            "MsgRequestVote".to_owned() => RaftMsgFullPolicy::Block,
+            "MsgAppend".to_owned() => RaftMsgFullPolicy::ForceSend
+        },
         advertise_addr: "example.com:443".to_owned(),
         status_addr: "example.com:443".to_owned(),
         status_thread_pool_size: 1,
+        bulk_addr: "example.com:444".to_owned(),
+        grpc_bulk_concurrency: 12,
+        snap_compression: SnapCompression::Lz4,
+        raft_msg_codec: RaftMsgCodec::SizeRecording,
+        snap_send_chunk_size: ReadableSize::mb(4),
+        snap_send_stall_timeout: ReadableDuration::secs(30),
+        snap_send_unreachable_window: ReadableDuration::secs(5),
         concurrent_send_snap_limit: 4,
         concurrent_recv_snap_limit: 4,
         grpc_compression_type: GrpcCompressionType::Gzip,
@@ -71,6 +84,24 @@ fn test_serde_custom_tikv_config() {
         grpc_stream_initial_window_size: ReadableSize(12_345),
         grpc_keepalive_time: ReadableDuration::secs(3),
         grpc_keepalive_timeout: ReadableDuration::secs(60),
+        grpc_connect_timeout: ReadableDuration::secs(7),
+        raft_client_flush_jitter: ReadableDuration::millis(5),
+        raft_client_reconnect_backoff: ReadableDuration::millis(500),
+        raft_client_max_reconnect_backoff: ReadableDuration::secs(30),
+        raft_client_gc_interval: ReadableDuration::minutes(5),
+        raft_msg_send_timeout: ReadableDuration::secs(30),
+        max_raft_msg_size: ReadableSize::mb(8),
+        max_outstanding_callbacks: 10000,
+        cmd_send_max_retry: 5,
+        raft_msg_send_max_retry: 2,
+        raft_msg_send_retry_backoff: ReadableDuration::millis(2),
+        local_read_shed_threshold: 1000,
+        local_read_shed_window: ReadableDuration::secs(2),
+        region_read_quota: 10000,
+        region_write_quota: 5000,
+        raft_msg_log_sample_interval: 100,
+        unreachable_report_dedup_interval: ReadableDuration::millis(100),
+        resolve_negative_cache_ttl: ReadableDuration::secs(3),
         end_point_concurrency: None,
         end_point_max_tasks: None,
         end_point_stack_size: None,
@@ -79,12 +110,30 @@ fn test_serde_custom_tikv_config() {
         end_point_batch_row_limit: 64,
         end_point_stream_batch_row_limit: 4096,
         end_point_request_max_handle_duration: ReadableDuration::secs(12),
+        end_point_memory_quota: ReadableSize::mb(512),
+        end_point_cache_capacity: ReadableSize::mb(64),
+        end_point_cache_ttl: ReadableDuration::secs(30),
+        end_point_overload_high_water: 0.95,
+        end_point_overload_low_water: 0.7,
+        end_point_overload_window: ReadableDuration::secs(10),
+        end_point_slow_log_threshold: ReadableDuration::millis(500),
         snap_max_write_bytes_per_sec: ReadableSize::mb(10),
         snap_max_total_size: ReadableSize::gb(10),
+        snap_max_pending_size: ReadableSize::gb(20),
+        snap_min_available_space: ReadableSize::gb(5),
+        snap_prefetch_on_apply: true,
         stats_concurrency: 10,
         heavy_load_threshold: 1000,
+        graceful_shutdown_timeout: ReadableDuration::secs(20),
+        max_requests_per_sec_per_client: 1000,
+        max_inflight_requests_per_connection: 64,
+        strict_peer_store_check: true,
+        memory_pressure_high_water: 0.9,
+        memory_pressure_low_water: 0.8,
+        memory_pressure_shed_methods: vec!["coprocessor".to_owned(), "kv_scan".to_owned()],
     };
     value.readpool = ReadPoolConfig {
+        coprocessor_isolation: ReadPoolIsolation::Shared,
         storage: StorageReadPoolConfig {
             high_concurrency: 1,
             normal_concurrency: 3,
@@ -93,6 +142,7 @@ fn test_serde_custom_tikv_config() {
             max_tasks_per_worker_normal: 1500,
             max_tasks_per_worker_low: 2500,
             stack_size: ReadableSize::mb(20),
+            high_priority_weight: 2,
         },
         coprocessor: CoprocessorReadPoolConfig {
             high_concurrency: 2,
@@ -102,6 +152,7 @@ fn test_serde_custom_tikv_config() {
             max_tasks_per_worker_normal: 1000,
             max_tasks_per_worker_low: 3000,
             stack_size: ReadableSize::mb(12),
+            high_priority_weight: 4,
         },
     };
     value.metric = MetricConfig {
@@ -418,6 +469,9 @@ fn test_serde_custom_tikv_config() {
         scheduler_concurrency: 123,
         scheduler_worker_pool_size: 1,
         scheduler_pending_write_threshold: ReadableSize::kb(123),
+        region_metrics_top_n: 8,
+        write_limit_bytes_per_sec: ReadableSize::mb(64),
+        write_limit_bypass_threshold: ReadableSize::kb(4),
     };
     value.coprocessor = CopConfig {
         split_region_on_table: true,
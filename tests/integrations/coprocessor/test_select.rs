@@ -15,19 +15,22 @@ use std::cmp;
 use std::i64;
 use std::thread;
 
+use futures::Stream;
 use protobuf::Message;
 
 use kvproto::coprocessor::Response;
 use kvproto::kvrpcpb::Context;
 use tipb::expression::{Expr, ExprType, ScalarFuncSig};
-use tipb::select::Chunk;
+use tipb::select::{Chunk, StreamResponse};
 
 use test_coprocessor::*;
 use test_storage::*;
 use tikv::coprocessor::codec::{datum, Datum};
+use tikv::coprocessor::ContinuationToken;
 use tikv::server::readpool;
 use tikv::server::Config;
 use tikv::storage::TestEngineBuilder;
+use tikv::util::config::ReadableSize;
 use tikv::util::codec::number::*;
 
 const FLAG_IGNORE_TRUNCATE: u64 = 1;
@@ -172,6 +175,99 @@ fn test_stream_batch_row_limit() {
     }
 }
 
+#[test]
+fn test_stream_resume() {
+    let data = vec![
+        (1, Some("name:0"), 2),
+        (2, Some("name:4"), 3),
+        (4, Some("name:3"), 1),
+        (5, Some("name:1"), 4),
+        (8, Some("name:2"), 4),
+    ];
+
+    let product = ProductTable::new();
+    let stream_row_limit = 2;
+    let (_, endpoint) = {
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let mut cfg = Config::default();
+        cfg.end_point_stream_batch_row_limit = stream_row_limit;
+        init_data_with_details(
+            Context::new(),
+            engine,
+            &product,
+            &data,
+            true,
+            &cfg,
+            &readpool::Config::default_for_test(),
+        )
+    };
+
+    let req = DAGSelect::from(&product).build();
+    // Only take the first response, as if the stream is broken after it.
+    let first = endpoint
+        .parse_and_handle_stream_request(req.clone(), None)
+        .wait()
+        .next()
+        .unwrap()
+        .unwrap();
+    let token = ContinuationToken::from_stream_response(&req, &first).unwrap().unwrap();
+
+    let mut rows = vec![];
+    let resps = endpoint.resume_stream_request(req, &token.encode(), None).wait();
+    for resp in resps {
+        let resp = resp.unwrap();
+        assert!(!resp.has_region_error(), "{:?}", resp);
+        let mut stream_resp = StreamResponse::new();
+        stream_resp.merge_from_bytes(resp.get_data()).unwrap();
+        let mut chunk = Chunk::new();
+        chunk.merge_from_bytes(stream_resp.get_data()).unwrap();
+        rows.extend(DAGChunkSpliter::new(vec![chunk], 3));
+    }
+    assert_eq!(rows.len(), data.len() - stream_row_limit);
+    for (row, &(id, name, cnt)) in rows.iter().zip(&data[stream_row_limit..]) {
+        let name_datum = name.map(|s| s.as_bytes()).into();
+        let expected_encoded =
+            datum::encode_value(&[Datum::I64(id), name_datum, cnt.into()]).unwrap();
+        let result_encoded = datum::encode_value(row).unwrap();
+        assert_eq!(result_encoded, &*expected_encoded);
+    }
+}
+
+#[test]
+fn test_memory_quota() {
+    let data = vec![
+        (1, Some("name:0"), 2),
+        (2, Some("name:4"), 3),
+        (4, Some("name:3"), 1),
+        (5, Some("name:1"), 4),
+    ];
+
+    let product = ProductTable::new();
+    let init_with_quota = |quota| {
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let mut cfg = Config::default();
+        cfg.end_point_memory_quota = ReadableSize(quota);
+        init_data_with_details(
+            Context::new(),
+            engine,
+            &product,
+            &data,
+            true,
+            &cfg,
+            &readpool::Config::default_for_test(),
+        ).1
+    };
+
+    let endpoint = init_with_quota(16);
+    let resp = handle_request(&endpoint, DAGSelect::from(&product).build());
+    assert!(resp.get_other_error().contains("memory quota"), "{:?}", resp);
+
+    let endpoint = init_with_quota(1024);
+    let mut resp = handle_select(&endpoint, DAGSelect::from(&product).build());
+    let spliter = DAGChunkSpliter::new(resp.take_chunks().into_vec(), 3);
+    assert_eq!(spliter.count(), data.len());
+}
+
 #[test]
 fn test_select_after_lease() {
     let data = vec![
@@ -200,6 +296,53 @@ fn test_select_after_lease() {
     }
 }
 
+#[test]
+fn test_result_cache() {
+    let data = vec![
+        (1, Some("name:0"), 2),
+        (2, Some("name:4"), 3),
+        (4, Some("name:3"), 1),
+        (5, Some("name:1"), 4),
+    ];
+
+    let product = ProductTable::new();
+    let (_cluster, raft_engine, ctx) = new_raft_engine(1, "");
+    let mut cfg = Config::default();
+    cfg.end_point_cache_capacity = ReadableSize::mb(1);
+    let (mut store, endpoint) = init_data_with_details(
+        ctx.clone(),
+        raft_engine,
+        &product,
+        &data,
+        true,
+        &cfg,
+        &readpool::Config::default_for_test(),
+    );
+
+    let mut req = DAGSelect::from(&product).build_with(ctx.clone(), &[0]);
+    req.mut_context().set_scan_detail(true);
+    let resp = handle_request(&endpoint, req.clone());
+    assert!(!resp.get_data().is_empty());
+    assert!(resp.get_exec_details().get_scan_detail().get_write().get_total() > 0);
+
+    // The same request is served from the cache, without scanning the region again.
+    let cached = handle_request(&endpoint, req.clone());
+    assert_eq!(cached.get_data(), resp.get_data());
+    assert_eq!(cached.get_exec_details().get_scan_detail().get_write().get_total(), 0);
+
+    // The region is scanned again once it's written.
+    store.begin();
+    store
+        .insert_into(&product)
+        .set(&product["id"], Datum::I64(6))
+        .set(&product["name"], Datum::Null)
+        .set(&product["count"], Datum::I64(0))
+        .execute_with_ctx(ctx.clone());
+    store.commit_with_ctx(ctx);
+    let resp = handle_request(&endpoint, req);
+    assert!(resp.get_exec_details().get_scan_detail().get_write().get_total() > 0);
+}
+
 #[test]
 fn test_scan_detail() {
     let data = vec![
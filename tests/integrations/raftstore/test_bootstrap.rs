@@ -23,7 +23,7 @@ use test_raftstore::*;
 use tikv::import::SSTImporter;
 use tikv::raftstore::coprocessor::CoprocessorHost;
 use tikv::raftstore::store::{
-    bootstrap_store, create_event_loop, keys, Engines, Peekable, SnapManager,
+    bootstrap_store, create_event_loop, keys, Engines, HostedRegions, Peekable, SnapManager,
 };
 use tikv::server::Node;
 use tikv::storage::{ALL_CFS, CF_RAFT};
@@ -112,6 +112,7 @@ fn test_node_bootstrap_with_prepared_data() {
         local_reader,
         coprocessor_host,
         importer,
+        HostedRegions::default(),
     ).unwrap();
     assert!(
         Arc::clone(&engine)
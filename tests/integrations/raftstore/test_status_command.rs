@@ -11,7 +11,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::mpsc;
+use std::time::Duration;
+
 use test_raftstore::*;
+use tikv::raftstore::store::RegionReadProgress;
+use tikv::raftstore::{Error, Result};
+use tikv::server::transport::RaftStoreRouter;
+use tikv::util::HandyRwLock;
 
 #[test]
 fn test_region_detail() {
@@ -34,3 +41,37 @@ fn test_region_detail() {
     assert!(region_detail.has_leader());
     assert_eq!(region_detail.get_leader(), &leader);
 }
+
+#[test]
+fn test_region_read_progress() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let leader = cluster.leader_of_region(1).unwrap();
+    must_get_equal(&cluster.get_engine(1), b"k1", b"v1");
+
+    let router = cluster.sim.rl().get_node_router(1);
+    let (tx, rx) = mpsc::channel();
+    let tx1 = tx.clone();
+    router
+        .region_read_progress(
+            1,
+            box move |res: Result<RegionReadProgress>| tx1.send(res).unwrap(),
+        )
+        .unwrap();
+    let progress = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+    assert!(progress.applied_index > 0);
+    assert!(progress.committed_index >= progress.applied_index);
+    assert_eq!(progress.leader, Some(leader));
+
+    router
+        .region_read_progress(
+            2,
+            box move |res: Result<RegionReadProgress>| tx.send(res).unwrap(),
+        )
+        .unwrap();
+    match rx.recv_timeout(Duration::from_secs(3)).unwrap() {
+        Err(Error::RegionNotFound(2)) => {}
+        res => panic!("expect region not found, but got {:?}", res),
+    }
+}
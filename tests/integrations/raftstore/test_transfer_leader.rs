@@ -11,7 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
@@ -19,6 +19,7 @@ use raft::eraftpb::MessageType;
 
 use test_raftstore::*;
 use tikv::util::config::*;
+use tikv::util::HandyRwLock;
 
 fn test_basic_transfer_leader<T: Simulator>(cluster: &mut Cluster<T>) {
     cluster.cfg.raft_store.raft_heartbeat_ticks = 20;
@@ -186,3 +187,37 @@ fn test_node_transfer_leader_during_snapshot() {
     let mut cluster = new_node_cluster(0, 3);
     test_transfer_leader_during_snapshot(&mut cluster);
 }
+
+#[test]
+fn test_node_leader_change_subscription() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+
+    let notified = Arc::new(Mutex::new(vec![]));
+    let notified1 = Arc::clone(&notified);
+    cluster
+        .sim
+        .rl()
+        .get_hosted_regions(3)
+        .subscribe_leader_change(1, box move |is_leader| notified1.lock().unwrap().push(is_leader))
+        .unwrap();
+
+    // The peer on store 3 learns every new leader, but stays a follower.
+    for &id in &[2, 1, 2] {
+        cluster.must_transfer_leader(1, new_peer(id, id));
+    }
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&cluster.get_engine(3), b"k2", b"v2");
+    assert!(notified.lock().unwrap().is_empty());
+
+    cluster.must_transfer_leader(1, new_peer(3, 3));
+    for _ in 0..100 {
+        if !notified.lock().unwrap().is_empty() {
+            break;
+        }
+        sleep_ms(10);
+    }
+    assert_eq!(*notified.lock().unwrap(), vec![true]);
+}
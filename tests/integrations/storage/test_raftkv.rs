@@ -16,7 +16,7 @@ use kvproto::kvrpcpb::Context;
 use test_raftstore::*;
 use tikv::raftstore::store::engine::IterOption;
 use tikv::storage::engine::*;
-use tikv::storage::{CFStatistics, CfName, Key, CF_DEFAULT};
+use tikv::storage::{CFStatistics, CfName, Key, CF_DEFAULT, CF_WRITE};
 use tikv::util::codec::bytes;
 use tikv::util::escape;
 use tikv::util::HandyRwLock;
@@ -41,11 +41,16 @@ fn test_raftkv() {
 
     get_put(&ctx, &storage);
     batch(&ctx, &storage);
+    delete_range(&ctx, &storage);
     seek(&ctx, &storage);
     near_seek(&ctx, &storage);
     cf(&ctx, &storage);
     empty_write(&ctx, &storage);
     wrong_context(&ctx, &storage);
+    batch_snapshot(&ctx, &storage);
+    flush(&ctx, &storage);
+    write_result(&ctx, &storage);
+    sync_snapshot(&ctx, &storage);
     // TODO: test multiple node
 }
 
@@ -81,6 +86,101 @@ fn test_read_leader_in_lease() {
     assert_eq!(can_read(&ctx, &storage, k2, v2), true);
 }
 
+#[test]
+fn test_approximate_size() {
+    let mut cluster = new_server_cluster(0, 1);
+    cluster.run();
+    assert_eq!(cluster.must_get(b"k1"), None);
+
+    let region = cluster.get_region(b"");
+    let leader = cluster.leader_of_region(region.get_id()).unwrap();
+    let storage = cluster.sim.rl().storages[&leader.get_id()].clone();
+    let mut ctx = Context::new();
+    ctx.set_region_id(region.get_id());
+    ctx.set_region_epoch(region.get_region_epoch().clone());
+    ctx.set_peer(leader.clone());
+
+    for i in 0..100 {
+        must_put(&ctx, &storage, format!("k{:03}", i).as_bytes(), &[b'v'; 100]);
+    }
+    cluster.get_engine(leader.get_store_id()).flush(true).unwrap();
+    let size = storage.approximate_size(&ctx).unwrap();
+    assert!(size.size >= 100 * 100, "{:?}", size);
+
+    // The estimate is reused for a while.
+    must_put(&ctx, &storage, b"k100", &[b'v'; 1024]);
+    assert_eq!(storage.approximate_size(&ctx).unwrap(), size);
+
+    // The region is checked like reads.
+    let mut stale_ctx = ctx.clone();
+    stale_ctx.mut_region_epoch().set_version(0);
+    match storage.approximate_size(&stale_ctx) {
+        Err(Error::Request(ref e)) if e.has_stale_epoch() => {}
+        res => panic!("expect stale epoch, but got {:?}", res),
+    }
+
+    // The cached estimate isn't served once the epoch of the request is stale.
+    cluster.must_split(&region, &Key::from_raw(b"k050").into_encoded());
+    match storage.approximate_size(&ctx) {
+        Err(Error::Request(ref e)) if e.has_stale_epoch() => {}
+        res => panic!("expect stale epoch, but got {:?}", res),
+    }
+}
+
+#[test]
+fn test_delete_range_out_of_region() {
+    let mut cluster = new_server_cluster(0, 1);
+    cluster.run();
+    assert_eq!(cluster.must_get(b"k1"), None);
+
+    let region = cluster.get_region(b"");
+    cluster.must_split(&region, &Key::from_raw(b"r3").into_encoded());
+    let (left, right) = {
+        let new_ctx = |key: &[u8]| {
+            let region = cluster.get_region(&Key::from_raw(key).into_encoded());
+            let mut ctx = Context::new();
+            ctx.set_region_id(region.get_id());
+            ctx.set_region_epoch(region.get_region_epoch().clone());
+            ctx.set_peer(region.get_peers()[0].clone());
+            ctx
+        };
+        (new_ctx(b"r1"), new_ctx(b"r3"))
+    };
+    let leader = cluster.leader_of_region(left.get_region_id()).unwrap();
+    cluster.leader_of_region(right.get_region_id()).unwrap();
+    let storage = cluster.sim.rl().storages[&leader.get_id()].clone();
+
+    for k in &[b"r1", b"r2"] {
+        must_put(&left, &storage, *k, b"v");
+    }
+    for k in &[b"r3", b"r4"] {
+        must_put(&right, &storage, *k, b"v");
+    }
+
+    // A range out of the region is refused.
+    let range = |start: &[u8], end: Vec<u8>| {
+        Modify::DeleteRange(CF_DEFAULT, Key::from_raw(start), Key::from_encoded(end))
+    };
+    match storage.write(&left, vec![range(b"r3", vec![])]) {
+        Err(Error::Request(ref e)) if e.has_key_not_in_region() => {}
+        res => panic!("expect key not in region, but got {:?}", res),
+    }
+
+    // A range running past the end of the region, where an empty end key is the end of the
+    // key space, only deletes the keys in the region.
+    storage.write(&left, vec![range(b"r2", vec![])]).unwrap();
+    assert_has(&left, &storage, b"r1", b"v");
+    assert_none(&left, &storage, b"r2");
+    assert_has(&right, &storage, b"r3", b"v");
+
+    // So is a range running past the start of the region.
+    let end = Key::from_raw(b"r4").into_encoded();
+    storage.write(&right, vec![range(b"", end)]).unwrap();
+    assert_has(&left, &storage, b"r1", b"v");
+    assert_none(&right, &storage, b"r3");
+    assert_has(&right, &storage, b"r4", b"v");
+}
+
 fn must_put<E: Engine>(ctx: &Context, engine: &E, key: &[u8], value: &[u8]) {
     engine.put(ctx, Key::from_raw(key), value.to_vec()).unwrap();
 }
@@ -216,6 +316,37 @@ fn batch<E: Engine>(ctx: &Context, engine: &E) {
     assert_none(ctx, engine, b"y");
 }
 
+fn batch_snapshot<E: Engine>(ctx: &Context, engine: &E) {
+    must_put(ctx, engine, b"x", b"1");
+    let mut wrong_ctx = ctx.to_owned();
+    wrong_ctx.set_region_id(ctx.get_region_id() + 1);
+    let batch = vec![ctx.clone(), wrong_ctx, ctx.clone(), ctx.clone()];
+
+    let (tx, rx) = ::std::sync::mpsc::channel();
+    engine
+        .async_batch_snapshot(batch, box move |res| tx.send(res).unwrap())
+        .unwrap();
+    let snaps: Vec<_> = rx.recv().unwrap().into_iter().map(|(_, s)| s).collect();
+    assert_eq!(snaps.len(), 4);
+    assert!(snaps[1].is_err());
+    for i in &[0, 2, 3] {
+        let v = snaps[*i].as_ref().unwrap().get(&Key::from_raw(b"x")).unwrap();
+        assert_eq!(v.unwrap(), b"1");
+    }
+    must_delete(ctx, engine, b"x");
+}
+
+fn flush<E: Engine>(ctx: &Context, engine: &E) {
+    must_put(ctx, engine, b"x", b"1");
+    engine.flush(ctx).unwrap();
+    assert_has(ctx, engine, b"x", b"1");
+
+    let mut wrong_ctx = ctx.to_owned();
+    wrong_ctx.set_region_id(ctx.get_region_id() + 1);
+    assert!(engine.flush(&wrong_ctx).is_err());
+    must_delete(ctx, engine, b"x");
+}
+
 fn seek<E: Engine>(ctx: &Context, engine: &E) {
     must_put(ctx, engine, b"x", b"1");
     assert_seek(ctx, engine, b"x", (b"x", b"1"));
@@ -269,10 +400,58 @@ fn cf<E: Engine>(ctx: &Context, engine: &E) {
     assert_none_cf(ctx, engine, "default", b"key");
 }
 
+fn delete_range<E: Engine>(ctx: &Context, engine: &E) {
+    for k in &[b"r1", b"r2", b"r3", b"r4"] {
+        must_put(ctx, engine, *k, b"v");
+        must_put_cf(ctx, engine, CF_WRITE, *k, b"v");
+    }
+    let range = |start: &[u8], end: &[u8]| {
+        Modify::DeleteRange(CF_DEFAULT, Key::from_raw(start), Key::from_raw(end))
+    };
+    engine.write(ctx, vec![range(b"r2", b"r4")]).unwrap();
+    assert_has(ctx, engine, b"r1", b"v");
+    assert_none(ctx, engine, b"r2");
+    assert_none(ctx, engine, b"r3");
+    assert_has(ctx, engine, b"r4", b"v");
+    // Other column families are untouched.
+    assert_has_cf(ctx, engine, CF_WRITE, b"r2", b"v");
+
+    engine.write(ctx, vec![range(b"r1", b"r5")]).unwrap();
+    for k in &[b"r1", b"r2", b"r3", b"r4"] {
+        assert_none(ctx, engine, *k);
+        must_delete_cf(ctx, engine, CF_WRITE, *k);
+    }
+}
+
 fn empty_write<E: Engine>(ctx: &Context, engine: &E) {
     engine.write(ctx, vec![]).unwrap_err();
 }
 
+fn write_result<E: Engine>(ctx: &Context, engine: &E) {
+    let mut last_index = 0;
+    for i in 0..5 {
+        let put = Modify::Put(CF_DEFAULT, Key::from_raw(b"x"), vec![i]);
+        let res = engine.write_with_result(ctx, vec![put]).unwrap();
+        assert!(res.applied_index > last_index, "{:?} {}", res, last_index);
+        last_index = res.applied_index;
+    }
+    must_delete(ctx, engine, b"x");
+}
+
+fn sync_snapshot(ctx: &Context, engine: &SimulateEngine) {
+    must_put(ctx, engine, b"x", b"1");
+    let snap = engine.sync_snapshot(ctx).unwrap();
+    assert_eq!(snap.get(&Key::from_raw(b"x")).unwrap().unwrap(), b"1");
+    must_delete(ctx, engine, b"x");
+
+    let mut stale_ctx = ctx.to_owned();
+    stale_ctx.mut_region_epoch().set_version(0);
+    match engine.sync_snapshot(&stale_ctx) {
+        Err(Error::Request(ref e)) if e.has_stale_epoch() => {}
+        res => panic!("expect stale epoch, but got {:?}", res.map(|_| ())),
+    }
+}
+
 fn wrong_context<E: Engine>(ctx: &Context, engine: &E) {
     let region_id = ctx.get_region_id();
     let mut ctx = ctx.to_owned();